@@ -1,34 +1,247 @@
 use anyhow::{anyhow, Error};
+use regex::Regex;
 use ron::ser::PrettyConfig;
 use serde::{Deserialize, Serialize};
 
-use crate::command::Command;
-use crate::debug;
+use crate::command::{
+    signal_name, Command, CommandResult, ResourceUsage, RunOptions, Timeout, PARTIAL_LINE,
+};
+use crate::encryption::{
+    from_hex, is_wrong_or_missing_key, to_hex, wrong_or_missing_key_error, EncryptionKey,
+    OutputDecryptor, OutputEncryptor,
+};
+use crate::env::EnvSnapshotOptions;
+use crate::output::Output;
+use crate::timestamp::describe_duration;
+use crate::{debug, info, warn};
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Read};
-use std::os::unix::fs::PermissionsExt;
+use std::io::{BufRead, BufReader, Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use ulid::Ulid;
 
+pub mod layered;
+pub mod memory;
+pub mod redis;
+pub mod sqlite;
+
+/// Options controlling how commands are run and whether their results are recorded.
 pub struct RecordOptions {
     /// The duration to cache a recorded result for.
     cache_for: Option<Duration>,
+    /// When a recorded result expires, as an absolute time.
+    expire_at: Option<SystemTime>,
     /// Array of exit codes to record, where the index is the exit code (so when `exit_codes[0] == true` we record the result for exit code 0).
     exit_codes: [bool; 256],
+    /// Only record when a line of stdout matches this pattern.
+    record_if_output_matches: Option<Regex>,
+    /// Don't record when a line of stdout matches this pattern.
+    skip_record_if_output_matches: Option<Regex>,
+    /// Only record when the command printed something to stdout (or stderr, when
+    /// `OutputRequired::Any` is used).
+    output_required: OutputRequired,
+    /// Record results of commands killed by a signal.
+    record_signals: bool,
+    /// How many previous results to keep when recording a new one.
+    keep_history: usize,
+    /// Environment variables to record alongside the result.
+    env_snapshot: Option<EnvSnapshotOptions>,
+    /// Labels to record alongside the result, for listing and removing results together.
+    tags: BTreeSet<String>,
+    /// An identifier to record alongside the result, like a ticket number or pipeline run.
+    id: Option<String>,
+    /// Stop commands running longer than this, without recording their result.
+    timeout: Option<Timeout>,
+    /// The exit code returned when a command times out.
+    timeout_exit_code: i32,
+    /// Capture the command's output without passing it through to stdout and stderr.
+    silent: bool,
+    /// Writers to pass the command's output through to, instead of stdout and stderr.
+    passthrough: Option<(SharedWriter, SharedWriter)>,
+}
+
+/// Which output a command must produce for its result to be recorded.
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputRequired {
+    /// Record whatever the command printed.
+    #[default]
+    None,
+    /// Only record when the command printed something to stdout.
+    Stdout,
+    /// Only record when the command printed something to stdout or stderr.
+    Any,
 }
 
 impl RecordOptions {
+    /// Sets which exit codes are recorded, indexed by exit code.
     pub fn set_exit_codes(&mut self, exit_codes: [bool; 256]) {
         self.exit_codes = exit_codes;
     }
 
+    /// Sets how long recorded results are cached for.
     pub fn set_cache_for(&mut self, cache_for: Option<Duration>) {
         self.cache_for = cache_for;
     }
 
+    /// Sets when recorded results expire, as an absolute time.
+    pub fn set_expire_at(&mut self, expire_at: Option<SystemTime>) {
+        self.expire_at = expire_at;
+    }
+
+    /// Only records results when a line of stdout matches `pattern`.
+    pub fn set_record_if_output_matches(&mut self, pattern: Option<Regex>) {
+        self.record_if_output_matches = pattern;
+    }
+
+    /// Doesn't record results when a line of stdout matches `pattern`.
+    pub fn set_skip_record_if_output_matches(&mut self, pattern: Option<Regex>) {
+        self.skip_record_if_output_matches = pattern;
+    }
+
+    /// Sets which output a command must produce for its result to be recorded.
+    pub fn set_output_required(&mut self, output_required: OutputRequired) {
+        self.output_required = output_required;
+    }
+
+    /// Sets whether results of commands killed by a signal are recorded.
+    pub fn set_record_signals(&mut self, record_signals: bool) {
+        self.record_signals = record_signals;
+    }
+
+    /// Sets how many previous results are kept when recording a new one.
+    pub fn set_keep_history(&mut self, keep_history: usize) {
+        self.keep_history = keep_history;
+    }
+
+    /// Sets the environment variables recorded alongside results.
+    pub fn set_env_snapshot(&mut self, env_snapshot: Option<EnvSnapshotOptions>) {
+        self.env_snapshot = env_snapshot;
+    }
+
+    /// Sets the tags recorded alongside results.
+    pub fn set_tags(&mut self, tags: BTreeSet<String>) {
+        self.tags = tags;
+    }
+
+    /// Sets the id recorded alongside results.
+    pub fn set_id(&mut self, id: Option<String>) {
+        self.id = id;
+    }
+
+    /// Stops commands running longer than `timeout`, exiting with `exit_code` instead.
+    pub fn set_timeout(&mut self, timeout: Option<Timeout>, exit_code: i32) {
+        self.timeout = timeout;
+        self.timeout_exit_code = exit_code;
+    }
+
+    /// Runs commands without passing their output through to stdout and stderr, for library
+    /// users that read the recorded output instead.
+    pub fn set_silent(&mut self, silent: bool) {
+        self.silent = silent;
+    }
+
+    /// Passes the command's output through to the given writers rather than stdout and stderr,
+    /// so it can be read as it was printed while still being recorded.
+    pub(crate) fn set_passthrough(&mut self, passthrough: Option<(SharedWriter, SharedWriter)>) {
+        self.passthrough = passthrough;
+    }
+
+    /// Whether a result with `exit_code` should be recorded.
     pub fn should_record(&self, exit_code: i32) -> bool {
         self.exit_codes[exit_code as usize]
     }
+
+    /// How the command is run. Stdout is checked against patterns in the order expected by
+    /// `skip_reason`.
+    pub(crate) fn run_options(&self) -> RunOptions {
+        RunOptions {
+            stdout_patterns: [
+                &self.record_if_output_matches,
+                &self.skip_record_if_output_matches,
+            ]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect(),
+            timeout: self.timeout,
+            silent: self.silent,
+            passthrough: self.passthrough.clone(),
+        }
+    }
+
+    /// When a result recorded at `now` expires, if ever.
+    pub(crate) fn expires_at(&self, now: SystemTime) -> Option<SystemTime> {
+        self.expire_at
+            .or(self.cache_for.map(|duration| now + duration))
+    }
+
+    /// The environment variables recorded alongside a result.
+    pub(crate) fn env(&self) -> BTreeMap<String, String> {
+        self.env_snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.snapshot())
+            .unwrap_or_default()
+    }
+
+    /// The tags recorded alongside a result.
+    pub(crate) fn tags(&self) -> &BTreeSet<String> {
+        &self.tags
+    }
+
+    /// The identifier recorded alongside a result.
+    pub(crate) fn id(&self) -> Option<String> {
+        self.id.clone()
+    }
+
+    /// Explains why a result shouldn't be recorded, or returns `None` if it should.
+    pub(crate) fn skip_reason(
+        &self,
+        exit_code: i32,
+        signal: Option<i32>,
+        output_matches: &[bool],
+        stdout_len: u64,
+        stderr_len: u64,
+    ) -> Option<String> {
+        if let Some(signal) = signal.filter(|_| !self.record_signals) {
+            return Some(format!("killed by {}", signal_name(signal)));
+        }
+
+        if !self.should_record(exit_code) {
+            return Some(format!("exit code {} isn't recorded", exit_code));
+        }
+
+        match self.output_required {
+            OutputRequired::Stdout if stdout_len == 0 => {
+                return Some("no output to stdout".into());
+            }
+            OutputRequired::Any if stdout_len == 0 && stderr_len == 0 => {
+                return Some("no output".into());
+            }
+            _ => (),
+        }
+
+        let mut output_matches = output_matches.iter().copied();
+
+        if let Some(pattern) = &self.record_if_output_matches {
+            if !output_matches.next().unwrap_or(false) {
+                return Some(format!("output doesn't match '{}'", pattern));
+            }
+        }
+
+        if let Some(pattern) = &self.skip_record_if_output_matches {
+            if output_matches.next().unwrap_or(false) {
+                return Some(format!("output matches '{}'", pattern));
+            }
+        }
+
+        None
+    }
 }
 
 impl Default for RecordOptions {
@@ -39,293 +252,3105 @@ impl Default for RecordOptions {
         RecordOptions {
             exit_codes,
             cache_for: None,
+            expire_at: None,
+            record_if_output_matches: None,
+            skip_record_if_output_matches: None,
+            output_required: OutputRequired::None,
+            record_signals: false,
+            keep_history: 0,
+            env_snapshot: None,
+            tags: BTreeSet::new(),
+            id: None,
+            timeout: None,
+            timeout_exit_code: 124,
+            silent: false,
+            passthrough: None,
         }
     }
 }
 
+/// Options controlling which cached results can be returned.
 #[derive(Default)]
 pub struct FindOptions {
     /// The maximum age of a cached result to consider. Results older than this will be ignored.
     pub max_age: Option<Duration>,
+    /// How long after a cached result becomes stale (by age or expiry) it can still be returned
+    /// while a new result is recorded in the background.
+    pub stale_while_revalidate: Option<Duration>,
+    /// Whether results past their expiry can be returned when no fresh result is found.
+    pub allow_expired: bool,
+    /// How long past their expiry results can be returned, when `allow_expired` is set.
+    pub allow_expired_for: Option<Duration>,
+    /// Moves the expiry of a fresh result this far from now each time it's found, so results
+    /// only expire once they go unused.
+    pub renew_for: Option<Duration>,
 }
 
 impl FindOptions {
+    /// Ignores results older than this.
     pub fn set_max_age(&mut self, s: Option<Duration>) {
         self.max_age = s;
     }
-}
 
-pub trait Cache<T: CacheEntry> {
-    fn remove(&self, hash: &str) -> anyhow::Result<bool>;
-    fn record(&self, command: &mut Command, options: &RecordOptions) -> anyhow::Result<i32>;
-    fn read(&self, hash: &str) -> anyhow::Result<Option<T>>;
-    fn find(&self, hash: &str, options: &FindOptions) -> anyhow::Result<Option<T>> {
-        self.read(hash).map(|result| {
-            result.filter(|result| result.is_fresh()).filter(|result| {
-                options
-                    .max_age
-                    .is_none_or(|duration| result.is_younger_than(duration))
-            })
-        })
+    /// Returns stale results for this long while recording a new result in the background.
+    pub fn set_stale_while_revalidate(&mut self, s: Option<Duration>) {
+        self.stale_while_revalidate = s;
     }
-}
 
-pub struct DiskCache {
-    root: std::path::PathBuf,
-    shared: bool,
-}
+    /// Returns expired results when no fresh result is found, for up to `s` past their expiry.
+    pub fn set_allow_expired(&mut self, allow_expired: bool, s: Option<Duration>) {
+        self.allow_expired = allow_expired;
+        self.allow_expired_for = s;
+    }
 
-impl DiskCache {
-    pub fn new(root: PathBuf, shared: bool) -> anyhow::Result<DiskCache> {
-        create_cache_dir(root.as_path(), shared)
-            .map_err(|_| unable_to_write_to_cache_error(&root))?;
-        Ok(DiskCache { root, shared })
+    /// Moves the expiry of found results this far from now.
+    pub fn set_renew_for(&mut self, s: Option<Duration>) {
+        self.renew_for = s;
     }
 
-    fn path(&self, hash: &str, suffix: &str) -> std::path::PathBuf {
-        self.root.join(format!("{hash}.{suffix}"))
+    /// Whether results past their expiry might still be used.
+    fn uses_expired(&self) -> bool {
+        self.allow_expired || self.stale_while_revalidate.is_some()
     }
+}
 
-    fn create_file(&self, path: &PathBuf) -> anyhow::Result<File> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(path)
-            .map_err(|_| unable_to_write_to_cache_error(path))?;
+/// Which results to include by the tags and id they were recorded with, as given to `list`
+/// and `remove` with `--tag` and `--id`.
+#[derive(Debug, Default, Clone)]
+pub struct TagFilter {
+    /// Results must have every one of these tags (or with `any`, at least one of them). No
+    /// tags matches every result.
+    pub tags: Vec<String>,
+    /// Whether results need only one of the tags, rather than all of them.
+    pub any: bool,
+    /// Results must have an id matching this pattern, where `*` matches any sequence of
+    /// characters, ignoring case.
+    pub id: Option<String>,
+}
 
-        let mode = if self.shared { 0o666 } else { 0o600 };
-        let mut file_permissions = file.metadata()?.permissions();
-        file_permissions.set_mode(mode);
-        std::fs::set_permissions(path, file_permissions)?;
-        Ok(file)
+impl TagFilter {
+    /// Whether the filter includes every result.
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty() && self.id.is_none()
     }
 
-    fn write(&self, hash: &str, entry: DiskCacheEntry) -> anyhow::Result<()> {
-        let path = self.path(hash, "ron");
-        let file = self.create_file(&path)?;
-        ron::ser::to_writer_pretty(file, &entry, PrettyConfig::default())
-            .map_err(|_| unable_to_write_to_cache_error(&path))?;
-        Ok(())
+    /// Whether `entry` has the tags and id the filter asks for.
+    pub fn matches(&self, entry: &impl CacheEntry) -> bool {
+        let has = |tag: &String| entry.tags().contains(tag);
+        let tagged = match self.any {
+            _ if self.tags.is_empty() => true,
+            true => self.tags.iter().any(has),
+            false => self.tags.iter().all(has),
+        };
+        tagged
+            && self.id.as_ref().is_none_or(|pattern| {
+                entry
+                    .id()
+                    .is_some_and(|id| crate::env::matches(pattern, id))
+            })
     }
 }
 
-pub fn unable_to_write_to_cache_error(path: &Path) -> Error {
-    anyhow!("unable to write file to cache {}", path.display())
+/// How `run` waits for another process running the same command.
+#[derive(Default)]
+pub struct LockOptions {
+    /// Run the command straight away, rather than waiting for the other process.
+    pub no_wait: bool,
+    /// How long to wait for the other process, before running the command anyway.
+    pub timeout: Option<Duration>,
 }
 
-pub fn unable_to_read_cache_entry_error(path: &Path) -> Error {
-    anyhow!("unable to read file from cache {}", path.display())
+/// An exclusive lock on a cache entry, released when dropped.
+pub struct CacheLock {
+    _file: Option<File>,
+    /// Releases locks that aren't held by an open file.
+    release: Option<Box<dyn FnOnce()>>,
 }
 
-fn create_cache_dir(path: &Path, shared: bool) -> anyhow::Result<()> {
-    if !path.exists() {
-        let grandparent = path.parent().unwrap();
-        if !grandparent.exists() {
-            std::fs::DirBuilder::new()
-                .recursive(true)
-                .create(grandparent)?;
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        if let Some(release) = self.release.take() {
+            release();
         }
-
-        std::fs::DirBuilder::new().create(path)?;
-        let mode = if shared { 0o777 } else { 0o700 };
-        let mut cache_permissions = path.metadata()?.permissions();
-        cache_permissions.set_mode(mode);
-        std::fs::set_permissions(path, cache_permissions)?;
     }
-    Ok(())
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct DiskCacheEntryMeta {
-    command: Command,
-    created: SystemTime,
-    expires: Option<SystemTime>,
-    status: i32,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct DiskCacheEntry {
-    meta: DiskCacheEntryMeta,
-    stdout: PathBuf,
-    stderr: PathBuf,
+/// The result of looking for a cached entry, including why an existing entry can't be used.
+pub enum FindOutcome<T> {
+    /// A usable entry was found.
+    Fresh(T),
+    /// No entry exists.
+    Missing,
+    /// An entry exists, but has passed its expiry (set with `--cache-for`).
+    Expired(T),
+    /// An entry exists, but is older than the maximum age (set with `--look-back`).
+    Stale(T),
 }
 
-impl CacheEntry for DiskCacheEntry {
-    fn created_at(&self) -> SystemTime {
-        self.meta.created
+impl<T> FindOutcome<T> {
+    /// The entry found, whether or not it's usable.
+    pub fn entry(&self) -> Option<&T> {
+        match self {
+            FindOutcome::Fresh(entry) | FindOutcome::Expired(entry) | FindOutcome::Stale(entry) => {
+                Some(entry)
+            }
+            FindOutcome::Missing => None,
+        }
     }
 
-    fn expires_at(&self) -> Option<SystemTime> {
-        self.meta.expires
+    pub fn fresh(self) -> Option<T> {
+        match self {
+            FindOutcome::Fresh(entry) => Some(entry),
+            _ => None,
+        }
     }
+}
 
-    fn command_status(&self) -> i32 {
-        self.meta.status
+impl<T: CacheEntry> FindOutcome<T> {
+    /// Checks whether the entry read for a command, if any, can be used.
+    fn new(entry: Option<T>, options: &FindOptions) -> Self {
+        match entry {
+            None => FindOutcome::Missing,
+            Some(entry) if !entry.is_fresh() => FindOutcome::Expired(entry),
+            Some(entry)
+                if !options
+                    .max_age
+                    .is_none_or(|duration| entry.is_younger_than(duration)) =>
+            {
+                FindOutcome::Stale(entry)
+            }
+            Some(entry) => FindOutcome::Fresh(entry),
+        }
     }
 
-    fn replay_command_output(&self) -> anyhow::Result<()> {
-        replay_output(File::open(&self.stdout)?, File::open(&self.stderr)?);
-        Ok(())
+    /// Why no usable entry was found, in a few words (such as "expired 3m ago"), or `None`
+    /// when one was.
+    pub fn reason(&self, options: &FindOptions) -> Option<String> {
+        match self {
+            FindOutcome::Fresh(_) => None,
+            FindOutcome::Missing => Some("missing".into()),
+            FindOutcome::Expired(entry) => Some(format!(
+                "expired {} ago",
+                ago(entry.expires_at().unwrap_or_else(|| entry.created_at()))
+            )),
+            FindOutcome::Stale(entry) => Some(format!(
+                "older than --look-back {} (created {} ago)",
+                describe_duration(options.max_age.unwrap_or_default()),
+                ago(entry.created_at())
+            )),
+        }
     }
 }
 
-impl Cache<DiskCacheEntry> for DiskCache {
-    fn read(&self, hash: &str) -> anyhow::Result<Option<DiskCacheEntry>> {
-        let path = self.path(hash, "ron");
-        debug(format!("looking for path: {}", path.display()));
-        if path.exists() {
-            let file =
-                std::fs::File::open(&path).map_err(|_| unable_to_read_cache_entry_error(&path))?;
-            let reader = BufReader::new(file);
-            let result: DiskCacheEntry = ron::de::from_reader(reader)?;
-            Ok(Some(result))
-        } else {
-            Ok(None)
+/// A command with a result in the cache, as listed for shell completion.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CachedCommand {
+    pub hash: String,
+    /// The command and its arguments, or the whole command line when run through a shell.
+    pub words: Vec<String>,
+    /// Whether the command was run through a shell, with `--shell`.
+    pub shell: bool,
+}
+
+impl CachedCommand {
+    pub fn new(hash: &str, command: &Command) -> CachedCommand {
+        let scope = &command.scope;
+        CachedCommand {
+            hash: hash.to_string(),
+            words: std::iter::once(scope.cmd().to_string())
+                .chain(scope.args().iter().cloned())
+                .collect(),
+            shell: scope.shell().is_some(),
         }
     }
+}
 
-    fn record(&self, command: &mut Command, options: &RecordOptions) -> anyhow::Result<i32> {
-        let now = SystemTime::now();
-        let ulid = &command.ulid;
-
-        let out = self.path(command.hash(), &format!("{ulid}.out"));
-        let err = self.path(command.hash(), &format!("{ulid}.err"));
-
-        let out_file = self.create_file(&out)?;
-        let err_file = self.create_file(&err)?;
-
-        let (status, _, _) = command.run(out_file, err_file)?;
-
-        if options.should_record(status) {
-            let meta = DiskCacheEntryMeta {
-                command: command.clone(),
-                created: now,
-                expires: options.cache_for.map(|duration| now + duration),
-                status,
-            };
+/// How long ago a time was, to the second, like `1d 2h 3m`.
+pub(crate) fn ago(time: SystemTime) -> String {
+    describe_duration(time.elapsed().unwrap_or_default())
+}
 
-            let entry = DiskCacheEntry {
-                meta,
-                stdout: out,
-                stderr: err,
-            };
+/// How long until a time, to the second, like `45m`.
+pub(crate) fn until(time: SystemTime) -> String {
+    describe_duration(time.duration_since(SystemTime::now()).unwrap_or_default())
+}
 
-            if let Some(existing) = self.read(command.hash())? {
-                std::fs::remove_file(existing.stdout)?;
-                std::fs::remove_file(existing.stderr)?;
+/// Somewhere results are stored, keyed by the hash of the command that produced them.
+pub trait Cache<T: CacheEntry> {
+    /// Removes the result recorded for `hash`, returning whether there was one.
+    fn remove(&self, hash: &str) -> anyhow::Result<bool>;
+    /// Runs `command`, recording its result if `options` allow it, and returns its exit code.
+    fn record(&self, command: &mut Command, options: &RecordOptions) -> anyhow::Result<i32>;
+    /// Reads the result recorded for `hash`, whether or not it's fresh.
+    fn read(&self, hash: &str) -> anyhow::Result<Option<T>>;
+    /// Reads a result recorded with `--keep-history`, where generation 0 is the current result,
+    /// 1 the result before it, and so on.
+    fn read_generation(&self, hash: &str, generation: usize) -> anyhow::Result<Option<T>>;
+    /// Reads the current result, treating one that can't be decrypted with the key given (if
+    /// any) as missing, as when deciding whether to run a command. It's left in place for
+    /// whoever has the key, rather than replaced.
+    fn read_usable(&self, hash: &str) -> anyhow::Result<Option<T>> {
+        match self.read(hash) {
+            Err(e) if is_wrong_or_missing_key(&e) => {
+                info(format!("{}, treating as missing", e));
+                Ok(None)
             }
-
-            self.write(command.hash(), entry)?;
-        } else {
-            std::fs::remove_file(&out)?;
-            std::fs::remove_file(&err)?;
+            result => result,
         }
-        Ok(status)
     }
-
-    fn remove(&self, hash: &str) -> anyhow::Result<bool> {
-        let path = self.path(hash, "ron");
-        debug(format!("cache remove: {}, {}", hash, path.display()));
-        if path.exists() {
-            std::fs::remove_file(&path).map_err(|_| unable_to_write_to_cache_error(&path))?;
-            Ok(true)
-        } else {
-            Ok(false)
+    /// Reads the current result and all previous results kept with `--keep-history`.
+    fn history(&self, hash: &str) -> anyhow::Result<Vec<T>> {
+        let mut history = vec![];
+        while let Some(entry) = self.read_generation(hash, history.len())? {
+            history.push(entry);
         }
+        Ok(history)
     }
-}
-
-pub(crate) fn replay_output<O>(stdout: O, stderr: O)
-where
-    O: Read,
-{
-    let mut stdout = OutputReader {
-        reader: BufReader::new(stdout),
+    /// Reads every entry in the cache, with its hash.
+    fn list(&self) -> anyhow::Result<Vec<(String, T)>>;
+    /// Whether a corrupt result for `hash` has been found and set aside. Corrupt results are
+    /// treated as missing when read.
+    fn has_corrupt(&self, _hash: &str) -> bool {
+        false
     }
-    .peekable();
-
-    let mut stderr = OutputReader {
-        reader: BufReader::new(stderr),
+    /// Lists the commands with a current result, for completing them in the shell.
+    fn commands(&self) -> anyhow::Result<Vec<CachedCommand>> {
+        Ok(self
+            .list()?
+            .iter()
+            .map(|(hash, entry)| CachedCommand::new(hash, entry.command()))
+            .collect())
     }
-    .peekable();
+    /// Attempts to take an exclusive lock on the given hash, without waiting. Returns `None`
+    /// when the lock is already held by another process.
+    fn try_lock(&self, hash: &str) -> anyhow::Result<Option<CacheLock>>;
+    /// Removes the lock files left behind by commands that have finished, skipping any in use,
+    /// and returns how many were removed.
+    fn remove_unused_locks(&self) -> anyhow::Result<usize> {
+        Ok(0)
+    }
+    /// Stores a copy of an entry read from another cache as the current result for `hash`,
+    /// replacing any existing result.
+    fn store(&self, hash: &str, entry: &impl CacheEntry) -> anyhow::Result<()>;
+    /// Moves when the current result for `hash` expires, rewriting its metadata but not its
+    /// output.
+    fn set_expiry(&self, hash: &str, expires: SystemTime) -> anyhow::Result<()>;
+    /// Pins or unpins the current result for `hash`, rewriting its metadata but not its
+    /// output. Returns `false` when there's no result to pin.
+    fn set_pinned(&self, hash: &str, pinned: bool) -> anyhow::Result<bool>;
+    /// Takes an exclusive lock on the given hash, waiting for up to `timeout` (or forever when
+    /// `None`) for another process to release it. Returns `None` if the timeout passes.
+    fn lock(&self, hash: &str, timeout: Option<Duration>) -> anyhow::Result<Option<CacheLock>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(lock) = self.try_lock(hash)? {
+                return Ok(Some(lock));
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(None);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+    /// Finds a result that is no longer fresh, but still within the stale-while-revalidate window.
+    fn find_stale(&self, hash: &str, options: &FindOptions) -> anyhow::Result<Option<T>> {
+        let Some(window) = options.stale_while_revalidate else {
+            return Ok(None);
+        };
 
-    loop {
-        match (stdout.peek(), stderr.peek()) {
-            (Some((ot, ol)), Some((et, el))) => {
-                if ot < et {
-                    print!("{}", ol);
-                    stdout.next();
-                } else {
-                    eprint!("{}", el);
-                    stderr.next();
-                }
+        self.read_usable(hash).map(|result| {
+            result.filter(|result| {
+                result
+                    .stale_at(options.max_age)
+                    .is_some_and(|stale_at| SystemTime::now() < stale_at + window)
+            })
+        })
+    }
+    /// Reads the result for `hash` along with why it would or wouldn't be used, as shown by
+    /// `explain`.
+    fn lookup(&self, hash: &str, options: &FindOptions) -> anyhow::Result<FindOutcome<T>> {
+        Ok(FindOutcome::new(self.read(hash)?, options))
+    }
+    /// Like `lookup`, but uses `read_usable`, so a result that can't be decrypted is missing.
+    fn lookup_usable(&self, hash: &str, options: &FindOptions) -> anyhow::Result<FindOutcome<T>> {
+        Ok(FindOutcome::new(self.read_usable(hash)?, options))
+    }
+    /// Finds a fresh result. An expired result that the options don't allow to be used is
+    /// removed along the way.
+    fn find(&self, hash: &str, options: &FindOptions) -> anyhow::Result<Option<T>> {
+        let outcome = self.lookup_usable(hash, options)?;
+        match outcome.reason(options) {
+            Some(reason) => info(format!("no fresh result for {}: {}", hash, reason)),
+            None => info(format!("found fresh result for {}", hash)),
+        }
+        if let FindOutcome::Expired(result) = &outcome {
+            if !options.uses_expired() {
+                self.remove_expired(hash, result);
             }
-            (Some((_, ol)), None) => {
-                print!("{}", ol);
-                stdout.next();
+        }
+        if let (FindOutcome::Fresh(result), Some(renew_for)) = (&outcome, options.renew_for) {
+            self.renew(hash, result, renew_for);
+        }
+        Ok(outcome.fresh())
+    }
+    /// Moves the expiry of a fresh result to `renew_for` from now, for `--renew-on-hit`. Like
+    /// removing expired results, this is best-effort, and is skipped if another process holds
+    /// the lock, the result has since been replaced, or the cache can't be written to (as when
+    /// it's read-only).
+    fn renew(&self, hash: &str, fresh: &T, renew_for: Duration) {
+        let Ok(Some(_lock)) = self.try_lock(hash) else {
+            return;
+        };
+        let unchanged = matches!(
+            self.read(hash),
+            Ok(Some(current)) if current.command().ulid == fresh.command().ulid
+        );
+        if unchanged {
+            debug(format!(
+                "renewing result for {} for {}",
+                hash,
+                humantime::format_duration(renew_for)
+            ));
+            if let Err(e) = self.set_expiry(hash, SystemTime::now() + renew_for) {
+                debug(format!("unable to renew result: {}", e));
             }
-            (None, Some((_, el))) => {
-                eprint!("{}", el);
-                stderr.next();
+        }
+    }
+    /// Removes an expired result, so caches used with `--cache-for` don't grow forever. This
+    /// is best-effort, and is skipped if another process holds the lock (as it may be recording
+    /// a new result), the result has since been replaced, or previous results are being kept.
+    fn remove_expired(&self, hash: &str, expired: &T) {
+        let Ok(Some(_lock)) = self.try_lock(hash) else {
+            return;
+        };
+        let unchanged = matches!(
+            self.read(hash),
+            Ok(Some(current)) if current.command().ulid == expired.command().ulid
+        );
+        let has_history = !matches!(self.read_generation(hash, 1), Ok(None));
+        if unchanged && !has_history {
+            debug(format!("removing expired result for {}", hash));
+            if let Err(e) = self.remove(hash) {
+                debug(format!("unable to remove expired result: {}", e));
             }
-            (None, None) => break,
         }
     }
+    /// Finds a result that has passed its expiry, if allowed by the options. Results must still
+    /// meet the maximum age.
+    fn find_expired(&self, hash: &str, options: &FindOptions) -> anyhow::Result<Option<T>> {
+        if !options.allow_expired {
+            return Ok(None);
+        }
+
+        Ok(match self.lookup_usable(hash, options)? {
+            FindOutcome::Expired(result)
+                if options
+                    .max_age
+                    .is_none_or(|duration| result.is_younger_than(duration)) =>
+            {
+                let expires = result.expires_at().unwrap_or_else(SystemTime::now);
+                options
+                    .allow_expired_for
+                    .is_none_or(|duration| SystemTime::now() < expires + duration)
+                    .then_some(result)
+            }
+            _ => None,
+        })
+    }
 }
 
-pub trait CacheEntry {
-    fn created_at(&self) -> SystemTime;
-    fn expires_at(&self) -> Option<SystemTime>;
-    fn command_status(&self) -> i32;
-    fn replay_command_output(&self) -> anyhow::Result<()>;
+/// The permissions given to files and directories created in a cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheModes {
+    /// The mode given to files.
+    pub file: u32,
+    /// The mode given to directories.
+    pub dir: u32,
+}
 
-    fn is_fresh(&self) -> bool {
-        self.expires_at()
-            .map_or(true, |expires| SystemTime::now() < expires)
+impl CacheModes {
+    /// Only the current user can read or write the cache.
+    pub const PRIVATE: CacheModes = CacheModes {
+        file: 0o600,
+        dir: 0o700,
+    };
+    /// Every user can read and write the cache, as used by `--share-cache`.
+    pub const SHARED: CacheModes = CacheModes {
+        file: 0o666,
+        dir: 0o777,
+    };
+
+    /// The shared modes when `shared` is set, otherwise the private ones.
+    pub fn new(shared: bool) -> CacheModes {
+        if shared {
+            CacheModes::SHARED
+        } else {
+            CacheModes::PRIVATE
+        }
     }
+}
 
-    fn is_younger_than(&self, duration: Duration) -> bool {
-        self.created_at().elapsed().unwrap() < duration
+impl Default for CacheModes {
+    fn default() -> Self {
+        CacheModes::PRIVATE
     }
+}
 
-    fn replay(&self) -> i32 {
-        self.replay_command_output().unwrap();
-        self.command_status()
+/// Parses a permission mode given in octal, like `660` or `2770`.
+pub fn parse_mode(mode: &str) -> anyhow::Result<u32> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if !digits.is_empty() && mode <= 0o7777 => Ok(mode),
+        _ => Err(anyhow!(
+            "invalid mode '{mode}', expected octal permissions like 660 or 2770"
+        )),
     }
 }
 
-pub struct OutputReader<R>
-where
-    R: Read,
-{
-    pub reader: BufReader<R>,
+/// A cache stored as files in a directory, with captured output shared between entries.
+pub struct DiskCache {
+    root: std::path::PathBuf,
+    modes: CacheModes,
+    /// Whether results are only replayed, never written, for caches on read-only storage.
+    read_only: bool,
+    /// The key encrypted entries are read with, and new entries encrypted with when `encrypt`
+    /// is set.
+    key: Option<Arc<EncryptionKey>>,
+    encrypt: bool,
 }
 
-impl<R> Iterator for OutputReader<R>
-where
-    R: Read,
-{
-    type Item = (u128, String);
+impl DiskCache {
+    /// Opens the cache in `root`, creating it unless `read_only` is set.
+    pub fn new(root: PathBuf, modes: CacheModes, read_only: bool) -> anyhow::Result<DiskCache> {
+        // A read-only cache is used as it is, even if it doesn't exist
+        if !read_only {
+            create_cache_dir(root.as_path(), modes)?;
+        }
+        Ok(DiskCache {
+            root,
+            modes,
+            read_only,
+            key: None,
+            encrypt: false,
+        })
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut line = String::new();
-        let mut bytes: [u8; 16] = [0; 16];
+    /// Sets the key used to read encrypted entries, and whether new entries are encrypted with
+    /// it. Entries recorded without encryption can still be read, so both kinds can share a
+    /// cache.
+    pub fn set_encryption(&mut self, key: Option<EncryptionKey>, encrypt: bool) {
+        self.encrypt = encrypt && key.is_some();
+        self.key = key.map(Arc::new);
+    }
 
-        // First 16 bytes are the timestamp
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
 
-        match self.reader.read_exact(&mut bytes) {
-            Ok(()) => (),
-            Err(_) => return None,
-        }
+    pub(crate) fn modes(&self) -> CacheModes {
+        self.modes
+    }
 
-        // Following the timestamp is the line contents
+    pub(crate) fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 
-        match self.reader.read_line(&mut line) {
+    /// Parses the contents of an entry's file. Unlike reading an entry, nothing is set aside
+    /// when the entry can't be parsed.
+    pub(crate) fn parse_entry(
+        &self,
+        path: PathBuf,
+        contents: String,
+    ) -> anyhow::Result<DiskCacheEntry> {
+        DiskCacheEntry::parse(
+            path,
+            contents,
+            |blob| self.blob_path(blob),
+            self.read_only,
+            self.key.as_ref(),
+        )
+    }
+
+    pub(crate) fn read_only_error(&self) -> Error {
+        anyhow!("cache {} is read-only", self.root.display())
+    }
+
+    fn path(&self, hash: &str, suffix: &str) -> std::path::PathBuf {
+        self.root.join(format!("{hash}.{suffix}"))
+    }
+
+    /// The path to an entry's metadata. The current result is generation 0 (`hash.ron`), and
+    /// previous results kept with `--keep-history` are `hash.1.ron`, `hash.2.ron` etc.
+    fn generation_path(&self, hash: &str, generation: usize) -> std::path::PathBuf {
+        match generation {
+            0 => self.path(hash, "ron"),
+            generation => self.path(hash, &format!("{generation}.ron")),
+        }
+    }
+
+    /// Where a corrupt entry is moved, so it no longer gets in the way but can still be
+    /// inspected.
+    pub(crate) fn corrupt_path(path: &Path) -> PathBuf {
+        path.with_extension("ron.corrupt")
+    }
+
+    fn set_aside(&self, path: &Path) {
+        if self.read_only {
+            return;
+        }
+        let corrupt = DiskCache::corrupt_path(path);
+        debug(format!("moving corrupt entry to {}", corrupt.display()));
+        if let Err(e) = std::fs::rename(path, &corrupt) {
+            debug(format!("unable to move corrupt entry: {}", e));
+        }
+    }
+
+    /// Moves the current and previous results back a generation, to make space for a new
+    /// result, removing any beyond `keep`. The current result is linked rather than moved, so
+    /// it stays readable until it's replaced.
+    fn rotate(&self, hash: &str, keep: usize) -> anyhow::Result<()> {
+        if let Some(oldest) = self.read_generation(hash, keep)? {
+            self.remove_output(&oldest)?;
+            std::fs::remove_file(self.generation_path(hash, keep))?;
+        }
+
+        for generation in (1..keep).rev() {
+            let path = self.generation_path(hash, generation);
+            if path.exists() {
+                std::fs::rename(&path, self.generation_path(hash, generation + 1))?;
+            }
+        }
+
+        let current = self.generation_path(hash, 0);
+        if keep > 0 && current.exists() {
+            std::fs::hard_link(&current, self.generation_path(hash, 1))
+                .map_err(|_| unable_to_write_to_cache_error(&current))?;
+        }
+        Ok(())
+    }
+
+    /// Where captured output is stored, named by the hash of its contents so identical output
+    /// recorded for different commands is only stored once.
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.root.join("blobs").join(hash)
+    }
+
+    /// The file counting how many entries refer to a blob.
+    fn blob_refs_path(&self, hash: &str) -> PathBuf {
+        self.root.join("blobs").join(format!("{hash}.refs"))
+    }
+
+    /// Locks the blob store, so only one process at a time updates reference counts.
+    fn lock_blobs(&self) -> anyhow::Result<CacheLock> {
+        let dir = self.root.join("blobs");
+        create_cache_dir(&dir, self.modes).map_err(|_| unable_to_write_to_cache_error(&dir))?;
+
+        let path = dir.join("lock");
+        let file = open_lock_file(&path, self.modes)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(unable_to_write_to_cache_error(&path));
+        }
+        Ok(CacheLock {
+            _file: Some(file),
+            release: None,
+        })
+    }
+
+    fn read_blob_refs(&self, hash: &str) -> anyhow::Result<usize> {
+        let path = self.blob_refs_path(hash);
+        match std::fs::read_to_string(&path) {
+            Ok(refs) => refs
+                .trim()
+                .parse()
+                .map_err(|_| unable_to_read_cache_entry_error(&path)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(_) => Err(unable_to_read_cache_entry_error(&path)),
+        }
+    }
+
+    fn write_blob_refs(&self, hash: &str, refs: usize) -> anyhow::Result<()> {
+        let path = self.blob_refs_path(hash);
+        self.create_file(&path)?
+            .write_all(refs.to_string().as_bytes())
+            .map_err(|_| unable_to_write_to_cache_error(&path))
+    }
+
+    /// Moves a captured output file into the blob store, returning the hash it's stored under.
+    /// When an identical blob is already stored, it's shared and the file removed. With
+    /// `--encrypt`, the blob is encrypted and named with the key.
+    fn store_blob(&self, path: &Path, include_timestamps: bool) -> anyhow::Result<String> {
+        let hash = File::open(path)
+            .and_then(|file| blob_hash(file, include_timestamps))
+            .map_err(|_| unable_to_read_cache_entry_error(path))?;
+        let key = self.key.as_ref().filter(|_| self.encrypt);
+        let hash = match key {
+            Some(key) => key.blob_name(&hash),
+            None => hash,
+        };
+        let _lock = self.lock_blobs()?;
+
+        let blob = self.blob_path(&hash);
+        if blob.exists() {
+            debug(format!("sharing output with {}", blob.display()));
+            std::fs::remove_file(path).map_err(|_| unable_to_write_to_cache_error(path))?;
+        } else if let Some(key) = key {
+            let mut output =
+                File::open(path).map_err(|_| unable_to_read_cache_entry_error(path))?;
+            let temp = blob.with_extension(format!("{}.tmp", Ulid::new()));
+            let mut encryptor = OutputEncryptor::new(key, self.create_file(&temp)?);
+            std::io::copy(&mut output, &mut encryptor)
+                .and_then(|_| encryptor.finish())
+                .and_then(|_| std::fs::rename(&temp, &blob))
+                .map_err(|_| {
+                    let _ = std::fs::remove_file(&temp);
+                    unable_to_write_to_cache_error(&blob)
+                })?;
+            std::fs::remove_file(path).map_err(|_| unable_to_write_to_cache_error(path))?;
+            debug(format!("wrote encrypted output to {}", blob.display()));
+        } else {
+            debug(format!("wrote output to {}", blob.display()));
+            std::fs::rename(path, &blob).map_err(|_| unable_to_write_to_cache_error(&blob))?;
+        }
+
+        self.write_blob_refs(&hash, self.read_blob_refs(&hash)? + 1)?;
+        Ok(hash)
+    }
+
+    /// The checksums of an entry's output, taken from the blobs as stored. When output is shared
+    /// with an existing blob, its timestamps can differ from those captured, so the checksums
+    /// can't be taken as the output is captured.
+    fn checksums(&self, blobs: &OutputBlobs) -> anyhow::Result<OutputChecksums> {
+        Ok(OutputChecksums {
+            stdout: OutputChecksum::of(&self.blob_path(&blobs.stdout))?,
+            stderr: OutputChecksum::of(&self.blob_path(&blobs.stderr))?,
+        })
+    }
+
+    /// Drops a reference to a blob, removing it once no entries refer to it.
+    fn release_blob(&self, hash: &str) -> anyhow::Result<()> {
+        let _lock = self.lock_blobs()?;
+
+        let refs = self.read_blob_refs(hash)?.saturating_sub(1);
+        if refs > 0 {
+            return self.write_blob_refs(hash, refs);
+        }
+
+        for path in [self.blob_path(hash), self.blob_refs_path(hash)] {
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(unable_to_write_to_cache_error(&path))
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes an entry's captured output, releasing its blobs, or removing its own files for
+    /// entries recorded before output was stored as blobs.
+    fn remove_output(&self, entry: &DiskCacheEntry) -> anyhow::Result<()> {
+        match &entry.header.blobs {
+            Some(blobs) => {
+                self.release_blob(&blobs.stdout)?;
+                self.release_blob(&blobs.stderr)
+            }
+            None => entry.remove_output(),
+        }
+    }
+
+    /// Writes a new entry in place of the existing one. The existing entry's output is only
+    /// removed once the new entry has replaced it, so readers never find an entry whose output
+    /// has gone.
+    fn replace(
+        &self,
+        hash: &str,
+        meta: DiskCacheEntryMeta,
+        blobs: OutputBlobs,
+    ) -> anyhow::Result<()> {
+        let existing = self.read(hash)?;
+        self.write(hash, meta, blobs, self.encrypt)?;
+        if let Some(existing) = existing {
+            self.remove_output(&existing)?;
+        }
+        Ok(())
+    }
+
+    /// Changes the metadata of the current result for `hash`, returning `false` when there's no
+    /// result. The entry is rewritten in full (as renaming the file is what makes it atomic),
+    /// but refers to the same blobs.
+    fn update_meta(
+        &self,
+        hash: &str,
+        update: impl FnOnce(&mut DiskCacheEntryMeta),
+    ) -> anyhow::Result<bool> {
+        if self.read_only {
+            return Err(self.read_only_error());
+        }
+
+        let Some(current) = self.read(hash)? else {
+            return Ok(false);
+        };
+        let record = parse_record(&current.path, &current.contents)?;
+        let Some(blobs) = record.blobs else {
+            return Err(anyhow!(
+                "{} was recorded before output was stored as blobs",
+                current.path.display()
+            ));
+        };
+        let mut meta = record.meta;
+        update(&mut meta);
+        self.write(hash, meta, blobs, current.encrypted)?;
+        Ok(true)
+    }
+
+    fn create_file(&self, path: &PathBuf) -> anyhow::Result<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|_| unable_to_write_to_cache_error(path))?;
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(self.modes.file))?;
+        Ok(file)
+    }
+
+    /// Writes an entry, encrypting all but its header when `encrypted` is set. Encrypted entries
+    /// are left out of the command index, which isn't encrypted.
+    fn write(
+        &self,
+        hash: &str,
+        meta: DiskCacheEntryMeta,
+        blobs: OutputBlobs,
+        encrypted: bool,
+    ) -> anyhow::Result<()> {
+        let key = match &self.key {
+            Some(key) if encrypted => Some(key),
+            None if encrypted => return Err(anyhow!("encrypting entries needs a key")),
+            _ => None,
+        };
+        let path = self.path(hash, "ron");
+        let command = CachedCommand::new(hash, &meta.command);
+        // Written to a temporary file and renamed, so readers never see a partial entry
+        let temp = self.path(hash, &format!("{}.ron.tmp", meta.command.ulid));
+        let mut file = self.create_file(&temp)?;
+        let mut header = DiskCacheEntryHeader {
+            created: meta.created,
+            expires: meta.expires,
+            status: meta.status,
+            signal: meta.signal,
+            blobs: Some(blobs.clone()),
+            checksums: meta.checksums.clone(),
+            pinned: meta.pinned,
+            record: None,
+        };
+        let record = DiskCacheRecord {
+            meta,
+            blobs: Some(blobs),
+            stdout: PathBuf::new(),
+            stderr: PathBuf::new(),
+        };
+        let prefix = match key {
+            Some(_) => ENCRYPTED_HEADER_PREFIX,
+            None => HEADER_PREFIX,
+        };
+        let result = ron::ser::to_string_pretty(&record, PrettyConfig::default())
+            .map_err(Error::from)
+            .and_then(|record| match key {
+                Some(key) => Ok(ron::to_string(&EncryptedRecord {
+                    encrypted: to_hex(&key.encrypt(record.as_bytes())?),
+                })?),
+                None => Ok(record),
+            })
+            .and_then(|record| {
+                header.record = Some(record_hash(&record));
+                let header = ron::to_string(&header)?;
+                Ok(write!(file, "{prefix}{header}\n{record}")?)
+            })
+            .map_err(|_| unable_to_write_to_cache_error(&temp))
+            .and_then(|_| {
+                file.sync_all()
+                    .map_err(|_| unable_to_write_to_cache_error(&temp))
+            })
+            .and_then(|_| {
+                std::fs::rename(&temp, &path).map_err(|_| unable_to_write_to_cache_error(&path))
+            });
+
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp);
+        }
+        result?;
+        debug(format!("wrote entry to {}", path.display()));
+
+        // Persist the rename itself. This can fail on some filesystems, and the entry has
+        // already been written, so errors are ignored.
+        if let Ok(dir) = File::open(&self.root) {
+            let _ = dir.sync_all();
+        }
+
+        // The index only speeds up completion, so a failure to update it isn't an error
+        if key.is_some() {
+            return Ok(());
+        }
+        if let Err(e) = self.index_command(&command) {
+            debug(format!("unable to update command index: {}", e));
+        }
+        Ok(())
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.root.join("commands")
+    }
+
+    fn lock_index(&self) -> anyhow::Result<CacheLock> {
+        let path = self.index_path().with_extension("lock");
+        let file = open_lock_file(&path, self.modes)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(unable_to_write_to_cache_error(&path));
+        }
+        Ok(CacheLock {
+            _file: Some(file),
+            release: None,
+        })
+    }
+
+    /// Adds a line for a newly written entry to the command index, if there is one. Until the
+    /// index is first read it doesn't exist, and nothing is added.
+    fn index_command(&self, command: &CachedCommand) -> anyhow::Result<()> {
+        let path = self.index_path();
+        let _lock = self.lock_index()?;
+        let mut index = match OpenOptions::new().append(true).open(&path) {
+            Ok(index) => index,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(_) => return Err(unable_to_write_to_cache_error(&path)),
+        };
+        index
+            .write_all(format!("{}\n", ron::to_string(command)?).as_bytes())
+            .map_err(|_| unable_to_write_to_cache_error(&path))
+    }
+
+    /// Reads the command index, keeping the last line for each entry that still exists.
+    fn read_index(&self) -> Option<(Vec<CachedCommand>, usize)> {
+        let index = std::fs::read_to_string(self.index_path()).ok()?;
+        let mut commands = BTreeMap::new();
+        let mut lines = 0;
+        for line in index.lines() {
+            lines += 1;
+            match ron::from_str::<CachedCommand>(line) {
+                Ok(command) => commands.insert(command.hash.clone(), command),
+                Err(_) => return None,
+            };
+        }
+        let commands = commands
+            .into_values()
+            .filter(|command| self.path(&command.hash, "ron").exists())
+            .collect();
+        Some((commands, lines))
+    }
+
+    /// Replaces the command index with one line for each of the given commands.
+    fn write_index(&self, commands: &[CachedCommand]) -> anyhow::Result<()> {
+        let path = self.index_path();
+        let temp = path.with_extension(format!("{}.tmp", Ulid::new()));
+        let mut contents = String::new();
+        for command in commands {
+            contents.push_str(&ron::to_string(command)?);
+            contents.push('\n');
+        }
+        self.create_file(&temp)?
+            .write_all(contents.as_bytes())
+            .map_err(|_| unable_to_write_to_cache_error(&temp))?;
+        std::fs::rename(&temp, &path).map_err(|_| {
+            let _ = std::fs::remove_file(&temp);
+            unable_to_write_to_cache_error(&path)
+        })
+    }
+}
+
+/// A buffer that output can be captured into from another thread.
+#[derive(Default, Clone)]
+pub(crate) struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    pub(crate) fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A writer that output can be passed through to from another thread, while it's still used
+/// elsewhere.
+#[derive(Clone)]
+pub(crate) struct SharedWriter(std::sync::Arc<std::sync::Mutex<dyn Write + Send>>);
+
+impl SharedWriter {
+    pub(crate) fn new(writer: impl Write + Send + 'static) -> Self {
+        SharedWriter(std::sync::Arc::new(std::sync::Mutex::new(writer)))
+    }
+}
+
+impl<W: Write + Send + 'static> From<std::sync::Arc<std::sync::Mutex<W>>> for SharedWriter {
+    fn from(writer: std::sync::Arc<std::sync::Mutex<W>>) -> Self {
+        SharedWriter(writer)
+    }
+}
+
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// The hash a captured output file is stored under. Each line is stored with a timestamp, which
+/// is only needed to interleave stdout with stderr on replay. When the other stream is empty the
+/// timestamps are left out, so output that's otherwise identical shares a blob.
+fn blob_hash(reader: impl Read, include_timestamps: bool) -> std::io::Result<String> {
+    let mut reader = BufReader::new(reader);
+    let mut hasher = sha1_smol::Sha1::new();
+
+    if include_timestamps {
+        loop {
+            let buffer = reader.fill_buf()?;
+            if buffer.is_empty() {
+                break;
+            }
+            hasher.update(buffer);
+            let length = buffer.len();
+            reader.consume(length);
+        }
+    } else {
+        for (_, line) in (OutputReader { reader }) {
+            hasher.update(&line);
+        }
+    }
+
+    Ok(hasher.digest().to_string())
+}
+
+/// The hashes stdout and stderr are stored under, as by `blob_hash`. Identical output recorded at
+/// different times can share a blob, so copies of output are compared by these hashes rather
+/// than byte for byte.
+pub(crate) fn output_hashes(stdout: &[u8], stderr: &[u8]) -> std::io::Result<(String, String)> {
+    Ok((
+        blob_hash(stdout, !stderr.is_empty())?,
+        blob_hash(stderr, !stdout.is_empty())?,
+    ))
+}
+
+/// Opens a lock file, without locking it.
+fn open_lock_file(path: &Path, modes: CacheModes) -> anyhow::Result<File> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|_| unable_to_write_to_cache_error(path))?;
+
+    // Lock files are shared between processes (and users, with a shared cache), so may
+    // already exist with permissions that can't be changed
+    let _ = std::fs::set_permissions(path, std::fs::Permissions::from_mode(modes.file));
+    Ok(file)
+}
+
+/// Attempts to take an exclusive lock on a lock file, without waiting.
+fn try_lock_file(path: &Path, modes: CacheModes) -> anyhow::Result<Option<CacheLock>> {
+    loop {
+        let file = open_lock_file(path, modes)?;
+
+        // The lock is released when the file is closed, including when the process dies
+        let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+        if result != 0 {
+            return Ok(None);
+        }
+
+        // A file removed as unused after it was opened no longer stops anyone else locking
+        // the path, so the new file there is locked instead
+        let in_place = match (file.metadata(), std::fs::metadata(path)) {
+            (Ok(locked), Ok(current)) => {
+                locked.dev() == current.dev() && locked.ino() == current.ino()
+            }
+            _ => false,
+        };
+        if in_place {
+            return Ok(Some(CacheLock {
+                _file: Some(file),
+                release: None,
+            }));
+        }
+    }
+}
+
+/// Removes a lock file unless it's in use. It's locked while it's removed, so anyone waiting
+/// to lock it finds it gone, and locks a new file instead.
+pub(crate) fn remove_lock_file(path: &Path, modes: CacheModes) -> anyhow::Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    match try_lock_file(path, modes)? {
+        Some(_lock) => match std::fs::remove_file(path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(unable_to_write_to_cache_error(path))
+            }
+            _ => Ok(true),
+        },
+        None => Ok(false),
+    }
+}
+
+/// Removes unused lock files named `<hash>.lock` in a directory, returning how many were
+/// removed.
+pub(crate) fn remove_unused_lock_files(dir: &Path, modes: CacheModes) -> anyhow::Result<usize> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(_) => return Err(unable_to_read_cache_entry_error(dir)),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let path = entry
+            .map_err(|_| unable_to_read_cache_entry_error(dir))?
+            .path();
+        let is_hash_lock = path
+            .extension()
+            .is_some_and(|extension| extension == "lock")
+            && path.file_stem().is_some_and(|stem| {
+                !stem.is_empty() && stem.as_bytes().iter().all(u8::is_ascii_hexdigit)
+            });
+        if is_hash_lock && remove_lock_file(&path, modes)? {
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn is_unset(path: &Path) -> bool {
+    path.as_os_str().is_empty()
+}
+
+/// Warns when a command's output couldn't be captured, for example because the disk is full,
+/// as its result can't be recorded. Returns whether capturing failed.
+fn capture_failed(result: &CommandResult) -> bool {
+    match &result.capture_error {
+        Some(e) => {
+            warn(format!("unable to capture output, result not cached: {e}"));
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn unable_to_write_to_cache_error(path: &Path) -> Error {
+    anyhow!("unable to write file to cache {}", path.display())
+}
+
+pub fn unable_to_read_cache_entry_error(path: &Path) -> Error {
+    anyhow!("unable to read file from cache {}", path.display())
+}
+
+fn cache_dir_error(action: &str, path: &Path, error: std::io::Error) -> Error {
+    anyhow!(
+        "unable to {action} cache directory {}: {error}",
+        path.display()
+    )
+}
+
+/// Creates the cache directory if needed, checking that an existing one can be written to.
+/// Errors name the path that couldn't be used and why.
+fn create_cache_dir(path: &Path, modes: CacheModes) -> anyhow::Result<()> {
+    match path.metadata() {
+        Ok(metadata) if !metadata.is_dir() => {
+            return Err(anyhow!("cache {} is not a directory", path.display()));
+        }
+        Ok(_) => return check_writable(path),
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            // Usually a file where a directory is expected, further up the path
+            let file = path
+                .ancestors()
+                .skip(1)
+                .find(|ancestor| ancestor.metadata().is_ok_and(|metadata| !metadata.is_dir()));
+            return Err(match file {
+                Some(file) => anyhow!(
+                    "unable to use cache {}: {} is not a directory",
+                    path.display(),
+                    file.display()
+                ),
+                None => cache_dir_error("read", path, e),
+            });
+        }
+        Err(_) => {}
+    }
+
+    if let Some(parent) = path.parent().filter(|parent| !parent.exists()) {
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .create(parent)
+            .map_err(|e| cache_dir_error("create", parent, e))?;
+    }
+
+    std::fs::DirBuilder::new()
+        .create(path)
+        .map_err(|e| cache_dir_error("create", path, e))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(modes.dir))
+        .map_err(|e| cache_dir_error("set permissions on", path, e))?;
+    Ok(())
+}
+
+fn check_writable(path: &Path) -> anyhow::Result<()> {
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::access(c_path.as_ptr(), libc::W_OK | libc::X_OK) } != 0 {
+        let error = std::io::Error::last_os_error();
+        return Err(cache_dir_error("write to", path, error));
+    }
+    Ok(())
+}
+
+/// A private cache directory for the current user in the system's temporary directory, used by
+/// `--cache-fallback tmp` when the configured cache can't be used.
+pub fn fallback_cache_dir() -> anyhow::Result<PathBuf> {
+    let uid = unsafe { libc::getuid() };
+    let path = std::env::temp_dir().join(format!("deja-{uid}"));
+    create_cache_dir(&path, CacheModes::PRIVATE)?;
+
+    // Anyone can create directories in the temporary directory, so make sure it's ours
+    let metadata = path.symlink_metadata()?;
+    if metadata.uid() != uid || metadata.permissions().mode() & 0o077 != 0 {
+        return Err(anyhow!(
+            "fallback cache {} isn't private to the current user",
+            path.display()
+        ));
+    }
+    Ok(path)
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DiskCacheEntryMeta {
+    command: Command,
+    created: SystemTime,
+    expires: Option<SystemTime>,
+    status: i32,
+    /// The signal that killed the command, if any (in which case `status` is 128 plus the signal).
+    #[serde(default)]
+    signal: Option<i32>,
+    /// Environment variables recorded with `--record-env`. These don't affect the cache key.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<String, String>,
+    /// How long the command took to run (not recorded by older versions).
+    #[serde(default)]
+    duration: Option<Duration>,
+    /// The CPU time and memory used by the command (not recorded by older versions).
+    #[serde(default)]
+    usage: Option<ResourceUsage>,
+    /// When a fresh run made with `--verify` found this result no longer matches the command.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    diverged: Option<SystemTime>,
+    /// Labels given with `--tag`. Like `env`, these don't affect the cache key.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    tags: BTreeSet<String>,
+    /// The identifier given with `--id`, which doesn't affect the cache key either.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// Checksums of the captured output, checked before it's replayed (only recorded by disk
+    /// caches, and not by older versions).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksums: Option<OutputChecksums>,
+    /// Whether the result has been pinned with `pin`, so it never expires and isn't removed by
+    /// `gc`.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pinned: bool,
+}
+
+impl DiskCacheEntryMeta {
+    /// The metadata of an entry from any cache.
+    fn from_entry(entry: &impl CacheEntry) -> DiskCacheEntryMeta {
+        DiskCacheEntryMeta {
+            command: entry.command().clone(),
+            created: entry.created_at(),
+            expires: entry.expires_at(),
+            status: entry.command_status(),
+            signal: entry.command_signal(),
+            env: entry.env().clone(),
+            duration: entry.duration(),
+            usage: entry.usage(),
+            diverged: entry.diverged_at(),
+            tags: entry.tags().clone(),
+            id: entry.id().map(String::from),
+            checksums: None,
+            pinned: entry.pinned(),
+        }
+    }
+}
+
+/// The hashes of the blobs holding an entry's captured output.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct OutputBlobs {
+    stdout: String,
+    stderr: String,
+}
+
+/// The length and BLAKE3 hash of a captured output file as it was stored, so output cut short
+/// or corrupted since (by a full disk or a flaky network mount) is noticed before it's replayed.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct OutputChecksum {
+    length: u64,
+    blake3: String,
+}
+
+impl OutputChecksum {
+    fn of(path: &Path) -> anyhow::Result<OutputChecksum> {
+        let mut hasher = blake3::Hasher::new();
+        let length = File::open(path)
+            .and_then(|mut file| std::io::copy(&mut file, &mut hasher))
+            .map_err(|_| unable_to_read_cache_entry_error(path))?;
+        Ok(OutputChecksum {
+            length,
+            blake3: hasher.finalize().to_hex().to_string(),
+        })
+    }
+
+    /// Checks an open output file against the checksum, comparing only its length unless
+    /// `contents` is set. The file is left ready to be read from the start.
+    fn verify(&self, file: &mut File, path: &Path, contents: bool) -> anyhow::Result<()> {
+        let corrupt = |reason: String| CorruptOutput {
+            path: path.to_path_buf(),
+            reason,
+        };
+
+        let length = file.metadata()?.len();
+        if length != self.length {
+            return Err(
+                corrupt(format!("expected {} bytes, found {}", self.length, length)).into(),
+            );
+        }
+
+        if contents {
+            let mut hasher = blake3::Hasher::new();
+            std::io::copy(file, &mut hasher)?;
+            file.rewind()?;
+            if hasher.finalize().to_hex().as_str() != self.blake3 {
+                return Err(corrupt("contents don't match checksum".into()).into());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The checksums of an entry's captured output.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct OutputChecksums {
+    stdout: OutputChecksum,
+    stderr: OutputChecksum,
+}
+
+/// Captured output that no longer matches the checksum recorded with it.
+#[derive(Debug)]
+pub struct CorruptOutput {
+    path: PathBuf,
+    reason: String,
+}
+
+impl CorruptOutput {
+    /// The output file that's corrupt.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl std::fmt::Display for CorruptOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "corrupt output {}: {}", self.path.display(), self.reason)
+    }
+}
+
+impl std::error::Error for CorruptOutput {}
+
+/// Starts the first line of an entry's file, which holds its header.
+const HEADER_PREFIX: &str = "// header: ";
+
+/// Starts the header of an encrypted entry instead. Versions without encryption don't
+/// recognise it, so they set the entry aside rather than replaying encrypted output.
+const ENCRYPTED_HEADER_PREFIX: &str = "// encrypted header: ";
+
+/// The fields needed to check whether an entry is fresh and to replay it. They're written on
+/// the first line of the entry's file, as a comment, so they can be read without parsing the
+/// command and scope that make up most of the file.
+#[derive(Debug, Deserialize, Serialize)]
+struct DiskCacheEntryHeader {
+    created: SystemTime,
+    expires: Option<SystemTime>,
+    status: i32,
+    signal: Option<i32>,
+    blobs: Option<OutputBlobs>,
+    #[serde(default)]
+    checksums: Option<OutputChecksums>,
+    #[serde(default)]
+    pinned: bool,
+    /// A hash of the rest of the file (from `record_hash`), checked when the entry is read so
+    /// a damaged record is found then, rather than when it's first used. Entries written by
+    /// older versions have none, and have their record parsed when they're read instead.
+    #[serde(default)]
+    record: Option<String>,
+}
+
+/// The record of an encrypted entry, as it's stored after the header.
+#[derive(Debug, Deserialize, Serialize)]
+struct EncryptedRecord {
+    /// The record's RON, encrypted and written as hex.
+    encrypted: String,
+}
+
+/// An entry as it's stored in its file, after the header.
+#[derive(Debug, Deserialize, Serialize)]
+struct DiskCacheRecord {
+    meta: DiskCacheEntryMeta,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blobs: Option<OutputBlobs>,
+    /// Paths to the captured output, for entries recorded before output was stored as blobs.
+    #[serde(default, skip_serializing_if = "is_unset")]
+    stdout: PathBuf,
+    #[serde(default, skip_serializing_if = "is_unset")]
+    stderr: PathBuf,
+}
+
+/// An entry read from a `DiskCache`. Only the header is parsed when the entry is read, with
+/// the rest of the file parsed the first time it's needed (for example, to show the command).
+#[derive(Debug)]
+pub struct DiskCacheEntry {
+    header: DiskCacheEntryHeader,
+    /// Paths to the captured output. Entries recorded before output was stored as blobs have
+    /// their own files, while for others these are set from the blobs when the entry is read.
+    stdout: PathBuf,
+    stderr: PathBuf,
+    path: PathBuf,
+    contents: String,
+    meta: OnceCell<DiskCacheEntryMeta>,
+    /// Whether the entry was read from a read-only cache, so it's left in place when corrupt.
+    read_only: bool,
+    /// Whether the entry was recorded with `--encrypt`. Its `contents` are decrypted when it's
+    /// read, as long as the key is given, while its output is decrypted as it's read.
+    encrypted: bool,
+    /// The key an encrypted entry was decrypted with, or `None` when it couldn't be.
+    key: Option<Arc<EncryptionKey>>,
+}
+
+impl DiskCacheEntry {
+    /// Reads an entry from the contents of its file, parsing only the header. Entries written
+    /// by older versions have no header, so are parsed in full.
+    fn parse(
+        path: PathBuf,
+        contents: String,
+        blob_path: impl Fn(&str) -> PathBuf,
+        read_only: bool,
+        key: Option<&Arc<EncryptionKey>>,
+    ) -> anyhow::Result<DiskCacheEntry> {
+        let first = contents.lines().next().unwrap_or_default();
+        let encrypted = first.starts_with(ENCRYPTED_HEADER_PREFIX);
+        let header = first
+            .strip_prefix(HEADER_PREFIX)
+            .or_else(|| first.strip_prefix(ENCRYPTED_HEADER_PREFIX))
+            .map(ron::from_str::<DiskCacheEntryHeader>)
+            .transpose()
+            .map_err(|_| unable_to_read_cache_entry_error(&path))?;
+
+        // Only the header is parsed here, but the rest of the entry is checked against its hash,
+        // so an entry cut short (as when the disk fills up) or otherwise damaged is found now
+        if let Some(expected) = header.as_ref().and_then(|header| header.record.as_ref()) {
+            let record = contents.split_once('\n').unwrap_or_default().1;
+            if record_hash(record) != *expected {
+                return Err(unable_to_read_cache_entry_error(&path));
+            }
+        }
+
+        // The rest of an encrypted entry is decrypted now, so it's parsed like any other entry.
+        // Without the right key, only the header can be used.
+        let (contents, key) = match key.filter(|_| encrypted) {
+            Some(key) => match decrypt_record(first, &contents, key) {
+                Some(contents) => (contents, Some(key.clone())),
+                None => (contents, None),
+            },
+            None => (contents, None),
+        };
+
+        let locked = encrypted && key.is_none();
+        let (header, stdout, stderr, meta) = match header {
+            Some(header) if header.record.is_some() || locked => {
+                (header, PathBuf::new(), PathBuf::new(), OnceCell::new())
+            }
+            // Written before records were hashed, so the only way to check it is to parse it
+            Some(header) => {
+                let record = parse_record(&path, &contents)?;
+                (
+                    header,
+                    PathBuf::new(),
+                    PathBuf::new(),
+                    OnceCell::from(record.meta),
+                )
+            }
+            None => {
+                let record = parse_record(&path, &contents)?;
+                let header = DiskCacheEntryHeader {
+                    created: record.meta.created,
+                    expires: record.meta.expires,
+                    status: record.meta.status,
+                    signal: record.meta.signal,
+                    blobs: record.blobs,
+                    checksums: record.meta.checksums.clone(),
+                    pinned: record.meta.pinned,
+                    record: None,
+                };
+                (
+                    header,
+                    record.stdout,
+                    record.stderr,
+                    OnceCell::from(record.meta),
+                )
+            }
+        };
+
+        let (stdout, stderr) = match &header.blobs {
+            Some(blobs) => (blob_path(&blobs.stdout), blob_path(&blobs.stderr)),
+            None => (relocate(&path, stdout), relocate(&path, stderr)),
+        };
+
+        Ok(DiskCacheEntry {
+            header,
+            stdout,
+            stderr,
+            path,
+            contents,
+            meta,
+            read_only,
+            encrypted,
+            key,
+        })
+    }
+
+    /// Whether the entry is encrypted, but couldn't be decrypted with the key given (if any),
+    /// so only the fields in its header can be used.
+    pub(crate) fn is_locked(&self) -> bool {
+        self.encrypted && self.key.is_none()
+    }
+
+    /// Reads captured output from an open file, decrypting it as it's read for encrypted
+    /// entries.
+    fn output_reader(&self, file: File) -> anyhow::Result<Box<dyn Read + Send>> {
+        if !self.encrypted {
+            return Ok(Box::new(file));
+        }
+        let key = self
+            .key
+            .as_ref()
+            .ok_or_else(|| wrong_or_missing_key_error(&self.path))?;
+        Ok(Box::new(OutputDecryptor::new(key.clone(), file)))
+    }
+
+    /// Opens and reads captured output, decrypting it for encrypted entries.
+    fn open_output(&self, path: &Path) -> anyhow::Result<Box<dyn Read + Send>> {
+        let file = File::open(path).map_err(|_| unable_to_read_cache_entry_error(path))?;
+        self.output_reader(file)
+    }
+
+    /// The rest of the entry, parsed on first use. It was checked when the entry was read
+    /// (and is kept in memory since), so it can always be parsed, unless the entry is locked.
+    fn meta(&self) -> &DiskCacheEntryMeta {
+        self.meta.get_or_init(|| {
+            parse_record(&self.path, &self.contents)
+                .expect("record checked when the entry was read")
+                .meta
+        })
+    }
+
+    /// Checks the captured output against the checksums recorded with it, reading it in full
+    /// when `contents` is set, or only comparing lengths otherwise. Returns false for entries
+    /// recorded without checksums, which can't be checked.
+    pub(crate) fn verify_output(&self, contents: bool) -> anyhow::Result<bool> {
+        let mut stdout = File::open(&self.stdout)?;
+        let mut stderr = File::open(&self.stderr)?;
+        self.verify(&mut stdout, &mut stderr, contents)
+    }
+
+    fn verify(&self, stdout: &mut File, stderr: &mut File, contents: bool) -> anyhow::Result<bool> {
+        let Some(checksums) = &self.header.checksums else {
+            return Ok(false);
+        };
+        checksums.stdout.verify(stdout, &self.stdout, contents)?;
+        checksums.stderr.verify(stderr, &self.stderr, contents)?;
+        Ok(true)
+    }
+
+    /// Moves the entry aside, along with any corrupt output, so the command is run and recorded
+    /// again without the new result sharing the corrupt output, but both can still be
+    /// inspected. The output is moved first, so the entry is only moved once it's gone.
+    pub(crate) fn set_aside(&self, output: Option<&Path>) -> std::io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let output = output.map(|output| {
+            let mut corrupt = output.as_os_str().to_owned();
+            corrupt.push(".corrupt");
+            (output, PathBuf::from(corrupt))
+        });
+        let entry = (self.path.as_path(), DiskCache::corrupt_path(&self.path));
+        for (path, corrupt) in output.into_iter().chain([entry]) {
+            debug(format!(
+                "moving corrupt {} to {}",
+                path.display(),
+                corrupt.display()
+            ));
+            std::fs::rename(path, &corrupt)?;
+        }
+        Ok(())
+    }
+
+    /// The entry's file.
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The files holding the captured stdout and stderr.
+    pub(crate) fn output_paths(&self) -> [&Path; 2] {
+        [&self.stdout, &self.stderr]
+    }
+
+    /// Removes the entry's own files holding the captured output. Files already removed are
+    /// ignored.
+    fn remove_output(&self) -> anyhow::Result<()> {
+        for path in [&self.stdout, &self.stderr] {
+            match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(unable_to_write_to_cache_error(path))
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Entries recorded before output was stored as blobs refer to their output files by absolute
+/// path, which no longer exists once the cache is moved. The files are always alongside the
+/// entry, so when the path is missing, the file of the same name next to the entry is used.
+fn relocate(entry: &Path, output: PathBuf) -> PathBuf {
+    let Some(dir) = entry.parent() else {
+        return output;
+    };
+    match output.file_name() {
+        Some(name) if output.is_relative() || !output.exists() => dir.join(name),
+        _ => output,
+    }
+}
+
+pub(crate) fn is_not_found(error: &Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound)
+}
+
+/// The hash of an entry's record, as written after its header, recorded in the header.
+fn record_hash(record: &str) -> String {
+    blake3::hash(record.as_bytes()).to_hex().to_string()
+}
+
+fn parse_record(path: &Path, contents: &str) -> anyhow::Result<DiskCacheRecord> {
+    ron::from_str(contents).map_err(|_| unable_to_read_cache_entry_error(path))
+}
+
+/// Decrypts the record of an encrypted entry, returning the entry's contents with the record
+/// in place of its encrypted form, or `None` if it can't be decrypted with the key.
+fn decrypt_record(header: &str, contents: &str, key: &EncryptionKey) -> Option<String> {
+    let rest = contents.get(header.len()..)?;
+    let record = ron::from_str::<EncryptedRecord>(rest).ok()?;
+    let record = key.decrypt(&from_hex(&record.encrypted)?)?;
+    Some(format!("{header}\n{}", String::from_utf8(record).ok()?))
+}
+
+impl CacheEntry for DiskCacheEntry {
+    fn created_at(&self) -> SystemTime {
+        self.header.created
+    }
+
+    fn expires_at(&self) -> Option<SystemTime> {
+        self.header.expires
+    }
+
+    fn command_status(&self) -> i32 {
+        self.header.status
+    }
+
+    fn command_signal(&self) -> Option<i32> {
+        self.header.signal
+    }
+
+    fn command(&self) -> &Command {
+        &self.meta().command
+    }
+
+    fn env(&self) -> &BTreeMap<String, String> {
+        &self.meta().env
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        self.meta().duration
+    }
+
+    fn usage(&self) -> Option<ResourceUsage> {
+        self.meta().usage
+    }
+
+    fn diverged_at(&self) -> Option<SystemTime> {
+        self.meta().diverged
+    }
+
+    fn tags(&self) -> &BTreeSet<String> {
+        &self.meta().tags
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.meta().id.as_deref()
+    }
+
+    fn pinned(&self) -> bool {
+        self.header.pinned
+    }
+
+    fn replay_command_output(&self, output: &mut Output) -> anyhow::Result<()> {
+        // Both files are opened and checked before anything is replayed, so output removed or
+        // corrupted since the entry was recorded is noticed before any of it is written. Once
+        // open, they stay readable.
+        let mut stdout = File::open(&self.stdout)?;
+        let mut stderr = File::open(&self.stderr)?;
+        if self.is_locked() {
+            return Err(wrong_or_missing_key_error(&self.path));
+        }
+        match self.verify(&mut stdout, &mut stderr, true) {
+            Ok(true) => (),
+            Ok(false) => debug(format!(
+                "no checksums recorded in {}, replaying output unverified",
+                self.path.display()
+            )),
+            Err(e) => {
+                if let Some(corrupt) = e.downcast_ref::<CorruptOutput>() {
+                    if let Err(e) = self.set_aside(Some(&corrupt.path)) {
+                        debug(format!("unable to move corrupt output aside: {}", e));
+                    }
+                }
+                return Err(e);
+            }
+        }
+        let stdout = self.output_reader(stdout)?;
+        let stderr = self.output_reader(stderr)?;
+        replay_output(stdout, stderr, output)?;
+        Ok(())
+    }
+
+    fn stdout(&self) -> anyhow::Result<String> {
+        let reader = OutputReader {
+            reader: BufReader::new(self.open_output(&self.stdout)?),
+        };
+        Ok(reader.into_string())
+    }
+
+    fn stdout_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        self.open_output(&self.stdout)
+    }
+
+    fn raw_output(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let read = |path: &PathBuf| {
+            let mut contents = vec![];
+            self.open_output(path)?
+                .read_to_end(&mut contents)
+                .map_err(|_| unable_to_read_cache_entry_error(path))?;
+            Ok::<_, Error>(contents)
+        };
+        Ok((read(&self.stdout)?, read(&self.stderr)?))
+    }
+
+    fn output_sizes(&self) -> Vec<(String, usize)> {
+        [&self.stdout, &self.stderr]
+            .into_iter()
+            .filter_map(|path| {
+                let metadata = std::fs::metadata(path).ok()?;
+                Some((path.display().to_string(), metadata.len() as usize))
+            })
+            .collect()
+    }
+}
+
+impl Cache<DiskCacheEntry> for DiskCache {
+    fn read(&self, hash: &str) -> anyhow::Result<Option<DiskCacheEntry>> {
+        self.read_generation(hash, 0)
+    }
+
+    fn read_generation(
+        &self,
+        hash: &str,
+        generation: usize,
+    ) -> anyhow::Result<Option<DiskCacheEntry>> {
+        let path = self.generation_path(hash, generation);
+        debug(format!("looking for path: {}", path.display()));
+        if path.exists() {
+            let contents = match std::fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                // Removed by another process since checking
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(_) => return Err(unable_to_read_cache_entry_error(&path)),
+            };
+            match self.parse_entry(path.clone(), contents) {
+                // Only the header of an entry that can't be decrypted can be read, which isn't
+                // enough to use it, but it isn't corrupt either
+                Ok(entry) if entry.is_locked() => Err(wrong_or_missing_key_error(&path)),
+                Ok(entry) => Ok(Some(entry)),
+                // A corrupt entry is treated as missing, so the command is run and recorded
+                // again rather than failing
+                Err(e) => {
+                    debug(format!("{}, treating as missing", e));
+                    self.set_aside(&path);
+                    Ok(None)
+                }
+            }
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn record(&self, command: &mut Command, options: &RecordOptions) -> anyhow::Result<i32> {
+        // A result that can't be decrypted with the key given (if any) is left for whoever has
+        // the key, rather than replaced
+        let locked = matches!(self.read(command.hash()), Err(e) if is_wrong_or_missing_key(&e));
+        if self.read_only || locked {
+            info(match self.read_only {
+                true => "not recording result: cache is read-only".into(),
+                false => {
+                    "not recording result: unable to decrypt the result it would replace".into()
+                }
+            });
+            let result = command.run(std::io::sink(), std::io::sink(), options.run_options())?;
+            if result.timed_out {
+                return Ok(options.timeout_exit_code);
+            }
+            if let Some(signal) = result.interrupted {
+                return Ok(128 + signal);
+            }
+            return Ok(result.status);
+        }
+
+        let now = SystemTime::now();
+        let ulid = &command.ulid;
+
+        let out = self.path(command.hash(), &format!("{ulid}.out"));
+        let err = self.path(command.hash(), &format!("{ulid}.err"));
+
+        let out_file = self.create_file(&out)?;
+        let err_file = self.create_file(&err)?;
+
+        let result = command.run(out_file, err_file, options.run_options())?;
+
+        if result.timed_out {
+            info("not recording result: command timed out".into());
+            std::fs::remove_file(&out)?;
+            std::fs::remove_file(&err)?;
+            return Ok(options.timeout_exit_code);
+        }
+
+        if let Some(signal) = result.interrupted {
+            info(format!(
+                "not recording result: interrupted by {}",
+                signal_name(signal)
+            ));
+            std::fs::remove_file(&out)?;
+            std::fs::remove_file(&err)?;
+            return Ok(128 + signal);
+        }
+
+        if capture_failed(&result) {
+            let _ = std::fs::remove_file(&out);
+            let _ = std::fs::remove_file(&err);
+            return Ok(result.status);
+        }
+
+        let status = result.status;
+        let stdout_len = std::fs::metadata(&out)?.len();
+        let stderr_len = std::fs::metadata(&err)?.len();
+        let skip_reason = options.skip_reason(
+            status,
+            result.signal,
+            &result.stdout_matches,
+            stdout_len,
+            stderr_len,
+        );
+
+        if let Some(reason) = &skip_reason {
+            info(format!("not recording result: {}", reason));
+        } else {
+            info(format!("recording result with exit code {}", status));
+        }
+
+        if skip_reason.is_none() {
+            let blobs = OutputBlobs {
+                stdout: self.store_blob(&out, stderr_len > 0)?,
+                stderr: self.store_blob(&err, stdout_len > 0)?,
+            };
+
+            let meta = DiskCacheEntryMeta {
+                command: command.clone(),
+                created: now,
+                expires: options.expires_at(now),
+                status,
+                signal: result.signal,
+                env: options.env(),
+                duration: Some(result.duration),
+                usage: result.usage,
+                diverged: None,
+                tags: options.tags().clone(),
+                id: options.id(),
+                checksums: Some(self.checksums(&blobs)?),
+                pinned: false,
+            };
+
+            if options.keep_history > 0 {
+                self.rotate(command.hash(), options.keep_history)?;
+                self.write(command.hash(), meta, blobs, self.encrypt)?;
+            } else {
+                self.replace(command.hash(), meta, blobs)?;
+            }
+        } else {
+            std::fs::remove_file(&out)?;
+            std::fs::remove_file(&err)?;
+        }
+        Ok(status)
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<(String, DiskCacheEntry)>> {
+        let mut entries = vec![];
+        for file in std::fs::read_dir(&self.root)
+            .map_err(|_| unable_to_read_cache_entry_error(&self.root))?
+        {
+            let path = file?.path();
+            let Some(hash) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(".ron"))
+                .filter(|hash| !hash.contains('.'))
+            else {
+                continue;
+            };
+
+            match self.read(hash) {
+                Ok(Some(entry)) => entries.push((hash.to_string(), entry)),
+                Ok(None) => (),
+                Err(e) => debug(format!("skipping {}: {}", path.display(), e)),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn has_corrupt(&self, hash: &str) -> bool {
+        DiskCache::corrupt_path(&self.path(hash, "ron")).exists()
+    }
+
+    /// Commands are listed from an index of the entries written, which is much faster than
+    /// reading every entry. The index is built from the entries the first time it's needed,
+    /// and rewritten without removed entries once they make up most of it.
+    fn commands(&self) -> anyhow::Result<Vec<CachedCommand>> {
+        let list = || -> anyhow::Result<Vec<CachedCommand>> {
+            Ok(self
+                .list()?
+                .iter()
+                .filter(|(_, entry)| !entry.encrypted)
+                .map(|(hash, entry)| CachedCommand::new(hash, entry.command()))
+                .collect())
+        };
+        match self.read_index() {
+            Some((commands, lines)) if self.read_only || lines <= 2 * commands.len() + 100 => {
+                return Ok(commands)
+            }
+            None if self.read_only => return list(),
+            _ => (),
+        }
+
+        // Entries written while the index is rebuilt wait for the lock, then add themselves
+        let _lock = self.lock_index()?;
+        let commands = match self.read_index() {
+            Some((commands, _)) => commands,
+            None => list()?,
+        };
+        self.write_index(&commands)?;
+        Ok(commands)
+    }
+
+    /// Nothing is written to a read-only cache, so there's nothing to coordinate and the lock
+    /// is always granted.
+    fn try_lock(&self, hash: &str) -> anyhow::Result<Option<CacheLock>> {
+        if self.read_only {
+            return Ok(Some(CacheLock {
+                _file: None,
+                release: None,
+            }));
+        }
+        try_lock_file(&self.path(hash, "lock"), self.modes)
+    }
+
+    fn remove_unused_locks(&self) -> anyhow::Result<usize> {
+        if self.read_only {
+            return Ok(0);
+        }
+        remove_unused_lock_files(&self.root, self.modes)
+    }
+
+    fn store(&self, hash: &str, entry: &impl CacheEntry) -> anyhow::Result<()> {
+        if self.read_only {
+            return Err(self.read_only_error());
+        }
+
+        let (stdout, stderr) = entry.raw_output()?;
+        let ulid = Ulid::new();
+        let store = |suffix: &str, content: &[u8], other: &[u8]| {
+            let path = self.path(hash, &format!("{ulid}.{suffix}"));
+            self.create_file(&path)?
+                .write_all(content)
+                .map_err(|_| unable_to_write_to_cache_error(&path))?;
+            self.store_blob(&path, !other.is_empty())
+        };
+        let blobs = OutputBlobs {
+            stdout: store("out", &stdout, &stderr)?,
+            stderr: store("err", &stderr, &stdout)?,
+        };
+
+        let mut meta = DiskCacheEntryMeta::from_entry(entry);
+        meta.checksums = Some(self.checksums(&blobs)?);
+        self.replace(hash, meta, blobs)
+    }
+
+    fn set_expiry(&self, hash: &str, expires: SystemTime) -> anyhow::Result<()> {
+        self.update_meta(hash, |meta| meta.expires = Some(expires))?;
+        Ok(())
+    }
+
+    fn set_pinned(&self, hash: &str, pinned: bool) -> anyhow::Result<bool> {
+        self.update_meta(hash, |meta| meta.pinned = pinned)
+    }
+
+    fn remove(&self, hash: &str) -> anyhow::Result<bool> {
+        if self.read_only {
+            return Err(self.read_only_error());
+        }
+
+        let path = self.path(hash, "ron");
+        debug(format!("cache remove: {}, {}", hash, path.display()));
+        if let Some(current) = self.read(hash)? {
+            std::fs::remove_file(&path).map_err(|_| unable_to_write_to_cache_error(&path))?;
+            self.remove_output(&current)?;
+
+            // Previous results kept with --keep-history are removed too
+            let mut generation = 1;
+            while let Some(previous) = self.read_generation(hash, generation)? {
+                self.remove_output(&previous)?;
+                let path = self.generation_path(hash, generation);
+                std::fs::remove_file(&path).map_err(|_| unable_to_write_to_cache_error(&path))?;
+                generation += 1;
+            }
+            remove_lock_file(&self.path(hash, "lock"), self.modes)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+pub(crate) fn replay_output<O>(stdout: O, stderr: O, output: &mut Output) -> std::io::Result<()>
+where
+    O: Read,
+{
+    let mut stdout = OutputReader {
+        reader: BufReader::new(stdout),
+    }
+    .peekable();
+
+    let mut stderr = OutputReader {
+        reader: BufReader::new(stderr),
+    }
+    .peekable();
+
+    loop {
+        match (stdout.peek(), stderr.peek()) {
+            (Some((ot, ol)), Some((et, el))) => {
+                if ot < et {
+                    output.stdout.write_all(ol)?;
+                    stdout.next();
+                } else {
+                    output.stderr.write_all(el)?;
+                    stderr.next();
+                }
+            }
+            (Some((_, ol)), None) => {
+                output.stdout.write_all(ol)?;
+                stdout.next();
+            }
+            (None, Some((_, el))) => {
+                output.stderr.write_all(el)?;
+                stderr.next();
+            }
+            (None, None) => break,
+        }
+    }
+    output.stdout.flush()?;
+    output.stderr.flush()
+}
+
+/// A result read from a cache.
+pub trait CacheEntry {
+    /// When the result was recorded.
+    fn created_at(&self) -> SystemTime;
+    /// When the result expires, if it was recorded with an expiry.
+    fn expires_at(&self) -> Option<SystemTime>;
+    /// The exit code of the command.
+    fn command_status(&self) -> i32;
+    /// The signal that killed the command, if it was killed by one.
+    fn command_signal(&self) -> Option<i32>;
+    /// The command the result was recorded for.
+    fn command(&self) -> &Command;
+    /// Environment variables recorded alongside the result.
+    fn env(&self) -> &BTreeMap<String, String>;
+    /// How long the command took to run, if known.
+    fn duration(&self) -> Option<Duration>;
+    /// The CPU time and memory used by the command, if known.
+    fn usage(&self) -> Option<ResourceUsage>;
+    /// When a fresh run of the command found different output or exit status, if it has.
+    fn diverged_at(&self) -> Option<SystemTime>;
+    /// Labels given with `--tag` when the result was recorded.
+    fn tags(&self) -> &BTreeSet<String>;
+    /// The identifier given with `--id` when the result was recorded.
+    fn id(&self) -> Option<&str>;
+    /// Whether the result has been pinned with `pin`, so it's fresh whenever it expires.
+    fn pinned(&self) -> bool;
+
+    /// Describes how long the command took to run, e.g. `42.3s` or `unknown`.
+    fn describe_duration(&self) -> String {
+        match self.duration() {
+            Some(duration) => format!("{:.1}s", duration.as_secs_f64()),
+            None => "unknown".into(),
+        }
+    }
+
+    /// Describes the CPU time used by the command, e.g. `1.2s user, 0.3s sys` or `unknown`.
+    fn describe_cpu_time(&self) -> String {
+        match self.usage() {
+            Some(usage) => format!(
+                "{:.1}s user, {:.1}s sys",
+                usage.user_time.as_secs_f64(),
+                usage.system_time.as_secs_f64()
+            ),
+            None => "unknown".into(),
+        }
+    }
+
+    /// Describes the peak memory used by the command, e.g. `12.3 MiB` or `unknown`.
+    fn describe_max_rss(&self) -> String {
+        match self.usage() {
+            Some(usage) => format!("{:.1} MiB", usage.max_rss as f64 / (1024.0 * 1024.0)),
+            None => "unknown".into(),
+        }
+    }
+
+    /// Describes how the command finished, e.g. `exit code 0` or `killed by SIGKILL`.
+    fn describe_status(&self) -> String {
+        match self.command_signal() {
+            Some(signal) => format!("killed by {}", signal_name(signal)),
+            None => format!("exit code {}", self.command_status()),
+        }
+    }
+    /// Writes the captured output to `output`, interleaving stdout and stderr as recorded.
+    fn replay_command_output(&self, output: &mut Output) -> anyhow::Result<()>;
+    /// The captured stdout of the command.
+    fn stdout(&self) -> anyhow::Result<String>;
+    /// The captured stdout as stored, to be read with an `OutputReader` a piece at a time.
+    fn stdout_reader(&self) -> anyhow::Result<Box<dyn Read + Send>>;
+    /// The captured stdout and stderr as stored, with a timestamp on each line, for copying the
+    /// entry into another cache.
+    fn raw_output(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)>;
+    /// The name and size of the stored stdout and stderr, without reading them. Output shared
+    /// with other results has the same name, so it can be counted once. Output that's missing
+    /// is left out.
+    fn output_sizes(&self) -> Vec<(String, usize)>;
+
+    /// Whether the result can be returned without running the command again.
+    fn is_fresh(&self) -> bool {
+        self.pinned()
+            || self
+                .expires_at()
+                .is_none_or(|expires| SystemTime::now() < expires)
+    }
+
+    /// When the result stops being fresh, either because it expires or because it becomes older
+    /// than `max_age`. Returns `None` when the result never becomes stale.
+    fn stale_at(&self, max_age: Option<Duration>) -> Option<SystemTime> {
+        let aged_at = max_age.map(|duration| self.created_at() + duration);
+        let expires = self.expires_at().filter(|_| !self.pinned());
+        match (expires, aged_at) {
+            (Some(expires), Some(aged)) => Some(expires.min(aged)),
+            (expires, aged) => expires.or(aged),
+        }
+    }
+
+    /// Entries created in the future, as happens when clocks are corrected or differ between
+    /// machines sharing a cache, are treated as brand new.
+    fn is_younger_than(&self, duration: Duration) -> bool {
+        self.created_at().elapsed().unwrap_or_default() < duration
+    }
+
+    /// Replays the captured output, returning the command's exit code. Returns `None`, having
+    /// replayed nothing, when the output was removed after the entry was read (as happens when
+    /// another process records a new result), which callers treat as a miss.
+    fn replay(&self, output: &mut Output) -> anyhow::Result<Option<i32>> {
+        match self.replay_command_output(output) {
+            Ok(()) => Ok(Some(self.command_status())),
+            Err(e) if is_not_found(&e) => {
+                debug("cached output has been removed, treating as a miss".into());
+                Ok(None)
+            }
+            Err(e) if e.is::<CorruptOutput>() => {
+                debug(format!("{}, treating as a miss", e));
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// A cached result marked as having diverged from a fresh run of its command at the given
+/// time, to be stored in place of the result.
+pub(crate) struct DivergedEntry<'a> {
+    pub entry: &'a dyn CacheEntry,
+    pub at: SystemTime,
+}
+
+impl CacheEntry for DivergedEntry<'_> {
+    fn created_at(&self) -> SystemTime {
+        self.entry.created_at()
+    }
+
+    fn expires_at(&self) -> Option<SystemTime> {
+        self.entry.expires_at()
+    }
+
+    fn command_status(&self) -> i32 {
+        self.entry.command_status()
+    }
+
+    fn command_signal(&self) -> Option<i32> {
+        self.entry.command_signal()
+    }
+
+    fn command(&self) -> &Command {
+        self.entry.command()
+    }
+
+    fn env(&self) -> &BTreeMap<String, String> {
+        self.entry.env()
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        self.entry.duration()
+    }
+
+    fn usage(&self) -> Option<ResourceUsage> {
+        self.entry.usage()
+    }
+
+    fn diverged_at(&self) -> Option<SystemTime> {
+        Some(self.at)
+    }
+
+    fn tags(&self) -> &BTreeSet<String> {
+        self.entry.tags()
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.entry.id()
+    }
+
+    fn pinned(&self) -> bool {
+        self.entry.pinned()
+    }
+
+    fn replay_command_output(&self, output: &mut Output) -> anyhow::Result<()> {
+        self.entry.replay_command_output(output)
+    }
+
+    fn stdout(&self) -> anyhow::Result<String> {
+        self.entry.stdout()
+    }
+
+    fn stdout_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        self.entry.stdout_reader()
+    }
+
+    fn raw_output(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        self.entry.raw_output()
+    }
+
+    fn output_sizes(&self) -> Vec<(String, usize)> {
+        self.entry.output_sizes()
+    }
+}
+
+pub struct OutputReader<R>
+where
+    R: Read,
+{
+    pub reader: BufReader<R>,
+}
+
+impl<R> OutputReader<R>
+where
+    R: Read,
+{
+    /// All of the captured output, as text.
+    pub fn into_string(self) -> String {
+        let bytes: Vec<u8> = self.flat_map(|(_, line)| line).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl<R> Iterator for OutputReader<R>
+where
+    R: Read,
+{
+    type Item = (u128, Vec<u8>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        let mut bytes: [u8; 16] = [0; 16];
+
+        // First 16 bytes are the timestamp
+
+        match self.reader.read_exact(&mut bytes) {
+            Ok(()) => (),
+            Err(_) => return None,
+        }
+        let timestamp = u128::from_be_bytes(bytes);
+
+        // Pieces of long lines give their length, otherwise the line runs to the next newline
+
+        if timestamp & PARTIAL_LINE != 0 {
+            let mut length = [0; 8];
+            self.reader.read_exact(&mut length).ok()?;
+            line.resize(u64::from_be_bytes(length) as usize, 0);
+            self.reader.read_exact(&mut line).ok()?;
+            return Some((timestamp & !PARTIAL_LINE, line));
+        }
+
+        match self.reader.read_until(b'\n', &mut line) {
             Ok(0) => None,
-            Ok(_) => Some((u128::from_be_bytes(bytes), line.to_string())),
+            Ok(_) => Some((timestamp, line)),
             Err(_) => None,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::memory::MemoryCache;
+    use super::*;
+    use crate::command::ScopeBuilder;
+    use ulid::Ulid;
+
+    /// Checks behaviour shared by every cache implementation.
+    fn check_cache<C, E>(cache: &C) -> anyhow::Result<()>
+    where
+        C: Cache<E>,
+        E: CacheEntry,
+    {
+        let mut command = Command::new(ScopeBuilder::new().cmd("echo").args("hello").build()?);
+        let hash = command.hash().to_string();
+        let options = FindOptions::default();
+
+        assert!(
+            cache.find(&hash, &options)?.is_none(),
+            "missing before recording"
+        );
+
+        assert_eq!(cache.record(&mut command, &RecordOptions::default())?, 0);
+        let entry = cache.find(&hash, &options)?.expect("found once recorded");
+        assert_eq!(entry.stdout()?, "hello\n");
+        assert_eq!(entry.command_status(), 0);
+        assert_eq!(cache.list()?.len(), 1, "listed once recorded");
+
+        let mut look_back = FindOptions::default();
+        look_back.set_max_age(Some(Duration::ZERO));
+        assert!(
+            matches!(cache.lookup(&hash, &look_back)?, FindOutcome::Stale(_)),
+            "stale when older than look back"
+        );
+
+        let mut expiring = RecordOptions::default();
+        expiring.set_cache_for(Some(Duration::ZERO));
+        cache.record(&mut command, &expiring)?;
+        assert!(
+            matches!(cache.lookup(&hash, &options)?, FindOutcome::Expired(_)),
+            "expired once cache for has passed"
+        );
+
+        let mut renewing = FindOptions::default();
+        renewing.set_renew_for(Some(Duration::from_secs(3600)));
+        let mut minute = RecordOptions::default();
+        minute.set_cache_for(Some(Duration::from_secs(60)));
+        cache.record(&mut command, &minute)?;
+        cache
+            .find(&hash, &renewing)?
+            .expect("fresh before renewing");
+        let renewed = cache.read(&hash)?.expect("still cached once renewed");
+        assert!(
+            renewed
+                .expires_at()
+                .is_some_and(|expires| expires > SystemTime::now() + Duration::from_secs(3000)),
+            "expiry moved on by a hit"
+        );
+        assert_eq!(renewed.stdout()?, "hello\n", "output kept when renewed");
+
+        let mut failing = Command::new(ScopeBuilder::new().cmd("false").build()?);
+        assert_eq!(cache.record(&mut failing, &RecordOptions::default())?, 1);
+        assert!(
+            cache.read(failing.hash())?.is_none(),
+            "failures not recorded"
+        );
+
+        assert!(cache.remove(&hash)?, "removes recorded entry");
+        assert!(cache.read(&hash)?.is_none(), "missing once removed");
+        assert!(!cache.remove(&hash)?, "nothing to remove");
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_cache() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        check_cache(&DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?)?;
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_cache() -> anyhow::Result<()> {
+        check_cache(&MemoryCache::new())
+    }
+
+    #[test]
+    fn test_sqlite_cache() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        check_cache(&sqlite::SqliteCache::open(
+            root.join("cache.db"),
+            CacheModes::PRIVATE,
+        )?)?;
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("660").unwrap(), 0o660);
+        assert_eq!(parse_mode("0660").unwrap(), 0o660);
+        assert_eq!(parse_mode("0o2770").unwrap(), 0o2770);
+        assert!(parse_mode("").is_err());
+        assert!(parse_mode("9").is_err());
+        assert!(parse_mode("17777").is_err());
+        assert!(parse_mode("rw-rw----").is_err());
+    }
+
+    #[test]
+    fn test_entries_created_in_the_future() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut command = Command::new(ScopeBuilder::new().cmd("echo").build()?);
+        let hash = command.hash().to_string();
+        cache.record(&mut command, &RecordOptions::default())?;
+
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        let entry = cache.read(&hash)?.expect("recorded");
+        cache.store(&hash, &entry.with_created(future))?;
+
+        let mut options = FindOptions::default();
+        options.set_max_age(Some(Duration::from_secs(1)));
+        options.set_allow_expired(true, None);
+        assert!(
+            matches!(cache.lookup(&hash, &options)?, FindOutcome::Fresh(_)),
+            "treated as brand new"
+        );
+
+        let mut expiring = RecordOptions::default();
+        expiring.set_cache_for(Some(Duration::ZERO));
+        cache.record(&mut command, &expiring)?;
+        let entry = cache.read(&hash)?.expect("recorded");
+        cache.store(&hash, &entry.with_created(future))?;
+        assert!(
+            cache.find_expired(&hash, &options)?.is_some(),
+            "expired, but within max age"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encrypted_disk_cache() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let key = || EncryptionKey::from_bytes(b"0123456789abcdef0123456789abcdef");
+        let mut cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        cache.set_encryption(Some(key()), true);
+        check_cache(&cache)?;
+
+        let mut secret = Command::new(ScopeBuilder::new().cmd("echo").args("hunter2").build()?);
+        cache.record(&mut secret, &RecordOptions::default())?;
+        let entry = cache.read(secret.hash())?.expect("recorded");
+        assert_eq!(entry.stdout()?, "hunter2\n", "round trips output");
+        assert_eq!(entry.command().to_string(), "echo hunter2");
+
+        let stored = |dir: &Path| -> anyhow::Result<Vec<u8>> {
+            let mut contents = vec![];
+            for file in std::fs::read_dir(dir)? {
+                let path = file?.path();
+                if path.is_file() {
+                    contents.extend(std::fs::read(path)?);
+                }
+            }
+            Ok(contents)
+        };
+        for contents in [stored(&root)?, stored(&root.join("blobs"))?] {
+            assert!(
+                !contents.windows(7).any(|window| window == b"hunter2"),
+                "nothing stored in plain text"
+            );
+        }
+
+        assert!(cache.set_pinned(secret.hash(), true)?);
+        let entry = cache.read(secret.hash())?.expect("still recorded");
+        assert!(
+            entry.pinned() && entry.encrypted,
+            "stays encrypted once updated"
+        );
+
+        let mut plain = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        plain.set_encryption(Some(key()), false);
+        let mut other = Command::new(ScopeBuilder::new().cmd("echo").args("plain").build()?);
+        plain.record(&mut other, &RecordOptions::default())?;
+        assert!(!plain.read(other.hash())?.expect("recorded").encrypted);
+        assert_eq!(
+            plain.read(secret.hash())?.expect("recorded").stdout()?,
+            "hunter2\n",
+            "encrypted and unencrypted entries share a cache"
+        );
+        assert!(
+            plain
+                .commands()?
+                .iter()
+                .all(|command| command.hash != secret.hash()),
+            "encrypted commands aren't indexed"
+        );
+
+        let mut wrong = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        wrong.set_encryption(
+            Some(EncryptionKey::from_bytes(
+                b"fedcba9876543210fedcba9876543210",
+            )),
+            false,
+        );
+        let missing = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        for cache in [&wrong, &missing] {
+            let error = cache.read(secret.hash()).unwrap_err().to_string();
+            assert!(error.contains("wrong or missing encryption key"), "{error}");
+            assert!(
+                cache.read(other.hash())?.is_some(),
+                "unencrypted still read"
+            );
+
+            // Treated as missing when running the command, but not replaced
+            assert!(cache
+                .find(secret.hash(), &FindOptions::default())?
+                .is_none());
+            let mut again = Command::new(secret.scope.clone());
+            let mut options = RecordOptions::default();
+            options.set_silent(true);
+            cache.record(&mut again, &options)?;
+        }
+        assert!(
+            root.join(format!("{}.ron", secret.hash())).exists(),
+            "not set aside as corrupt"
+        );
+        assert_eq!(
+            cache.read(secret.hash())?.expect("recorded").command().ulid,
+            secret.ulid,
+            "not replaced"
+        );
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only_cache() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let mut recorded = Command::new(ScopeBuilder::new().cmd("echo").args("recorded").build()?);
+        DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?
+            .record(&mut recorded, &RecordOptions::default())?;
+        let files = std::fs::read_dir(&root)?.count();
+
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, true)?;
+        let mut missing = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        assert!(cache.read(recorded.hash())?.is_some(), "replays results");
+        assert_eq!(cache.record(&mut missing, &RecordOptions::default())?, 0);
+        assert!(
+            cache.read(missing.hash())?.is_none(),
+            "doesn't record results"
+        );
+        assert!(cache.try_lock(missing.hash())?.is_some());
+        assert!(
+            cache.remove(recorded.hash()).is_err(),
+            "can't remove results"
+        );
+        assert_eq!(std::fs::read_dir(&root)?.count(), files, "writes nothing");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_unused_lock_files_are_removed() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let other = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let mut command = Command::new(ScopeBuilder::new().cmd("echo").args("hi").build()?);
+        let hash = command.hash().to_string();
+        let lock_path = cache.path(&hash, "lock");
+
+        let lock = cache.try_lock(&hash)?;
+        assert_eq!(other.remove_unused_locks()?, 0, "kept while in use");
+        assert!(lock_path.exists());
+        drop(lock);
+        assert_eq!(other.remove_unused_locks()?, 1);
+        assert!(!lock_path.exists());
+
+        let mut options = RecordOptions::default();
+        options.set_silent(true);
+        let lock = cache.try_lock(&hash)?;
+        cache.record(&mut command, &options)?;
+        drop(lock);
+        assert!(cache.remove(&hash)?);
+        assert!(!lock_path.exists(), "removed with the entry");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_commands_are_indexed() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let mut first = Command::new(ScopeBuilder::new().cmd("echo").args("first").build()?);
+        cache.record(&mut first, &RecordOptions::default())?;
+        assert!(!root.join("commands").exists(), "no index until needed");
+
+        let commands = cache.commands()?;
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].words, vec!["echo", "first"]);
+        assert!(!commands[0].shell);
+        assert!(
+            root.join("commands").exists(),
+            "index built when first read"
+        );
+
+        let mut second = Command::new(
+            ScopeBuilder::new()
+                .cmd("echo second")
+                .shell("/bin/sh")
+                .build()?,
+        );
+        cache.record(&mut second, &RecordOptions::default())?;
+        let commands = cache.commands()?;
+        assert_eq!(commands.len(), 2, "recorded entries added to the index");
+        let shell = commands
+            .iter()
+            .find(|command| command.hash == second.hash())
+            .expect("indexed");
+        assert_eq!(shell.words, vec!["echo second"]);
+        assert!(shell.shell);
+
+        cache.remove(first.hash())?;
+        let commands = cache.commands()?;
+        assert_eq!(commands.len(), 1, "removed entries aren't listed");
+
+        let read_only = DiskCache::new(root.clone(), CacheModes::PRIVATE, true)?;
+        assert_eq!(
+            read_only.commands()?,
+            commands,
+            "read-only caches use the index"
+        );
+        std::fs::remove_file(root.join("commands"))?;
+        assert_eq!(
+            read_only.commands()?,
+            commands,
+            "read-only caches list entries without an index"
+        );
+        assert!(!root.join("commands").exists(), "writes nothing");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_writes_are_never_read() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let mut command = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        let hash = command.hash().to_string();
+
+        cache.record(&mut command, &RecordOptions::default())?;
+        let complete = std::fs::read_to_string(cache.path(&hash, "ron"))?;
+
+        let leftovers = std::fs::read_dir(&root)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".tmp"))
+            .count();
+        assert_eq!(leftovers, 0, "no temporary files left after writing");
+
+        // A write interrupted part way through leaves only a truncated temporary file
+        let partial = cache.path(&hash, &format!("{}.ron.tmp", Ulid::new()));
+        std::fs::write(&partial, &complete[..complete.len() / 2])?;
+
+        let entry = cache.read(&hash)?.expect("entry is still readable");
+        assert_eq!(entry.command_status(), 0, "reads the complete entry");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_entries_are_set_aside() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let mut command = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        let hash = command.hash().to_string();
+        let path = cache.path(&hash, "ron");
+
+        cache.record(&mut command, &RecordOptions::default())?;
+        let complete = std::fs::read_to_string(&path)?;
+        let (header, record) = complete.split_once('\n').unwrap_or_default();
+        let header_length = header.len();
+
+        // Still ends like a complete entry, and would only fail to parse when first used
+        let damaged = format!("{header}\n{}", record.replacen("command", "commend", 1));
+        // As written by older versions, without a hash of the record
+        let unhashed_header =
+            header.replacen(&format!(",record:Some(\"{}\")", record_hash(record)), "", 1);
+        assert_ne!(unhashed_header, header);
+        let unhashed = format!(
+            "{unhashed_header}\n{}",
+            record.replacen("command", "commend", 1)
+        );
+
+        for (description, contents) in [
+            ("empty", ""),
+            ("truncated header", &complete[..header_length / 2]),
+            ("truncated record", &complete[..complete.len() / 2]),
+            ("wrong schema", "(name: \"something else\")"),
+            ("damaged record", &damaged),
+            ("damaged record without hash", &unhashed),
+        ] {
+            std::fs::write(&path, contents)?;
+            assert!(!cache.has_corrupt(&hash), "{description}");
+            assert!(
+                cache.read(&hash)?.is_none(),
+                "{description}: treated as missing"
+            );
+            assert!(!path.exists(), "{description}: moved aside");
+            assert!(
+                cache.has_corrupt(&hash),
+                "{description}: corrupt entry found"
+            );
+            std::fs::remove_file(DiskCache::corrupt_path(&path))?;
+        }
+
+        std::fs::write(&path, format!("{unhashed_header}\n{record}"))?;
+        assert!(
+            cache.read(&hash)?.is_some(),
+            "undamaged record without hash"
+        );
+
+        // Left in place in a read-only cache
+        std::fs::write(&path, "")?;
+        let read_only = DiskCache::new(root.clone(), CacheModes::PRIVATE, true)?;
+        assert!(read_only.read(&hash)?.is_none());
+        assert!(path.exists(), "not moved");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_output_is_set_aside() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let mut command = Command::new(ScopeBuilder::new().cmd("echo").args("checked").build()?);
+        let hash = command.hash().to_string();
+        let mut options = RecordOptions::default();
+        options.set_silent(true);
+        cache.record(&mut command, &options)?;
+
+        let entry = cache.read(&hash)?.expect("entry is readable");
+        assert!(entry.verify_output(true)?, "output matches checksums");
+
+        // Same length, so only noticed when the contents are checked
+        let blob = entry.stdout.clone();
+        let mut contents = std::fs::read(&blob)?;
+        *contents.last_mut().unwrap() = b'?';
+        std::fs::write(&blob, &contents)?;
+        assert!(entry.verify_output(false)?, "length still matches");
+        assert!(entry.verify_output(true).unwrap_err().is::<CorruptOutput>());
+
+        std::fs::write(&blob, &contents[..contents.len() / 2])?;
+        assert!(entry
+            .verify_output(false)
+            .unwrap_err()
+            .is::<CorruptOutput>());
+
+        let mut output = Output::new(std::io::sink(), std::io::sink());
+        assert_eq!(entry.replay(&mut output)?, None, "treated as a miss");
+        assert!(cache.has_corrupt(&hash), "entry moved aside");
+        assert!(!blob.exists(), "output moved aside");
+
+        // Recorded again without sharing the corrupt output
+        cache.record(&mut command, &options)?;
+        let entry = cache.read(&hash)?.expect("entry is recorded again");
+        assert!(entry.verify_output(true)?);
+        assert_eq!(entry.stdout()?, "checked\n");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_replaying_while_recording() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        // Output differs every time, so each new result removes the previous result's output
+        let scope = ScopeBuilder::new().cmd("date").args("+%s%N").build()?;
+        let hash = Command::new(scope.clone()).hash().to_string();
+        let mut options = RecordOptions::default();
+        options.set_silent(true);
+        cache.record(&mut Command::new(scope.clone()), &options)?;
+
+        let recording = std::sync::atomic::AtomicBool::new(true);
+        let replayed = std::thread::scope(|threads| -> anyhow::Result<usize> {
+            let writer = threads.spawn(|| {
+                let result = (0..100).try_for_each(|_| {
+                    cache
+                        .record(&mut Command::new(scope.clone()), &options)
+                        .map(|_| ())
+                });
+                recording.store(false, std::sync::atomic::Ordering::SeqCst);
+                result
+            });
+
+            let mut replayed = 0;
+            while recording.load(std::sync::atomic::Ordering::SeqCst) {
+                if let Some(entry) = cache.find(&hash, &FindOptions::default())? {
+                    let mut output = Output::new(std::io::sink(), std::io::sink());
+                    if entry.replay(&mut output)?.is_some() {
+                        replayed += 1;
+                    }
+                }
+            }
+            writer.join().expect("writer finishes")?;
+            Ok(replayed)
+        })?;
+        assert!(replayed > 0, "replays results while recording");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_expired_entries_are_removed() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let other = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let mut command = Command::new(ScopeBuilder::new().cmd("echo").args("hi").build()?);
+        let hash = command.hash().to_string();
+        let blobs = || -> anyhow::Result<usize> {
+            Ok(std::fs::read_dir(root.join("blobs"))?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().len() == 40)
+                .count())
+        };
+
+        let mut expiring = RecordOptions::default();
+        expiring.set_cache_for(Some(Duration::ZERO));
+        expiring.set_silent(true);
+        cache.record(&mut command, &expiring)?;
+        assert!(blobs()? > 0);
+
+        let mut allow_expired = FindOptions::default();
+        allow_expired.set_allow_expired(true, None);
+        assert!(cache.find(&hash, &allow_expired)?.is_none());
+        assert!(cache.read(&hash)?.is_some(), "kept while it might be used");
+
+        // Another process has already read the expired entry when it's removed
+        assert!(matches!(
+            other.lookup(&hash, &FindOptions::default())?,
+            FindOutcome::Expired(_)
+        ));
+        assert!(cache.find(&hash, &FindOptions::default())?.is_none());
+        assert!(!cache.path(&hash, "ron").exists(), "entry removed");
+        assert_eq!(blobs()?, 0, "output removed");
+        assert!(other.find(&hash, &FindOptions::default())?.is_none());
+
+        // Not while a new result is being recorded
+        cache.record(&mut command, &expiring)?;
+        let lock = other.try_lock(&hash)?;
+        assert!(cache.find(&hash, &FindOptions::default())?.is_none());
+        assert!(cache.read(&hash)?.is_some(), "kept while locked");
+        drop(lock);
+
+        // Not when previous results are kept
+        expiring.set_keep_history(1);
+        cache.record(&mut command, &expiring)?;
+        assert!(cache.find(&hash, &FindOptions::default())?.is_none());
+        assert_eq!(cache.history(&hash)?.len(), 2, "history kept");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_renewed_entries_expire_once_idle() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let read_only = DiskCache::new(root.clone(), CacheModes::PRIVATE, true)?;
+        let mut command = Command::new(ScopeBuilder::new().cmd("echo").args("hi").build()?);
+        let hash = command.hash().to_string();
+        let ttl = Duration::from_millis(400);
+
+        let mut expiring = RecordOptions::default();
+        expiring.set_cache_for(Some(ttl));
+        expiring.set_silent(true);
+        cache.record(&mut command, &expiring)?;
+        let blobs = cache.read(&hash)?.unwrap().header.blobs.unwrap();
+
+        let mut renewing = FindOptions::default();
+        renewing.set_renew_for(Some(ttl));
+        let expires = cache.read(&hash)?.unwrap().expires_at();
+        assert!(read_only.find(&hash, &renewing)?.is_some());
+        assert_eq!(
+            cache.read(&hash)?.unwrap().expires_at(),
+            expires,
+            "read-only caches aren't renewed"
+        );
+
+        // Used for longer than it was cached for
+        for _ in 0..4 {
+            std::thread::sleep(ttl / 2);
+            assert!(cache.find(&hash, &renewing)?.is_some(), "renewed by use");
+        }
+        let renewed = cache.read(&hash)?.unwrap().header.blobs.unwrap();
+        assert_eq!(
+            (renewed.stdout, renewed.stderr),
+            (blobs.stdout, blobs.stderr),
+            "output isn't rewritten"
+        );
+
+        std::thread::sleep(ttl + ttl / 4);
+        assert!(cache.find(&hash, &renewing)?.is_none(), "expires once idle");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_identical_output_is_shared() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let blobs = || -> anyhow::Result<usize> {
+            Ok(std::fs::read_dir(root.join("blobs"))?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_name().len() == 40)
+                .count())
+        };
+
+        let mut first = Command::new(
+            ScopeBuilder::new()
+                .cmd("echo")
+                .args("same")
+                .key("first")
+                .build()?,
+        );
+        let mut second = Command::new(
+            ScopeBuilder::new()
+                .cmd("echo")
+                .args("same")
+                .key("second")
+                .build()?,
+        );
+        cache.record(&mut first, &RecordOptions::default())?;
+        cache.record(&mut second, &RecordOptions::default())?;
+
+        assert_eq!(blobs()?, 2, "stdout and stderr shared between entries");
+
+        cache.remove(first.hash())?;
+        assert_eq!(blobs()?, 2, "blobs kept while still referenced");
+        let entry = cache.read(second.hash())?.expect("entry is still readable");
+        assert_eq!(entry.stdout()?, "same\n");
+
+        cache.remove(second.hash())?;
+        assert_eq!(blobs()?, 0, "blobs removed once unreferenced");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_header_is_read_without_parsing_entry() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let mut command = Command::new(ScopeBuilder::new().cmd("echo").args("header").build()?);
+        let hash = command.hash().to_string();
+        let mut options = RecordOptions::default();
+        options.set_silent(true);
+        cache.record(&mut command, &options)?;
+
+        let path = cache.path(&hash, "ron");
+        let contents = std::fs::read_to_string(&path)?;
+        let header = contents.lines().next().unwrap();
+        assert!(header.starts_with(HEADER_PREFIX));
+
+        let entry = cache.read(&hash)?.expect("entry is readable");
+        assert_eq!(
+            entry.command().ulid,
+            command.ulid,
+            "rest of entry is parsed"
+        );
+
+        // Only the header is needed to check and replay the entry, with the rest of the entry
+        // only checked against its hash
+        let header = header.replacen(
+            &record_hash(contents.split_once('\n').unwrap().1),
+            &record_hash("(not an entry)"),
+            1,
+        );
+        std::fs::write(&path, format!("{header}\n(not an entry)"))?;
+        let entry = cache
+            .find(&hash, &FindOptions::default())?
+            .expect("entry is fresh");
+        assert_eq!(entry.command_status(), 0);
+        assert_eq!(entry.stdout()?, "header\n");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_based_entries_are_read() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let command = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        let hash = command.hash().to_string();
+
+        // Entries recorded by older versions refer to their own output files
+        let out = cache.path(&hash, "old.out");
+        std::fs::write(&out, [&0u128.to_be_bytes()[..], b"old\n"].concat())?;
+        let err = cache.path(&hash, "old.err");
+        std::fs::write(&err, "")?;
+        let record = DiskCacheRecord {
+            meta: DiskCacheEntryMeta {
+                command,
+                created: SystemTime::now(),
+                expires: None,
+                status: 0,
+                signal: None,
+                env: BTreeMap::new(),
+                duration: None,
+                usage: None,
+                diverged: None,
+                tags: BTreeSet::new(),
+                id: None,
+                checksums: None,
+                pinned: false,
+            },
+            blobs: None,
+            stdout: out.clone(),
+            stderr: err,
+        };
+        std::fs::write(
+            cache.path(&hash, "ron"),
+            ron::ser::to_string_pretty(&record, PrettyConfig::default())?,
+        )?;
+
+        let entry = cache.read(&hash)?.expect("entry is readable");
+        assert_eq!(entry.stdout()?, "old\n");
+
+        cache.remove(&hash)?;
+        assert!(!out.exists(), "output removed with entry");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_moved_cache_is_readable() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let cache = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let mut options = RecordOptions::default();
+        options.set_silent(true);
+
+        let mut command = Command::new(ScopeBuilder::new().cmd("echo").args("moved").build()?);
+        let hash = command.hash().to_string();
+        cache.record(&mut command, &options)?;
+
+        // Entries recorded by older versions refer to their output files by absolute path
+        let old = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        let old_hash = old.hash().to_string();
+        let out = cache.path(&old_hash, "old.out");
+        std::fs::write(&out, [&0u128.to_be_bytes()[..], b"old\n"].concat())?;
+        let err = cache.path(&old_hash, "old.err");
+        std::fs::write(&err, "")?;
+        let record = DiskCacheRecord {
+            meta: DiskCacheEntryMeta::from_entry(&cache.read(&hash)?.expect("recorded")),
+            blobs: None,
+            stdout: out,
+            stderr: err,
+        };
+        std::fs::write(
+            cache.path(&old_hash, "ron"),
+            ron::ser::to_string_pretty(&record, PrettyConfig::default())?,
+        )?;
+
+        let moved = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        std::fs::rename(&root, &moved)?;
+        let cache = DiskCache::new(moved.clone(), CacheModes::PRIVATE, false)?;
+
+        let entry = cache
+            .find(&hash, &FindOptions::default())?
+            .expect("entry is found");
+        let mut output = Output::new(std::io::sink(), std::io::sink());
+        assert_eq!(entry.replay(&mut output)?, Some(0), "replays");
+        assert_eq!(entry.stdout()?, "moved\n");
+
+        let entry = cache.read(&old_hash)?.expect("old entry is found");
+        assert_eq!(entry.stdout()?, "old\n", "old entry is relocated");
+        cache.remove(&old_hash)?;
+        assert!(
+            !cache.path(&old_hash, "old.out").exists(),
+            "old output removed"
+        );
+
+        std::fs::remove_dir_all(&moved)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::Read;
+use std::time::{Duration, SystemTime};
+
+use super::{Cache, CacheEntry, CacheLock, RecordOptions};
+use crate::command::{Command, ResourceUsage};
+use crate::debug;
+use crate::output::Output;
+
+/// Two caches used together, such as a fast local cache in front of a shared team cache.
+/// Results are looked up in the primary cache first, then the secondary, with results found
+/// only in the secondary copied into the primary. New results are recorded in the primary.
+pub struct LayeredCache<P, S> {
+    primary: P,
+    secondary: S,
+    /// Whether new results are also copied into the secondary cache.
+    populate_secondary: bool,
+    /// Whether results are removed from the secondary cache too.
+    remove_all_layers: bool,
+}
+
+impl<P, S> LayeredCache<P, S> {
+    /// Layers `primary` over `secondary`.
+    pub fn new(
+        primary: P,
+        secondary: S,
+        populate_secondary: bool,
+        remove_all_layers: bool,
+    ) -> Self {
+        LayeredCache {
+            primary,
+            secondary,
+            populate_secondary,
+            remove_all_layers,
+        }
+    }
+}
+
+/// An entry from one of the layers of a `LayeredCache`.
+pub enum LayeredCacheEntry<A, B> {
+    Primary(A),
+    Secondary(B),
+}
+
+impl<A: CacheEntry, B: CacheEntry> LayeredCacheEntry<A, B> {
+    fn entry(&self) -> &dyn CacheEntry {
+        match self {
+            LayeredCacheEntry::Primary(entry) => entry,
+            LayeredCacheEntry::Secondary(entry) => entry,
+        }
+    }
+}
+
+impl<A: CacheEntry, B: CacheEntry> CacheEntry for LayeredCacheEntry<A, B> {
+    fn created_at(&self) -> SystemTime {
+        self.entry().created_at()
+    }
+
+    fn expires_at(&self) -> Option<SystemTime> {
+        self.entry().expires_at()
+    }
+
+    fn command_status(&self) -> i32 {
+        self.entry().command_status()
+    }
+
+    fn command_signal(&self) -> Option<i32> {
+        self.entry().command_signal()
+    }
+
+    fn command(&self) -> &Command {
+        self.entry().command()
+    }
+
+    fn env(&self) -> &BTreeMap<String, String> {
+        self.entry().env()
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        self.entry().duration()
+    }
+
+    fn usage(&self) -> Option<ResourceUsage> {
+        self.entry().usage()
+    }
+
+    fn diverged_at(&self) -> Option<SystemTime> {
+        self.entry().diverged_at()
+    }
+
+    fn tags(&self) -> &BTreeSet<String> {
+        self.entry().tags()
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.entry().id()
+    }
+
+    fn pinned(&self) -> bool {
+        self.entry().pinned()
+    }
+
+    fn replay_command_output(&self, output: &mut Output) -> anyhow::Result<()> {
+        self.entry().replay_command_output(output)
+    }
+
+    fn stdout(&self) -> anyhow::Result<String> {
+        self.entry().stdout()
+    }
+
+    fn stdout_reader(&self) -> anyhow::Result<Box<dyn Read + Send>> {
+        self.entry().stdout_reader()
+    }
+
+    fn raw_output(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        self.entry().raw_output()
+    }
+
+    fn output_sizes(&self) -> Vec<(String, usize)> {
+        self.entry().output_sizes()
+    }
+}
+
+impl<A, B, P, S> Cache<LayeredCacheEntry<A, B>> for LayeredCache<P, S>
+where
+    A: CacheEntry,
+    B: CacheEntry,
+    P: Cache<A>,
+    S: Cache<B>,
+{
+    fn remove(&self, hash: &str) -> anyhow::Result<bool> {
+        let removed = self.primary.remove(hash)?;
+        if self.remove_all_layers {
+            return Ok(self.secondary.remove(hash)? || removed);
+        }
+        Ok(removed)
+    }
+
+    fn record(&self, command: &mut Command, options: &RecordOptions) -> anyhow::Result<i32> {
+        let status = self.primary.record(command, options)?;
+
+        if self.populate_secondary {
+            // Only copy the result if one was just recorded
+            let recorded = self
+                .primary
+                .read(command.hash())?
+                .filter(|entry| entry.command().ulid == command.ulid);
+            if let Some(entry) = recorded {
+                if let Err(e) = self.secondary.store(command.hash(), &entry) {
+                    debug(format!("unable to populate secondary cache: {}", e));
+                }
+            }
+        }
+
+        Ok(status)
+    }
+
+    /// Reads the primary cache's result if it's fresh, and otherwise the secondary cache's,
+    /// copying it into the primary cache. When neither is fresh, whichever result exists is
+    /// returned, so callers can report why it can't be used.
+    fn read(&self, hash: &str) -> anyhow::Result<Option<LayeredCacheEntry<A, B>>> {
+        let primary = self.primary.read(hash)?;
+        if primary.as_ref().is_some_and(|entry| entry.is_fresh()) {
+            return Ok(primary.map(LayeredCacheEntry::Primary));
+        }
+
+        let secondary = self.secondary.read(hash)?;
+        match secondary {
+            Some(entry) if entry.is_fresh() => {
+                debug(format!("copying {} from secondary cache", hash));
+                if let Err(e) = self.primary.store(hash, &entry) {
+                    debug(format!("unable to copy from secondary cache: {}", e));
+                }
+                Ok(Some(LayeredCacheEntry::Secondary(entry)))
+            }
+            secondary => Ok(primary
+                .map(LayeredCacheEntry::Primary)
+                .or(secondary.map(LayeredCacheEntry::Secondary))),
+        }
+    }
+
+    /// Only the primary cache keeps history.
+    fn read_generation(
+        &self,
+        hash: &str,
+        generation: usize,
+    ) -> anyhow::Result<Option<LayeredCacheEntry<A, B>>> {
+        match generation {
+            0 => self.read(hash),
+            generation => Ok(self
+                .primary
+                .read_generation(hash, generation)?
+                .map(LayeredCacheEntry::Primary)),
+        }
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<(String, LayeredCacheEntry<A, B>)>> {
+        let mut entries = self
+            .primary
+            .list()?
+            .into_iter()
+            .map(|(hash, entry)| (hash, LayeredCacheEntry::Primary(entry)))
+            .collect::<Vec<_>>();
+
+        let hashes = entries
+            .iter()
+            .map(|(hash, _)| hash.clone())
+            .collect::<HashSet<_>>();
+        for (hash, entry) in self.secondary.list()? {
+            if !hashes.contains(&hash) {
+                entries.push((hash, LayeredCacheEntry::Secondary(entry)));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn has_corrupt(&self, hash: &str) -> bool {
+        self.primary.has_corrupt(hash) || self.secondary.has_corrupt(hash)
+    }
+
+    /// Commands are only recorded into the primary cache, so only it is locked.
+    fn try_lock(&self, hash: &str) -> anyhow::Result<Option<CacheLock>> {
+        self.primary.try_lock(hash)
+    }
+
+    fn remove_unused_locks(&self) -> anyhow::Result<usize> {
+        Ok(self.primary.remove_unused_locks()? + self.secondary.remove_unused_locks()?)
+    }
+
+    fn store(&self, hash: &str, entry: &impl CacheEntry) -> anyhow::Result<()> {
+        self.primary.store(hash, entry)
+    }
+
+    /// Results found in the secondary cache are copied into the primary, so only the primary
+    /// cache's result is renewed.
+    fn set_expiry(&self, hash: &str, expires: SystemTime) -> anyhow::Result<()> {
+        self.primary.set_expiry(hash, expires)
+    }
+
+    /// Only results in the primary cache are pinned, as the secondary cache may be shared.
+    fn set_pinned(&self, hash: &str, pinned: bool) -> anyhow::Result<bool> {
+        self.primary.set_pinned(hash, pinned)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::memory::MemoryCache;
+    use crate::command::ScopeBuilder;
+
+    fn layered(populate_secondary: bool) -> LayeredCache<MemoryCache, MemoryCache> {
+        LayeredCache::new(
+            MemoryCache::new(),
+            MemoryCache::new(),
+            populate_secondary,
+            false,
+        )
+    }
+
+    #[test]
+    fn test_secondary_hits_are_copied_into_primary() -> anyhow::Result<()> {
+        let cache = layered(false);
+        let mut command = Command::new(ScopeBuilder::new().cmd("echo").args("shared").build()?);
+        let hash = command.hash().to_string();
+
+        cache
+            .secondary
+            .record(&mut command, &RecordOptions::default())?;
+        assert!(cache.primary.read(&hash)?.is_none());
+
+        let entry = cache.read(&hash)?.expect("found in secondary");
+        assert!(matches!(entry, LayeredCacheEntry::Secondary(_)));
+        assert_eq!(entry.stdout()?, "shared\n");
+
+        let entry = cache.read(&hash)?.expect("copied into primary");
+        assert!(matches!(entry, LayeredCacheEntry::Primary(_)));
+        assert_eq!(entry.stdout()?, "shared\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_populates_secondary() -> anyhow::Result<()> {
+        let mut command = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        let hash = command.hash().to_string();
+
+        let cache = layered(false);
+        cache.record(&mut command, &RecordOptions::default())?;
+        assert!(cache.primary.read(&hash)?.is_some());
+        assert!(
+            cache.secondary.read(&hash)?.is_none(),
+            "only records to primary"
+        );
+
+        let cache = layered(true);
+        cache.record(&mut command, &RecordOptions::default())?;
+        assert!(cache.secondary.read(&hash)?.is_some(), "writes through");
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_all_layers() -> anyhow::Result<()> {
+        let mut command = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        let hash = command.hash().to_string();
+
+        for remove_all_layers in [false, true] {
+            let cache = LayeredCache::new(
+                MemoryCache::new(),
+                MemoryCache::new(),
+                true,
+                remove_all_layers,
+            );
+            cache.record(&mut command, &RecordOptions::default())?;
+            assert!(cache.remove(&hash)?);
+            assert!(cache.primary.read(&hash)?.is_none());
+            assert_eq!(cache.secondary.read(&hash)?.is_none(), remove_all_layers);
+        }
+        Ok(())
+    }
+}
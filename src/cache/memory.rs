@@ -0,0 +1,270 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, SystemTime};
+
+use super::{
+    capture_failed, replay_output, Cache, CacheEntry, CacheLock, OutputReader, RecordOptions,
+    SharedBuffer,
+};
+use crate::command::{signal_name, Command, ResourceUsage};
+use crate::info;
+use crate::output::Output;
+
+/// A cache holding results in memory, for tests and for programs using deja that don't need
+/// results to outlive them. Only the current result for each command is kept, so there's no
+/// history.
+#[derive(Default)]
+pub struct MemoryCache {
+    entries: RefCell<HashMap<String, MemoryCacheEntry>>,
+}
+
+impl MemoryCache {
+    /// An empty cache.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// A result held in a `MemoryCache`.
+#[derive(Debug, Clone)]
+pub struct MemoryCacheEntry {
+    command: Command,
+    created: SystemTime,
+    expires: Option<SystemTime>,
+    status: i32,
+    signal: Option<i32>,
+    env: BTreeMap<String, String>,
+    duration: Option<Duration>,
+    usage: Option<ResourceUsage>,
+    diverged: Option<SystemTime>,
+    tags: BTreeSet<String>,
+    id: Option<String>,
+    pinned: bool,
+    /// Captured output, in the same format as output files in a `DiskCache`.
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+#[cfg(test)]
+impl MemoryCacheEntry {
+    /// Moves the entry's creation time, to test entries recorded at other times.
+    pub(crate) fn with_created(mut self, created: SystemTime) -> Self {
+        self.created = created;
+        self
+    }
+}
+
+impl CacheEntry for MemoryCacheEntry {
+    fn created_at(&self) -> SystemTime {
+        self.created
+    }
+
+    fn expires_at(&self) -> Option<SystemTime> {
+        self.expires
+    }
+
+    fn command_status(&self) -> i32 {
+        self.status
+    }
+
+    fn command_signal(&self) -> Option<i32> {
+        self.signal
+    }
+
+    fn command(&self) -> &Command {
+        &self.command
+    }
+
+    fn env(&self) -> &BTreeMap<String, String> {
+        &self.env
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    fn usage(&self) -> Option<ResourceUsage> {
+        self.usage
+    }
+
+    fn diverged_at(&self) -> Option<SystemTime> {
+        self.diverged
+    }
+
+    fn tags(&self) -> &BTreeSet<String> {
+        &self.tags
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    fn pinned(&self) -> bool {
+        self.pinned
+    }
+
+    fn replay_command_output(&self, output: &mut Output) -> anyhow::Result<()> {
+        replay_output(&self.stdout[..], &self.stderr[..], output)?;
+        Ok(())
+    }
+
+    fn stdout(&self) -> anyhow::Result<String> {
+        let reader = OutputReader {
+            reader: std::io::BufReader::new(&self.stdout[..]),
+        };
+        Ok(reader.into_string())
+    }
+
+    fn stdout_reader(&self) -> anyhow::Result<Box<dyn std::io::Read + Send>> {
+        Ok(Box::new(std::io::Cursor::new(self.stdout.clone())))
+    }
+
+    fn raw_output(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        Ok((self.stdout.clone(), self.stderr.clone()))
+    }
+
+    fn output_sizes(&self) -> Vec<(String, usize)> {
+        let ulid = &self.command.ulid;
+        vec![
+            (format!("{ulid}.out"), self.stdout.len()),
+            (format!("{ulid}.err"), self.stderr.len()),
+        ]
+    }
+}
+
+impl Cache<MemoryCacheEntry> for MemoryCache {
+    fn remove(&self, hash: &str) -> anyhow::Result<bool> {
+        Ok(self.entries.borrow_mut().remove(hash).is_some())
+    }
+
+    fn record(&self, command: &mut Command, options: &RecordOptions) -> anyhow::Result<i32> {
+        let now = SystemTime::now();
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+
+        let result = command.run(stdout.clone(), stderr.clone(), options.run_options())?;
+
+        if result.timed_out {
+            info("not recording result: command timed out".into());
+            return Ok(options.timeout_exit_code);
+        }
+
+        if let Some(signal) = result.interrupted {
+            info(format!(
+                "not recording result: interrupted by {}",
+                signal_name(signal)
+            ));
+            return Ok(128 + signal);
+        }
+
+        if capture_failed(&result) {
+            return Ok(result.status);
+        }
+
+        let (stdout, stderr) = (stdout.take(), stderr.take());
+        if let Some(reason) = options.skip_reason(
+            result.status,
+            result.signal,
+            &result.stdout_matches,
+            stdout.len() as u64,
+            stderr.len() as u64,
+        ) {
+            info(format!("not recording result: {}", reason));
+            return Ok(result.status);
+        }
+
+        let entry = MemoryCacheEntry {
+            command: command.clone(),
+            created: now,
+            expires: options.expires_at(now),
+            status: result.status,
+            signal: result.signal,
+            env: options.env(),
+            duration: Some(result.duration),
+            usage: result.usage,
+            diverged: None,
+            tags: options.tags().clone(),
+            id: options.id(),
+            pinned: false,
+            stdout,
+            stderr,
+        };
+
+        self.entries
+            .borrow_mut()
+            .insert(command.hash().to_string(), entry);
+        Ok(result.status)
+    }
+
+    fn store(&self, hash: &str, entry: &impl CacheEntry) -> anyhow::Result<()> {
+        let (stdout, stderr) = entry.raw_output()?;
+        let entry = MemoryCacheEntry {
+            command: entry.command().clone(),
+            created: entry.created_at(),
+            expires: entry.expires_at(),
+            status: entry.command_status(),
+            signal: entry.command_signal(),
+            env: entry.env().clone(),
+            duration: entry.duration(),
+            usage: entry.usage(),
+            diverged: entry.diverged_at(),
+            tags: entry.tags().clone(),
+            id: entry.id().map(String::from),
+            pinned: entry.pinned(),
+            stdout,
+            stderr,
+        };
+
+        self.entries.borrow_mut().insert(hash.to_string(), entry);
+        Ok(())
+    }
+
+    fn set_expiry(&self, hash: &str, expires: SystemTime) -> anyhow::Result<()> {
+        if let Some(entry) = self.entries.borrow_mut().get_mut(hash) {
+            entry.expires = Some(expires);
+        }
+        Ok(())
+    }
+
+    fn set_pinned(&self, hash: &str, pinned: bool) -> anyhow::Result<bool> {
+        Ok(match self.entries.borrow_mut().get_mut(hash) {
+            Some(entry) => {
+                entry.pinned = pinned;
+                true
+            }
+            None => false,
+        })
+    }
+
+    fn read(&self, hash: &str) -> anyhow::Result<Option<MemoryCacheEntry>> {
+        Ok(self.entries.borrow().get(hash).cloned())
+    }
+
+    fn read_generation(
+        &self,
+        hash: &str,
+        generation: usize,
+    ) -> anyhow::Result<Option<MemoryCacheEntry>> {
+        match generation {
+            0 => self.read(hash),
+            _ => Ok(None),
+        }
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<(String, MemoryCacheEntry)>> {
+        Ok(self
+            .entries
+            .borrow()
+            .iter()
+            .map(|(hash, entry)| (hash.clone(), entry.clone()))
+            .collect())
+    }
+
+    /// Locks only coordinate separate processes, and a memory cache belongs to just one, so
+    /// the lock is always granted.
+    fn try_lock(&self, _hash: &str) -> anyhow::Result<Option<CacheLock>> {
+        Ok(Some(CacheLock {
+            _file: None,
+            release: None,
+        }))
+    }
+}
@@ -0,0 +1,474 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime};
+
+use ::redis::{Client, Commands, Connection, RedisResult, Script};
+use ulid::Ulid;
+
+use super::{
+    capture_failed, replay_output, Cache, CacheEntry, CacheLock, DiskCacheEntryMeta, OutputReader,
+    RecordOptions, SharedBuffer,
+};
+use crate::command::{signal_name, Command, ResourceUsage};
+use crate::output::Output;
+use crate::{debug, info};
+
+/// How long to wait to connect to (or hear back from) the server before giving up on the cache.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long a lock is held for if the process holding it never releases it.
+const LOCK_TTL: Duration = Duration::from_secs(300);
+
+/// Deletes a lock, but only if it's still held by the process releasing it.
+const RELEASE_LOCK: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+/// Whether a cache path is the URL of a Redis server.
+pub fn is_redis_url(path: &str) -> bool {
+    path.starts_with("redis://") || path.starts_with("rediss://")
+}
+
+/// A cache stored in Redis, so results can be shared between machines. Results expire using
+/// Redis' own TTLs, and only the current result for each command is kept.
+///
+/// The cache is never allowed to stop a command running: when the server can't be reached,
+/// every lookup misses and results aren't recorded.
+pub struct RedisCache {
+    url: String,
+    connection: RefCell<Option<Connection>>,
+}
+
+pub struct RedisCacheEntry {
+    meta: DiskCacheEntryMeta,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl CacheEntry for RedisCacheEntry {
+    fn created_at(&self) -> SystemTime {
+        self.meta.created
+    }
+
+    fn expires_at(&self) -> Option<SystemTime> {
+        self.meta.expires
+    }
+
+    fn command_status(&self) -> i32 {
+        self.meta.status
+    }
+
+    fn command_signal(&self) -> Option<i32> {
+        self.meta.signal
+    }
+
+    fn command(&self) -> &Command {
+        &self.meta.command
+    }
+
+    fn env(&self) -> &BTreeMap<String, String> {
+        &self.meta.env
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        self.meta.duration
+    }
+
+    fn usage(&self) -> Option<ResourceUsage> {
+        self.meta.usage
+    }
+
+    fn diverged_at(&self) -> Option<SystemTime> {
+        self.meta.diverged
+    }
+
+    fn tags(&self) -> &BTreeSet<String> {
+        &self.meta.tags
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.meta.id.as_deref()
+    }
+
+    fn pinned(&self) -> bool {
+        self.meta.pinned
+    }
+
+    fn replay_command_output(&self, output: &mut Output) -> anyhow::Result<()> {
+        replay_output(&self.stdout[..], &self.stderr[..], output)?;
+        Ok(())
+    }
+
+    fn stdout(&self) -> anyhow::Result<String> {
+        let reader = OutputReader {
+            reader: std::io::BufReader::new(&self.stdout[..]),
+        };
+        Ok(reader.into_string())
+    }
+
+    fn stdout_reader(&self) -> anyhow::Result<Box<dyn std::io::Read + Send>> {
+        Ok(Box::new(std::io::Cursor::new(self.stdout.clone())))
+    }
+
+    fn raw_output(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        Ok((self.stdout.clone(), self.stderr.clone()))
+    }
+
+    fn output_sizes(&self) -> Vec<(String, usize)> {
+        let ulid = &self.meta.command.ulid;
+        vec![
+            (format!("{ulid}.out"), self.stdout.len()),
+            (format!("{ulid}.err"), self.stderr.len()),
+        ]
+    }
+}
+
+fn key(hash: &str, suffix: &str) -> String {
+    format!("deja:{hash}:{suffix}")
+}
+
+fn connect(url: &str) -> RedisResult<Connection> {
+    let connection = Client::open(url)?.get_connection_with_timeout(TIMEOUT)?;
+    connection.set_read_timeout(Some(TIMEOUT))?;
+    connection.set_write_timeout(Some(TIMEOUT))?;
+    Ok(connection)
+}
+
+impl RedisCache {
+    /// Uses the redis server at `url`, treated as an empty cache when it can't be reached.
+    pub fn open(url: &str) -> RedisCache {
+        let connection = connect(url)
+            .map_err(|e| debug(format!("redis cache unavailable: {}", e)))
+            .ok();
+
+        RedisCache {
+            url: url.to_string(),
+            connection: RefCell::new(connection),
+        }
+    }
+
+    /// Runs the given commands against the server, or returns `None` (noting why with
+    /// `--debug`) when the server can't be reached.
+    fn with_connection<T>(&self, f: impl FnOnce(&mut Connection) -> RedisResult<T>) -> Option<T> {
+        let mut connection = self.connection.borrow_mut();
+        let result = f(connection.as_mut()?);
+
+        match result {
+            Ok(result) => Some(result),
+            Err(e) => {
+                debug(format!("redis cache unavailable: {}", e));
+                // Don't keep waiting on a server that's gone away
+                if e.is_io_error() || e.is_timeout() || e.is_connection_dropped() {
+                    *connection = None;
+                }
+                None
+            }
+        }
+    }
+
+    /// Writes an entry's keys together. Keys are removed by Redis once the entry expires, with
+    /// the smallest TTL being a millisecond, as Redis rejects zero.
+    fn write(
+        &self,
+        hash: &str,
+        meta: &DiskCacheEntryMeta,
+        stdout: &[u8],
+        stderr: &[u8],
+    ) -> anyhow::Result<()> {
+        // Pinned results keep their keys forever, whenever they expire
+        let ttl = meta_expiry_ttl(meta.expires.filter(|_| !meta.pinned), SystemTime::now());
+        let meta = ron::to_string(meta)?;
+
+        self.with_connection(|connection| {
+            let mut pipe = ::redis::pipe();
+            pipe.atomic();
+            for (suffix, value) in [
+                ("meta", meta.as_bytes()),
+                ("stdout", stdout),
+                ("stderr", stderr),
+            ] {
+                let set = pipe.cmd("SET").arg(key(hash, suffix)).arg(value);
+                if let Some(ttl) = ttl {
+                    set.arg("PX").arg(ttl.as_millis().max(1) as u64);
+                }
+                set.ignore();
+            }
+            pipe.query::<()>(connection)
+        });
+        Ok(())
+    }
+
+    fn entry(&self, meta: Vec<u8>, stdout: Vec<u8>, stderr: Vec<u8>) -> Option<RedisCacheEntry> {
+        match ron::de::from_bytes(&meta) {
+            Ok(meta) => Some(RedisCacheEntry {
+                meta,
+                stdout,
+                stderr,
+            }),
+            Err(e) => {
+                debug(format!("unable to parse entry from {}: {}", self.url, e));
+                None
+            }
+        }
+    }
+}
+
+impl Cache<RedisCacheEntry> for RedisCache {
+    fn remove(&self, hash: &str) -> anyhow::Result<bool> {
+        let keys = ["meta", "stdout", "stderr"].map(|suffix| key(hash, suffix));
+        let removed = self
+            .with_connection(|connection| connection.del::<_, usize>(&keys))
+            .unwrap_or(0);
+        Ok(removed > 0)
+    }
+
+    fn record(&self, command: &mut Command, options: &RecordOptions) -> anyhow::Result<i32> {
+        let now = SystemTime::now();
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+
+        let result = command.run(stdout.clone(), stderr.clone(), options.run_options())?;
+
+        if result.timed_out {
+            info("not recording result: command timed out".into());
+            return Ok(options.timeout_exit_code);
+        }
+
+        if let Some(signal) = result.interrupted {
+            info(format!(
+                "not recording result: interrupted by {}",
+                signal_name(signal)
+            ));
+            return Ok(128 + signal);
+        }
+
+        if capture_failed(&result) {
+            return Ok(result.status);
+        }
+
+        let status = result.status;
+        let (stdout, stderr) = (stdout.take(), stderr.take());
+        if let Some(reason) = options.skip_reason(
+            status,
+            result.signal,
+            &result.stdout_matches,
+            stdout.len() as u64,
+            stderr.len() as u64,
+        ) {
+            info(format!("not recording result: {}", reason));
+            return Ok(status);
+        }
+
+        info(format!("recording result with exit code {}", status));
+
+        let meta = DiskCacheEntryMeta {
+            command: command.clone(),
+            created: now,
+            expires: options.expires_at(now),
+            status,
+            signal: result.signal,
+            env: options.env(),
+            duration: Some(result.duration),
+            usage: result.usage,
+            diverged: None,
+            tags: options.tags().clone(),
+            id: options.id(),
+            checksums: None,
+            pinned: false,
+        };
+        self.write(command.hash(), &meta, &stdout, &stderr)?;
+        Ok(status)
+    }
+
+    fn store(&self, hash: &str, entry: &impl CacheEntry) -> anyhow::Result<()> {
+        let (stdout, stderr) = entry.raw_output()?;
+        self.write(
+            hash,
+            &DiskCacheEntryMeta::from_entry(entry),
+            &stdout,
+            &stderr,
+        )
+    }
+
+    /// The output keys are left as they are, with only their TTLs moved.
+    fn set_expiry(&self, hash: &str, expires: SystemTime) -> anyhow::Result<()> {
+        let meta = self
+            .with_connection(|connection| connection.get::<_, Option<Vec<u8>>>(key(hash, "meta")))
+            .flatten();
+        let Some(meta) = meta else {
+            return Ok(());
+        };
+        let mut meta: DiskCacheEntryMeta = ron::de::from_bytes(&meta)?;
+        meta.expires = Some(expires);
+        let ttl = meta_expiry_ttl(meta.expires.filter(|_| !meta.pinned), SystemTime::now());
+        let meta = ron::to_string(&meta)?;
+
+        self.with_connection(|connection| {
+            let mut pipe = ::redis::pipe();
+            pipe.atomic();
+            let set = pipe.cmd("SET").arg(key(hash, "meta")).arg(meta);
+            if let Some(ttl) = ttl {
+                let ttl = ttl.as_millis().max(1) as u64;
+                set.arg("PX").arg(ttl).ignore();
+                for suffix in ["stdout", "stderr"] {
+                    pipe.cmd("PEXPIRE").arg(key(hash, suffix)).arg(ttl).ignore();
+                }
+            } else {
+                set.ignore();
+            }
+            pipe.query::<()>(connection)
+        });
+        Ok(())
+    }
+
+    /// The output is written again along with the metadata, so its keys' TTLs match.
+    fn set_pinned(&self, hash: &str, pinned: bool) -> anyhow::Result<bool> {
+        let Some(mut entry) = self.read(hash)? else {
+            return Ok(false);
+        };
+        entry.meta.pinned = pinned;
+        self.write(hash, &entry.meta, &entry.stdout, &entry.stderr)?;
+        Ok(true)
+    }
+
+    fn read(&self, hash: &str) -> anyhow::Result<Option<RedisCacheEntry>> {
+        let keys = ["meta", "stdout", "stderr"].map(|suffix| key(hash, suffix));
+        let values =
+            self.with_connection(|connection| connection.mget::<_, Vec<Option<Vec<u8>>>>(&keys));
+
+        Ok(match values.as_deref() {
+            Some([Some(meta), Some(stdout), Some(stderr)]) => {
+                self.entry(meta.clone(), stdout.clone(), stderr.clone())
+            }
+            _ => None,
+        })
+    }
+
+    fn read_generation(
+        &self,
+        hash: &str,
+        generation: usize,
+    ) -> anyhow::Result<Option<RedisCacheEntry>> {
+        match generation {
+            0 => self.read(hash),
+            _ => Ok(None),
+        }
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<(String, RedisCacheEntry)>> {
+        let keys = self
+            .with_connection(|connection| {
+                connection
+                    .scan_match::<_, String>(key("*", "meta"))
+                    .map(|keys| keys.collect::<Vec<_>>())
+            })
+            .unwrap_or_default();
+
+        let mut entries = vec![];
+        for key in keys {
+            let Some(hash) = key
+                .strip_prefix("deja:")
+                .and_then(|key| key.strip_suffix(":meta"))
+            else {
+                continue;
+            };
+
+            if let Some(entry) = self.read(hash)? {
+                entries.push((hash.to_string(), entry));
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Locks are shared through the server, so only one machine runs a command at once. A lock
+    /// left by a process that died expires after `LOCK_TTL`. When the server can't be reached
+    /// the lock is granted, so the command still runs.
+    fn try_lock(&self, hash: &str) -> anyhow::Result<Option<CacheLock>> {
+        let lock_key = key(hash, "lock");
+        let token = Ulid::new().to_string();
+
+        let acquired = self.with_connection(|connection| {
+            ::redis::cmd("SET")
+                .arg(&lock_key)
+                .arg(&token)
+                .arg("NX")
+                .arg("PX")
+                .arg(LOCK_TTL.as_millis() as u64)
+                .query::<Option<String>>(connection)
+                .map(|reply| reply.is_some())
+        });
+
+        let release: Option<Box<dyn FnOnce()>> = match acquired {
+            Some(false) => return Ok(None),
+            Some(true) => {
+                let url = self.url.clone();
+                Some(Box::new(move || {
+                    let released = connect(&url).and_then(|mut connection| {
+                        Script::new(RELEASE_LOCK)
+                            .key(&lock_key)
+                            .arg(&token)
+                            .invoke::<i32>(&mut connection)
+                    });
+                    if let Err(e) = released {
+                        debug(format!("unable to release lock {}: {}", lock_key, e));
+                    }
+                }))
+            }
+            None => None,
+        };
+
+        Ok(Some(CacheLock {
+            _file: None,
+            release,
+        }))
+    }
+}
+
+/// How long until a result expires, or `None` if it never does.
+fn meta_expiry_ttl(expires: Option<SystemTime>, now: SystemTime) -> Option<Duration> {
+    expires.map(|expires| expires.duration_since(now).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::ScopeBuilder;
+
+    #[test]
+    fn test_is_redis_url() {
+        assert!(is_redis_url("redis://localhost:6379/0"));
+        assert!(is_redis_url("rediss://cache.example.com"));
+        assert!(!is_redis_url("/home/user/.cache/deja"));
+        assert!(!is_redis_url("cache.db"));
+    }
+
+    #[test]
+    fn test_unavailable_server() -> anyhow::Result<()> {
+        // Nothing listens on port 1, so every operation falls back to behaving as a miss
+        let cache = RedisCache::open("redis://127.0.0.1:1/0");
+        let mut command = Command::new(ScopeBuilder::new().cmd("true").build()?);
+
+        assert_eq!(cache.record(&mut command, &RecordOptions::default())?, 0);
+        assert!(cache.read(command.hash())?.is_none());
+        assert!(cache.list()?.is_empty());
+        assert!(!cache.remove(command.hash())?);
+        assert!(cache.try_lock(command.hash())?.is_some(), "lock is granted");
+        Ok(())
+    }
+
+    #[test]
+    fn test_meta_expiry_ttl() {
+        let now = SystemTime::now();
+        assert_eq!(meta_expiry_ttl(None, now), None);
+        assert_eq!(
+            meta_expiry_ttl(Some(now + Duration::from_secs(60)), now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            meta_expiry_ttl(Some(now - Duration::from_secs(60)), now),
+            Some(Duration::ZERO)
+        );
+    }
+}
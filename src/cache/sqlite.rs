@@ -0,0 +1,621 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::ops::RangeInclusive;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Transaction};
+
+use super::{
+    blob_hash, capture_failed, create_cache_dir, remove_lock_file, remove_unused_lock_files,
+    replay_output, try_lock_file, unable_to_read_cache_entry_error, unable_to_write_to_cache_error,
+    Cache, CacheEntry, CacheLock, CacheModes, DiskCache, DiskCacheEntryMeta, OutputReader,
+    RecordOptions, SharedBuffer,
+};
+use crate::command::{signal_name, Command, ResourceUsage};
+use crate::output::Output;
+use crate::{debug, info};
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS blobs (
+    hash TEXT PRIMARY KEY,
+    content BLOB NOT NULL,
+    refs INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS entries (
+    hash TEXT NOT NULL,
+    generation INTEGER NOT NULL,
+    created INTEGER NOT NULL,
+    expires INTEGER,
+    meta TEXT NOT NULL,
+    stdout TEXT NOT NULL REFERENCES blobs (hash),
+    stderr TEXT NOT NULL REFERENCES blobs (hash),
+    PRIMARY KEY (hash, generation)
+);
+
+CREATE INDEX IF NOT EXISTS entries_by_created ON entries (generation, created);
+"#;
+
+/// How long to wait for another process writing to the database.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A cache stored in a single SQLite database, which copes better than a directory of files
+/// with very large numbers of entries. As with `DiskCache`, identical output is stored once.
+pub struct SqliteCache {
+    path: PathBuf,
+    connection: Connection,
+    modes: CacheModes,
+}
+
+pub struct SqliteCacheEntry {
+    meta: DiskCacheEntryMeta,
+    /// The database holding the output, which is only loaded when needed.
+    path: PathBuf,
+    stdout: String,
+    stderr: String,
+}
+
+impl SqliteCacheEntry {
+    fn output(&self, hash: &str) -> anyhow::Result<Vec<u8>> {
+        let connection = Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .map_err(|_| unable_to_read_cache_entry_error(&self.path))?;
+        connection
+            .query_row(
+                "SELECT content FROM blobs WHERE hash = ?1",
+                params![hash],
+                |row| row.get(0),
+            )
+            .map_err(|_| unable_to_read_cache_entry_error(&self.path))
+    }
+}
+
+impl CacheEntry for SqliteCacheEntry {
+    fn created_at(&self) -> SystemTime {
+        self.meta.created
+    }
+
+    fn expires_at(&self) -> Option<SystemTime> {
+        self.meta.expires
+    }
+
+    fn command_status(&self) -> i32 {
+        self.meta.status
+    }
+
+    fn command_signal(&self) -> Option<i32> {
+        self.meta.signal
+    }
+
+    fn command(&self) -> &Command {
+        &self.meta.command
+    }
+
+    fn env(&self) -> &BTreeMap<String, String> {
+        &self.meta.env
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        self.meta.duration
+    }
+
+    fn usage(&self) -> Option<ResourceUsage> {
+        self.meta.usage
+    }
+
+    fn diverged_at(&self) -> Option<SystemTime> {
+        self.meta.diverged
+    }
+
+    fn tags(&self) -> &BTreeSet<String> {
+        &self.meta.tags
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.meta.id.as_deref()
+    }
+
+    fn pinned(&self) -> bool {
+        self.meta.pinned
+    }
+
+    fn replay_command_output(&self, output: &mut Output) -> anyhow::Result<()> {
+        let stdout = self.output(&self.stdout)?;
+        let stderr = self.output(&self.stderr)?;
+        replay_output(&stdout[..], &stderr[..], output)?;
+        Ok(())
+    }
+
+    fn stdout(&self) -> anyhow::Result<String> {
+        let stdout = self.output(&self.stdout)?;
+        let reader = OutputReader {
+            reader: std::io::BufReader::new(&stdout[..]),
+        };
+        Ok(reader.into_string())
+    }
+
+    fn stdout_reader(&self) -> anyhow::Result<Box<dyn std::io::Read + Send>> {
+        Ok(Box::new(std::io::Cursor::new(self.output(&self.stdout)?)))
+    }
+
+    fn raw_output(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        Ok((self.output(&self.stdout)?, self.output(&self.stderr)?))
+    }
+
+    fn output_sizes(&self) -> Vec<(String, usize)> {
+        let Ok(connection) =
+            Connection::open_with_flags(&self.path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+        else {
+            return vec![];
+        };
+        [&self.stdout, &self.stderr]
+            .into_iter()
+            .filter_map(|hash| {
+                let size: i64 = connection
+                    .query_row(
+                        "SELECT length(content) FROM blobs WHERE hash = ?1",
+                        params![hash],
+                        |row| row.get(0),
+                    )
+                    .ok()?;
+                Some((hash.clone(), size as usize))
+            })
+            .collect()
+    }
+}
+
+fn seconds(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Stores output as a blob, or adds a reference to an identical blob, returning its hash.
+fn store_blob(
+    transaction: &Transaction,
+    content: &[u8],
+    include_timestamps: bool,
+) -> anyhow::Result<String> {
+    let hash = blob_hash(content, include_timestamps)?;
+    transaction.execute(
+        "INSERT INTO blobs (hash, content, refs) VALUES (?1, ?2, 1)
+         ON CONFLICT (hash) DO UPDATE SET refs = refs + 1",
+        params![hash, content],
+    )?;
+    Ok(hash)
+}
+
+/// Removes a command's entries with generations in the given range, along with any blobs no
+/// longer referenced.
+fn delete_entries(
+    transaction: &Transaction,
+    hash: &str,
+    generations: RangeInclusive<usize>,
+) -> anyhow::Result<usize> {
+    let (from, to) = (
+        *generations.start() as i64,
+        (*generations.end()).min(i64::MAX as usize) as i64,
+    );
+
+    let mut statement = transaction.prepare(
+        "SELECT stdout, stderr FROM entries WHERE hash = ?1 AND generation BETWEEN ?2 AND ?3",
+    )?;
+    let blobs = statement
+        .query_map(params![hash, from, to], |row| {
+            Ok([row.get::<_, String>(0)?, row.get::<_, String>(1)?])
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let deleted = transaction.execute(
+        "DELETE FROM entries WHERE hash = ?1 AND generation BETWEEN ?2 AND ?3",
+        params![hash, from, to],
+    )?;
+
+    for blob in blobs.iter().flatten() {
+        transaction.execute(
+            "UPDATE blobs SET refs = refs - 1 WHERE hash = ?1",
+            params![blob],
+        )?;
+        transaction.execute(
+            "DELETE FROM blobs WHERE hash = ?1 AND refs <= 0",
+            params![blob],
+        )?;
+    }
+
+    Ok(deleted)
+}
+
+/// Inserts an entry as the given generation, replacing any already there.
+fn insert_entry(
+    transaction: &Transaction,
+    hash: &str,
+    generation: usize,
+    meta: &DiskCacheEntryMeta,
+    stdout: &[u8],
+    stderr: &[u8],
+) -> anyhow::Result<()> {
+    let stdout_blob = store_blob(transaction, stdout, !stderr.is_empty())?;
+    let stderr_blob = store_blob(transaction, stderr, !stdout.is_empty())?;
+
+    delete_entries(transaction, hash, generation..=generation)?;
+    transaction.execute(
+        "INSERT INTO entries (hash, generation, created, expires, meta, stdout, stderr)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            hash,
+            generation,
+            seconds(meta.created),
+            meta.expires.map(seconds),
+            ron::to_string(meta)?,
+            stdout_blob,
+            stderr_blob
+        ],
+    )?;
+    Ok(())
+}
+
+impl SqliteCache {
+    /// Opens the database without ever writing to it, so results can be looked up without side
+    /// effects. A database that doesn't exist yet is treated as empty, rather than created.
+    pub fn open_read_only(path: PathBuf, modes: CacheModes) -> anyhow::Result<SqliteCache> {
+        let connection = if path.exists() {
+            Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+                .map_err(|_| unable_to_read_cache_entry_error(&path))?
+        } else {
+            let connection = Connection::open_in_memory()?;
+            connection.execute_batch(SCHEMA)?;
+            connection
+        };
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+
+        Ok(SqliteCache {
+            path,
+            connection,
+            modes,
+        })
+    }
+
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: PathBuf, modes: CacheModes) -> anyhow::Result<SqliteCache> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            create_cache_dir(parent, modes)?;
+        }
+
+        let connection = Connection::open(&path).map_err(|e| {
+            anyhow::anyhow!("unable to write file to cache {}: {e}", path.display())
+        })?;
+
+        let _ = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(modes.file));
+
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection
+            .execute_batch(SCHEMA)
+            .map_err(|_| unable_to_write_to_cache_error(&path))?;
+
+        Ok(SqliteCache {
+            path,
+            connection,
+            modes,
+        })
+    }
+
+    /// The directory holding lock files, which live outside the database so they're released
+    /// when a process dies.
+    fn locks_path(&self) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push(".locks");
+        PathBuf::from(path)
+    }
+
+    fn entry(
+        &self,
+        hash: &str,
+        meta: String,
+        stdout: String,
+        stderr: String,
+    ) -> anyhow::Result<SqliteCacheEntry> {
+        let meta = ron::from_str(&meta).map_err(|e| {
+            debug(format!("unable to parse entry {}: {}", hash, e));
+            unable_to_read_cache_entry_error(&self.path)
+        })?;
+
+        Ok(SqliteCacheEntry {
+            meta,
+            path: self.path.clone(),
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Copies every entry (including history) from a disk cache, replacing any entries
+    /// already stored for the same commands. Returns the number of commands imported.
+    pub fn import(&self, disk: &DiskCache) -> anyhow::Result<usize> {
+        let entries = disk.list()?;
+        let transaction = self.connection.unchecked_transaction()?;
+
+        for (hash, _) in &entries {
+            delete_entries(&transaction, hash, 0..=usize::MAX)?;
+            for (generation, entry) in disk.history(hash)?.into_iter().enumerate() {
+                let read = |path: &Path| {
+                    std::fs::read(path).map_err(|_| unable_to_read_cache_entry_error(path))
+                };
+                let stdout = read(&entry.stdout)?;
+                let stderr = read(&entry.stderr)?;
+                insert_entry(
+                    &transaction,
+                    hash,
+                    generation,
+                    entry.meta(),
+                    &stdout,
+                    &stderr,
+                )?;
+            }
+        }
+
+        transaction.commit()?;
+        Ok(entries.len())
+    }
+}
+
+impl Cache<SqliteCacheEntry> for SqliteCache {
+    fn remove(&self, hash: &str) -> anyhow::Result<bool> {
+        debug(format!("cache remove: {}, {}", hash, self.path.display()));
+        let transaction = self.connection.unchecked_transaction()?;
+        let deleted = delete_entries(&transaction, hash, 0..=usize::MAX)?;
+        transaction.commit()?;
+        remove_lock_file(&self.locks_path().join(format!("{hash}.lock")), self.modes)?;
+        Ok(deleted > 0)
+    }
+
+    fn store(&self, hash: &str, entry: &impl CacheEntry) -> anyhow::Result<()> {
+        let (stdout, stderr) = entry.raw_output()?;
+        let transaction = self.connection.unchecked_transaction()?;
+        insert_entry(
+            &transaction,
+            hash,
+            0,
+            &DiskCacheEntryMeta::from_entry(entry),
+            &stdout,
+            &stderr,
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn set_expiry(&self, hash: &str, expires: SystemTime) -> anyhow::Result<()> {
+        let transaction = self.connection.unchecked_transaction()?;
+        let meta = transaction
+            .query_row(
+                "SELECT meta FROM entries WHERE hash = ?1 AND generation = 0",
+                params![hash],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        if let Some(meta) = meta {
+            let mut meta: DiskCacheEntryMeta =
+                ron::from_str(&meta).map_err(|_| unable_to_read_cache_entry_error(&self.path))?;
+            meta.expires = Some(expires);
+            transaction.execute(
+                "UPDATE entries SET expires = ?1, meta = ?2 WHERE hash = ?3 AND generation = 0",
+                params![seconds(expires), ron::to_string(&meta)?, hash],
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn set_pinned(&self, hash: &str, pinned: bool) -> anyhow::Result<bool> {
+        let transaction = self.connection.unchecked_transaction()?;
+        let meta = transaction
+            .query_row(
+                "SELECT meta FROM entries WHERE hash = ?1 AND generation = 0",
+                params![hash],
+                |row| row.get::<_, String>(0),
+            )
+            .optional()?;
+        let Some(meta) = meta else {
+            return Ok(false);
+        };
+        let mut meta: DiskCacheEntryMeta =
+            ron::from_str(&meta).map_err(|_| unable_to_read_cache_entry_error(&self.path))?;
+        meta.pinned = pinned;
+        transaction.execute(
+            "UPDATE entries SET meta = ?1 WHERE hash = ?2 AND generation = 0",
+            params![ron::to_string(&meta)?, hash],
+        )?;
+        transaction.commit()?;
+        Ok(true)
+    }
+
+    fn record(&self, command: &mut Command, options: &RecordOptions) -> anyhow::Result<i32> {
+        let now = SystemTime::now();
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+
+        let result = command.run(stdout.clone(), stderr.clone(), options.run_options())?;
+
+        if result.timed_out {
+            info("not recording result: command timed out".into());
+            return Ok(options.timeout_exit_code);
+        }
+
+        if let Some(signal) = result.interrupted {
+            info(format!(
+                "not recording result: interrupted by {}",
+                signal_name(signal)
+            ));
+            return Ok(128 + signal);
+        }
+
+        if capture_failed(&result) {
+            return Ok(result.status);
+        }
+
+        let status = result.status;
+        let (stdout, stderr) = (stdout.take(), stderr.take());
+        if let Some(reason) = options.skip_reason(
+            status,
+            result.signal,
+            &result.stdout_matches,
+            stdout.len() as u64,
+            stderr.len() as u64,
+        ) {
+            info(format!("not recording result: {}", reason));
+            return Ok(status);
+        }
+
+        info(format!("recording result with exit code {}", status));
+
+        let meta = DiskCacheEntryMeta {
+            command: command.clone(),
+            created: now,
+            expires: options.expires_at(now),
+            status,
+            signal: result.signal,
+            env: options.env(),
+            duration: Some(result.duration),
+            usage: result.usage,
+            diverged: None,
+            tags: options.tags().clone(),
+            id: options.id(),
+            checksums: None,
+            pinned: false,
+        };
+
+        let hash = command.hash();
+        let transaction = self.connection.unchecked_transaction()?;
+
+        if options.keep_history > 0 {
+            delete_entries(&transaction, hash, options.keep_history..=usize::MAX)?;
+            // Moved through negative generations, so the primary key is never shared mid-update
+            transaction.execute(
+                "UPDATE entries SET generation = -(generation + 1) WHERE hash = ?1",
+                params![hash],
+            )?;
+            transaction.execute(
+                "UPDATE entries SET generation = -generation WHERE hash = ?1",
+                params![hash],
+            )?;
+        }
+
+        insert_entry(&transaction, hash, 0, &meta, &stdout, &stderr)?;
+        transaction
+            .commit()
+            .map_err(|_| unable_to_write_to_cache_error(&self.path))?;
+        Ok(status)
+    }
+
+    fn read(&self, hash: &str) -> anyhow::Result<Option<SqliteCacheEntry>> {
+        self.read_generation(hash, 0)
+    }
+
+    fn read_generation(
+        &self,
+        hash: &str,
+        generation: usize,
+    ) -> anyhow::Result<Option<SqliteCacheEntry>> {
+        debug(format!(
+            "looking for entry: {} (generation {}) in {}",
+            hash,
+            generation,
+            self.path.display()
+        ));
+
+        let row = self
+            .connection
+            .query_row(
+                "SELECT meta, stdout, stderr FROM entries WHERE hash = ?1 AND generation = ?2",
+                params![hash, generation],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .map_err(|_| unable_to_read_cache_entry_error(&self.path))?;
+
+        row.map(|(meta, stdout, stderr)| self.entry(hash, meta, stdout, stderr))
+            .transpose()
+    }
+
+    fn list(&self) -> anyhow::Result<Vec<(String, SqliteCacheEntry)>> {
+        let mut statement = self.connection.prepare(
+            "SELECT hash, meta, stdout, stderr FROM entries
+             WHERE generation = 0 ORDER BY created",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?;
+
+        let mut entries = vec![];
+        for row in rows {
+            let (hash, meta, stdout, stderr): (String, String, String, String) = row?;
+            match self.entry(&hash, meta, stdout, stderr) {
+                Ok(entry) => entries.push((hash, entry)),
+                Err(e) => debug(format!("skipping {}: {}", hash, e)),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn try_lock(&self, hash: &str) -> anyhow::Result<Option<CacheLock>> {
+        let locks = self.locks_path();
+        create_cache_dir(&locks, self.modes).map_err(|_| unable_to_write_to_cache_error(&locks))?;
+        try_lock_file(&locks.join(format!("{hash}.lock")), self.modes)
+    }
+
+    fn remove_unused_locks(&self) -> anyhow::Result<usize> {
+        remove_unused_lock_files(&self.locks_path(), self.modes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::command::ScopeBuilder;
+    use ulid::Ulid;
+
+    #[test]
+    fn test_import() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let disk = DiskCache::new(root.join("disk"), CacheModes::PRIVATE, false)?;
+        let mut command = Command::new(ScopeBuilder::new().cmd("echo").args("hello").build()?);
+        let mut options = RecordOptions::default();
+        options.set_keep_history(1);
+        disk.record(&mut command, &options)?;
+        disk.record(&mut command, &options)?;
+
+        let sqlite = SqliteCache::open(root.join("cache.db"), CacheModes::PRIVATE)?;
+        assert_eq!(sqlite.import(&disk)?, 1);
+
+        let history = sqlite.history(command.hash())?;
+        assert_eq!(history.len(), 2, "imports history");
+        assert_eq!(history[0].stdout()?, "hello\n");
+        assert_eq!(history[1].stdout()?, "hello\n");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_read_only() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let mut command = Command::new(ScopeBuilder::new().cmd("echo").args("hello").build()?);
+
+        let missing = SqliteCache::open_read_only(root.join("cache.db"), CacheModes::PRIVATE)?;
+        assert!(missing.read(command.hash())?.is_none());
+        assert!(!root.exists(), "doesn't create the database");
+
+        SqliteCache::open(root.join("cache.db"), CacheModes::PRIVATE)?
+            .record(&mut command, &RecordOptions::default())?;
+        let cache = SqliteCache::open_read_only(root.join("cache.db"), CacheModes::PRIVATE)?;
+        assert_eq!(
+            cache.read(command.hash())?.expect("found").stdout()?,
+            "hello\n"
+        );
+        assert!(cache.remove(command.hash()).is_err(), "never writes");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -1,165 +1,1222 @@
 use anyhow::anyhow;
 use core::str;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::ffi::OsString;
 use std::fmt::Formatter;
 use std::io::Write;
-use std::path::PathBuf;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
 use std::{
-    io::{BufRead, BufReader},
+    io::{BufRead, BufReader, BufWriter, Read},
     process::Stdio,
+    sync::atomic::{AtomicI32, Ordering},
     thread,
-    time::Instant,
+    time::{Duration, Instant},
 };
 use ulid::Ulid;
 
-use crate::hash::{self, Hash};
-
+use crate::cache::SharedWriter;
+use crate::debug;
+use crate::document::WatchedValue;
+use crate::git::GitState;
+use crate::hash::{self, Hash, HashBuilder, SymlinkMode};
+use crate::progress::Progress;
+use crate::watch_cache::WatchCache;
+
+/// How much output is read, and buffered before writing, at once. Lines longer than this are
+/// captured in pieces, so memory use doesn't depend on the length of lines.
+const CAPTURE_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Set in the timestamp of a piece of output that doesn't end a line. Rather than running to
+/// the next newline, the piece's length follows the timestamp, as 8 bytes.
+pub(crate) const PARTIAL_LINE: u128 = 1 << 127;
+
+/// Passes output from `reader` through to `output`, while capturing it to `writer` with a
+/// timestamp on each line. Returns the writer, or the first error writing to it, along with
+/// which patterns matched. After an error, output is still read and passed through, so the
+/// command is never left blocked writing to a full pipe.
+///
+/// Long lines are captured (and matched against patterns) in pieces of at most
+/// `CAPTURE_BUFFER_SIZE` bytes, each marked with `PARTIAL_LINE`.
 fn capture_output<R, W, O>(
     start: Instant,
-    mut reader: R,
-    mut writer: W,
-    mut output: O,
-) -> thread::JoinHandle<W>
+    reader: R,
+    writer: W,
+    output: O,
+    patterns: Vec<Regex>,
+) -> thread::JoinHandle<(std::io::Result<W>, Vec<bool>)>
 where
-    R: BufRead + Send + 'static,
+    R: Read + Send + 'static,
     W: Write + Send + 'static,
     O: Write + Send + 'static,
 {
     thread::spawn(move || {
-        let line = &mut String::new();
-        while let Ok(count) = reader.read_line(line) {
+        let mut reader = BufReader::with_capacity(CAPTURE_BUFFER_SIZE, reader);
+        let mut writer = BufWriter::with_capacity(CAPTURE_BUFFER_SIZE, writer);
+        let mut output = BufWriter::with_capacity(CAPTURE_BUFFER_SIZE, output);
+        let mut capture_error = None;
+        let mut output_failed = false;
+        let mut matches = vec![false; patterns.len()];
+        let line = &mut Vec::with_capacity(CAPTURE_BUFFER_SIZE);
+        let limit = CAPTURE_BUFFER_SIZE as u64;
+        while let Ok(count) = reader.by_ref().take(limit).read_until(b'\n', line) {
             if count == 0 {
                 break;
             }
-            let bytes = line.as_bytes();
 
-            output.write_all(bytes).unwrap();
+            if !output_failed {
+                if let Err(e) = output.write_all(line) {
+                    debug(format!("unable to pass output through: {}", e));
+                    output_failed = true;
+                }
+            }
+
+            if capture_error.is_none() {
+                if let Err(e) = write_line_piece(&mut writer, start.elapsed().as_nanos(), line) {
+                    capture_error = Some(e);
+                }
+            }
 
-            let elapsed = start.elapsed().as_nanos().to_be_bytes();
+            if matches.iter().any(|matched| !matched) {
+                let text = String::from_utf8_lossy(line);
+                for (pattern, matched) in patterns.iter().zip(matches.iter_mut()) {
+                    if !*matched {
+                        *matched = pattern.is_match(&text);
+                    }
+                }
+            }
 
-            writer.write_all(&elapsed).unwrap();
-            writer.write_all(bytes).unwrap();
+            // Output is passed through whenever the command has nothing more to read yet, so
+            // it appears as promptly as it would without capturing
+            if reader.buffer().is_empty() && !output_failed {
+                output_failed = output.flush().is_err();
+            }
 
             line.clear();
         }
-        writer
+        let _ = output.flush();
+
+        let writer = match capture_error {
+            // Dropping the writer would try to write out the rest of its buffer
+            Some(e) => {
+                let _ = writer.into_parts();
+                Err(e)
+            }
+            None => writer.into_inner().map_err(|e| e.into_error()),
+        };
+        (writer, matches)
     })
 }
 
+/// Writes a line, or piece of a line, of captured output after its timestamp.
+fn write_line_piece<W: Write>(writer: &mut W, elapsed: u128, line: &[u8]) -> std::io::Result<()> {
+    if line.ends_with(b"\n") {
+        writer.write_all(&elapsed.to_be_bytes())?;
+    } else {
+        writer.write_all(&(elapsed | PARTIAL_LINE).to_be_bytes())?;
+        writer.write_all(&(line.len() as u64).to_be_bytes())?;
+    }
+    writer.write_all(line)
+}
+
+/// How long to wait after sending the timeout signal before killing a command.
+const TIMEOUT_KILL_AFTER: Duration = Duration::from_secs(5);
+
+/// How long to wait for output to finish being captured after a command without its own
+/// process group times out or is interrupted. Processes started by the command can keep its
+/// output open after it has been killed, and can't be killed with it.
+const TIMEOUT_CAPTURE_WAIT: Duration = Duration::from_secs(1);
+
+/// A limit on how long a command can run for.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    /// How long the command can run for.
+    pub duration: Duration,
+    /// The signal sent to the command when the timeout passes.
+    pub signal: i32,
+}
+
+/// Options controlling how a command is run.
+#[derive(Default)]
+pub struct RunOptions {
+    /// Patterns each line of stdout is checked against as it's captured.
+    pub stdout_patterns: Vec<Regex>,
+    pub timeout: Option<Timeout>,
+    /// Capture output without also passing it through to stdout and stderr.
+    pub silent: bool,
+    /// Pass output through to these writers instead of stdout and stderr, even when silent.
+    pub(crate) passthrough: Option<(SharedWriter, SharedWriter)>,
+}
+
+/// The result of running a command.
+pub struct CommandResult {
+    /// The exit code, or 128 plus the signal number when the command was killed by a signal.
+    pub status: i32,
+    /// The signal that killed the command, if any.
+    pub signal: Option<i32>,
+    /// Whether any line of stdout matched each of the patterns in `RunOptions`.
+    pub stdout_matches: Vec<bool>,
+    /// Whether the command was stopped for running longer than its timeout.
+    pub timed_out: bool,
+    /// How long the command took to run.
+    pub duration: Duration,
+    /// The CPU time and memory used by the command.
+    pub usage: Option<ResourceUsage>,
+    /// The error, if any, that stopped output being captured. The command still runs to
+    /// completion, but its captured output is incomplete.
+    pub capture_error: Option<std::io::Error>,
+    /// A signal (SIGINT, SIGTERM or SIGHUP) that deja received while the command ran, which
+    /// was passed on to the command. Its result shouldn't be recorded.
+    pub interrupted: Option<i32>,
+}
+
+/// The resources used by a command, as reported by the operating system once it finishes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResourceUsage {
+    /// CPU time spent running the command's own code.
+    pub user_time: Duration,
+    /// CPU time spent in the kernel on the command's behalf.
+    pub system_time: Duration,
+    /// The peak resident set size, in bytes.
+    pub max_rss: u64,
+}
+
+impl ResourceUsage {
+    fn from_rusage(usage: &libc::rusage) -> Self {
+        let time =
+            |time: libc::timeval| Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000);
+
+        // Linux reports the peak resident set size in kilobytes, macOS in bytes
+        let max_rss = usage.ru_maxrss as u64;
+        let max_rss = if cfg!(target_os = "macos") {
+            max_rss
+        } else {
+            max_rss * 1024
+        };
+
+        ResourceUsage {
+            user_time: time(usage.ru_utime),
+            system_time: time(usage.ru_stime),
+            max_rss,
+        }
+    }
+}
+
+/// Signals that can be given by name.
+const SIGNALS: [(&str, i32); 11] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ABRT", libc::SIGABRT),
+    ("KILL", libc::SIGKILL),
+    ("SEGV", libc::SIGSEGV),
+    ("PIPE", libc::SIGPIPE),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+];
+
+/// Parses a signal given as a name (e.g. `TERM` or `SIGTERM`) or number.
+pub fn parse_signal(signal: &str) -> anyhow::Result<i32> {
+    if let Ok(number) = signal.parse::<i32>() {
+        if !(1..=max_signal()).contains(&number) {
+            return Err(anyhow!("invalid signal '{}'", signal));
+        }
+        return Ok(number);
+    }
+
+    let name = signal.to_uppercase();
+    let name = name.strip_prefix("SIG").unwrap_or(&name);
+    SIGNALS
+        .iter()
+        .find(|(signal_name, _)| *signal_name == name)
+        .map(|(_, number)| *number)
+        .ok_or_else(|| anyhow!("invalid signal '{}'", signal))
+}
+
+/// The highest signal number, including real-time signals where they're supported.
+#[cfg(target_os = "linux")]
+fn max_signal() -> i32 {
+    libc::SIGRTMAX()
+}
+
+/// The highest signal number, including real-time signals where they're supported.
+#[cfg(not(target_os = "linux"))]
+fn max_signal() -> i32 {
+    31
+}
+
+/// Describes a signal by name where known (e.g. `SIGKILL`), otherwise by number.
+pub fn signal_name(signal: i32) -> String {
+    SIGNALS
+        .iter()
+        .find(|(_, number)| *number == signal)
+        .map(|(name, _)| format!("SIG{}", name))
+        .unwrap_or_else(|| format!("signal {}", signal))
+}
+
+/// Signals that are passed on to a running command, so it can finish before deja exits.
+const FORWARDED_SIGNALS: [i32; 3] = [libc::SIGINT, libc::SIGTERM, libc::SIGHUP];
+
+/// Where signals are forwarded to: the command's process group (as a negative number, as
+/// `kill` takes it) when it has its own, otherwise the command itself, or 0 when no command is
+/// running.
+static FORWARD_TO: AtomicI32 = AtomicI32::new(0);
+
+/// The last signal received while a command was running, or 0 if none was.
+static RECEIVED_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(
+    signal: libc::c_int,
+    info: *mut libc::siginfo_t,
+    _: *mut libc::c_void,
+) {
+    RECEIVED_SIGNAL.store(signal, Ordering::SeqCst);
+
+    // A command in deja's process group has already been sent signals from the terminal (like
+    // Ctrl-C), which come from the kernel rather than another process, so only other signals
+    // need passing on. A command in its own group needs all of them.
+    let target = FORWARD_TO.load(Ordering::SeqCst);
+    let from_terminal = unsafe { (*info).si_pid() } == 0;
+    if target < 0 || (target > 0 && !from_terminal) {
+        unsafe { libc::kill(target, signal) };
+    }
+}
+
+/// Whether deja is in the foreground of the terminal its stdin reads from. Only the foreground
+/// process group can read from a terminal (others are stopped when they try), so commands then
+/// stay in deja's group rather than getting their own.
+fn in_terminal_foreground() -> bool {
+    unsafe { libc::isatty(0) == 1 && libc::tcgetpgrp(0) == libc::getpgrp() }
+}
+
+/// Forwards `FORWARDED_SIGNALS` to a running command until dropped, when the previous handlers
+/// are restored. Signals that deja was started ignoring (as with `nohup`) stay ignored. It's
+/// created before the command is spawned, so no signal is missed in between.
+struct SignalForwarder {
+    previous: Vec<(i32, libc::sigaction)>,
+}
+
+impl SignalForwarder {
+    fn new() -> Self {
+        FORWARD_TO.store(0, Ordering::SeqCst);
+        RECEIVED_SIGNAL.store(0, Ordering::SeqCst);
+
+        let mut previous = Vec::new();
+        for signal in FORWARDED_SIGNALS {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = forward_signal as *const () as usize;
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+                libc::sigemptyset(&mut action.sa_mask);
+
+                let mut old: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(signal, &action, &mut old) != 0 {
+                    continue;
+                }
+                if old.sa_sigaction == libc::SIG_IGN {
+                    libc::sigaction(signal, &old, std::ptr::null_mut());
+                } else {
+                    previous.push((signal, old));
+                }
+            }
+        }
+        SignalForwarder { previous }
+    }
+
+    /// Starts forwarding signals to `target` (from `signal_target`), passing on any signal
+    /// received while the command was being spawned.
+    fn forward_to(&self, target: libc::pid_t) {
+        FORWARD_TO.store(target, Ordering::SeqCst);
+        if let Some(signal) = self.received() {
+            unsafe { libc::kill(target, signal) };
+        }
+    }
+
+    /// The signal received while the command was running, if any.
+    fn received(&self) -> Option<i32> {
+        match RECEIVED_SIGNAL.load(Ordering::SeqCst) {
+            0 => None,
+            signal => Some(signal),
+        }
+    }
+}
+
+impl Drop for SignalForwarder {
+    fn drop(&mut self) {
+        FORWARD_TO.store(0, Ordering::SeqCst);
+        for (signal, action) in &self.previous {
+            unsafe { libc::sigaction(*signal, action, std::ptr::null_mut()) };
+        }
+    }
+}
+
+/// Waits for the child to exit (or just checks, unless `block` is set), returning its status and
+/// the resources it used. `Child::wait` doesn't report resource usage, so this waits directly.
+fn wait4(
+    child: &std::process::Child,
+    block: bool,
+) -> std::io::Result<Option<(std::process::ExitStatus, ResourceUsage)>> {
+    let options = if block { 0 } else { libc::WNOHANG };
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+
+    loop {
+        let pid =
+            unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, options, &mut usage) };
+
+        match pid {
+            0 => return Ok(None),
+            -1 => {
+                let error = std::io::Error::last_os_error();
+                if error.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+            _ => {
+                return Ok(Some((
+                    std::process::ExitStatus::from_raw(status),
+                    ResourceUsage::from_rusage(&usage),
+                )))
+            }
+        }
+    }
+}
+
+type WaitResult = (std::process::ExitStatus, ResourceUsage, bool);
+
+/// What to send signals to for a running command: its process group (as a negative number) when
+/// it leads its own, so processes it started get them too, otherwise just the command.
+fn signal_target(child: &std::process::Child, own_group: bool) -> libc::pid_t {
+    let pid = child.id() as libc::pid_t;
+    if own_group {
+        -pid
+    } else {
+        pid
+    }
+}
+
+/// Waits for the child to finish, signalling `target` (from `signal_target`) if it runs past the
+/// timeout, and killing it if the child still hasn't finished after `TIMEOUT_KILL_AFTER`. When the
+/// child has its own process group, processes it started are stopped too. Returns whether it
+/// timed out.
+fn wait_with_timeout(
+    child: &mut std::process::Child,
+    timeout: Option<Timeout>,
+    target: libc::pid_t,
+) -> std::io::Result<WaitResult> {
+    let finished = |(status, usage), timed_out| (status, usage, timed_out);
+
+    let Some(timeout) = timeout else {
+        return Ok(finished(wait4(child, true)?.unwrap(), false));
+    };
+
+    let poll = |child: &mut std::process::Child, until: Instant| loop {
+        if let Some(result) = wait4(child, false)? {
+            return Ok::<_, std::io::Error>(Some(result));
+        }
+        if Instant::now() >= until {
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+
+    if let Some(result) = poll(child, Instant::now() + timeout.duration)? {
+        return Ok(finished(result, false));
+    }
+
+    debug(format!(
+        "command timed out, sending signal {}",
+        timeout.signal
+    ));
+    unsafe { libc::kill(target, timeout.signal) };
+
+    if let Some(result) = poll(child, Instant::now() + TIMEOUT_KILL_AFTER)? {
+        return Ok(finished(result, true));
+    }
+
+    debug("command still running, killing".into());
+    unsafe { libc::kill(target, libc::SIGKILL) };
+    Ok(finished(wait4(child, true)?.unwrap(), true))
+}
+
+/// How the binary a command resolves to is included in the cache key.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryWatchMode {
+    /// Hash the contents of the binary.
+    Content,
+    /// Hash the path, size and modification time of the binary.
+    Metadata,
+}
+
+impl std::str::FromStr for BinaryWatchMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "content" => Ok(BinaryWatchMode::Content),
+            "metadata" => Ok(BinaryWatchMode::Metadata),
+            _ => Err(anyhow!(
+                "invalid binary watch mode '{}', use either content or metadata",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for BinaryWatchMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BinaryWatchMode::Content => write!(f, "content"),
+            BinaryWatchMode::Metadata => write!(f, "metadata"),
+        }
+    }
+}
+
+/// The binary a command resolves to, along with a hash identifying its version.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct CommandBinary {
+    path: PathBuf,
+    mode: BinaryWatchMode,
+    hash: String,
+}
+
+impl CommandBinary {
+    /// Finds the binary `cmd` runs, hashing it as `mode` asks.
+    pub fn resolve(cmd: &str, mode: BinaryWatchMode) -> anyhow::Result<Self> {
+        let path = resolve_command(cmd)
+            .ok_or_else(|| anyhow!("unable to resolve command binary: {}", cmd))?;
+
+        let unable_to_read = |e: std::io::Error| {
+            anyhow!("unable to read command binary '{}': {}", path.display(), e)
+        };
+
+        let hash = match mode {
+            BinaryWatchMode::Content => {
+                Hash::from(std::fs::read(&path).map_err(unable_to_read)?.as_slice())
+            }
+            BinaryWatchMode::Metadata => {
+                let metadata = path.metadata().map_err(unable_to_read)?;
+                let modified = metadata
+                    .modified()
+                    .map_err(unable_to_read)?
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos();
+                Hash::from(&vec![
+                    Hash::from(path.as_os_str().as_bytes()),
+                    Hash::from(metadata.len().to_string().as_str()),
+                    Hash::from(modified.to_string().as_str()),
+                ])
+            }
+        };
+
+        Ok(CommandBinary {
+            path,
+            mode,
+            hash: hash.hex(),
+        })
+    }
+}
+
+/// Resolves a command to the executable that would be run, searching `PATH` when
+/// the command doesn't contain a `/`.
+fn resolve_command(cmd: &str) -> Option<PathBuf> {
+    let is_executable = |path: &PathBuf| {
+        path.metadata()
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    };
+
+    let path = if cmd.contains('/') {
+        Some(PathBuf::from(cmd)).filter(is_executable)
+    } else {
+        std::env::var_os("PATH").and_then(|paths| {
+            std::env::split_paths(&paths)
+                .map(|dir| dir.join(cmd))
+                .find(is_executable)
+        })
+    };
+
+    path.and_then(|path| std::fs::canonicalize(path).ok())
+}
+
+/// Version of the inputs and algorithm used to generate cache keys. This is included
+/// in every hash, so changing it invalidates all existing cache entries. It must be
+/// bumped whenever a change alters the hash generated for an existing scope, and
+/// only then (the tests pinning known hashes will fail when this is needed).
+pub const HASH_FORMAT_VERSION: &str = "2";
+
+/// Variables still passed to a command run with a cleared environment, besides those it's
+/// given explicitly.
+pub const CLEAR_ENV_KEEPS: [&str; 3] = ["PATH", "HOME", "TERM"];
+
+/// Stores paths as strings, as serde does by default, except that paths which aren't valid
+/// UTF-8 (and which serde refuses to serialize) are stored as their raw bytes.
+pub(crate) mod stored_path {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(untagged)]
+    enum StoredPath {
+        Utf8(String),
+        Bytes(Vec<u8>),
+    }
+
+    impl From<&Path> for StoredPath {
+        fn from(path: &Path) -> Self {
+            match path.to_str() {
+                Some(path) => StoredPath::Utf8(path.to_string()),
+                None => StoredPath::Bytes(path.as_os_str().as_bytes().to_vec()),
+            }
+        }
+    }
+
+    impl From<StoredPath> for PathBuf {
+        fn from(path: StoredPath) -> Self {
+            match path {
+                StoredPath::Utf8(path) => PathBuf::from(path),
+                StoredPath::Bytes(bytes) => PathBuf::from(OsStr::from_bytes(&bytes)),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        StoredPath::from(path).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        StoredPath::deserialize(deserializer).map(PathBuf::from)
+    }
+
+    pub mod vec {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            paths: &[PathBuf],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_seq(paths.iter().map(|path| StoredPath::from(path.as_path())))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<PathBuf>, D::Error> {
+            let paths = Vec::<StoredPath>::deserialize(deserializer)?;
+            Ok(paths.into_iter().map(PathBuf::from).collect())
+        }
+    }
+}
+
+/// Builds a `Scope`, from the command and its arguments plus anything else that should
+/// affect whether a cached result is used.
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct ScopeBuilder {
     format: String,
+    key: Option<String>,
     cmd: String,
     args: Vec<String>,
+    shell: Option<String>,
+    ignore_args: Vec<String>,
+    ignore_args_with_value: Vec<String>,
+    exclude_args: bool,
     shared: bool,
     user: Option<String>,
     pwd: Option<OsString>,
+    hostname: Option<String>,
+    platform: Option<String>,
+    command_binary: Option<CommandBinary>,
+    git: Option<GitState>,
+    #[serde(with = "stored_path::vec")]
     watch_paths: Vec<PathBuf>,
+    watch_symlinks: SymlinkMode,
+    watch_values: Vec<WatchedValue>,
     watch_scope: HashSet<String>,
     watch_env: HashMap<String, String>,
+    watch_env_exists: HashMap<String, bool>,
+    /// Variables set for the command when it runs, which are also part of the scope.
+    #[serde(default)]
+    set_env: HashMap<String, String>,
+    /// When the command runs with a cleared environment, the variables passed through to it.
+    #[serde(default)]
+    clear_env: Option<BTreeSet<String>>,
+    /// The directory the command runs in, when it isn't the current directory. This isn't
+    /// part of the scope itself, as it's already included as `pwd` (unless excluded).
+    #[serde(default)]
+    chdir: Option<OsString>,
+    /// Only speeds up hashing, so isn't part of the scope.
+    #[serde(skip)]
+    watch_cache: Option<WatchCache>,
+    /// Only changes what's shown while hashing, so isn't part of the scope.
+    #[serde(skip)]
+    watch_progress: bool,
 }
 
 impl ScopeBuilder {
+    /// An empty scope, with the current hash format.
     pub fn new() -> Self {
         ScopeBuilder {
-            format: env!("CARGO_PKG_VERSION").to_string(),
+            format: HASH_FORMAT_VERSION.to_string(),
             shared: false,
             ..Default::default()
         }
     }
 
+    /// Uses `key` in place of the command and arguments when hashing.
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// The command to run.
     pub fn cmd(mut self, cmd: impl Into<String>) -> Self {
         self.cmd = cmd.into();
         self
     }
 
+    /// The arguments the command is run with.
     pub fn args<T>(mut self, args: impl IntoArgs<T>) -> Self {
         self.args = args.into_args();
         self
     }
 
+    /// Runs the command through the given shell, as `shell -c cmd`, so `cmd` can be a whole
+    /// command line with pipes and redirects.
+    pub fn shell(mut self, shell: impl Into<String>) -> Self {
+        self.shell = Some(shell.into());
+        self
+    }
+
+    /// Arguments left out of the scope, by value or by `#` and position, like `#2`.
+    pub fn ignore_args(mut self, ignore_args: Vec<String>) -> Self {
+        self.ignore_args = ignore_args;
+        self
+    }
+
+    /// Arguments left out of the scope along with the value that follows them.
+    pub fn ignore_args_with_value(mut self, ignore_args_with_value: Vec<String>) -> Self {
+        self.ignore_args_with_value = ignore_args_with_value;
+        self
+    }
+
+    /// Leaves all the arguments out of the scope.
+    pub fn exclude_args(mut self, exclude_args: bool) -> Self {
+        self.exclude_args = exclude_args;
+        self
+    }
+
+    /// Shares the result between users, leaving the user out of the scope.
     pub fn shared(mut self, shared: bool) -> Self {
         self.shared = shared;
         self
     }
 
+    /// The user, as included in the scope.
     pub fn user(mut self, user: impl Into<String>) -> Self {
         self.user = Some(user.into());
         self
     }
 
+    /// The working directory, as included in the scope.
     pub fn pwd(mut self, pwd: PathBuf) -> Self {
         self.pwd = Some(pwd.as_os_str().to_os_string());
         self
     }
 
+    /// Runs the command in the given directory, rather than the current directory.
+    pub fn chdir(mut self, chdir: PathBuf) -> Self {
+        self.chdir = Some(chdir.into_os_string());
+        self
+    }
+
+    /// The hostname, as included in the scope.
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// The platform, as included in the scope.
+    pub fn platform(mut self, platform: impl Into<String>) -> Self {
+        self.platform = Some(platform.into());
+        self
+    }
+
+    /// The binary the command resolves to, as included in the scope.
+    pub fn command_binary(mut self, command_binary: CommandBinary) -> Self {
+        self.command_binary = Some(command_binary);
+        self
+    }
+
+    /// The state of the git repository, as included in the scope.
+    pub fn git(mut self, git: GitState) -> Self {
+        self.git = Some(git);
+        self
+    }
+
+    /// Files and directories whose contents are included in the scope.
     pub fn watch_paths(mut self, watch_paths: Vec<PathBuf>) -> Self {
         self.watch_paths = watch_paths;
         self
     }
 
+    /// Whether symlinks in watched paths are followed.
+    pub fn watch_symlinks(mut self, watch_symlinks: SymlinkMode) -> Self {
+        self.watch_symlinks = watch_symlinks;
+        self
+    }
+
+    /// Reuses remembered hashes of watched paths that haven't changed.
+    pub fn watch_cache(mut self, watch_cache: WatchCache) -> Self {
+        self.watch_cache = Some(watch_cache);
+        self
+    }
+
+    /// Shows progress on stderr when hashing watched paths takes a while, if stderr is a
+    /// terminal.
+    pub fn watch_progress(mut self, watch_progress: bool) -> Self {
+        self.watch_progress = watch_progress;
+        self
+    }
+
+    /// Values from JSON or YAML documents included in the scope.
+    pub fn watch_values(mut self, watch_values: Vec<WatchedValue>) -> Self {
+        self.watch_values = watch_values;
+        self
+    }
+
+    /// Arbitrary strings included in the scope.
     pub fn watch_scope(mut self, watch_scope: impl IntoWatchScope) -> Self {
         self.watch_scope = watch_scope.into_watch_scope();
         self
     }
 
+    /// Environment variables whose values are included in the scope.
     pub fn watch_env<T>(mut self, watch_env: impl IntoEnv<T>) -> Self {
         self.watch_env = watch_env.into_env();
         self
     }
 
+    /// Environment variables whose presence is included in the scope.
+    pub fn watch_env_exists(mut self, watch_env_exists: HashMap<String, bool>) -> Self {
+        self.watch_env_exists = watch_env_exists;
+        self
+    }
+
+    /// Sets environment variables for the command when it runs. As they can change its output,
+    /// they're hashed too, in the same way as watched variables.
+    pub fn set_env<T>(mut self, set_env: impl IntoEnv<T>) -> Self {
+        self.set_env = set_env.into_env();
+        self
+    }
+
+    /// Runs the command with only `CLEAR_ENV_KEEPS`, the given variables and those set with
+    /// `set_env` in its environment. Which variables are kept is part of the scope, though
+    /// their values aren't.
+    pub fn clear_env(mut self, keep: impl IntoIterator<Item = String>) -> Self {
+        let mut kept: BTreeSet<String> = CLEAR_ENV_KEEPS.map(String::from).into();
+        kept.extend(keep);
+        self.clear_env = Some(kept);
+        self
+    }
+
+    #[cfg(test)]
     pub fn hash(&self) -> anyhow::Result<String> {
-        let format_hash = hash::Hash::from(&self.format);
-        let cmd_hash = hash::Hash::from(&self.cmd);
-        let args_hash = hash::Hash::from(&self.args);
-        let shared_hash = hash::Hash::from(self.shared);
-        let user_hash = hash::Hash::from(&self.user);
-        let pwd_hash = hash::Hash::from(&self.pwd);
+        Ok(self.hashes()?.hash.hex())
+    }
+
+    /// Hashes each component of the scope, and combines them into the final hash.
+    pub fn hashes(&self) -> anyhow::Result<ScopeHashes> {
+        if let Some(ignore) = self
+            .ignore_args
+            .iter()
+            .find(|ignore| ignore.starts_with('#') && ignore_position(ignore).is_none())
+        {
+            return Err(anyhow!(
+                "invalid --ignore-arg '{}', use # and a position from 1, like '#2'",
+                ignore
+            ));
+        }
+
+        let component = |name: &str| HashBuilder::new(name);
+        let optional = |name: &str, value: Option<&[u8]>| component(name).optional(value).finish();
+        let format_hash = component("format").str(&self.format).finish();
+
+        if let Some(key) = &self.key {
+            return Ok(ScopeHashes::new(
+                vec![
+                    ("format".into(), format_hash),
+                    ("key".into(), component("key").str(key).finish()),
+                ],
+                vec![],
+            ));
+        }
+
+        let cmd_hash = component("cmd").str(&self.cmd).finish();
+        let (mut hashed_args, _) =
+            partition_args(&self.args, &self.ignore_args, &self.ignore_args_with_value);
+        if self.exclude_args {
+            hashed_args.clear();
+        }
+        let args_hash = component("args")
+            .strs(hashed_args.iter().map(String::as_str))
+            .bool(self.exclude_args)
+            .finish();
+        let shared_hash = component("shared").bool(self.shared).finish();
+        let user_hash = optional("user", self.user.as_deref().map(str::as_bytes));
+        let pwd_hash = optional("pwd", self.pwd.as_deref().map(|pwd| pwd.as_bytes()));
+        let hostname_hash = optional("hostname", self.hostname.as_deref().map(str::as_bytes));
+        let platform_hash = optional("platform", self.platform.as_deref().map(str::as_bytes));
+        let command_binary = self
+            .command_binary
+            .as_ref()
+            .map(|binary| format!("{}:{}", binary.mode, binary.hash));
+        let command_binary_hash = optional(
+            "command_binary",
+            command_binary.as_deref().map(str::as_bytes),
+        );
         let watch_scope_hash = hash::Hash::from(&self.watch_scope);
         let watch_env_hash = hash::Hash::from(&self.watch_env);
-        let watch_paths_hash = hash::Hash::try_from(&self.watch_paths)?;
-        let hash = hash::Hash::from(&vec![
-            format_hash,
-            cmd_hash,
-            args_hash,
-            shared_hash,
-            user_hash,
-            pwd_hash,
-            watch_scope_hash,
-            watch_env_hash,
-            watch_paths_hash,
-        ]);
-        Ok(hash.hex())
+        let watch_env_exists_hash = hash::Hash::from(&self.watch_env_exists);
+
+        let mut watch_values = component("watch_values");
+        watch_values.count(self.watch_values.len());
+        for watched in &self.watch_values {
+            watch_values
+                .str(&watched.format.to_string())
+                .bytes(watched.path.as_os_str().as_bytes())
+                .str(&watched.pointer)
+                .str(&watched.value);
+        }
+        let watch_values_hash = watch_values.finish();
+
+        let watch_symlinks_hash = component("watch_symlinks")
+            .str(&self.watch_symlinks.to_string())
+            .finish();
+        let mut progress = self.watch_progress.then(Progress::stderr).flatten();
+        let mut file_hashed = |path: &Path, bytes: u64| {
+            if let Some(progress) = &mut progress {
+                // Progress names the watched path being hashed, rather than each file in it
+                let watched = self
+                    .watch_paths
+                    .iter()
+                    .find(|watched| path.starts_with(watched))
+                    .map_or(path, PathBuf::as_path);
+                progress.file_hashed(watched, bytes);
+            }
+        };
+        let watch_path_hashes = match &self.watch_cache {
+            Some(watch_cache) => {
+                watch_cache.hash_paths(&self.watch_paths, self.watch_symlinks, &mut file_hashed)?
+            }
+            None => self
+                .watch_paths
+                .iter()
+                .map(|path| {
+                    Ok((
+                        path.clone(),
+                        Hash::try_from_path_with_progress(
+                            path,
+                            self.watch_symlinks,
+                            &mut file_hashed,
+                        )?,
+                    ))
+                })
+                .collect::<anyhow::Result<Vec<(PathBuf, Hash)>>>()?,
+        };
+        // Erases any progress shown, before anything else is written to stderr
+        drop(progress);
+        let mut watch_paths = component("watch_paths");
+        watch_paths.count(watch_path_hashes.len());
+        for (_, hash) in &watch_path_hashes {
+            watch_paths.hash(hash);
+        }
+        let watch_paths_hash = watch_paths.finish();
+
+        let mut components = vec![
+            ("format".into(), format_hash),
+            ("cmd".into(), cmd_hash),
+            ("args".into(), args_hash),
+            ("shared".into(), shared_hash),
+            ("user".into(), user_hash),
+            ("pwd".into(), pwd_hash),
+            ("hostname".into(), hostname_hash),
+            ("platform".into(), platform_hash),
+            ("command_binary".into(), command_binary_hash),
+            ("watch_scope".into(), watch_scope_hash),
+            ("watch_env".into(), watch_env_hash),
+            ("watch_env_exists".into(), watch_env_exists_hash),
+            ("watch_symlinks".into(), watch_symlinks_hash),
+            ("watch_paths".into(), watch_paths_hash),
+            ("watch_values".into(), watch_values_hash),
+        ];
+
+        // Only included when set, so existing cache keys are unchanged
+        if let Some(git) = &self.git {
+            let dirty = git.dirty.map(|dirty| dirty.to_string());
+            components.push((
+                "git".into(),
+                component("git")
+                    .str(&git.mode.to_string())
+                    .optional(git.commit.as_deref().map(str::as_bytes))
+                    .optional(git.tag.as_deref().map(str::as_bytes))
+                    .optional(dirty.as_deref().map(str::as_bytes))
+                    .finish(),
+            ));
+        }
+
+        if let Some(shell) = &self.shell {
+            components.push(("shell".into(), component("shell").str(shell).finish()));
+        }
+
+        if !self.set_env.is_empty() {
+            components.push(("set_env".into(), hash::Hash::from(&self.set_env)));
+        }
+
+        if let Some(kept) = &self.clear_env {
+            components.push((
+                "clear_env".into(),
+                component("clear_env")
+                    .strs(kept.iter().map(String::as_str))
+                    .finish(),
+            ));
+        }
+
+        let mut hashes = ScopeHashes::new(components, watch_path_hashes);
+        hashes.watch_env = self
+            .watch_env
+            .iter()
+            .map(|(name, value)| (name.clone(), Hash::from(value)))
+            .collect();
+        hashes.watch_env.sort_by(|a, b| a.0.cmp(&b.0));
+        hashes.watch_scope = self
+            .watch_scope
+            .iter()
+            .map(|scope| (scope.clone(), Hash::from(scope)))
+            .collect();
+        hashes.watch_scope.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(hashes)
     }
 
+    /// Hashes everything in the scope, resolving it into a `Scope`.
     pub fn build(self) -> anyhow::Result<Scope> {
+        let hashes = self.hashes()?;
         Ok(Scope {
-            hash: self.hash()?,
+            hash: hashes.hash.hex(),
+            hashes,
             format: self.format,
+            key: self.key,
             cmd: self.cmd,
             args: self.args,
+            shell: self.shell,
+            ignore_args: self.ignore_args,
+            ignore_args_with_value: self.ignore_args_with_value,
+            exclude_args: self.exclude_args,
             user: self.user,
             pwd: self.pwd,
+            hostname: self.hostname,
+            platform: self.platform,
+            command_binary: self.command_binary,
+            git: self.git,
             watch_paths: self.watch_paths,
+            watch_symlinks: self.watch_symlinks,
+            watch_values: self.watch_values,
             watch_scope: self.watch_scope,
             watch_env: self.watch_env,
+            watch_env_exists: self.watch_env_exists,
+            set_env: self.set_env,
+            clear_env: self.clear_env,
+            chdir: self.chdir,
         })
     }
 }
 
+/// Everything that identifies a command's result in the cache, hashed to give its key.
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct Scope {
     format: String,
+    key: Option<String>,
     cmd: String,
     args: Vec<String>,
+    shell: Option<String>,
+    ignore_args: Vec<String>,
+    ignore_args_with_value: Vec<String>,
+    exclude_args: bool,
     user: Option<String>,
     pwd: Option<OsString>,
+    hostname: Option<String>,
+    platform: Option<String>,
+    command_binary: Option<CommandBinary>,
+    git: Option<GitState>,
+    #[serde(with = "stored_path::vec")]
     watch_paths: Vec<PathBuf>,
+    watch_symlinks: SymlinkMode,
+    watch_values: Vec<WatchedValue>,
     watch_scope: HashSet<String>,
     watch_env: HashMap<String, String>,
+    watch_env_exists: HashMap<String, bool>,
+    #[serde(default)]
+    set_env: HashMap<String, String>,
+    #[serde(default)]
+    clear_env: Option<BTreeSet<String>>,
+    #[serde(default)]
+    chdir: Option<OsString>,
     hash: String,
+    #[serde(skip)]
+    hashes: ScopeHashes,
+}
+
+/// The hashes of each component of a scope, in the order they're combined to give
+/// the final hash. Watched paths are hashed individually, and then combined into
+/// the single `watch_paths` component.
+///
+/// Each watched variable and scope string is also hashed on its own, so it's clear which
+/// changed, though only the combined `watch_env` and `watch_scope` hashes make up the key.
+#[derive(Debug, Default, Clone)]
+pub struct ScopeHashes {
+    pub components: Vec<(String, Hash)>,
+    pub watch_paths: Vec<(PathBuf, Hash)>,
+    pub watch_env: Vec<(String, Hash)>,
+    pub watch_scope: Vec<(String, Hash)>,
+    pub hash: Hash,
+}
+
+impl ScopeHashes {
+    fn new(components: Vec<(String, Hash)>, watch_paths: Vec<(PathBuf, Hash)>) -> Self {
+        let mut builder = HashBuilder::new("scope");
+        for (name, hash) in &components {
+            builder.str(name).hash(hash);
+        }
+        let hash = builder.finish();
+        ScopeHashes {
+            components,
+            watch_paths,
+            hash,
+            ..Default::default()
+        }
+    }
+
+    /// Describes each component hash on its own line, followed by the final hash.
+    pub fn describe(&self) -> String {
+        let mut result = format!("format_version: {}\n", HASH_FORMAT_VERSION);
+        for (name, hash) in &self.components {
+            result.push_str(format!("{}: {}\n", name, hash).as_str());
+            if name == "watch_paths" {
+                for (path, hash) in &self.watch_paths {
+                    result.push_str(
+                        format!("watch_path {}: {}\n", path.to_string_lossy(), hash).as_str(),
+                    );
+                }
+            }
+            if name == "watch_env" {
+                for (name, hash) in &self.watch_env {
+                    result.push_str(format!("watch_env {}: {}\n", name, hash).as_str());
+                }
+            }
+            if name == "watch_scope" {
+                for (scope, hash) in &self.watch_scope {
+                    result.push_str(format!("watch_scope \"{}\": {}\n", scope, hash).as_str());
+                }
+            }
+        }
+        result.push_str(format!("hash: {}\n", self.hash).as_str());
+        result
+    }
+
+    /// The same details as `describe`, in a form that can be serialized (for `--json`).
+    pub fn summary(&self) -> HashesSummary {
+        HashesSummary {
+            format_version: HASH_FORMAT_VERSION,
+            components: self
+                .components
+                .iter()
+                .map(|(name, hash)| (name.clone(), hash.to_string()))
+                .collect(),
+            watch_paths: self
+                .watch_paths
+                .iter()
+                .map(|(path, hash)| (path.to_string_lossy().to_string(), hash.to_string()))
+                .collect(),
+            watch_env: self
+                .watch_env
+                .iter()
+                .map(|(name, hash)| (name.clone(), hash.to_string()))
+                .collect(),
+            watch_scope: self
+                .watch_scope
+                .iter()
+                .map(|(scope, hash)| (scope.clone(), hash.to_string()))
+                .collect(),
+            hash: self.hash.to_string(),
+        }
+    }
+}
+
+/// The hashes of each component of a scope, as written by `hash --components --json`.
+#[derive(Debug, Serialize)]
+pub struct HashesSummary {
+    pub format_version: &'static str,
+    pub components: BTreeMap<String, String>,
+    pub watch_paths: BTreeMap<String, String>,
+    pub watch_env: BTreeMap<String, String>,
+    pub watch_scope: BTreeMap<String, String>,
+    pub hash: String,
+}
+
+/// Splits arguments into those included in the cache key, and those ignored.
+///
+/// Each entry in `ignore` is either an argument value to ignore, or `#` followed by the
+/// position (starting from 1) of the argument to ignore, like `#2`. Each entry in `ignore_with_value`
+/// is an option which is ignored along with the value following it (or the value
+/// given after `=`, as in `--option=value`).
+fn partition_args(
+    args: &[String],
+    ignore: &[String],
+    ignore_with_value: &[String],
+) -> (Vec<String>, Vec<String>) {
+    let mut hashed = Vec::new();
+    let mut ignored = Vec::new();
+    let mut skip_next = false;
+
+    for (index, arg) in args.iter().enumerate() {
+        let is_ignored = if skip_next {
+            skip_next = false;
+            true
+        } else if ignore_with_value.contains(arg) {
+            skip_next = true;
+            true
+        } else {
+            ignore_with_value
+                .iter()
+                .any(|option| arg.starts_with(&format!("{option}=")))
+                || ignore
+                    .iter()
+                    .any(|ignore| ignore == arg || ignore_position(ignore) == Some(index + 1))
+        };
+
+        if is_ignored {
+            ignored.push(arg.clone());
+        } else {
+            hashed.push(arg.clone());
+        }
+    }
+
+    (hashed, ignored)
+}
+
+/// The position given by an entry in `--ignore-arg`, like `#2`, or `None` for a value.
+fn ignore_position(ignore: &str) -> Option<usize> {
+    ignore
+        .strip_prefix('#')?
+        .parse()
+        .ok()
+        .filter(|position| *position > 0)
 }
 
 pub trait IntoArgs<T> {
@@ -228,7 +1285,28 @@ impl IntoWatchScope for Vec<String> {
 }
 
 impl Scope {
-    pub fn explanation(&self) -> ScopeExplanation {
+    /// The hash of each part of the scope.
+    pub fn hashes(&self) -> &ScopeHashes {
+        &self.hashes
+    }
+
+    /// The command that's run.
+    pub fn cmd(&self) -> &str {
+        &self.cmd
+    }
+
+    /// The arguments the command is run with.
+    pub fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// The shell the command is run through, with `--shell`.
+    pub fn shell(&self) -> Option<&str> {
+        self.shell.as_deref()
+    }
+
+    /// Describes each part of the scope, as shown by `explain`.
+    pub fn explanation(&self) -> ScopeExplanation<'_> {
         ScopeExplanation { scope: self }
     }
 }
@@ -238,6 +1316,33 @@ pub struct ScopeExplanation<'a> {
 }
 
 impl<'a> ScopeExplanation<'a> {
+    /// The hash of a watched path, as computed when the scope was built. Paths aren't hashed
+    /// when a key overrides the scope.
+    fn watch_path_hash(&self, path: &Path) -> Option<&Hash> {
+        self.scope
+            .hashes
+            .watch_paths
+            .iter()
+            .find(|(watched, _)| watched == path)
+            .map(|(_, hash)| hash)
+    }
+
+    fn explain_format(&self, result: &mut String) {
+        result.push_str(format!("format: {}\n", self.scope.format).as_str());
+    }
+
+    fn explain_key(&self, result: &mut String) {
+        if let Some(key) = &self.scope.key {
+            result.push_str(
+                format!(
+                    "key: {} (overridden, all other components are ignored)\n",
+                    key
+                )
+                .as_str(),
+            );
+        }
+    }
+
     fn explain_cmd_and_args(&self, result: &mut String) {
         result.push_str(format!("cmd: {}", self.scope.cmd).as_str());
         for arg in &self.scope.args {
@@ -246,9 +1351,29 @@ impl<'a> ScopeExplanation<'a> {
         result.push('\n');
     }
 
+    fn explain_ignored_args(&self, result: &mut String) {
+        if self.scope.exclude_args {
+            result.push_str("ignored args: all\n");
+            return;
+        }
+        let (_, ignored) = partition_args(
+            &self.scope.args,
+            &self.scope.ignore_args,
+            &self.scope.ignore_args_with_value,
+        );
+        if !ignored.is_empty() {
+            result.push_str("ignored args:");
+            for arg in ignored {
+                result.push_str(format!(" {}", arg).as_str());
+            }
+            result.push('\n');
+        }
+    }
+
     fn explain_user(&self, result: &mut String) {
-        if let Some(user) = &self.scope.user {
-            result.push_str(format!("user: {}\n", user).as_str());
+        match &self.scope.user {
+            Some(user) => result.push_str(format!("user: {}\n", user).as_str()),
+            None => result.push_str("user: excluded\n"),
         }
     }
 
@@ -258,11 +1383,68 @@ impl<'a> ScopeExplanation<'a> {
         }
     }
 
-    fn explain_watch_scope(&self, result: &mut String) {
-        if !self.scope.watch_scope.is_empty() {
-            result.push_str("scope:");
-            for scope in &self.scope.watch_scope {
-                result.push_str(format!(" \"{}\"", scope).as_str());
+    fn explain_chdir(&self, result: &mut String) {
+        if let Some(chdir) = &self.scope.chdir {
+            result.push_str(format!("runs in: {}\n", chdir.to_string_lossy()).as_str());
+        }
+    }
+
+    fn explain_shell(&self, result: &mut String) {
+        if let Some(shell) = &self.scope.shell {
+            result.push_str(format!("shell: {} -c\n", shell).as_str());
+        }
+    }
+
+    fn explain_hostname(&self, result: &mut String) {
+        if let Some(hostname) = &self.scope.hostname {
+            result.push_str(format!("hostname: {}\n", hostname).as_str());
+        }
+    }
+
+    fn explain_platform(&self, result: &mut String) {
+        if let Some(platform) = &self.scope.platform {
+            result.push_str(format!("platform: {}\n", platform).as_str());
+        }
+    }
+
+    fn explain_command_binary(&self, result: &mut String) {
+        if let Some(binary) = &self.scope.command_binary {
+            result.push_str(
+                format!(
+                    "binary: {} ({}: {})\n",
+                    binary.path.to_string_lossy(),
+                    binary.mode,
+                    binary.hash
+                )
+                .as_str(),
+            );
+        }
+    }
+
+    fn explain_git(&self, result: &mut String) {
+        if let Some(git) = &self.scope.git {
+            result.push_str(
+                format!(
+                    "git: {} ({})\n",
+                    git.commit.as_deref().unwrap_or("no commits"),
+                    git.mode
+                )
+                .as_str(),
+            );
+            if let Some(tag) = &git.tag {
+                result.push_str(format!("git tag: {}\n", tag).as_str());
+            }
+            if let Some(dirty) = git.dirty {
+                result.push_str(format!("git dirty: {}\n", dirty).as_str());
+            }
+        }
+    }
+
+    fn explain_watch_scope(&self, result: &mut String) {
+        if !self.scope.watch_scope.is_empty() {
+            result.push_str("scope:");
+            for scope in &self.scope.watch_scope {
+                result.push_str(format!(" \"{}\"", scope).as_str());
             }
             result.push('\n');
         }
@@ -272,11 +1454,30 @@ impl<'a> ScopeExplanation<'a> {
         if !self.scope.watch_paths.is_empty() {
             result.push_str("paths:\n");
             for path in &self.scope.watch_paths {
+                let hash = self
+                    .watch_path_hash(path)
+                    .map_or("not hashed".to_string(), Hash::to_string);
+                result.push_str(format!("  {}: {}\n", path.to_string_lossy(), hash).as_str());
+            }
+            result.push_str(format!("symlinks: {}\n", self.scope.watch_symlinks).as_str());
+        }
+    }
+
+    fn explain_watch_values(&self, result: &mut String) {
+        if !self.scope.watch_values.is_empty() {
+            result.push_str("values:\n");
+            for watched in &self.scope.watch_values {
+                let mut value = watched.value.clone();
+                if value.chars().count() > 60 {
+                    value = format!("{}…", value.chars().take(60).collect::<String>());
+                }
                 result.push_str(
                     format!(
-                        "  {}: {}\n",
-                        path.to_string_lossy(),
-                        Hash::try_from(path).unwrap()
+                        "  {}:{} ({}): {}\n",
+                        watched.path.to_string_lossy(),
+                        watched.pointer,
+                        watched.format,
+                        value
                     )
                     .as_str(),
                 );
@@ -293,59 +1494,261 @@ impl<'a> ScopeExplanation<'a> {
         }
     }
 
+    fn explain_watch_env_exists(&self, result: &mut String) {
+        if !self.scope.watch_env_exists.is_empty() {
+            result.push_str("env exists:\n");
+            for (key, exists) in &self.scope.watch_env_exists {
+                result.push_str(format!("  {}: {}\n", key, exists).as_str());
+            }
+        }
+    }
+
+    fn explain_set_env(&self, result: &mut String) {
+        if !self.scope.set_env.is_empty() {
+            result.push_str("set env:\n");
+            for (key, value) in self.scope.set_env.iter().collect::<BTreeMap<_, _>>() {
+                result.push_str(format!("  {}: {}\n", key, value).as_str());
+            }
+        }
+    }
+
+    fn explain_clear_env(&self, result: &mut String) {
+        if let Some(kept) = &self.scope.clear_env {
+            let kept = kept.iter().map(String::as_str).collect::<Vec<_>>();
+            result.push_str(format!("clear env: keeping {}\n", kept.join(" ")).as_str());
+        }
+    }
+
     pub fn explain(&self) -> String {
         let mut result = String::new();
+        self.explain_format(&mut result);
+        // A key replaces every other component, so there's nothing else to explain
+        if self.scope.key.is_some() {
+            self.explain_key(&mut result);
+            return result;
+        }
         self.explain_cmd_and_args(&mut result);
+        self.explain_shell(&mut result);
+        self.explain_ignored_args(&mut result);
         self.explain_user(&mut result);
         self.explain_pwd(&mut result);
+        self.explain_chdir(&mut result);
+        self.explain_hostname(&mut result);
+        self.explain_platform(&mut result);
+        self.explain_command_binary(&mut result);
+        self.explain_git(&mut result);
         self.explain_watch_scope(&mut result);
         self.explain_watch_paths(&mut result);
+        self.explain_watch_values(&mut result);
         self.explain_watch_env(&mut result);
+        self.explain_watch_env_exists(&mut result);
+        self.explain_set_env(&mut result);
+        self.explain_clear_env(&mut result);
         result
     }
+    /// The same details as `explain`, in a form that can be serialized (for `--json`).
+    pub fn summary(&self) -> ScopeSummary {
+        let scope = self.scope;
+        let ignored_args = if scope.exclude_args {
+            scope.args.clone()
+        } else {
+            partition_args(
+                &scope.args,
+                &scope.ignore_args,
+                &scope.ignore_args_with_value,
+            )
+            .1
+        };
+
+        ScopeSummary {
+            format: scope.format.clone(),
+            key: scope.key.clone(),
+            cmd: scope.cmd.clone(),
+            args: scope.args.clone(),
+            shell: scope.shell.clone(),
+            ignored_args,
+            user: scope.user.clone(),
+            pwd: scope
+                .pwd
+                .as_ref()
+                .map(|pwd| pwd.to_string_lossy().to_string()),
+            hostname: scope.hostname.clone(),
+            platform: scope.platform.clone(),
+            binary: scope.command_binary.as_ref().map(|binary| BinarySummary {
+                path: binary.path.to_string_lossy().to_string(),
+                mode: binary.mode.to_string(),
+                hash: binary.hash.clone(),
+            }),
+            git: scope.git.as_ref().map(|git| GitSummary {
+                mode: git.mode.to_string(),
+                commit: git.commit.clone(),
+                tag: git.tag.clone(),
+                dirty: git.dirty,
+            }),
+            scope: scope.watch_scope.iter().cloned().collect(),
+            paths: scope
+                .watch_paths
+                .iter()
+                .map(|path| {
+                    let hash = self.watch_path_hash(path).map(Hash::to_string);
+                    (path.to_string_lossy().to_string(), hash)
+                })
+                .collect(),
+            symlinks: scope.watch_symlinks.to_string(),
+            values: scope
+                .watch_values
+                .iter()
+                .map(|watched| ValueSummary {
+                    path: watched.path.to_string_lossy().to_string(),
+                    pointer: watched.pointer.clone(),
+                    format: watched.format.to_string(),
+                    value: watched.value.clone(),
+                })
+                .collect(),
+            env: scope.watch_env.clone().into_iter().collect(),
+            env_exists: scope.watch_env_exists.clone().into_iter().collect(),
+            set_env: scope.set_env.clone().into_iter().collect(),
+            clear_env: scope.clear_env.clone(),
+            chdir: scope
+                .chdir
+                .as_ref()
+                .map(|chdir| chdir.to_string_lossy().to_string()),
+        }
+    }
+}
+
+/// The components of a scope, as written by `explain --json`. Fields that aren't part of the
+/// scope are `null` (or empty), rather than left out.
+#[derive(Debug, Serialize)]
+pub struct ScopeSummary {
+    pub format: String,
+    pub key: Option<String>,
+    pub cmd: String,
+    pub args: Vec<String>,
+    pub shell: Option<String>,
+    pub ignored_args: Vec<String>,
+    pub user: Option<String>,
+    pub pwd: Option<String>,
+    pub hostname: Option<String>,
+    pub platform: Option<String>,
+    pub binary: Option<BinarySummary>,
+    pub git: Option<GitSummary>,
+    pub scope: BTreeSet<String>,
+    pub paths: BTreeMap<String, Option<String>>,
+    pub symlinks: String,
+    pub values: Vec<ValueSummary>,
+    pub env: BTreeMap<String, String>,
+    pub env_exists: BTreeMap<String, bool>,
+    pub set_env: BTreeMap<String, String>,
+    pub clear_env: Option<BTreeSet<String>>,
+    pub chdir: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BinarySummary {
+    pub path: String,
+    pub mode: String,
+    pub hash: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GitSummary {
+    pub mode: String,
+    pub commit: Option<String>,
+    pub tag: Option<String>,
+    pub dirty: Option<bool>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct ValueSummary {
+    pub path: String,
+    pub pointer: String,
+    pub format: String,
+    pub value: String,
+}
+
+/// A command to run, along with the scope its result is cached under.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Command {
+    /// Identifies this run of the command.
     pub ulid: String,
+    /// The scope the command's result is cached under.
     pub scope: Scope,
 }
 
 impl Command {
+    /// A new run of the command in `scope`.
     pub fn new(scope: Scope) -> Self {
         let ulid = Ulid::new().to_string();
         Command { ulid, scope }
     }
 
+    /// The hash the command's result is cached under.
     pub fn hash(&self) -> &str {
         &self.scope.hash
     }
 
-    pub fn run<O, E>(&mut self, stdout_capture: O, stderr_capture: E) -> anyhow::Result<(i32, O, E)>
+    /// Runs the command, capturing its output.
+    pub fn run<O, E>(
+        &mut self,
+        stdout_capture: O,
+        stderr_capture: E,
+        options: RunOptions,
+    ) -> anyhow::Result<CommandResult>
     where
         O: Write + Send + 'static,
         E: Write + Send + 'static,
     {
-        let mut child = std::process::Command::new(&self.scope.cmd)
-            .args(&self.scope.args)
+        let (program, args) = match &self.scope.shell {
+            Some(shell) => (shell, vec!["-c".to_string(), self.scope.cmd.clone()]),
+            None => (&self.scope.cmd, self.scope.args.clone()),
+        };
+        let mut command = std::process::Command::new(program);
+        if let Some(chdir) = &self.scope.chdir {
+            command.current_dir(chdir);
+        }
+        if let Some(kept) = &self.scope.clear_env {
+            command.env_clear();
+            for name in kept {
+                if let Some(value) = std::env::var_os(name) {
+                    command.env(name, value);
+                }
+            }
+        }
+        // A command gets its own process group, so signals can reach any processes it starts,
+        // unless it might need to read from the terminal
+        let own_group = !in_terminal_foreground();
+        if own_group {
+            command.process_group(0);
+        }
+        let signals = SignalForwarder::new();
+        let mut child = command
+            .args(args)
+            .envs(&self.scope.set_env)
             .stdout(Stdio::piped())
             .stderr(Stdio::piped())
             .spawn()
             .map_err(|e| {
                 let message = match e.kind() {
                     std::io::ErrorKind::NotFound => {
-                        format!("command not found: {}", self.scope.cmd)
+                        format!("command not found: {}", program)
                     }
                     std::io::ErrorKind::PermissionDenied => {
-                        format!("permission denied running command: {}", self.scope.cmd)
+                        format!("permission denied running command: {}", program)
                     }
-                    _ => format!("error running command: {}", self.scope.cmd),
+                    _ => format!("error running command: {}", program),
                 };
 
                 anyhow!("{}", message)
             })?;
 
         let start = Instant::now();
+        let (echo_stdout, echo_stderr): (Box<dyn Write + Send>, Box<dyn Write + Send>) =
+            match options.passthrough {
+                Some((stdout, stderr)) => (Box::new(stdout), Box::new(stderr)),
+                None if options.silent => (Box::new(std::io::sink()), Box::new(std::io::sink())),
+                None => (Box::new(std::io::stdout()), Box::new(std::io::stderr())),
+            };
 
         let child_stdout = child
             .stdout
@@ -353,32 +1756,81 @@ impl Command {
             .ok_or_else(|| anyhow!("unable to capture stdout"))?;
         let child_stdout_handle = capture_output(
             start,
-            BufReader::new(child_stdout),
+            child_stdout,
             stdout_capture,
-            std::io::stdout(),
+            echo_stdout,
+            options.stdout_patterns,
         );
 
         let child_stderr = child
             .stderr
             .take()
             .ok_or_else(|| anyhow!("unable to capture stderr"))?;
-        let child_stderr_handle = capture_output(
-            start,
-            BufReader::new(child_stderr),
-            stderr_capture,
-            std::io::stderr(),
-        );
-
-        let status = child
-            .wait()
-            .map_err(|e| anyhow!("error waiting for command to finish: {}", e))?
-            .code()
-            .unwrap_or(1);
+        let child_stderr_handle =
+            capture_output(start, child_stderr, stderr_capture, echo_stderr, vec![]);
+
+        let target = signal_target(&child, own_group);
+        signals.forward_to(target);
+        let (status, usage, timed_out) = wait_with_timeout(&mut child, options.timeout, target)
+            .map_err(|e| anyhow!("error waiting for command to finish: {}", e))?;
+        let interrupted = signals.received();
+        drop(signals);
+        let signal = status.signal();
+        let status = match signal {
+            Some(signal) => 128 + signal,
+            None => status.code().unwrap_or(1),
+        };
+
+        if timed_out || interrupted.is_some() {
+            // Output is incomplete, so isn't recorded. When the command has its own group,
+            // anything it started that's still running is killed, so its output closes and
+            // capture can finish. Otherwise, only wait briefly, as processes it started can
+            // keep its output open.
+            let capture_finished = if target < 0 {
+                unsafe { libc::kill(target, libc::SIGKILL) };
+                true
+            } else {
+                let until = Instant::now() + TIMEOUT_CAPTURE_WAIT;
+                while !(child_stdout_handle.is_finished() && child_stderr_handle.is_finished())
+                    && Instant::now() < until
+                {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                child_stdout_handle.is_finished() && child_stderr_handle.is_finished()
+            };
+            if capture_finished {
+                let _ = child_stdout_handle.join();
+                let _ = child_stderr_handle.join();
+            } else {
+                debug("output still open, leaving capture unfinished".into());
+            }
 
-        let stdout = child_stdout_handle.join().unwrap();
-        let stderr = child_stderr_handle.join().unwrap();
+            return Ok(CommandResult {
+                status,
+                signal,
+                stdout_matches: vec![],
+                timed_out,
+                duration: start.elapsed(),
+                usage: Some(usage),
+                capture_error: None,
+                interrupted,
+            });
+        }
 
-        Ok((status, stdout, stderr))
+        let capture_failed = || anyhow!("unable to capture output of {}", self.scope.cmd);
+        let (stdout, stdout_matches) = child_stdout_handle.join().map_err(|_| capture_failed())?;
+        let (stderr, _) = child_stderr_handle.join().map_err(|_| capture_failed())?;
+
+        Ok(CommandResult {
+            status,
+            signal,
+            stdout_matches,
+            timed_out,
+            duration: start.elapsed(),
+            usage: Some(usage),
+            capture_error: stdout.err().or(stderr.err()),
+            interrupted,
+        })
     }
 }
 
@@ -395,6 +1847,9 @@ impl std::fmt::Display for Command {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cache::OutputReader;
+    use crate::git::GitWatchMode;
+    use crate::output::Output;
 
     fn assert_unique<T>(elements: Vec<T>)
     where
@@ -410,9 +1865,228 @@ mod test {
         ScopeBuilder::new()
     }
 
+    #[test]
+    fn test_run_records_usage() -> anyhow::Result<()> {
+        let mut command = Command::new(scope().cmd("true").build()?);
+        let result = command.run(std::io::sink(), std::io::sink(), RunOptions::default())?;
+
+        let usage = result.usage.expect("usage is recorded");
+        assert!(usage.max_rss > 0, "max rss is recorded");
+        Ok(())
+    }
+
+    /// A writer that fails once it has been given `remaining` bytes, like a file on a disk that
+    /// fills up.
+    struct FailingWriter {
+        remaining: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::StorageFull));
+            }
+            let count = buf.len().min(self.remaining);
+            self.remaining -= count;
+            Ok(count)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_capture_write_failure() -> anyhow::Result<()> {
+        use crate::cache::SharedBuffer;
+
+        let input = "line\n".repeat(100_000).into_bytes();
+        let echo = SharedBuffer::default();
+        let (writer, _) = capture_output(
+            Instant::now(),
+            std::io::Cursor::new(input.clone()),
+            FailingWriter { remaining: 100 },
+            echo.clone(),
+            vec![],
+        )
+        .join()
+        .unwrap();
+
+        assert!(writer.is_err(), "returns the error");
+        assert_eq!(echo.take(), input, "still passes all output through");
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_capture_write_failure() -> anyhow::Result<()> {
+        let mut command = Command::new(scope().cmd("seq").args("100000").build()?);
+        let options = RunOptions {
+            silent: true,
+            ..Default::default()
+        };
+        let result = command.run(FailingWriter { remaining: 100 }, std::io::sink(), options)?;
+
+        assert_eq!(result.status, 0, "command runs to completion");
+        assert_eq!(
+            result.capture_error.map(|e| e.kind()),
+            Some(std::io::ErrorKind::StorageFull)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture_output() -> anyhow::Result<()> {
+        use crate::cache::SharedBuffer;
+
+        let input = b"first\n\nthird \xff line\nno newline".to_vec();
+        let (capture, echo) = (SharedBuffer::default(), SharedBuffer::default());
+        let patterns = vec![Regex::new("^third")?, Regex::new("missing")?];
+        let (_, matches) = capture_output(
+            Instant::now(),
+            std::io::Cursor::new(input.clone()),
+            capture.clone(),
+            echo.clone(),
+            patterns,
+        )
+        .join()
+        .unwrap();
+
+        assert_eq!(echo.take(), input, "passes output through unchanged");
+        assert_eq!(matches, vec![true, false]);
+
+        // Each line is stored after a 16 byte timestamp, and the unfinished last line after
+        // its length too
+        let captured = capture.take();
+        assert_eq!(&captured[16..22], b"first\n");
+        let lines: Vec<Vec<u8>> = OutputReader {
+            reader: BufReader::new(&captured[..]),
+        }
+        .map(|(_, line)| line)
+        .collect();
+        assert_eq!(
+            lines,
+            input
+                .split_inclusive(|byte| *byte == b'\n')
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    /// Produces `remaining` bytes of output without a single newline.
+    struct LongLine {
+        remaining: usize,
+    }
+
+    impl Read for LongLine {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let count = buf.len().min(self.remaining);
+            buf[..count].fill(b'a');
+            self.remaining -= count;
+            Ok(count)
+        }
+    }
+
+    /// Records the largest single write, which is at least as large as anything buffered.
+    #[derive(Clone, Default)]
+    struct LargestWrite(std::sync::Arc<std::sync::Mutex<(usize, usize)>>);
+
+    impl Write for LargestWrite {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let (largest, total) = &mut *self.0.lock().unwrap();
+            *largest = (*largest).max(buf.len());
+            *total += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_capture_long_lines() -> anyhow::Result<()> {
+        let length = 100 * 1024 * 1024;
+        let (capture, echo) = (LargestWrite::default(), LargestWrite::default());
+        let (writer, _) = capture_output(
+            Instant::now(),
+            LongLine { remaining: length },
+            capture.clone(),
+            echo.clone(),
+            vec![Regex::new("b")?],
+        )
+        .join()
+        .unwrap();
+        writer?;
+
+        let (largest, total) = *echo.0.lock().unwrap();
+        assert!(largest <= CAPTURE_BUFFER_SIZE, "passed through in pieces");
+        assert_eq!(total, length);
+        let (largest, _) = *capture.0.lock().unwrap();
+        assert!(largest <= CAPTURE_BUFFER_SIZE, "captured in pieces");
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_lines_are_replayed() -> anyhow::Result<()> {
+        use crate::cache::{replay_output, SharedBuffer};
+
+        let mut input = vec![b'a'; CAPTURE_BUFFER_SIZE * 3 + 10];
+        input.extend_from_slice(b"\nshort\n");
+        input.extend(vec![b'b'; CAPTURE_BUFFER_SIZE + 1]);
+
+        let (capture, echo) = (SharedBuffer::default(), SharedBuffer::default());
+        capture_output(
+            Instant::now(),
+            std::io::Cursor::new(input.clone()),
+            capture.clone(),
+            echo.clone(),
+            vec![],
+        )
+        .join()
+        .unwrap()
+        .0?;
+
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut output = Output::new(stdout.clone(), stderr.clone());
+        replay_output(&capture.take()[..], &[][..], &mut output)?;
+        assert_eq!(stdout.take(), input, "replays output unchanged");
+        Ok(())
+    }
+
+    /// Measures how quickly lots of short lines are captured and passed through. Run with
+    /// `cargo test --release capture_throughput -- --ignored --nocapture > /dev/null`.
+    #[test]
+    #[ignore]
+    fn test_capture_throughput() -> anyhow::Result<()> {
+        let lines = 5_000_000;
+        let script = format!("yes | head -n {lines}");
+        let mut command = Command::new(
+            scope()
+                .cmd("sh")
+                .args(vec!["-c".to_string(), script.clone()])
+                .build()?,
+        );
+
+        let start = Instant::now();
+        let result = command.run(std::io::sink(), std::io::sink(), RunOptions::default())?;
+        let elapsed = start.elapsed();
+        assert_eq!(result.status, 0);
+
+        let start = Instant::now();
+        std::process::Command::new("sh")
+            .args(["-c", &script])
+            .status()?;
+        eprintln!(
+            "captured {lines} lines in {:?} (running directly took {:?})",
+            elapsed,
+            start.elapsed()
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_scope() {
-        let cmds = vec!["echo", "cat", "ls"];
+        let cmds = ["echo", "cat", "ls"];
         let mut hashes = cmds
             .iter()
             .map(|cmd| ScopeBuilder::new().cmd(cmd.to_string()).hash().unwrap())
@@ -428,6 +2102,112 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_scope_known_hashes() -> anyhow::Result<()> {
+        // These hashes are pinned, so any change that would invalidate existing cache
+        // entries is caught. If one of these fails, either fix the change so existing
+        // hashes are preserved, or bump HASH_FORMAT_VERSION and update the hashes.
+        assert_eq!(
+            scope().hash()?,
+            "ae3aba46de9637328b0c08e827b4dcffcf30801fe39b87e59e60ba6c201d410a",
+            "empty scope"
+        );
+
+        assert_eq!(
+            scope()
+                .cmd("echo")
+                .args("hello world")
+                .user("deja")
+                .pwd("/tmp".into())
+                .hash()?,
+            "7f25d3bcf29b4ff06b70f6a496394d7768af16a8f8091e946e897abc4ee27feb",
+            "command with user and directory"
+        );
+
+        assert_eq!(
+            scope()
+                .cmd("echo")
+                .shared(true)
+                .watch_scope(vec!["a".into(), "b".into()])
+                .watch_env("A=1 B=2")
+                .watch_paths(vec![PathBuf::from("test/fixtures/empty-a.txt")])
+                .hash()?,
+            "1bf03487202b4847aac8e5694c17d708379b0241e8982c01c99705f45c6338d9",
+            "command with watched values"
+        );
+
+        assert_eq!(
+            scope().key("v1").hash()?,
+            "403e170ff6156cf51a0dd9a41271c5aa77e5991c99891a851d0996b26a259a24",
+            "key override"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_non_utf8_watch_path() -> anyhow::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = std::env::temp_dir().join(format!("deja-non-utf8-{}", Ulid::new()));
+        std::fs::create_dir_all(&root)?;
+        let path = root.join(std::ffi::OsStr::from_bytes(b"not \xff utf8"));
+        std::fs::write(&path, "contents")?;
+
+        let scope = scope()
+            .cmd("cat")
+            .watch_paths(vec![path.clone(), root.clone()])
+            .build()?;
+        let stored: Scope = ron::from_str(&ron::to_string(&scope)?)?;
+        assert_eq!(stored.watch_paths, vec![path, root.clone()]);
+
+        // Valid paths are stored as strings, as they always have been
+        assert!(ron::to_string(&scope)?.contains(&format!("{:?}", root.to_str().unwrap())));
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain_deleted_watch_path() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("deja-explain-{}", Ulid::new()));
+        std::fs::write(&path, "contents")?;
+        let scope = scope()
+            .cmd("echo")
+            .watch_paths(vec![path.clone()])
+            .build()?;
+        let hash = Hash::try_from_path(&path, SymlinkMode::Follow)?.to_string();
+
+        // The path is only hashed once, when the scope is built
+        std::fs::remove_file(&path)?;
+        let explanation = scope.explanation();
+        assert!(explanation
+            .explain()
+            .contains(&format!("  {}: {}\n", path.display(), hash)));
+        assert_eq!(
+            explanation.summary().paths[&path.to_string_lossy().to_string()],
+            Some(hash)
+        );
+
+        let keyed = ScopeBuilder::new()
+            .key("v1")
+            .watch_paths(vec![path.clone()])
+            .build()?;
+        assert_eq!(
+            keyed.explanation().explain(),
+            format!(
+                "format: {}\nkey: v1 (overridden, all other components are ignored)\n",
+                keyed.format
+            ),
+            "components that aren't hashed aren't explained"
+        );
+        assert_eq!(
+            keyed.explanation().summary().paths[&path.to_string_lossy().to_string()],
+            None
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_scope_empty() -> anyhow::Result<()> {
         assert_eq!(scope().hash()?, scope().hash()?, "empty scopes are equal");
@@ -458,6 +2238,190 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_scope_hostname() -> anyhow::Result<()> {
+        assert_eq!(
+            scope().hostname("a").hash()?,
+            scope().hostname("a").hash()?,
+            "hashes are equal with the same hostname"
+        );
+
+        assert_ne!(
+            scope().hostname("a").hash()?,
+            scope().hostname("b").hash()?,
+            "hashes are different when hostnames are different"
+        );
+
+        assert_ne!(
+            scope().hash()?,
+            scope().hostname("a").hash()?,
+            "hashes are different when hostname is included"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_signal() -> anyhow::Result<()> {
+        assert_eq!(parse_signal("TERM")?, libc::SIGTERM);
+        assert_eq!(parse_signal("sigint")?, libc::SIGINT);
+        assert_eq!(parse_signal("9")?, libc::SIGKILL);
+        assert!(parse_signal("NOPE").is_err(), "rejects unknown signals");
+        assert!(parse_signal("0").is_err(), "rejects signals out of range");
+        assert!(parse_signal("-9").is_err(), "rejects signals out of range");
+        assert!(
+            parse_signal("65536").is_err(),
+            "rejects signals out of range"
+        );
+        assert_eq!(parse_signal(&max_signal().to_string())?, max_signal());
+        assert_eq!(signal_name(libc::SIGKILL), "SIGKILL");
+        assert_eq!(signal_name(64), "signal 64");
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_git() -> anyhow::Result<()> {
+        let git = |commit: &str, dirty: Option<bool>| GitState {
+            mode: GitWatchMode::HeadDirty,
+            commit: Some(commit.into()),
+            tag: None,
+            dirty,
+        };
+
+        assert_ne!(
+            scope().git(git("a", Some(false))).hash()?,
+            scope().git(git("b", Some(false))).hash()?,
+            "hashes are different when commits are different"
+        );
+
+        assert_ne!(
+            scope().git(git("a", Some(false))).hash()?,
+            scope().git(git("a", Some(true))).hash()?,
+            "hashes are different when dirty state is different"
+        );
+
+        assert_ne!(
+            scope().hash()?,
+            scope().git(git("a", None)).hash()?,
+            "hashes are different when git state is included"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_shell() -> anyhow::Result<()> {
+        assert_ne!(
+            scope().cmd("echo hi").hash()?,
+            scope().cmd("echo hi").shell("/bin/sh").hash()?,
+            "hashes are different when run through a shell"
+        );
+
+        assert_ne!(
+            scope().cmd("echo hi").shell("/bin/sh").hash()?,
+            scope().cmd("echo hi").shell("/bin/bash").hash()?,
+            "hashes are different when shells are different"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_platform() -> anyhow::Result<()> {
+        assert_ne!(
+            scope().platform("linux-x86_64").hash()?,
+            scope().platform("macos-aarch64").hash()?,
+            "hashes are different when platforms are different"
+        );
+
+        assert_ne!(
+            scope().hostname("linux-x86_64").hash()?,
+            scope().platform("linux-x86_64").hash()?,
+            "hostname and platform hash differently"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_key() -> anyhow::Result<()> {
+        assert_eq!(
+            scope().key("v1").cmd("echo").args("a").hash()?,
+            scope().key("v1").cmd("ls").pwd("/tmp".into()).hash()?,
+            "hashes are equal with the same key, regardless of other components"
+        );
+
+        assert_ne!(
+            scope().key("v1").hash()?,
+            scope().key("v2").hash()?,
+            "hashes are different when keys are different"
+        );
+
+        assert_ne!(
+            scope().key("v1").hash()?,
+            scope().watch_scope(vec!["v1".into()]).hash()?,
+            "key hashes differently to a scope with the same string"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_hashes() -> anyhow::Result<()> {
+        let paths = vec![
+            PathBuf::from("test/fixtures/empty-a.txt"),
+            PathBuf::from("test/fixtures/empty-b.txt"),
+        ];
+        let hashes = scope().cmd("echo").watch_paths(paths).hashes()?;
+
+        assert_eq!(
+            hashes.hash.hex(),
+            scope()
+                .cmd("echo")
+                .watch_paths(vec![
+                    PathBuf::from("test/fixtures/empty-a.txt"),
+                    PathBuf::from("test/fixtures/empty-b.txt"),
+                ])
+                .hash()?,
+            "final hash matches scope hash"
+        );
+
+        let names = hashes
+            .components
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names[..3], ["format", "cmd", "args"]);
+        assert_eq!(hashes.watch_paths.len(), 2, "each watch path is hashed");
+
+        let description = hashes.describe();
+        assert!(description.contains("watch_path test/fixtures/empty-a.txt: "));
+        assert!(description.ends_with(&format!("hash: {}\n", hashes.hash)));
+
+        let a = scope()
+            .cmd("echo")
+            .watch_env("A=1 B=1")
+            .watch_scope(vec!["x".to_string()]);
+        let b = scope()
+            .cmd("echo")
+            .watch_env("A=1 B=2")
+            .watch_scope(vec!["x".to_string()]);
+        let (a, b) = (a.hashes()?, b.hashes()?);
+        let env = |hashes: &ScopeHashes, i: usize| hashes.watch_env[i].1.hex();
+        assert_eq!(env(&a, 0), env(&b, 0), "unchanged variable hashes the same");
+        assert_ne!(
+            env(&a, 1),
+            env(&b, 1),
+            "changed variable hashes differently"
+        );
+
+        let description = a.describe();
+        assert!(description.contains(&format!("watch_env B: {}\n", env(&a, 1))));
+        assert!(description.contains(&format!("watch_scope \"x\": {}\n", a.watch_scope[0].1)));
+
+        Ok(())
+    }
+
     #[test]
     fn test_scopes() -> anyhow::Result<()> {
         assert_unique(vec![
@@ -491,6 +2455,92 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_scope_set_env() -> anyhow::Result<()> {
+        assert_unique(vec![
+            scope().cmd("date").hash()?,
+            scope().cmd("date").set_env("TZ=UTC").hash()?,
+            scope().cmd("date").set_env("TZ=EST").hash()?,
+            scope().cmd("date").watch_env("TZ=UTC").hash()?,
+        ]);
+        assert_eq!(
+            scope().set_env("TZ=UTC LANG=C").hash()?,
+            scope().set_env("LANG=C TZ=UTC").hash()?,
+            "hashes are equal regardless of order of env vars"
+        );
+
+        let scope = scope().set_env("TZ=UTC LANG=C").build()?;
+        assert!(scope
+            .explanation()
+            .explain()
+            .ends_with("set env:\n  LANG: C\n  TZ: UTC\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_clear_env() -> anyhow::Result<()> {
+        assert_unique(vec![
+            scope().cmd("make").hash()?,
+            scope().cmd("make").clear_env(vec![]).hash()?,
+            scope().cmd("make").clear_env(vec!["CC".into()]).hash()?,
+        ]);
+        assert_eq!(
+            scope()
+                .clear_env(vec!["CC".into(), "CFLAGS".into()])
+                .hash()?,
+            scope()
+                .clear_env(vec!["CFLAGS".into(), "CC".into()])
+                .hash()?,
+            "hashes are equal regardless of order of kept variables"
+        );
+        assert!(scope()
+            .clear_env(vec!["CC".into()])
+            .build()?
+            .explanation()
+            .explain()
+            .ends_with("clear env: keeping CC HOME PATH TERM\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_chdir() -> anyhow::Result<()> {
+        assert_eq!(
+            scope().pwd("/a".into()).hash()?,
+            scope().pwd("/a".into()).chdir("/b".into()).hash()?,
+            "the directory is only part of the key as the pwd"
+        );
+        assert!(scope()
+            .chdir("/b".into())
+            .build()?
+            .explanation()
+            .explain()
+            .ends_with("runs in: /b\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_env_exists() -> anyhow::Result<()> {
+        assert_ne!(
+            scope()
+                .watch_env_exists(HashMap::from([("CI".to_string(), true)]))
+                .hash()?,
+            scope()
+                .watch_env_exists(HashMap::from([("CI".to_string(), false)]))
+                .hash()?,
+            "hashes are different when env var is set or unset"
+        );
+
+        assert_ne!(
+            scope()
+                .watch_env_exists(HashMap::from([("CI".to_string(), false)]))
+                .hash()?,
+            scope().watch_env("CI=").hash()?,
+            "hashes are different to watching an empty env var value"
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_scope_args() -> anyhow::Result<()> {
         assert_ne!(
@@ -508,6 +2558,101 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_scope_ignore_args() -> anyhow::Result<()> {
+        assert_eq!(
+            scope().args("--verbose a").hash()?,
+            scope()
+                .args("--verbose --log-file /tmp/xyz a")
+                .ignore_args_with_value(vec!["--log-file".into()])
+                .hash()?,
+            "hashes are equal when ignored option and value are removed"
+        );
+
+        assert_eq!(
+            scope().args("a").hash()?,
+            scope()
+                .args("--log-file=/tmp/xyz a")
+                .ignore_args_with_value(vec!["--log-file".into()])
+                .hash()?,
+            "hashes are equal when ignored option is given with ="
+        );
+
+        assert_eq!(
+            scope().args("a c").hash()?,
+            scope().args("a b c").ignore_args(vec!["b".into()]).hash()?,
+            "hashes are equal when ignored argument is removed by value"
+        );
+
+        assert_eq!(
+            scope().args("a c").hash()?,
+            scope()
+                .args("a b c")
+                .ignore_args(vec!["#2".into()])
+                .hash()?,
+            "hashes are equal when ignored argument is removed by position"
+        );
+
+        assert_ne!(
+            scope().args("a c").hash()?,
+            scope().args("a b c").ignore_args(vec!["2".into()]).hash()?,
+            "numbers without # are ignored by value, not position"
+        );
+
+        assert_eq!(
+            scope().args("a").hash()?,
+            scope().args("2 a 2").ignore_args(vec!["2".into()]).hash()?,
+            "numbers without # are ignored by value"
+        );
+
+        assert!(scope().ignore_args(vec!["#0".into()]).hash().is_err());
+        assert!(scope().ignore_args(vec!["#b".into()]).hash().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_exclude_args() -> anyhow::Result<()> {
+        assert_eq!(
+            scope().args("--color").exclude_args(true).hash()?,
+            scope().args("--verbose").exclude_args(true).hash()?,
+            "hashes are equal when args are excluded"
+        );
+
+        assert_ne!(
+            scope().exclude_args(true).hash()?,
+            scope().hash()?,
+            "hashes are different to having no args"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition_args() {
+        let args = "run --log-file /tmp/x --seed=1 -v".into_args();
+        let (hashed, ignored) = partition_args(
+            &args,
+            &["-v".to_string()],
+            &["--log-file".to_string(), "--seed".to_string()],
+        );
+        assert_eq!(hashed, vec!["run"]);
+        assert_eq!(ignored, vec!["--log-file", "/tmp/x", "--seed=1", "-v"]);
+    }
+
+    #[test]
+    fn test_resolve_command() {
+        assert!(resolve_command("sh").is_some(), "resolves command on PATH");
+        assert!(
+            resolve_command("deja-missing-command").is_none(),
+            "doesn't resolve missing command"
+        );
+        assert!(
+            resolve_command("./test/fixtures/empty-a.txt").is_none(),
+            "doesn't resolve non-executable file"
+        );
+    }
+
     #[test]
     fn test_scope_scope() -> anyhow::Result<()> {
         assert_ne!(
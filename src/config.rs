@@ -0,0 +1,509 @@
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use toml::Spanned;
+
+/// The name of a project's configuration file, found in the current directory or any of its
+/// parents.
+pub const PROJECT_CONFIG: &str = ".deja.toml";
+
+/// A value given for an option in a configuration file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigValue {
+    /// A quoted string.
+    String(String),
+    /// A whole number.
+    Integer(i64),
+    /// `true` or `false`.
+    Boolean(bool),
+    /// A list of values.
+    Array(Vec<ConfigValue>),
+}
+
+impl ConfigValue {
+    /// The value as it would be given on the command line, with an array giving each of its
+    /// values in turn.
+    pub fn strings(&self) -> Vec<String> {
+        match self {
+            ConfigValue::String(value) => vec![value.clone()],
+            ConfigValue::Integer(value) => vec![value.to_string()],
+            ConfigValue::Boolean(value) => vec![value.to_string()],
+            ConfigValue::Array(values) => values.iter().flat_map(|value| value.strings()).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigValue::String(value) => write!(f, "{:?}", value),
+            ConfigValue::Integer(value) => write!(f, "{}", value),
+            ConfigValue::Boolean(value) => write!(f, "{}", value),
+            ConfigValue::Array(values) => {
+                let values = values.iter().map(|value| value.to_string());
+                write!(f, "[{}]", values.collect::<Vec<_>>().join(", "))
+            }
+        }
+    }
+}
+
+/// An option set in a configuration file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigOption {
+    /// The name of the option, as its long flag without the leading dashes.
+    pub key: String,
+    /// The value given for the option.
+    pub value: ConfigValue,
+    /// The file the option was read from.
+    pub file: PathBuf,
+}
+
+/// A named command, with its own default options, run as `deja run @name`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preset {
+    /// The name of the preset, as used after `@`.
+    pub name: String,
+    /// The command and its arguments.
+    pub command: Vec<String>,
+    /// Options used when running the preset, before any given on the command line.
+    pub options: Vec<ConfigOption>,
+    /// The file the preset was read from.
+    pub file: PathBuf,
+}
+
+/// Default options read from configuration files. Files are written in TOML, with each key
+/// naming an option (like `cache-for = "1h"` or `exclude-pwd = true`) and arrays for options
+/// given more than once (like `watch-env = ["CI", "RAILS_ENV"]`). Presets are given as `[presets.<name>]` tables, with a `command` key and any options for it.
+#[derive(Debug, Default)]
+pub struct Config {
+    options: Vec<ConfigOption>,
+    presets: Vec<Preset>,
+}
+
+impl Config {
+    /// Reads the user's configuration file, then the nearest project configuration file in
+    /// `dir` or its parents, with options in the project's file taking precedence. Missing
+    /// files are skipped.
+    pub fn load(user_config: Option<PathBuf>, dir: &Path) -> anyhow::Result<Config> {
+        let project_config = dir
+            .ancestors()
+            .map(|dir| dir.join(PROJECT_CONFIG))
+            .find(|path| path.is_file());
+
+        let mut config = Config::default();
+        for path in user_config.into_iter().chain(project_config) {
+            if path.is_file() {
+                config.read(&path)?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// Reads options and presets from a file, replacing any already set.
+    pub fn read(&mut self, path: &Path) -> anyhow::Result<()> {
+        let (options, presets) = read_tables(path, "presets")?;
+        for option in options {
+            self.options.retain(|existing| existing.key != option.key);
+            self.options.push(option);
+        }
+        for preset in presets {
+            self.presets.retain(|existing| existing.name != preset.name);
+            self.presets.push(preset);
+        }
+        Ok(())
+    }
+
+    /// Every option set, in the order they were read.
+    pub fn options(&self) -> &[ConfigOption] {
+        &self.options
+    }
+
+    /// The option set for `key`, if any.
+    pub fn get(&self, key: &str) -> Option<&ConfigOption> {
+        self.options.iter().find(|option| option.key == key)
+    }
+
+    /// Every preset defined, in the order they were read.
+    pub fn presets(&self) -> &[Preset] {
+        &self.presets
+    }
+
+    /// The preset called `name`, if any.
+    pub fn preset(&self, name: &str) -> Option<&Preset> {
+        self.presets.iter().find(|preset| preset.name == name)
+    }
+}
+
+/// Reads a manifest of commands for `warm`, written like a configuration file but with
+/// `[commands.<name>]` tables in place of presets. Returns the options given for every command,
+/// then each command with its own options.
+pub fn read_commands(path: &Path) -> anyhow::Result<(Vec<ConfigOption>, Vec<Preset>)> {
+    read_tables(path, "commands")
+}
+
+/// Reads the options in a file, and the named commands in its `[<table>.<name>]` tables.
+fn read_tables(path: &Path, table: &str) -> anyhow::Result<(Vec<ConfigOption>, Vec<Preset>)> {
+    let parsed = read_parsed(path, table)?;
+    let option = |(key, value)| ConfigOption {
+        key,
+        value,
+        file: path.to_path_buf(),
+    };
+
+    let options = parsed.options.into_iter().map(option).collect();
+    let mut commands = vec![];
+    for (name, mut options) in parsed.tables {
+        let command = match options.iter().position(|(key, _)| key == "command") {
+            Some(index) => options.remove(index).1.strings(),
+            None => vec![],
+        };
+        if command.is_empty() {
+            return Err(anyhow!(
+                "{} '{}' in '{}' has no command",
+                singular(table),
+                name,
+                path.display()
+            ));
+        }
+        commands.push(Preset {
+            name,
+            command,
+            options: options.into_iter().map(option).collect(),
+            file: path.to_path_buf(),
+        });
+    }
+    Ok((options, commands))
+}
+
+/// Reads the keys and values in a file written like a configuration file, with `[<table>.<name>]`
+/// tables, without treating them as options.
+pub(crate) fn read_parsed(path: &Path, table: &str) -> anyhow::Result<Parsed> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("unable to read '{}': {}", path.display(), e))?;
+    parse(&contents, table).map_err(|e| anyhow!("unable to parse '{}': {}", path.display(), e))
+}
+
+/// Where the user's configuration file is kept, in `$XDG_CONFIG_HOME/deja` or `~/.config/deja`.
+pub fn user_config_path() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".config")))?;
+    Some(dir.join("deja").join("config.toml"))
+}
+
+/// The keys and values in a file, in order, with those in `[<table>.<name>]` tables kept
+/// separately for each name.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Parsed {
+    pub(crate) options: Vec<(String, ConfigValue)>,
+    pub(crate) tables: Vec<(String, Vec<(String, ConfigValue)>)>,
+}
+
+/// Parses the keys and values in a TOML file, where `table` is the only table allowed, like
+/// `presets`. Underscores in keys are treated as dashes, so `cache_for` and `cache-for` are
+/// the same option.
+fn parse(contents: &str, table: &str) -> anyhow::Result<Parsed> {
+    // Keys are read with where they were found, so errors can give the line
+    let document: BTreeMap<Spanned<String>, toml::Value> =
+        toml::from_str(contents).map_err(|e| syntax_error(contents, &e))?;
+    let mut document: Vec<_> = document.into_iter().collect();
+    document.sort_by_key(|(key, _)| key.span().start);
+
+    let mut parsed = Parsed::default();
+    for (key, value) in document {
+        let (line, _) = position(contents, key.span().start);
+        let only_tables = || {
+            anyhow!(
+                "line {}: only [{}.<name>] tables are supported",
+                line,
+                table
+            )
+        };
+        let key = key.into_inner();
+        match value {
+            toml::Value::Table(tables) if key == table => {
+                for (name, options) in tables {
+                    let toml::Value::Table(options) = options else {
+                        return Err(only_tables());
+                    };
+                    if options.values().any(toml::Value::is_table) {
+                        return Err(only_tables());
+                    }
+                    let options = values(options)
+                        .map_err(|e| anyhow!("{} '{}': {}", singular(table), name, e))?;
+                    parsed.tables.push((name, options));
+                }
+            }
+            toml::Value::Table(_) => return Err(only_tables()),
+            _ if key == table => return Err(only_tables()),
+            value => push_value(&mut parsed.options, key, value)
+                .map_err(|e| anyhow!("line {}: {}", line, e))?,
+        }
+    }
+    Ok(parsed)
+}
+
+/// The keys and values in a table.
+fn values(table: toml::Table) -> anyhow::Result<Vec<(String, ConfigValue)>> {
+    let mut values = vec![];
+    for (key, value) in table {
+        push_value(&mut values, key, value)?;
+    }
+    Ok(values)
+}
+
+fn push_value(
+    values: &mut Vec<(String, ConfigValue)>,
+    key: String,
+    value: toml::Value,
+) -> anyhow::Result<()> {
+    let key = key.replace('_', "-");
+    if values.iter().any(|(existing, _)| *existing == key) {
+        return Err(anyhow!("'{}' is set more than once", key));
+    }
+    let value = config_value(value).map_err(|e| anyhow!("{}, for '{}'", e, key))?;
+    values.push((key, value));
+    Ok(())
+}
+
+fn config_value(value: toml::Value) -> anyhow::Result<ConfigValue> {
+    match value {
+        toml::Value::String(value) => Ok(ConfigValue::String(value)),
+        toml::Value::Integer(value) => Ok(ConfigValue::Integer(value)),
+        toml::Value::Boolean(value) => Ok(ConfigValue::Boolean(value)),
+        toml::Value::Array(values) => Ok(ConfigValue::Array(
+            values
+                .into_iter()
+                .map(config_value)
+                .collect::<anyhow::Result<_>>()?,
+        )),
+        _ => Err(anyhow!("expected a string, integer, boolean or array")),
+    }
+}
+
+/// Describes invalid TOML on a single line, starting with where it was found.
+fn syntax_error(contents: &str, e: &toml::de::Error) -> anyhow::Error {
+    let message = match e.message().lines().collect::<Vec<_>>().join(", ") {
+        message if message.is_empty() => "invalid TOML".to_string(),
+        message => message,
+    };
+    match e.span() {
+        Some(span) => {
+            let (line, column) = position(contents, span.start);
+            anyhow!("line {}, column {}: {}", line, column, message)
+        }
+        None => anyhow!("{}", message),
+    }
+}
+
+/// The line and column of a byte offset, counting from 1.
+fn position(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+/// What a table like `presets` holds one of, for errors.
+fn singular(table: &str) -> &str {
+    table.strip_suffix('s').unwrap_or(table)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ulid::Ulid;
+
+    fn string(value: &str) -> ConfigValue {
+        ConfigValue::String(value.into())
+    }
+
+    #[test]
+    fn test_parse() -> anyhow::Result<()> {
+        let options = parse(
+            r#"
+# Defaults for this project
+cache-for = "1h"   # an hour
+exclude_pwd = true
+lock-timeout = '30s'
+timeout-exit-code = 1_00
+watch-env = [
+  "CI",
+  "RAILS_ENV", # trailing comma
+]
+"watch-scope" = "tab\tand \"quotes\" é"
+"#,
+            "presets",
+        )?;
+        assert_eq!(
+            options.options,
+            vec![
+                ("cache-for".into(), string("1h")),
+                ("exclude-pwd".into(), ConfigValue::Boolean(true)),
+                ("lock-timeout".into(), string("30s")),
+                ("timeout-exit-code".into(), ConfigValue::Integer(100)),
+                (
+                    "watch-env".into(),
+                    ConfigValue::Array(vec![string("CI"), string("RAILS_ENV")])
+                ),
+                ("watch-scope".into(), string("tab\tand \"quotes\" é")),
+            ]
+        );
+        assert_eq!(parse("", "presets")?, Parsed::default());
+
+        let parsed = parse(
+            r#"
+cache-for = "1h"
+
+[presets.metadata]
+command = ["cargo", "metadata"]
+watch_path = "Cargo.lock"
+
+[ presets."with space" ]
+command = "ls"
+"#,
+            "presets",
+        )?;
+        assert_eq!(parsed.options, vec![("cache-for".into(), string("1h"))]);
+        assert_eq!(
+            parsed.tables,
+            vec![
+                (
+                    "metadata".into(),
+                    vec![
+                        (
+                            "command".into(),
+                            ConfigValue::Array(vec![string("cargo"), string("metadata")])
+                        ),
+                        ("watch-path".into(), string("Cargo.lock")),
+                    ]
+                ),
+                ("with space".into(), vec![("command".into(), string("ls"))]),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |contents: &str| parse(contents, "presets").unwrap_err().to_string();
+        assert_eq!(
+            error("cache-for = \"1h"),
+            "line 1, column 16: invalid basic string"
+        );
+        assert_eq!(
+            error("\ncache-for 1h"),
+            "line 2, column 11: expected `.`, `=`"
+        );
+        assert_eq!(
+            error("cache-for = 1h"),
+            "line 1, column 14: expected newline, `#`"
+        );
+        assert_eq!(
+            error("exclude-pwd = yes"),
+            "line 1, column 15: invalid string, expected `\"`, `'`"
+        );
+        assert_eq!(
+            error("exclude-pwd = 1.5"),
+            "line 1: expected a string, integer, boolean or array, for 'exclude-pwd'"
+        );
+        assert_eq!(
+            error("a = 1\nb = 2\na = 3"),
+            "line 3, column 1: duplicate key `a` in document root"
+        );
+        assert_eq!(
+            error("cache_for = \"1h\"\ncache-for = \"2h\""),
+            "line 2: 'cache-for' is set more than once"
+        );
+        assert_eq!(
+            error("[run]"),
+            "line 1: only [presets.<name>] tables are supported"
+        );
+        assert_eq!(
+            error("[presets.a.b]"),
+            "line 1: only [presets.<name>] tables are supported"
+        );
+        assert_eq!(
+            error("\npresets = \"a\""),
+            "line 2: only [presets.<name>] tables are supported"
+        );
+        assert_eq!(
+            error("[presets.a]\ncache-for = 1.5"),
+            "preset 'a': expected a string, integer, boolean or array, for 'cache-for'"
+        );
+        assert_eq!(
+            error("[presets.a]\n[presets.a]"),
+            "line 2, column 1: invalid table header, duplicate key `a` in table `presets`"
+        );
+        assert_eq!(
+            error("watch-env = [\"A\" \"B\"]"),
+            "line 1, column 18: invalid array, expected `]`"
+        );
+    }
+
+    #[test]
+    fn test_load() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-config-{}", Ulid::new()));
+        let project = root.join("project");
+        let nested = project.join("nested");
+        std::fs::create_dir_all(&nested)?;
+
+        let user = root.join("config.toml");
+        std::fs::write(&user, "cache-for = \"1d\"\nexclude-pwd = true\n")?;
+        std::fs::write(project.join(PROJECT_CONFIG), "cache-for = \"1h\"\n")?;
+
+        let config = Config::load(Some(user.clone()), &nested)?;
+        let cache_for = config.get("cache-for").unwrap();
+        assert_eq!(cache_for.value, string("1h"), "project config wins");
+        assert_eq!(cache_for.file, project.join(PROJECT_CONFIG));
+        assert_eq!(config.get("exclude-pwd").unwrap().file, user);
+
+        std::fs::write(
+            &user,
+            "[presets.list]\ncommand = [\"ls\", \"-l\"]\ncache-for = \"1m\"\n[presets.date]\ncommand = \"date\"\n",
+        )?;
+        std::fs::write(
+            project.join(PROJECT_CONFIG),
+            "[presets.list]\ncommand = \"ls\"\n",
+        )?;
+        let config = Config::load(Some(user.clone()), &nested)?;
+        let list = config.preset("list").unwrap();
+        assert_eq!(list.command, vec!["ls"], "project presets win");
+        assert!(list.options.is_empty(), "options aren't merged");
+        let date = config.preset("date").unwrap();
+        assert_eq!(
+            (date.command.clone(), date.file.clone()),
+            (vec!["date".into()], user.clone())
+        );
+
+        std::fs::write(
+            project.join(PROJECT_CONFIG),
+            "[presets.empty]\ncache-for = \"1m\"\n",
+        )?;
+        let error = Config::load(None, &nested).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!(
+                "preset 'empty' in '{}' has no command",
+                project.join(PROJECT_CONFIG).display()
+            )
+        );
+
+        let config = Config::load(Some(root.join("missing.toml")), &root)?;
+        assert!(config.options().is_empty(), "missing files are skipped");
+
+        std::fs::write(project.join(PROJECT_CONFIG), "cache-for = ")?;
+        let error = Config::load(None, &nested).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!(
+                "unable to parse '{}': line 1, column 13: invalid TOML",
+                project.join(PROJECT_CONFIG).display()
+            )
+        );
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
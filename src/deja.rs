@@ -1,129 +1,2747 @@
+use crate::cache::ago;
+use crate::cache::output_hashes;
+use crate::cache::until;
 use crate::cache::Cache;
 use crate::cache::CacheEntry;
 use crate::cache::FindOptions;
+use crate::cache::FindOutcome;
+use crate::cache::LockOptions;
+use crate::cache::OutputReader;
 use crate::cache::RecordOptions;
-use crate::command::Command;
+use crate::cache::TagFilter;
+use crate::cache::{sqlite::SqliteCache, DiskCache};
+use crate::cache::{DivergedEntry, SharedBuffer, SharedWriter};
+use crate::command::{signal_name, Command, HashesSummary, ScopeSummary};
+use crate::config::Config;
+use crate::debug;
+use crate::disabled;
+use crate::output::Output;
+use crate::retention::{describe_keep, RetentionPolicy};
+use crate::stats::{Counters, Outcome};
+use crate::timestamp::describe_duration;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::io::{BufRead, Write};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use ulid::Ulid;
 
 fn record<E>(
     cmd: &mut Command,
     cache: &impl Cache<E>,
-    options: RecordOptions,
+    options: &RecordOptions,
 ) -> anyhow::Result<i32>
 where
     E: CacheEntry,
 {
-    let result = cache.record(cmd, &options)?;
+    let result = cache.record(cmd, options)?;
     Ok(result)
 }
 
+/// Runs the command and records the result, noting whether it was recorded.
+fn record_with_status<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    options: &RecordOptions,
+) -> anyhow::Result<(i32, Status)>
+where
+    E: CacheEntry,
+{
+    let start = Instant::now();
+    let status = record(cmd, cache, options)?;
+    let duration = start.elapsed();
+    debug(format!(
+        "ran {} in {:.3}s",
+        cmd.hash(),
+        duration.as_secs_f64()
+    ));
+
+    let recorded = cache
+        .read_usable(cmd.hash())?
+        .is_some_and(|entry| entry.command().ulid == cmd.ulid);
+    Ok(match recorded {
+        true => (status, Status::Recorded(duration)),
+        false => (status, Status::NotRecorded(duration)),
+    })
+}
+
+/// Replays a cached result, logging how long it took.
+fn replay<E>(result: &E, output: &mut Output) -> anyhow::Result<Option<i32>>
+where
+    E: CacheEntry,
+{
+    let start = Instant::now();
+    let status = result.replay(output)?;
+    if status.is_some() {
+        debug(format!(
+            "replayed result in {:.3}s",
+            start.elapsed().as_secs_f64()
+        ));
+    }
+    Ok(status)
+}
+
+/// Runs the command without looking up or recording a result, as if deja weren't there.
+fn bypass(cmd: &mut Command) -> anyhow::Result<i32> {
+    debug(format!(
+        "caching disabled, running {} without cache",
+        cmd.hash()
+    ));
+    let result = cmd.run(std::io::sink(), std::io::sink(), Default::default())?;
+    Ok(result.status)
+}
+
+/// Where a command's result came from, reported with `--print-status`.
+enum Status {
+    /// A fresh result was replayed, with when it was created and how long its command took.
+    Hit(SystemTime, Option<Duration>),
+    /// A stale result was replayed, while a new one is recorded in the background.
+    Stale(SystemTime, Option<Duration>),
+    /// An expired result was replayed.
+    Expired(SystemTime, Option<Duration>),
+    /// The command was run, and its result recorded.
+    Recorded(Duration),
+    /// The command was run, but its result wasn't recorded.
+    NotRecorded(Duration),
+    /// No result was found, and the command wasn't run.
+    Miss,
+    /// Caching is disabled.
+    Disabled,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Status::Hit(created, _) => write!(f, "hit (age {})", ago(*created)),
+            Status::Stale(created, _) => {
+                write!(f, "stale (age {}, revalidating)", ago(*created))
+            }
+            Status::Expired(created, _) => write!(f, "expired (age {})", ago(*created)),
+            Status::Recorded(duration) => {
+                write!(f, "miss (recorded, {:.1}s)", duration.as_secs_f64())
+            }
+            Status::NotRecorded(duration) => {
+                write!(f, "miss (not recorded, {:.1}s)", duration.as_secs_f64())
+            }
+            Status::Miss => write!(f, "miss"),
+            Status::Disabled => write!(f, "disabled"),
+        }
+    }
+}
+
+/// Writes where the result came from to stderr, once everything else has been written.
+fn print_status(output: &mut Output, status: &Status) -> std::io::Result<()> {
+    writeln!(output.stderr, "deja: {}", status)
+}
+
+/// Counts a lookup by `run` or `read` as a hit or miss, never failing the command when the
+/// counters can't be written.
+fn count(counters: Option<&Counters>, cmd: &Command, result: &Status) {
+    let Some(counters) = counters else {
+        return;
+    };
+    let outcome = match result {
+        Status::Hit(_, duration) | Status::Stale(_, duration) | Status::Expired(_, duration) => {
+            Outcome::Hit(duration.unwrap_or_default())
+        }
+        Status::Recorded(_) | Status::NotRecorded(_) | Status::Miss => Outcome::Miss,
+        Status::Disabled => return,
+    };
+    if let Err(e) = counters.record(cmd.hash(), outcome) {
+        debug(format!("unable to update counters: {}", e));
+    }
+}
+
+/// Set in the environment of hooks, so deja run from within a hook doesn't run hooks again.
+const HOOK_ENV: &str = "DEJA_HOOK";
+
+/// Command lines run once `run` or `read` knows whether a cached result was found.
+#[derive(Default)]
+pub struct Hooks {
+    /// Run after a cached result is replayed.
+    pub on_hit: Option<String>,
+    /// Run when no usable result is cached, after the command (if any) has run.
+    pub on_miss: Option<String>,
+}
+
+impl Hooks {
+    /// Runs the hook for where the result came from with `sh -c`, describing the result in
+    /// `DEJA_HASH`, `DEJA_COMMAND`, `DEJA_STATUS` and (for a hit) `DEJA_ENTRY_AGE`. The hook's
+    /// output goes to stderr, so it never mixes with the command's output, and a failing hook
+    /// only prints a warning.
+    fn run(
+        &self,
+        output: &mut Output,
+        cmd: &Command,
+        status: i32,
+        result: &Status,
+    ) -> anyhow::Result<()> {
+        let (name, hook, created) = match result {
+            Status::Hit(created, _) | Status::Stale(created, _) | Status::Expired(created, _) => {
+                ("--on-hit", &self.on_hit, Some(*created))
+            }
+            Status::Recorded(_) | Status::NotRecorded(_) | Status::Miss => {
+                ("--on-miss", &self.on_miss, None)
+            }
+            Status::Disabled => return Ok(()),
+        };
+        let Some(hook) = hook else {
+            return Ok(());
+        };
+        if std::env::var_os(HOOK_ENV).is_some() {
+            debug(format!("not running {} hook from within a hook", name));
+            return Ok(());
+        }
+
+        let mut command = std::process::Command::new("sh");
+        command
+            .args(["-c", hook])
+            .env(HOOK_ENV, "1")
+            .env("DEJA_HASH", cmd.hash())
+            .env("DEJA_COMMAND", cmd.to_string())
+            .env("DEJA_STATUS", status.to_string())
+            .env_remove("DEJA_ENTRY_AGE")
+            .stdin(Stdio::null());
+        if let Some(created) = created {
+            let age = SystemTime::now()
+                .duration_since(created)
+                .unwrap_or_default()
+                .as_secs();
+            command.env("DEJA_ENTRY_AGE", age.to_string());
+        }
+
+        debug(format!("running {} hook: {}", name, hook));
+        match command.output() {
+            Ok(result) => {
+                output.stderr.write_all(&result.stdout)?;
+                output.stderr.write_all(&result.stderr)?;
+                if !result.status.success() {
+                    writeln!(
+                        output.stderr,
+                        "deja: warning: {} hook failed with {}",
+                        name,
+                        describe_exit(result.status)
+                    )?;
+                }
+            }
+            Err(e) => writeln!(
+                output.stderr,
+                "deja: warning: unable to run {} hook: {}",
+                name, e
+            )?,
+        }
+        Ok(())
+    }
+}
+
+/// Describes how a process exited, like `exit code 1` or `signal SIGKILL`.
+fn describe_exit(status: std::process::ExitStatus) -> String {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => format!("exit code {}", code),
+        (None, Some(signal)) => format!("signal {}", signal_name(signal)),
+        (None, None) => "an unknown status".to_string(),
+    }
+}
+
+/// Replays a fresh cached result for the command, or runs it and records the result. When
+/// another process is already running the command, waits for its result (per `lock_options`).
+/// Stale results allowed by `stale_while_revalidate` are refreshed by running the current
+/// executable again in the background, so this is only supported by the deja binary. Whether
+/// the result was a hit or miss is added to any `counters`.
+#[allow(clippy::too_many_arguments)]
 pub fn run<E>(
     cmd: &mut Command,
     cache: &impl Cache<E>,
+    output: &mut Output,
     record_options: RecordOptions,
     read_options: FindOptions,
+    lock_options: LockOptions,
+    print: bool,
+    hooks: &Hooks,
+    verify: Option<&Verify>,
+    counters: Option<&Counters>,
 ) -> anyhow::Result<i32>
 where
     E: CacheEntry,
 {
-    if let Some(result) = cache.find(cmd.hash(), &read_options)? {
-        Ok(result.replay())
-    } else {
-        record(cmd, cache, record_options)
+    let (status, result) = run_with_status(
+        cmd,
+        cache,
+        output,
+        &record_options,
+        read_options,
+        lock_options,
+    )?;
+    match verify {
+        Some(verify) if matches!(result, Status::Hit(..)) && verify.is_due() => {
+            if verify.background {
+                if let Err(e) = run_in_background("--verify-now") {
+                    debug(format!("unable to start verification: {}", e));
+                }
+            } else {
+                self::verify(cmd, cache, output, record_options, verify.update)?;
+            }
+        }
+        _ => (),
+    }
+    count(counters, cmd, &result);
+    hooks.run(output, cmd, status, &result)?;
+    if print {
+        print_status(output, &result)?;
     }
+    Ok(status)
 }
 
-pub fn read<E>(
+fn run_with_status<E>(
     cmd: &mut Command,
     cache: &impl Cache<E>,
+    output: &mut Output,
+    record_options: &RecordOptions,
     read_options: FindOptions,
-    cache_miss_exit_code: i32,
-) -> anyhow::Result<i32>
+    lock_options: LockOptions,
+) -> anyhow::Result<(i32, Status)>
 where
     E: CacheEntry,
 {
+    if disabled() {
+        return Ok((bypass(cmd)?, Status::Disabled));
+    }
+
     if let Some(result) = cache.find(cmd.hash(), &read_options)? {
-        Ok(result.replay())
+        if let Some(status) = replay(&result, output)? {
+            return Ok((status, Status::Hit(result.created_at(), result.duration())));
+        }
+    }
+
+    if let Some(result) = cache.find_stale(cmd.hash(), &read_options)? {
+        if let Some(status) = replay(&result, output)? {
+            if let Err(e) = run_in_background("--revalidate") {
+                debug(format!("unable to start revalidation: {}", e));
+            }
+            return Ok((
+                status,
+                Status::Stale(result.created_at(), result.duration()),
+            ));
+        }
+    }
+
+    // Only one process runs the command at once. Others wait, then replay its result
+    let lock = if lock_options.no_wait {
+        cache.try_lock(cmd.hash())?
     } else {
-        Ok(cache_miss_exit_code)
+        cache.lock(cmd.hash(), lock_options.timeout)?
+    };
+
+    if lock.is_none() {
+        debug(format!("{} is locked, running anyway", cmd.hash()));
+    } else if let Some(result) = cache.find(cmd.hash(), &read_options)? {
+        if let Some(status) = replay(&result, output)? {
+            return Ok((status, Status::Hit(result.created_at(), result.duration())));
+        }
     }
+
+    record_with_status(cmd, cache, record_options)
 }
 
-pub fn force<E>(
+/// Runs the command and records the result, without looking for a cached result first.
+pub fn refresh<E>(
     cmd: &mut Command,
     cache: &impl Cache<E>,
+    output: &mut Output,
     record_options: RecordOptions,
+    print: bool,
+    hooks: &Hooks,
 ) -> anyhow::Result<i32>
 where
     E: CacheEntry,
 {
-    record(cmd, cache, record_options)?;
-    Ok(0)
+    let (status, result) = if disabled() {
+        (bypass(cmd)?, Status::Disabled)
+    } else {
+        record_with_status(cmd, cache, &record_options)?
+    };
+    hooks.run(output, cmd, status, &result)?;
+    if print {
+        print_status(output, &result)?;
+    }
+    Ok(status)
 }
 
-pub fn explain<E>(
+/// Records a new result for a stale entry, unless another process is already doing so or a
+/// fresh result has been recorded since.
+pub fn revalidate<E>(
     cmd: &mut Command,
     cache: &impl Cache<E>,
+    record_options: RecordOptions,
     read_options: FindOptions,
 ) -> anyhow::Result<i32>
 where
     E: CacheEntry,
 {
-    println!("{}", cmd.scope.explanation().explain());
+    if let Some(_lock) = cache.try_lock(cmd.hash())? {
+        if cache.find(cmd.hash(), &read_options)?.is_none() {
+            record(cmd, cache, &record_options)?;
+        }
+    } else {
+        debug(format!("revalidation already running for {}", cmd.hash()));
+    }
+    Ok(0)
+}
 
-    let hash = cmd.hash();
+/// Runs deja again with the same arguments in a detached process, adding the given global flag
+/// (like `--revalidate`) before them. The process has no access to the terminal.
+fn run_in_background(flag: &str) -> anyhow::Result<()> {
+    let args = std::iter::once(OsString::from(flag)).chain(std::env::args_os().skip(1));
 
-    let description = if let Some(result) = cache.read(hash)? {
-        if !result.is_fresh() {
-            let expires_at_ago = result.expires_at().unwrap().elapsed()?.as_secs();
-            format!("Expired: entry in cache expired {expires_at_ago} seconds ago")
-        } else if !read_options
-            .max_age
-            .is_none_or(|duration| result.is_younger_than(duration))
-        {
-            let max_age = read_options.max_age.unwrap().as_secs();
-            format!("Stale: entry in cache created longer than {max_age} seconds ago")
-        } else {
-            format!("Fresh: entry for {hash} available in cache")
+    let mut command = std::process::Command::new(std::env::current_exe()?);
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+
+    command.spawn()?;
+    Ok(())
+}
+
+/// How `run` checks replayed results against a fresh run of the command, to find results that
+/// should have been invalidated but weren't, such as when a watched path is missing.
+pub struct Verify {
+    /// The chance of checking each time a fresh result is replayed, from 0 to 1.
+    pub probability: f64,
+    /// Record the fresh result in place of the cached one.
+    pub update: bool,
+    /// Check in a detached process, rather than before exiting.
+    pub background: bool,
+}
+
+impl Verify {
+    /// Whether to check the result being replayed now.
+    fn is_due(&self) -> bool {
+        let roll = Ulid::new().random() as f64 / (1u128 << 80) as f64;
+        roll < self.probability
+    }
+}
+
+/// Compares output as it's written with the captured output of a cached result, reading the
+/// cached output only as far as it's been compared, so neither is held in memory.
+struct OutputComparison {
+    cached: OutputReader<Box<dyn std::io::Read + Send>>,
+    /// The current line of cached output, compared up to `position`.
+    line: Vec<u8>,
+    position: usize,
+    same: bool,
+}
+
+impl OutputComparison {
+    fn new(entry: &impl CacheEntry) -> anyhow::Result<Self> {
+        Ok(OutputComparison {
+            cached: OutputReader {
+                reader: std::io::BufReader::new(entry.stdout_reader()?),
+            },
+            line: vec![],
+            position: 0,
+            same: true,
+        })
+    }
+
+    /// Whether everything written matched the cached output, with none of it left over.
+    fn finish(&mut self) -> bool {
+        self.same
+            && self.position == self.line.len()
+            && self.cached.all(|(_, line)| line.is_empty())
+    }
+}
+
+impl Write for OutputComparison {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut rest = buf;
+        while self.same && !rest.is_empty() {
+            if self.position == self.line.len() {
+                match self.cached.next() {
+                    Some((_, line)) => (self.line, self.position) = (line, 0),
+                    None => self.same = false,
+                }
+                continue;
+            }
+            let length = rest.len().min(self.line.len() - self.position);
+            self.same = rest[..length] == self.line[self.position..self.position + length];
+            self.position += length;
+            rest = &rest[length..];
         }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the command again and compares its stdout and exit status with the cached result,
+/// without passing its output through. When they differ, a warning is printed and the cached
+/// result is marked as diverged, unless `update` records the fresh result in its place.
+/// Returns 1 when they differ.
+pub fn verify<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    mut record_options: RecordOptions,
+    update: bool,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let Some(cached) = cache.read_usable(cmd.hash())? else {
+        debug(format!("no result for {} to verify", cmd.hash()));
+        return Ok(0);
+    };
+
+    let comparison = Arc::new(Mutex::new(OutputComparison::new(&cached)?));
+    record_options.set_passthrough(Some((
+        SharedWriter::from(comparison.clone()),
+        SharedWriter::new(std::io::sink()),
+    )));
+    let (status, recorded) = if update {
+        let status = record(cmd, cache, &record_options)?;
+        let recorded = cache
+            .read(cmd.hash())?
+            .is_some_and(|entry| entry.command().ulid == cmd.ulid);
+        (status, recorded)
     } else {
-        format!("Missing: no entry found in cache for {hash}")
+        let result = cmd.run(
+            std::io::sink(),
+            std::io::sink(),
+            record_options.run_options(),
+        )?;
+        if result.timed_out || result.interrupted.is_some() {
+            debug(format!(
+                "unable to verify {}, command didn't finish",
+                cmd.hash()
+            ));
+            return Ok(0);
+        }
+        (result.status, false)
     };
 
-    println!("{}", description);
+    let mut differences = vec![];
+    if !comparison.lock().unwrap().finish() {
+        differences.push("stdout differs".to_string());
+    }
+    if status != cached.command_status() {
+        differences.push(format!(
+            "exit status was {}, now {}",
+            cached.command_status(),
+            status
+        ));
+    }
+    if differences.is_empty() {
+        debug(format!("verified {} against a fresh run", cmd.hash()));
+        return Ok(0);
+    }
 
-    Ok(0)
+    writeln!(
+        output.stderr,
+        "deja: warning: cached result differs from a fresh run ({}){}",
+        differences.join(" and "),
+        if recorded {
+            ", replaced it with the fresh result"
+        } else {
+            ""
+        }
+    )?;
+    if !recorded {
+        let diverged = DivergedEntry {
+            entry: &cached,
+            at: SystemTime::now(),
+        };
+        if let Err(e) = cache.store(cmd.hash(), &diverged) {
+            debug(format!("unable to mark {} as diverged: {}", cmd.hash(), e));
+        }
+    }
+    Ok(1)
 }
 
-pub fn test<E>(
+/// What `read` does when no cached result is found.
+pub enum OnMiss {
+    /// Exit with the given status.
+    Exit(i32),
+    /// Run the given command (without caching), and exit with its status.
+    Exec(Vec<String>),
+}
+
+/// Finds a fresh result, polling until one appears or the wait has passed. Freshness is
+/// checked on every poll, so a result that expires while waiting isn't returned.
+fn wait_for<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    read_options: &FindOptions,
+    wait: Option<Duration>,
+) -> anyhow::Result<Option<E>>
+where
+    E: CacheEntry,
+{
+    let deadline = wait.map(|wait| Instant::now() + wait);
+    let mut delay = Duration::from_millis(50);
+
+    loop {
+        if let Some(result) = cache.find(cmd.hash(), read_options)? {
+            return Ok(Some(result));
+        }
+
+        let remaining = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => Duration::ZERO,
+        };
+
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+
+        debug(format!("waiting for {}", cmd.hash()));
+        std::thread::sleep(delay.min(remaining));
+        delay = (delay * 2).min(Duration::from_secs(1));
+    }
+}
+
+/// Replays a cached result for the command without ever running it, waiting up to `wait` for
+/// one to be recorded. When there's no result, does what `on_miss` says. Whether the result
+/// was a hit or miss is added to any `counters`.
+#[allow(clippy::too_many_arguments)]
+pub fn read<E>(
     cmd: &mut Command,
     cache: &impl Cache<E>,
+    output: &mut Output,
     read_options: FindOptions,
+    wait: Option<Duration>,
+    on_miss: OnMiss,
+    quiet: bool,
+    print: bool,
+    hooks: &Hooks,
+    counters: Option<&Counters>,
 ) -> anyhow::Result<i32>
 where
     E: CacheEntry,
 {
-    if let Some(_result) = cache.find(cmd.hash(), &read_options)? {
-        Ok(0)
-    } else {
-        Ok(1)
+    let (status, result) =
+        read_with_status(cmd, cache, output, read_options, wait, on_miss, quiet)?;
+    count(counters, cmd, &result);
+    hooks.run(output, cmd, status, &result)?;
+    if print {
+        print_status(output, &result)?;
     }
+    Ok(status)
 }
 
-pub fn remove<E>(cmd: &mut Command, cache: &impl Cache<E>) -> anyhow::Result<i32>
+fn read_with_status<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    read_options: FindOptions,
+    wait: Option<Duration>,
+    on_miss: OnMiss,
+    quiet: bool,
+) -> anyhow::Result<(i32, Status)>
 where
     E: CacheEntry,
 {
-    if cache.remove(cmd.hash())? {
+    let miss = if disabled() {
+        debug(format!(
+            "caching disabled, treating {} as missing",
+            cmd.hash()
+        ));
+        Status::Disabled
+    } else {
+        if let Some(result) = wait_for(cmd, cache, &read_options, wait)? {
+            if let Some(status) = replay(&result, output)? {
+                return Ok((status, Status::Hit(result.created_at(), result.duration())));
+            }
+        }
+        if let Some(result) = cache.find_expired(cmd.hash(), &read_options)? {
+            if !quiet {
+                writeln!(
+                    output.stderr,
+                    "deja: replaying expired result cached {} ago",
+                    ago(result.created_at())
+                )?;
+            }
+            if let Some(status) = replay(&result, output)? {
+                return Ok((
+                    status,
+                    Status::Expired(result.created_at(), result.duration()),
+                ));
+            }
+        }
+        Status::Miss
+    };
+
+    let status = match on_miss {
+        OnMiss::Exit(status) => status,
+        OnMiss::Exec(words) => {
+            let status = std::process::Command::new(&words[0])
+                .args(&words[1..])
+                .status()
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::NotFound => {
+                        anyhow::anyhow!("command not found: {}", words[0])
+                    }
+                    _ => anyhow::anyhow!("error running command: {}", words[0]),
+                })?;
+            status.code().unwrap_or(1)
+        }
+    };
+    Ok((status, miss))
+}
+
+/// Runs the command and records the result, returning 0 rather than its exit code when
+/// `exit_zero` is set.
+pub fn force<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    record_options: RecordOptions,
+    exit_zero: bool,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let status = if disabled() {
+        bypass(cmd)?
+    } else {
+        record(cmd, cache, &record_options)?
+    };
+    if exit_zero {
         Ok(0)
     } else {
-        Ok(1)
+        Ok(status)
+    }
+}
+
+/// The state of a command's cached result, as written by `test` and `explain` with `--json`.
+#[derive(Serialize)]
+struct ResultState {
+    status: &'static str,
+    created: Option<String>,
+    expires: Option<String>,
+    age: Option<u64>,
+    expires_in: Option<i64>,
+}
+
+impl ResultState {
+    fn new<T: CacheEntry>(outcome: &FindOutcome<T>) -> Self {
+        let status = match outcome {
+            FindOutcome::Fresh(_) => "hit",
+            FindOutcome::Missing => "miss",
+            FindOutcome::Expired(_) => "expired",
+            FindOutcome::Stale(_) => "stale",
+        };
+        let entry = outcome.entry();
+        ResultState {
+            status,
+            created: entry.map(|entry| format_time(entry.created_at())),
+            expires: entry.and_then(|entry| entry.expires_at()).map(format_time),
+            age: entry.map(|entry| age_secs(entry.created_at())),
+            expires_in: entry
+                .and_then(|entry| entry.expires_at())
+                .map(expires_in_secs),
+        }
+    }
+}
+
+/// The output of `explain --json`.
+#[derive(Serialize)]
+struct Explanation {
+    scope: ScopeSummary,
+    hashes: HashesSummary,
+    hash: String,
+    #[serde(flatten)]
+    state: ResultState,
+    duration: Option<f64>,
+    env: BTreeMap<String, String>,
+    id: Option<String>,
+}
+
+/// A result in the output of `list --json`.
+#[derive(Serialize)]
+struct ListedResult {
+    hash: String,
+    command: String,
+    created: String,
+    expires: Option<String>,
+    age: u64,
+    expires_in: Option<i64>,
+    duration: Option<f64>,
+    status: i32,
+    signal: Option<i32>,
+    user_time: Option<f64>,
+    system_time: Option<f64>,
+    max_rss: Option<u64>,
+    diverged: Option<String>,
+    tags: Vec<String>,
+    id: Option<String>,
+    pinned: bool,
+}
+
+fn format_time(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// How many whole seconds ago a time was.
+fn age_secs(time: SystemTime) -> u64 {
+    time.elapsed().unwrap_or_default().as_secs()
+}
+
+/// How many whole seconds until a time, negative once it's passed.
+fn expires_in_secs(time: SystemTime) -> i64 {
+    match time.duration_since(SystemTime::now()) {
+        Ok(remaining) => remaining.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+/// Describes when a time is relative to now, like `in 45m` or `3m ago`.
+fn describe_relative(time: SystemTime) -> String {
+    if time > SystemTime::now() {
+        format!("in {}", until(time))
+    } else {
+        format!("{} ago", ago(time))
+    }
+}
+
+/// Writes a value as a single line of JSON.
+fn write_json(output: &mut Output, value: &impl Serialize) -> anyhow::Result<()> {
+    serde_json::to_writer(&mut output.stdout, value)?;
+    writeln!(output.stdout)?;
+    Ok(())
+}
+
+/// Describes how the command's cache key is made up, and whether a usable result is cached.
+pub fn explain<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    read_options: FindOptions,
+    json: bool,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let hash = cmd.hash();
+    let outcome = cache.lookup(hash, &read_options)?;
+
+    if json {
+        let entry = outcome.entry();
+        write_json(
+            output,
+            &Explanation {
+                scope: cmd.scope.explanation().summary(),
+                hashes: cmd.scope.hashes().summary(),
+                hash: hash.to_string(),
+                state: ResultState::new(&outcome),
+                duration: entry
+                    .and_then(|entry| entry.duration())
+                    .map(|duration| duration.as_secs_f64()),
+                env: entry.map(|entry| entry.env().clone()).unwrap_or_default(),
+                id: entry.and_then(|entry| entry.id()).map(String::from),
+            },
+        )?;
+        return Ok(0);
+    }
+
+    writeln!(output.stdout, "{}", cmd.scope.explanation().explain())?;
+
+    let description = match &outcome {
+        FindOutcome::Expired(result) => {
+            let expired = ago(result.expires_at().unwrap_or_else(|| result.created_at()));
+            format!("Expired: entry in cache expired {expired} ago")
+        }
+        FindOutcome::Stale(_) => {
+            let max_age = describe_duration(read_options.max_age.unwrap_or_default());
+            format!("Stale: entry in cache created longer than {max_age} ago")
+        }
+        FindOutcome::Fresh(result) => format!(
+            "Fresh: entry for {hash} available in cache ({})",
+            result.describe_status()
+        ),
+        FindOutcome::Missing if cache.has_corrupt(hash) => {
+            format!("Missing: corrupt entry found in cache for {hash}, and set aside")
+        }
+        FindOutcome::Missing => format!("Missing: no entry found in cache for {hash}"),
+    };
+
+    writeln!(output.stdout, "{}", description)?;
+
+    if let Some(entry) = outcome.entry() {
+        writeln!(
+            output.stdout,
+            "entry recorded in {}",
+            entry.describe_duration()
+        )?;
+        writeln!(
+            output.stdout,
+            "entry created {}",
+            describe_relative(entry.created_at())
+        )?;
+        if let Some(expires) = entry.expires_at() {
+            writeln!(
+                output.stdout,
+                "entry expires at {} ({})",
+                humantime::format_rfc3339_seconds(expires),
+                describe_relative(expires)
+            )?;
+        }
+        if let Some(id) = entry.id() {
+            writeln!(output.stdout, "entry id: {}", id)?;
+        }
+        print_env(entry, output)?;
+    }
+
+    Ok(0)
+}
+
+fn describe_tags(entry: &impl CacheEntry) -> String {
+    entry
+        .tags()
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn print_env(entry: &impl CacheEntry, output: &mut Output) -> std::io::Result<()> {
+    if !entry.env().is_empty() {
+        writeln!(output.stdout, "recorded env:")?;
+        for (name, value) in entry.env() {
+            writeln!(output.stdout, "  {}={}", name, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Describes a cached result, where generation 0 is the current result.
+pub fn show<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    generation: usize,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let Some(entry) = cache.read_generation(cmd.hash(), generation)? else {
+        writeln!(
+            output.stderr,
+            "deja: no entry found in cache for {}",
+            cmd.hash()
+        )?;
+        return Ok(1);
+    };
+
+    let expires = entry
+        .expires_at()
+        .map(|expires| {
+            format!(
+                "{} ({})",
+                humantime::format_rfc3339_seconds(expires),
+                describe_relative(expires)
+            )
+        })
+        .unwrap_or_else(|| "never".into());
+
+    writeln!(output.stdout, "hash: {}", cmd.hash())?;
+    writeln!(output.stdout, "command: {}", entry.command())?;
+    if let Some(id) = entry.id() {
+        writeln!(output.stdout, "id: {}", id)?;
+    }
+    writeln!(
+        output.stdout,
+        "created: {} ({})",
+        humantime::format_rfc3339_seconds(entry.created_at()),
+        describe_relative(entry.created_at())
+    )?;
+    writeln!(output.stdout, "expires: {}", expires)?;
+    writeln!(output.stdout, "status: {}", entry.describe_status())?;
+    writeln!(output.stdout, "duration: {}", entry.describe_duration())?;
+    writeln!(output.stdout, "cpu time: {}", entry.describe_cpu_time())?;
+    writeln!(output.stdout, "max rss: {}", entry.describe_max_rss())?;
+    if let Some(diverged) = entry.diverged_at() {
+        writeln!(
+            output.stdout,
+            "diverged: {} (a fresh run made by --verify differed)",
+            humantime::format_rfc3339_seconds(diverged)
+        )?;
+    }
+    if !entry.tags().is_empty() {
+        writeln!(output.stdout, "tags: {}", describe_tags(&entry))?;
+    }
+    if entry.pinned() {
+        writeln!(output.stdout, "pinned: yes (fresh whenever it expires)")?;
     }
+    print_env(&entry, output)?;
+
+    Ok(0)
 }
 
-pub fn hash<E>(cmd: &mut Command, _cache: &impl Cache<E>) -> anyhow::Result<i32>
+/// Lists the current and previous results for the command.
+pub fn history<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+) -> anyhow::Result<i32>
 where
     E: CacheEntry,
 {
-    println!("{}", cmd.hash());
+    let history = cache.history(cmd.hash())?;
+    if history.is_empty() {
+        writeln!(
+            output.stderr,
+            "deja: no entry found in cache for {}",
+            cmd.hash()
+        )?;
+        return Ok(1);
+    }
+
+    for (generation, entry) in history.iter().enumerate() {
+        writeln!(
+            output.stdout,
+            "{}  {}  {:>8}  {}",
+            generation,
+            humantime::format_rfc3339_seconds(entry.created_at()),
+            entry.describe_duration(),
+            entry.describe_status()
+        )?;
+    }
+
     Ok(0)
 }
+
+/// The stdout of a result as text, or its stderr with `stderr`.
+fn output_text(entry: &impl CacheEntry, stderr: bool) -> anyhow::Result<String> {
+    if !stderr {
+        return entry.stdout();
+    }
+    let (_, stderr) = entry.raw_output()?;
+    let reader = OutputReader {
+        reader: std::io::BufReader::new(&stderr[..]),
+    };
+    Ok(reader.into_string())
+}
+
+/// Writes a unified diff between the stdout (or stderr, with `stderr`) of two generations of
+/// results, returning 1 when they differ. Changed lines matching `ignore` don't count.
+pub fn diff<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    (from, to): (usize, usize),
+    ignore: Option<&Regex>,
+    stderr: bool,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let read = |generation| -> anyhow::Result<String> {
+        let entry = cache
+            .read_generation(cmd.hash(), generation)?
+            .ok_or_else(|| {
+                anyhow::anyhow!("no generation {} in cache for {}", generation, cmd.hash())
+            })?;
+        output_text(&entry, stderr)
+    };
+
+    let (from_output, to_output) = (read(from)?, read(to)?);
+    match crate::diff::unified(
+        &from_output,
+        &to_output,
+        &format!("generation {}", from),
+        &format!("generation {}", to),
+        ignore,
+    ) {
+        Some(diff) => {
+            write!(output.stdout, "{}", diff)?;
+            Ok(1)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Writes a unified diff between the stdout (or stderr, with `stderr`) of the cached result
+/// for a command and a fresh run of it, noting when their exit statuses differ too. The fresh
+/// output isn't passed through, and is only recorded with `update`. Returns 0 when they're
+/// the same, 1 when they differ, or 2 when there's no cached result to compare.
+pub fn diff_fresh<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    mut options: RecordOptions,
+    update: bool,
+    ignore: Option<&Regex>,
+    stderr: bool,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let Some(cached) = cache.read(cmd.hash())? else {
+        writeln!(output.stderr, "no cached result to compare with")?;
+        return Ok(2);
+    };
+    // Read before running, as recording a new result can remove the cached output
+    let cached_output = output_text(&cached, stderr)?;
+
+    let (fresh_stdout, fresh_stderr) = (SharedBuffer::default(), SharedBuffer::default());
+    options.set_passthrough(Some((
+        SharedWriter::new(fresh_stdout.clone()),
+        SharedWriter::new(fresh_stderr.clone()),
+    )));
+    let status = if update {
+        cache.record(cmd, &options)?
+    } else {
+        let result = cmd.run(std::io::sink(), std::io::sink(), options.run_options())?;
+        if let Some(signal) = result.interrupted {
+            return Ok(128 + signal);
+        }
+        result.status
+    };
+    let fresh_output = if stderr { fresh_stderr } else { fresh_stdout }.take();
+
+    let stream = if stderr { "stderr" } else { "stdout" };
+    let diff = crate::diff::unified(
+        &cached_output,
+        &String::from_utf8_lossy(&fresh_output),
+        &format!("cached {}", stream),
+        &format!("fresh {}", stream),
+        ignore,
+    );
+    if let Some(diff) = &diff {
+        write!(output.stdout, "{}", diff)?;
+    }
+    let cached_status = cached.command_status();
+    if status != cached_status {
+        writeln!(
+            output.stdout,
+            "exit status differs: cached {}, fresh {}",
+            cached_status, status
+        )?;
+    }
+
+    Ok(if diff.is_none() && status == cached_status {
+        0
+    } else {
+        1
+    })
+}
+
+/// Lists every result in the cache matching `tags`, oldest first. With `json`, the results are
+/// written as a single array, always including the resources used.
+pub fn list<E>(
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    long: bool,
+    json: bool,
+    tags: &TagFilter,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let mut entries = cache.list()?;
+    entries.retain(|(_, entry)| tags.matches(entry));
+    entries.sort_by_key(|(_, entry)| entry.created_at());
+
+    if json {
+        let results = entries
+            .iter()
+            .map(|(hash, entry)| {
+                let usage = entry.usage();
+                ListedResult {
+                    hash: hash.clone(),
+                    command: entry.command().to_string(),
+                    created: format_time(entry.created_at()),
+                    expires: entry.expires_at().map(format_time),
+                    age: age_secs(entry.created_at()),
+                    expires_in: entry.expires_at().map(expires_in_secs),
+                    duration: entry.duration().map(|duration| duration.as_secs_f64()),
+                    status: entry.command_status(),
+                    signal: entry.command_signal(),
+                    user_time: usage.map(|usage| usage.user_time.as_secs_f64()),
+                    system_time: usage.map(|usage| usage.system_time.as_secs_f64()),
+                    max_rss: usage.map(|usage| usage.max_rss),
+                    diverged: entry.diverged_at().map(format_time),
+                    tags: entry.tags().iter().cloned().collect(),
+                    id: entry.id().map(String::from),
+                    pinned: entry.pinned(),
+                }
+            })
+            .collect::<Vec<_>>();
+        write_json(output, &results)?;
+        return Ok(0);
+    }
+
+    for (hash, entry) in entries {
+        let usage = if long {
+            format!(
+                "{:>22}  {:>12}  ",
+                entry.describe_cpu_time(),
+                entry.describe_max_rss()
+            )
+        } else {
+            String::new()
+        };
+        let mut status = entry.describe_status();
+        if entry.diverged_at().is_some() {
+            status.push_str(", diverged");
+        }
+        if entry.pinned() {
+            status.push_str(", pinned");
+        }
+        let id = match entry.id() {
+            Some(id) => format!("  (id: {})", id),
+            None => String::new(),
+        };
+        let tags = if entry.tags().is_empty() {
+            String::new()
+        } else {
+            format!("  [{}]", describe_tags(&entry))
+        };
+
+        writeln!(
+            output.stdout,
+            "{}  {}  {:>12}  {:>8}  {}{:<20}  {}{}{}",
+            &hash[..12.min(hash.len())],
+            humantime::format_rfc3339_seconds(entry.created_at()),
+            format!("{} ago", ago(entry.created_at())),
+            entry.describe_duration(),
+            usage,
+            status,
+            entry.command(),
+            id,
+            tags
+        )?;
+    }
+
+    Ok(0)
+}
+
+/// Writes each preset from the configuration files, with its command and any options it sets.
+pub fn list_presets(config: &Config, output: &mut Output) -> anyhow::Result<i32> {
+    let width = config
+        .presets()
+        .iter()
+        .map(|preset| preset.name.len())
+        .max()
+        .unwrap_or_default();
+
+    for preset in config.presets() {
+        writeln!(
+            output.stdout,
+            "@{:<width$}  {}",
+            preset.name,
+            shell_words::join(&preset.command)
+        )?;
+        for option in &preset.options {
+            writeln!(
+                output.stdout,
+                "{:indent$}{} = {}",
+                "",
+                option.key,
+                option.value,
+                indent = width + 3
+            )?;
+        }
+    }
+    Ok(0)
+}
+
+/// Checks for a cached result without replaying it, returning 0 when fresh, 1 when missing,
+/// 2 when expired and 3 when stale. With `json`, the result's state is also written, and with
+/// `why`, the reason there's no usable result is written to stderr.
+///
+/// With `exit_with_status`, a fresh result's recorded exit status is returned instead, and the
+/// given code whenever there's no usable result.
+pub fn test<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    read_options: FindOptions,
+    json: bool,
+    why: bool,
+    exit_with_status: Option<i32>,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let outcome = if disabled() {
+        debug(format!(
+            "caching disabled, treating {} as missing",
+            cmd.hash()
+        ));
+        FindOutcome::Missing
+    } else {
+        cache.lookup_usable(cmd.hash(), &read_options)?
+    };
+
+    if json {
+        write_json(output, &ResultState::new(&outcome))?;
+    }
+
+    if let Some(reason) = outcome.reason(&read_options).filter(|_| why) {
+        writeln!(output.stderr, "{}", reason)?;
+    }
+
+    match (outcome, exit_with_status) {
+        (FindOutcome::Fresh(entry), Some(_)) => Ok(entry.command_status()),
+        (_, Some(miss_exit_code)) => Ok(miss_exit_code),
+        (FindOutcome::Fresh(_), None) => Ok(0),
+        (FindOutcome::Missing, None) => Ok(1),
+        (FindOutcome::Expired(_), None) => Ok(2),
+        (FindOutcome::Stale(_), None) => Ok(3),
+    }
+}
+
+/// The output of `run --dry-run --json`.
+#[derive(Serialize)]
+struct DryRun {
+    hash: String,
+    #[serde(flatten)]
+    state: ResultState,
+    run: bool,
+    record: bool,
+    record_exit_codes: Vec<i32>,
+}
+
+/// Describes exit codes as ranges, like `0, 2-5`.
+fn describe_exit_codes(codes: &[i32]) -> String {
+    let mut ranges: Vec<(i32, i32)> = vec![];
+    for &code in codes {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == code => *end = code,
+            _ => ranges.push((code, code)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{start}-{end}"),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Reports what `run` (or `force`) would do, without running the command or writing to the
+/// cache: the hash, whether a usable result is cached, and whether a run would be recorded.
+/// Returns 0 when a cached result would be replayed, or 1 when the command would run.
+#[allow(clippy::too_many_arguments)]
+pub fn dry_run<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    read_options: FindOptions,
+    record_options: &RecordOptions,
+    force: bool,
+    read_only: bool,
+    json: bool,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let hash = cmd.hash().to_string();
+    let outcome = if disabled() {
+        FindOutcome::Missing
+    } else {
+        cache.lookup_usable(&hash, &read_options)?
+    };
+    let run = force || !matches!(outcome, FindOutcome::Fresh(_));
+    let record = run && !read_only && !disabled();
+    let exit_codes = (0..256)
+        .filter(|code| record_options.should_record(*code))
+        .collect::<Vec<_>>();
+
+    if json {
+        write_json(
+            output,
+            &DryRun {
+                hash,
+                state: ResultState::new(&outcome),
+                run,
+                record,
+                record_exit_codes: exit_codes,
+            },
+        )?;
+    } else {
+        writeln!(output.stdout, "hash: {}", hash)?;
+        let cached = match &outcome {
+            FindOutcome::Fresh(entry) => format!("fresh, created {} ago", ago(entry.created_at())),
+            _ if disabled() => "not looked up, caching is disabled".to_string(),
+            outcome => outcome.reason(&read_options).unwrap_or_default(),
+        };
+        writeln!(output.stdout, "cached: {}", cached)?;
+        let runs = match (run, force) {
+            (false, _) => "no, the cached result would be replayed",
+            (true, true) => "yes, any cached result is ignored",
+            (true, false) => "yes",
+        };
+        writeln!(output.stdout, "run: {}", runs)?;
+        let record = if !run {
+            "no, nothing runs".to_string()
+        } else if disabled() {
+            "no, caching is disabled".to_string()
+        } else if read_only {
+            "no, the cache is read-only".to_string()
+        } else {
+            format!("if it exits with {}", describe_exit_codes(&exit_codes))
+        };
+        writeln!(output.stdout, "record: {}", record)?;
+    }
+
+    Ok(if run { 1 } else { 0 })
+}
+
+/// Removes the command's results from the cache, returning 1 when there were none.
+pub fn remove<E>(cmd: &mut Command, cache: &impl Cache<E>) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    if cache.remove(cmd.hash())? {
+        Ok(0)
+    } else {
+        Ok(1)
+    }
+}
+
+/// Pins (or with `pinned` false, unpins) the current result for the command. A pinned result
+/// is fresh whenever it expires, and `gc` keeps it. Returns 1 when no result is cached.
+pub fn pin<E>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    pinned: bool,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    if cache.set_pinned(cmd.hash(), pinned)? {
+        Ok(0)
+    } else {
+        writeln!(
+            output.stderr,
+            "deja: no entry found in cache for {}",
+            cmd.hash()
+        )?;
+        Ok(1)
+    }
+}
+
+/// Removes every result matching `tags`, along with its output, writing each one removed to
+/// stdout. Returns 1 when nothing is removed.
+pub fn remove_tagged<E>(
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    tags: &TagFilter,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let mut entries = cache.list()?;
+    entries.retain(|(_, entry)| tags.matches(entry));
+    entries.sort_by_key(|(_, entry)| entry.created_at());
+
+    let mut removed = 0;
+    for (hash, entry) in entries {
+        if cache.remove(&hash)? {
+            removed += 1;
+            writeln!(output.stdout, "removed {}", entry.command())?;
+        }
+    }
+
+    Ok(if removed > 0 { 0 } else { 1 })
+}
+
+/// Which results `gc` removes, besides those that have expired.
+#[derive(Default)]
+pub struct Gc {
+    /// Remove results recorded longer ago than this.
+    pub older_than: Option<Duration>,
+    /// Remove results recorded before this time.
+    pub created_before: Option<SystemTime>,
+    /// Also remove pinned results, which are otherwise always kept.
+    pub include_pinned: bool,
+    /// Remove results the retention policy doesn't keep.
+    pub policy: Option<RetentionPolicy>,
+    /// Say which rule in the retention policy removed each result.
+    pub verbose: bool,
+}
+
+impl Gc {
+    /// Why the result should be removed, or `None` when it should be kept.
+    fn reason(&self, entry: &impl CacheEntry, now: SystemTime) -> Option<String> {
+        let created = entry.created_at();
+        let policy = self.policy.as_ref().and_then(|policy| policy.keep(entry));
+        if entry.pinned() && !self.include_pinned {
+            None
+        } else if entry.expires_at().is_some_and(|expires| expires <= now) {
+            Some("expired".into())
+        } else if self
+            .older_than
+            .is_some_and(|older_than| created + older_than <= now)
+        {
+            Some("too old".into())
+        } else if self
+            .created_before
+            .is_some_and(|created_before| created < created_before)
+        {
+            Some("created before cutoff".into())
+        } else if let Some((keep, rule)) = policy.filter(|(keep, _)| created + *keep <= now) {
+            Some(self.describe_policy(describe_keep(keep), rule))
+        } else {
+            None
+        }
+    }
+
+    /// Describes why the retention policy removes a result, saying which rule did with `verbose`.
+    fn describe_policy(&self, reason: String, rule: String) -> String {
+        if self.verbose {
+            format!("{}, by {}", reason, rule)
+        } else {
+            reason
+        }
+    }
+}
+
+/// Removes every result that has expired or that `gc` says is too old, along with its output,
+/// writing each one removed to stdout and then how many were removed and the space their output
+/// took. With a retention policy, results older than it keeps them are removed too, then the
+/// oldest until the rest fit in its `max-size`. Pinned results are kept unless `gc` includes
+/// them. With `dry_run`, reports what would be removed without removing anything.
+pub fn gc<E>(
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    gc: &Gc,
+    dry_run: bool,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let now = SystemTime::now();
+    let mut entries = cache.list()?;
+    entries.sort_by_key(|(_, entry)| entry.created_at());
+
+    let mut removals = vec![];
+    let mut kept = vec![];
+    for (hash, entry) in entries {
+        match gc.reason(&entry, now) {
+            Some(reason) => removals.push((hash, entry, reason)),
+            None => kept.push((hash, entry)),
+        }
+    }
+
+    // Output shared with results that are kept isn't freed
+    let mut stored = StoredOutput::new(
+        removals
+            .iter()
+            .map(|(_, entry, _)| entry)
+            .chain(kept.iter().map(|(_, entry)| entry)),
+    );
+
+    // Once results are removed by age, the oldest are removed until the rest fit
+    if let Some(policy) = &gc.policy {
+        if let Some(max_size) = policy.max_size() {
+            let mut kept_output = StoredOutput::new(kept.iter().map(|(_, entry)| entry));
+            let mut total = kept_output.size();
+            for (hash, entry) in kept {
+                if total <= max_size {
+                    break;
+                }
+                if entry.pinned() && !gc.include_pinned {
+                    continue;
+                }
+                total -= kept_output.remove(&entry);
+                let reason = gc.describe_policy(
+                    format!("over max-size {}", describe_size(max_size)),
+                    format!("max-size in {}", policy.file().display()),
+                );
+                removals.push((hash, entry, reason));
+            }
+        }
+    }
+
+    let (mut removed, mut bytes) = (0, 0);
+    for (hash, entry, reason) in removals {
+        let freed = stored.remove(&entry);
+        if dry_run {
+            writeln!(
+                output.stdout,
+                "would remove {} ({})",
+                entry.command(),
+                reason
+            )?;
+        } else if cache.remove(&hash)? {
+            writeln!(output.stdout, "removed {} ({})", entry.command(), reason)?;
+        } else {
+            continue;
+        }
+        removed += 1;
+        bytes += freed;
+    }
+    if !dry_run {
+        let locks = cache.remove_unused_locks()?;
+        debug(format!("removed {} unused lock files", locks));
+    }
+
+    writeln!(
+        output.stdout,
+        "{} {} results, {}",
+        if dry_run { "would remove" } else { "removed" },
+        removed,
+        describe_size(bytes)
+    )?;
+    Ok(0)
+}
+
+/// The output stored for a set of results, taking sizes from the files (or other storage) it's
+/// in. Output shared between results is counted once, and only freed once no result uses it.
+struct StoredOutput(HashMap<String, (usize, usize)>);
+
+impl StoredOutput {
+    fn new<'a, E: CacheEntry + 'a>(entries: impl Iterator<Item = &'a E>) -> Self {
+        let mut stored = HashMap::new();
+        for entry in entries {
+            for (name, size) in entry.output_sizes() {
+                stored.entry(name).or_insert((size, 0)).1 += 1;
+            }
+        }
+        StoredOutput(stored)
+    }
+
+    /// The total size of the output, counting shared output once.
+    fn size(&self) -> usize {
+        self.0.values().map(|(size, _)| size).sum()
+    }
+
+    /// Removes a result's output, returning how many bytes are freed: the size of any output
+    /// no other result uses.
+    fn remove(&mut self, entry: &impl CacheEntry) -> usize {
+        let mut freed = 0;
+        for (name, _) in entry.output_sizes() {
+            if let Some((size, uses)) = self.0.get_mut(&name) {
+                *uses -= 1;
+                if *uses == 0 {
+                    freed += *size;
+                    self.0.remove(&name);
+                }
+            }
+        }
+        freed
+    }
+}
+
+/// Describes a number of bytes, like `512 B`, `1.2 KiB` or `2.0 GiB`.
+pub(crate) fn describe_size(bytes: usize) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1048576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        1048576..1073741824 => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+        _ => format!("{:.1} GiB", bytes as f64 / (1024.0 * 1024.0 * 1024.0)),
+    }
+}
+
+/// Parses the results chosen at the `remove --interactive` prompt: numbers and ranges like
+/// `1 3-4`, separated by spaces or commas, or `all`. Returns `None` when the choice isn't valid.
+fn parse_selection(input: &str, count: usize) -> Option<BTreeSet<usize>> {
+    let input = input.trim();
+    if input == "all" {
+        return Some((1..=count).collect());
+    }
+    let mut selected = BTreeSet::new();
+    for part in input.split([' ', ',']).filter(|part| !part.is_empty()) {
+        let (start, end) = part.split_once('-').unwrap_or((part, part));
+        let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+        if start < 1 || start > end || end > count {
+            return None;
+        }
+        selected.extend(start..=end);
+    }
+    Some(selected)
+}
+
+/// Lists the cached results whose command matches `matching` and that match `tags`, asks which
+/// to remove on `input`, and removes the ones chosen along with their output. The list and
+/// prompt are written to stderr, and each result removed to stdout. Returns 1 when nothing is removed.
+pub fn remove_interactive<E>(
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    input: &mut impl BufRead,
+    matching: Option<&Regex>,
+    tags: &TagFilter,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    let mut entries = cache
+        .list()?
+        .into_iter()
+        .filter(|(_, entry)| {
+            matching.is_none_or(|matching| matching.is_match(&entry.command().to_string()))
+                && tags.matches(entry)
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by_key(|(_, entry)| entry.created_at());
+
+    if entries.is_empty() {
+        writeln!(output.stderr, "no cached results to remove")?;
+        return Ok(1);
+    }
+
+    let width = entries.len().to_string().len();
+    for (number, (_, entry)) in entries.iter().enumerate() {
+        let (stdout, stderr) = entry.raw_output()?;
+        writeln!(
+            output.stderr,
+            "{:>width$}  {:>10}  {:<14}  {:>10}  {}",
+            number + 1,
+            format!("{} ago", ago(entry.created_at())),
+            entry.describe_status(),
+            describe_size(stdout.len() + stderr.len()),
+            entry.command()
+        )?;
+    }
+
+    let selected = loop {
+        write!(
+            output.stderr,
+            "Remove which results? (numbers or ranges like 1 3-4, or all): "
+        )?;
+        output.stderr.flush()?;
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            writeln!(output.stderr)?;
+            break BTreeSet::new();
+        }
+        match parse_selection(&line, entries.len()) {
+            Some(selected) => break selected,
+            None => writeln!(
+                output.stderr,
+                "invalid choice '{}', use numbers from 1 to {}",
+                line.trim(),
+                entries.len()
+            )?,
+        }
+    };
+
+    let mut removed = 0;
+    for number in selected {
+        let (hash, entry) = &entries[number - 1];
+        if cache.remove(hash)? {
+            removed += 1;
+            writeln!(output.stdout, "removed {}", entry.command())?;
+        }
+    }
+    writeln!(
+        output.stderr,
+        "removed {} of {} results",
+        removed,
+        entries.len()
+    )?;
+
+    Ok(if removed > 0 { 0 } else { 1 })
+}
+
+/// Copies the current result for a command from one cache to another, unless it's already
+/// there. Returns `None` when there's no result to copy.
+fn sync<E, F>(
+    cmd: &Command,
+    from: &impl Cache<E>,
+    to: &impl Cache<F>,
+) -> anyhow::Result<Option<bool>>
+where
+    E: CacheEntry,
+    F: CacheEntry,
+{
+    let hash = cmd.hash();
+    let Some(entry) = from.read(hash)? else {
+        return Ok(None);
+    };
+
+    let is_copy = |existing: &F| {
+        existing.command().ulid == entry.command().ulid
+            && existing.created_at() == entry.created_at()
+    };
+    if to.read(hash)?.is_some_and(|existing| is_copy(&existing)) {
+        debug(format!("{} already synced", hash));
+        return Ok(Some(false));
+    }
+
+    to.store(hash, &entry)?;
+
+    // Check the output arrived intact, as the cache may be on a network share
+    let hashes = |entry: &dyn CacheEntry| -> anyhow::Result<_> {
+        let (stdout, stderr) = entry.raw_output()?;
+        Ok(output_hashes(&stdout, &stderr)?)
+    };
+    let stored = to.read(hash)?.filter(is_copy);
+    match stored {
+        Some(stored) if hashes(&stored)? == hashes(&entry)? => Ok(Some(true)),
+        _ => Err(anyhow::anyhow!(
+            "unable to verify copied result for {}",
+            hash
+        )),
+    }
+}
+
+/// Copies the current result for a command to a remote cache.
+pub fn push<E, R>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    remote: &impl Cache<R>,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+    R: CacheEntry,
+{
+    match sync(cmd, cache, remote)? {
+        Some(true) => writeln!(output.stdout, "pushed {}", cmd.hash())?,
+        Some(false) => writeln!(output.stdout, "{} already pushed", cmd.hash())?,
+        None => return Ok(1),
+    }
+    Ok(0)
+}
+
+/// Copies the current result for a command from a remote cache.
+pub fn pull<E, R>(
+    cmd: &mut Command,
+    cache: &impl Cache<E>,
+    output: &mut Output,
+    remote: &impl Cache<R>,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+    R: CacheEntry,
+{
+    match sync(cmd, remote, cache)? {
+        Some(true) => writeln!(output.stdout, "pulled {}", cmd.hash())?,
+        Some(false) => writeln!(output.stdout, "{} already pulled", cmd.hash())?,
+        None => return Ok(1),
+    }
+    Ok(0)
+}
+
+/// Copies every result from a disk cache into a SQLite cache.
+pub fn import(cache: &SqliteCache, output: &mut Output, from: &DiskCache) -> anyhow::Result<i32> {
+    let count = cache.import(from)?;
+    writeln!(output.stdout, "imported {} cached results", count)?;
+    Ok(0)
+}
+
+/// Writes the command's cache key, or with `components` the hash of each part of it.
+pub fn hash<E>(
+    cmd: &mut Command,
+    _cache: &impl Cache<E>,
+    output: &mut Output,
+    components: bool,
+    json: bool,
+) -> anyhow::Result<i32>
+where
+    E: CacheEntry,
+{
+    if json && components {
+        write_json(output, &cmd.scope.hashes().summary())?;
+    } else if json {
+        write_json(output, &serde_json::json!({ "hash": cmd.hash() }))?;
+    } else if components {
+        write!(output.stdout, "{}", cmd.scope.hashes().describe())?;
+    } else {
+        writeln!(output.stdout, "{}", cmd.hash())?;
+    }
+    Ok(0)
+}
+
+/// Hashes the command line on each line of `input`, built into a command by `build`, writing
+/// each hash and line separated by a tab. Blank lines and those starting with `#` are skipped.
+/// Lines that can't be built are reported with their line number, and the rest still hashed.
+/// Returns 1 if any line failed.
+pub fn hash_lines(
+    input: &mut impl BufRead,
+    output: &mut Output,
+    json: bool,
+    mut build: impl FnMut(&str) -> anyhow::Result<Command>,
+) -> anyhow::Result<i32> {
+    let mut failed = false;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match build(line) {
+            Ok(cmd) if json => write_json(
+                output,
+                &serde_json::json!({ "hash": cmd.hash(), "command": line }),
+            )?,
+            Ok(cmd) => writeln!(output.stdout, "{}\t{}", cmd.hash(), line)?,
+            Err(e) => {
+                failed = true;
+                writeln!(output.stderr, "deja: line {}: {}", index + 1, e)?;
+            }
+        }
+    }
+    Ok(if failed { 1 } else { 0 })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::memory::MemoryCache;
+    use crate::cache::CacheModes;
+    use crate::cache::SharedBuffer;
+    use crate::command::ScopeBuilder;
+
+    #[test]
+    fn test_run_then_test() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut cmd = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        let output = &mut Output::new(std::io::sink(), std::io::sink());
+
+        assert_eq!(
+            test(
+                &mut cmd,
+                &cache,
+                output,
+                FindOptions::default(),
+                false,
+                false,
+                None
+            )?,
+            1
+        );
+
+        let status = run(
+            &mut cmd,
+            &cache,
+            output,
+            RecordOptions::default(),
+            FindOptions::default(),
+            LockOptions::default(),
+            false,
+            &Hooks::default(),
+            None,
+            None,
+        )?;
+        assert_eq!(status, 0);
+        assert_eq!(
+            test(
+                &mut cmd,
+                &cache,
+                output,
+                FindOptions::default(),
+                false,
+                false,
+                None
+            )?,
+            0
+        );
+
+        assert_eq!(remove(&mut cmd, &cache)?, 0);
+        assert_eq!(
+            test(
+                &mut cmd,
+                &cache,
+                output,
+                FindOptions::default(),
+                false,
+                false,
+                None
+            )?,
+            1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_test_exit_with_status() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let output = &mut Output::new(std::io::sink(), std::io::sink());
+        let mut succeeds = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        let mut fails = Command::new(ScopeBuilder::new().cmd("false").build()?);
+        let exit_with_status = |cmd: &mut Command, output: &mut Output, options| {
+            test(cmd, &cache, output, options, false, false, Some(125))
+        };
+
+        assert_eq!(
+            exit_with_status(&mut fails, output, FindOptions::default())?,
+            125,
+            "miss"
+        );
+
+        let mut record_options = RecordOptions::default();
+        let mut exit_codes = [false; 256];
+        exit_codes[0] = true;
+        exit_codes[1] = true;
+        record_options.set_exit_codes(exit_codes);
+        cache.record(&mut succeeds, &record_options)?;
+        cache.record(&mut fails, &record_options)?;
+
+        assert_eq!(
+            exit_with_status(&mut succeeds, output, FindOptions::default())?,
+            0,
+            "cached success"
+        );
+        assert_eq!(
+            exit_with_status(&mut fails, output, FindOptions::default())?,
+            1,
+            "cached failure"
+        );
+
+        let look_back = || {
+            let mut options = FindOptions::default();
+            options.set_max_age(Some(Duration::ZERO));
+            options
+        };
+        assert_eq!(
+            exit_with_status(&mut fails, output, look_back())?,
+            125,
+            "older than look back"
+        );
+        assert_eq!(
+            test(&mut fails, &cache, output, look_back(), false, false, None)?,
+            3,
+            "unchanged without the flag"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync() -> anyhow::Result<()> {
+        let (local, remote) = (MemoryCache::new(), MemoryCache::new());
+        let mut cmd = Command::new(ScopeBuilder::new().cmd("echo").args("synced").build()?);
+
+        assert_eq!(sync(&cmd, &local, &remote)?, None, "nothing to sync");
+
+        local.record(&mut cmd, &RecordOptions::default())?;
+        assert_eq!(sync(&cmd, &local, &remote)?, Some(true));
+        assert_eq!(
+            sync(&cmd, &local, &remote)?,
+            Some(false),
+            "skips existing result"
+        );
+        assert_eq!(remote.read(cmd.hash())?.unwrap().stdout()?, "synced\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_output_shared_with_an_earlier_copy() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        let local = MemoryCache::new();
+        let remote = DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?;
+        let mut silent = RecordOptions::default();
+        silent.set_silent(true);
+
+        // The output recorded again has new timestamps, but is stored in the same blob as before
+        let mut cmd = Command::new(ScopeBuilder::new().cmd("echo").args("synced").build()?);
+        local.record(&mut cmd, &silent)?;
+        assert_eq!(sync(&cmd, &local, &remote)?, Some(true));
+        local.remove(cmd.hash())?;
+        let mut cmd = Command::new(cmd.scope.clone());
+        local.record(&mut cmd, &silent)?;
+        assert_eq!(sync(&cmd, &local, &remote)?, Some(true));
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_hooks() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut cmd = Command::new(ScopeBuilder::new().cmd("echo").args("hooked").build()?);
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut output = Output::new(stdout.clone(), stderr.clone());
+        let hooks = Hooks {
+            on_hit: Some("echo hit $DEJA_STATUS $DEJA_ENTRY_AGE; exit 3".into()),
+            on_miss: Some("echo miss $DEJA_COMMAND ${DEJA_ENTRY_AGE-none}".into()),
+        };
+        let mut silent = RecordOptions::default();
+        silent.set_silent(true);
+        let mut run = |output: &mut Output, silent| {
+            run(
+                &mut cmd,
+                &cache,
+                output,
+                silent,
+                FindOptions::default(),
+                LockOptions::default(),
+                false,
+                &hooks,
+                None,
+                None,
+            )
+        };
+
+        assert_eq!(run(&mut output, silent)?, 0);
+        assert_eq!(stdout.take(), b"", "hook output isn't written to stdout");
+        assert_eq!(stderr.take(), b"miss echo hooked none\n");
+
+        assert_eq!(
+            run(&mut output, RecordOptions::default())?,
+            0,
+            "failing hook"
+        );
+        assert_eq!(stdout.take(), b"hooked\n");
+        assert_eq!(
+            String::from_utf8(stderr.take())?,
+            "hit 0 0\ndeja: warning: --on-hit hook failed with exit code 3\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_is_written_to_writers() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut cmd = Command::new(ScopeBuilder::new().cmd("echo").args("captured").build()?);
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut output = Output::new(stdout.clone(), stderr.clone());
+
+        let mut record_options = RecordOptions::default();
+        record_options.set_silent(true);
+        cache.record(&mut cmd, &record_options)?;
+
+        let status = run(
+            &mut cmd,
+            &cache,
+            &mut output,
+            RecordOptions::default(),
+            FindOptions::default(),
+            LockOptions::default(),
+            true,
+            &Hooks::default(),
+            None,
+            None,
+        )?;
+        assert_eq!(status, 0);
+        assert_eq!(stdout.take(), b"captured\n", "replays into writer");
+        assert_eq!(stderr.take(), b"deja: hit (age 0s)\n", "prints status");
+
+        show(&mut cmd, &cache, &mut output, 0)?;
+        let report = String::from_utf8(stdout.take())?;
+        assert!(report.starts_with(&format!("hash: {}\n", cmd.hash())));
+        assert!(stderr.take().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_counts_hits_and_misses() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut cmd = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        let dir = std::env::temp_dir().join(format!("deja-counters-{}", Ulid::new()));
+        std::fs::create_dir(&dir)?;
+        let counters = Counters::new(dir.join("counters"), crate::cache::CacheModes::PRIVATE);
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut output = Output::new(stdout.clone(), stderr.clone());
+
+        for _ in 0..3 {
+            run(
+                &mut cmd,
+                &cache,
+                &mut output,
+                RecordOptions::default(),
+                FindOptions::default(),
+                LockOptions::default(),
+                false,
+                &Hooks::default(),
+                None,
+                Some(&counters),
+            )?;
+        }
+        crate::stats::stats(&counters, &mut output, 7, true)?;
+        std::fs::remove_dir_all(&dir)?;
+
+        let stats: serde_json::Value = serde_json::from_slice(&stdout.take())?;
+        assert_eq!(stats["hits"], 2);
+        assert_eq!(stats["misses"], 1);
+        assert_eq!(stats["days"].as_array().map(Vec::len), Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_status() {
+        let created = SystemTime::now() - Duration::from_secs(252);
+        assert_eq!(Status::Hit(created, None).to_string(), "hit (age 4m 12s)");
+        assert_eq!(
+            Status::Recorded(Duration::from_millis(8300)).to_string(),
+            "miss (recorded, 8.3s)"
+        );
+        assert_eq!(
+            Status::NotRecorded(Duration::from_millis(40)).to_string(),
+            "miss (not recorded, 0.0s)"
+        );
+    }
+
+    #[test]
+    fn test_hash_lines() -> anyhow::Result<()> {
+        let stdout = SharedBuffer::default();
+        let stderr = SharedBuffer::default();
+        let mut output = Output::new(stdout.clone(), stderr.clone());
+        let build = |line: &str| -> anyhow::Result<Command> {
+            let words = shell_words::split(line)?;
+            Ok(Command::new(
+                ScopeBuilder::new()
+                    .cmd(words[0].clone())
+                    .args(words[1..].to_vec())
+                    .build()?,
+            ))
+        };
+        let echo = build("echo 'a b'")?;
+
+        let mut input = "# comment\n\necho 'a b'\necho 'unterminated\n  ls  \n".as_bytes();
+        assert_eq!(hash_lines(&mut input, &mut output, false, build)?, 1);
+        let hashes = String::from_utf8(stdout.take())?;
+        let lines = hashes.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], format!("{}\techo 'a b'", echo.hash()));
+        assert!(lines[1].ends_with("\tls"), "trims lines");
+        assert_eq!(
+            String::from_utf8(stderr.take())?,
+            "deja: line 4: missing closing quote\n"
+        );
+
+        let mut input = "echo 'a b'\n".as_bytes();
+        assert_eq!(hash_lines(&mut input, &mut output, true, build)?, 0);
+        let json: serde_json::Value = serde_json::from_slice(&stdout.take())?;
+        assert_eq!(
+            json,
+            serde_json::json!({ "hash": echo.hash(), "command": "echo 'a b'" })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_output() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut cmd = Command::new(ScopeBuilder::new().cmd("echo").args("json").build()?);
+        let stdout = SharedBuffer::default();
+        let mut output = Output::new(stdout.clone(), std::io::sink());
+        let json =
+            || -> anyhow::Result<serde_json::Value> { Ok(serde_json::from_slice(&stdout.take())?) };
+
+        assert_eq!(
+            test(
+                &mut cmd,
+                &cache,
+                &mut output,
+                FindOptions::default(),
+                true,
+                false,
+                None
+            )?,
+            1
+        );
+        assert_eq!(
+            json()?,
+            serde_json::json!({ "status": "miss", "created": null, "expires": null, "age": null, "expires_in": null })
+        );
+
+        hash(&mut cmd, &cache, &mut output, false, true)?;
+        assert_eq!(json()?, serde_json::json!({ "hash": cmd.hash() }));
+
+        hash(&mut cmd, &cache, &mut output, true, true)?;
+        assert_eq!(json()?["hash"], cmd.hash());
+
+        let mut record_options = RecordOptions::default();
+        record_options.set_silent(true);
+        cache.record(&mut cmd, &record_options)?;
+
+        test(
+            &mut cmd,
+            &cache,
+            &mut output,
+            FindOptions::default(),
+            true,
+            false,
+            None,
+        )?;
+        assert_eq!(json()?["status"], "hit");
+
+        explain(&mut cmd, &cache, &mut output, FindOptions::default(), true)?;
+        let explanation = json()?;
+        assert_eq!(explanation["scope"]["cmd"], "echo");
+        assert_eq!(explanation["scope"]["args"], serde_json::json!(["json"]));
+        assert_eq!(explanation["hash"], cmd.hash());
+        assert_eq!(explanation["hashes"]["hash"], cmd.hash());
+        assert_eq!(explanation["status"], "hit");
+        assert_eq!(explanation["age"], 0, "age in seconds");
+        assert_eq!(explanation["expires_in"], serde_json::Value::Null);
+
+        list(&cache, &mut output, false, true, &TagFilter::default())?;
+        let results = json()?;
+        assert_eq!(results.as_array().map(Vec::len), Some(1));
+        assert_eq!(results[0]["hash"], cmd.hash());
+        assert_eq!(results[0]["status"], 0);
+        assert_eq!(results[0]["age"], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut cmd = Command::new(ScopeBuilder::new().cmd("echo").args("dry").build()?);
+        let stdout = SharedBuffer::default();
+        let mut output = Output::new(stdout.clone(), std::io::sink());
+        let mut record_options = RecordOptions::default();
+        let mut exit_codes = [false; 256];
+        for code in [0, 2, 3, 4, 255] {
+            exit_codes[code] = true;
+        }
+        record_options.set_exit_codes(exit_codes);
+        let dry_run = |cmd: &mut Command, output: &mut Output, force, read_only, json| {
+            dry_run(
+                cmd,
+                &cache,
+                output,
+                FindOptions::default(),
+                &record_options,
+                force,
+                read_only,
+                json,
+            )
+        };
+
+        assert_eq!(dry_run(&mut cmd, &mut output, false, false, false)?, 1);
+        assert_eq!(
+            String::from_utf8(stdout.take())?,
+            format!(
+                "hash: {}\ncached: missing\nrun: yes\nrecord: if it exits with 0, 2-4, 255\n",
+                cmd.hash()
+            )
+        );
+        assert!(cache.read(cmd.hash())?.is_none(), "doesn't run the command");
+
+        dry_run(&mut cmd, &mut output, false, true, false)?;
+        assert!(String::from_utf8(stdout.take())?.ends_with("record: no, the cache is read-only\n"));
+
+        let mut silent = RecordOptions::default();
+        silent.set_silent(true);
+        cache.record(&mut cmd, &silent)?;
+        assert_eq!(dry_run(&mut cmd, &mut output, false, false, false)?, 0);
+        let report = String::from_utf8(stdout.take())?;
+        assert!(
+            report.contains("cached: fresh, created 0s ago\n"),
+            "{}",
+            report
+        );
+        assert!(report.contains("run: no, the cached result would be replayed\n"));
+        assert!(report.ends_with("record: no, nothing runs\n"));
+
+        assert_eq!(dry_run(&mut cmd, &mut output, true, false, true)?, 1);
+        let json: serde_json::Value = serde_json::from_slice(&stdout.take())?;
+        assert_eq!(json["hash"], cmd.hash());
+        assert_eq!(json["status"], "hit");
+        assert_eq!(json["run"], true);
+        assert_eq!(json["record"], true);
+        assert_eq!(
+            json["record_exit_codes"],
+            serde_json::json!([0, 2, 3, 4, 255])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_fresh() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let file = std::env::temp_dir().join(format!("deja-diff-{}", ulid::Ulid::new()));
+        std::fs::write(&file, "at 10:00\nok\n")?;
+        let script = format!("cat {}; echo err >&2", file.display());
+        let mut cmd = Command::new(ScopeBuilder::new().shell("sh").cmd(&script).build()?);
+        let stdout = SharedBuffer::default();
+        let mut output = Output::new(stdout.clone(), std::io::sink());
+        let mut diff_fresh = |update, ignore: Option<&Regex>, stderr| {
+            diff_fresh(
+                &mut cmd,
+                &cache,
+                &mut output,
+                RecordOptions::default(),
+                update,
+                ignore,
+                stderr,
+            )
+        };
+
+        assert_eq!(diff_fresh(false, None, false)?, 2, "nothing cached");
+        let mut silent = RecordOptions::default();
+        silent.set_silent(true);
+        cache.record(
+            &mut Command::new(ScopeBuilder::new().shell("sh").cmd(&script).build()?),
+            &silent,
+        )?;
+
+        std::fs::write(&file, "at 10:05\nok\n")?;
+        assert_eq!(diff_fresh(false, None, false)?, 1);
+        assert_eq!(
+            String::from_utf8(stdout.take())?,
+            "--- cached stdout\n+++ fresh stdout\n@@ -1,2 +1,2 @@\n-at 10:00\n+at 10:05\n ok\n"
+        );
+        let ignore = Regex::new("^at ")?;
+        assert_eq!(diff_fresh(false, Some(&ignore), false)?, 0);
+        assert_eq!(diff_fresh(false, None, true)?, 0, "stderr is the same");
+
+        assert_eq!(diff_fresh(true, None, false)?, 1);
+        stdout.take();
+        assert_eq!(
+            diff_fresh(false, None, false)?,
+            0,
+            "fresh result was recorded"
+        );
+        assert_eq!(stdout.take(), b"");
+
+        std::fs::remove_file(&file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let file = std::env::temp_dir().join(format!("deja-verify-{}", ulid::Ulid::new()));
+        std::fs::write(&file, "first\nsecond\n")?;
+        let script = format!("cat {}", file.display());
+        let command = || ScopeBuilder::new().shell("sh").cmd(&script).build();
+        let stderr = SharedBuffer::default();
+        let mut output = Output::new(std::io::sink(), stderr.clone());
+        let mut silent = RecordOptions::default();
+        silent.set_silent(true);
+        cache.record(&mut Command::new(command()?), &silent)?;
+        let mut verify = |update| {
+            verify(
+                &mut Command::new(command()?),
+                &cache,
+                &mut output,
+                RecordOptions::default(),
+                update,
+            )
+        };
+        let diverged = || -> anyhow::Result<bool> {
+            let cmd = Command::new(command()?);
+            Ok(cache.read(cmd.hash())?.unwrap().diverged_at().is_some())
+        };
+
+        assert_eq!(verify(false)?, 0);
+        assert_eq!(stderr.take(), b"");
+        assert!(!diverged()?);
+
+        for (changed, description) in [
+            ("first\nsecond\nthird\n", "more output"),
+            ("first\n", "less output"),
+            ("first\nsecomd\n", "different output"),
+        ] {
+            std::fs::write(&file, changed)?;
+            assert_eq!(verify(false)?, 1, "{}", description);
+            assert_eq!(
+                String::from_utf8(stderr.take())?,
+                "deja: warning: cached result differs from a fresh run (stdout differs)\n"
+            );
+        }
+        assert!(diverged()?, "marks the cached result");
+
+        std::fs::write(&file, "replaced\n")?;
+        assert_eq!(verify(true)?, 1);
+        assert_eq!(
+            String::from_utf8(stderr.take())?,
+            "deja: warning: cached result differs from a fresh run (stdout differs), replaced \
+             it with the fresh result\n"
+        );
+        assert!(!diverged()?, "fresh result isn't marked");
+        assert_eq!(verify(false)?, 0);
+
+        std::fs::remove_file(&file)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_selection() {
+        let selection = |input| parse_selection(input, 5).map(|s| s.into_iter().collect());
+        assert_eq!(selection("1 3-4\n"), Some(vec![1, 3, 4]));
+        assert_eq!(selection("2,2, 5"), Some(vec![2, 5]));
+        assert_eq!(selection("all"), Some(vec![1, 2, 3, 4, 5]));
+        assert_eq!(selection(""), Some(vec![]));
+        assert_eq!(selection("0"), None);
+        assert_eq!(selection("6"), None);
+        assert_eq!(selection("4-2"), None);
+        assert_eq!(selection("one"), None);
+    }
+
+    #[test]
+    fn test_remove_interactive() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut commands = ["first", "second", "third"]
+            .map(|arg| Command::new(ScopeBuilder::new().cmd("echo").args(arg).build().unwrap()));
+        for cmd in commands.iter_mut() {
+            cache.record(cmd, &RecordOptions::default())?;
+        }
+        let stdout = SharedBuffer::default();
+        let stderr = SharedBuffer::default();
+        let mut output = Output::new(stdout.clone(), stderr.clone());
+
+        let matching = Regex::new("th")?;
+        let mut input = std::io::Cursor::new("2\n1\n");
+        assert_eq!(
+            remove_interactive(
+                &cache,
+                &mut output,
+                &mut input,
+                Some(&matching),
+                &TagFilter::default()
+            )?,
+            0
+        );
+        assert_eq!(String::from_utf8(stdout.take())?, "removed echo third\n");
+        let shown = String::from_utf8(stderr.take())?;
+        assert!(!shown.contains("first"), "{}", shown);
+        assert!(shown.contains("invalid choice '2', use numbers from 1 to 1"));
+        assert!(shown.ends_with("removed 1 of 1 results\n"));
+        assert!(cache.read(commands[2].hash())?.is_none());
+        assert!(cache.read(commands[0].hash())?.is_some());
+
+        let mut input = std::io::Cursor::new("");
+        assert_eq!(
+            remove_interactive(&cache, &mut output, &mut input, None, &TagFilter::default())?,
+            1
+        );
+        assert_eq!(cache.list()?.len(), 2, "removes nothing at end of input");
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_tagged() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let tagged = [("first", "infra prod"), ("second", "infra"), ("third", "")];
+        let mut commands = tagged.map(|(arg, _)| {
+            Command::new(ScopeBuilder::new().cmd("echo").args(arg).build().unwrap())
+        });
+        for (cmd, (_, tags)) in commands.iter_mut().zip(tagged) {
+            let mut options = RecordOptions::default();
+            options.set_tags(tags.split_whitespace().map(String::from).collect());
+            cache.record(cmd, &options)?;
+        }
+        let stdout = SharedBuffer::default();
+        let mut output = Output::new(stdout.clone(), SharedBuffer::default());
+        let filter = |tags: &[&str], any| TagFilter {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            any,
+            id: None,
+        };
+
+        assert_eq!(
+            remove_tagged(&cache, &mut output, &filter(&["infra", "prod"], false))?,
+            0
+        );
+        assert_eq!(String::from_utf8(stdout.take())?, "removed echo first\n");
+        assert_eq!(
+            remove_tagged(&cache, &mut output, &filter(&["prod", "dev"], true))?,
+            1,
+            "nothing left with either tag"
+        );
+        assert_eq!(
+            remove_tagged(&cache, &mut output, &filter(&["infra", "dev"], true))?,
+            0
+        );
+        assert_eq!(String::from_utf8(stdout.take())?, "removed echo second\n");
+        assert!(cache.read(commands[2].hash())?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_id() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let ids = [
+            ("first", "JIRA-12"),
+            ("second", "jira-13"),
+            ("third", "build-7"),
+        ];
+        let mut commands = ids.map(|(arg, _)| {
+            Command::new(ScopeBuilder::new().cmd("echo").args(arg).build().unwrap())
+        });
+        for (cmd, (_, id)) in commands.iter_mut().zip(ids) {
+            let mut options = RecordOptions::default();
+            options.set_id(Some(id.to_string()));
+            cache.record(cmd, &options)?;
+        }
+        let stdout = SharedBuffer::default();
+        let mut output = Output::new(stdout.clone(), SharedBuffer::default());
+        let filter = |id: &str| TagFilter {
+            id: Some(id.to_string()),
+            ..TagFilter::default()
+        };
+
+        list(&cache, &mut output, false, true, &filter("JIRA-*"))?;
+        let results: serde_json::Value = serde_json::from_slice(&stdout.take())?;
+        assert_eq!(
+            results
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|result| result["id"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            vec!["JIRA-12", "jira-13"],
+            "matches ignoring case"
+        );
+
+        // Recording again replaces the id
+        let mut options = RecordOptions::default();
+        options.set_id(Some("JIRA-14".into()));
+        cache.record(&mut commands[2], &options)?;
+        assert_eq!(
+            cache.read(commands[2].hash())?.unwrap().id(),
+            Some("JIRA-14")
+        );
+
+        assert_eq!(remove_tagged(&cache, &mut output, &filter("*-14"))?, 0);
+        assert_eq!(String::from_utf8(stdout.take())?, "removed echo third\n");
+        assert_eq!(remove_tagged(&cache, &mut output, &filter("build-*"))?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut commands = ["first", "second"]
+            .map(|arg| Command::new(ScopeBuilder::new().cmd("echo").args(arg).build().unwrap()));
+        let mut expired = RecordOptions::default();
+        expired.set_cache_for(Some(Duration::ZERO));
+        cache.record(&mut commands[0], &expired)?;
+        cache.record(&mut commands[1], &RecordOptions::default())?;
+        let stdout = SharedBuffer::default();
+        let mut output = Output::new(stdout.clone(), SharedBuffer::default());
+
+        gc(&cache, &mut output, &Gc::default(), true)?;
+        let report = String::from_utf8(stdout.take())?;
+        assert!(
+            report.starts_with("would remove echo first (expired)\nwould remove 1 results, "),
+            "{}",
+            report
+        );
+        assert_eq!(cache.list()?.len(), 2, "dry run removes nothing");
+
+        gc(&cache, &mut output, &Gc::default(), false)?;
+        assert!(String::from_utf8(stdout.take())?.starts_with("removed echo first (expired)\n"));
+        assert!(cache.read(commands[1].hash())?.is_some());
+
+        let older_than = Gc {
+            older_than: Some(Duration::from_secs(60)),
+            ..Gc::default()
+        };
+        gc(&cache, &mut output, &older_than, false)?;
+        assert_eq!(
+            String::from_utf8(stdout.take())?,
+            "removed 0 results, 0 B\n"
+        );
+
+        let created_before = Gc {
+            created_before: Some(SystemTime::now()),
+            ..Gc::default()
+        };
+        gc(&cache, &mut output, &created_before, false)?;
+        assert!(String::from_utf8(stdout.take())?
+            .starts_with("removed echo second (created before cutoff)\nremoved 1 results, "));
+        assert!(cache.list()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut cmd = Command::new(ScopeBuilder::new().cmd("echo").build()?);
+        let mut expired = RecordOptions::default();
+        expired.set_cache_for(Some(Duration::ZERO));
+        cache.record(&mut cmd, &expired)?;
+        let stderr = SharedBuffer::default();
+        let mut output = Output::new(SharedBuffer::default(), stderr.clone());
+
+        assert_eq!(pin(&mut cmd, &cache, &mut output, true)?, 0);
+        assert!(
+            cache.find(cmd.hash(), &FindOptions::default())?.is_some(),
+            "pinned results are fresh whenever they expire"
+        );
+        gc(&cache, &mut output, &Gc::default(), false)?;
+        assert!(cache.read(cmd.hash())?.is_some(), "gc keeps pinned results");
+
+        let include_pinned = Gc {
+            include_pinned: true,
+            ..Gc::default()
+        };
+        gc(&cache, &mut output, &include_pinned, false)?;
+        assert!(cache.read(cmd.hash())?.is_none());
+
+        assert_eq!(pin(&mut cmd, &cache, &mut output, true)?, 1);
+        assert_eq!(
+            String::from_utf8(stderr.take())?,
+            format!("deja: no entry found in cache for {}\n", cmd.hash())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain_entry_created_in_the_future() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut cmd = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        let stdout = SharedBuffer::default();
+        let mut output = Output::new(stdout.clone(), SharedBuffer::default());
+
+        cache.record(&mut cmd, &RecordOptions::default())?;
+        let hash = cmd.hash().to_string();
+        let entry = cache.read(&hash)?.expect("recorded");
+        let future = SystemTime::now() + Duration::from_secs(3600);
+        cache.store(&hash, &entry.with_created(future))?;
+
+        let mut read_options = FindOptions::default();
+        read_options.set_max_age(Some(Duration::from_secs(1)));
+        explain(&mut cmd, &cache, &mut output, read_options, false)?;
+        let explanation = String::from_utf8(stdout.take())?;
+        assert!(explanation.contains("Fresh: entry"), "{explanation}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_why() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let mut cmd = Command::new(ScopeBuilder::new().cmd("true").build()?);
+        let mut recorded = cmd.clone();
+        let (stdout, stderr) = (SharedBuffer::default(), SharedBuffer::default());
+        let mut output = Output::new(stdout.clone(), stderr.clone());
+        let mut why = |read_options: FindOptions| -> anyhow::Result<(i32, String)> {
+            let status = test(
+                &mut cmd,
+                &cache,
+                &mut output,
+                read_options,
+                false,
+                true,
+                None,
+            )?;
+            assert!(stdout.take().is_empty(), "nothing written to stdout");
+            Ok((status, String::from_utf8(stderr.take())?))
+        };
+
+        assert_eq!(why(FindOptions::default())?, (1, "missing\n".into()));
+
+        let mut record_options = RecordOptions::default();
+        record_options.set_cache_for(Some(Duration::ZERO));
+        cache.record(&mut recorded, &record_options)?;
+        assert_eq!(why(FindOptions::default())?, (2, "expired 0s ago\n".into()));
+
+        cache.record(&mut recorded, &RecordOptions::default())?;
+        assert_eq!(why(FindOptions::default())?, (0, "".into()));
+
+        let mut look_back = FindOptions::default();
+        look_back.set_max_age(Some(Duration::ZERO));
+        assert_eq!(
+            why(look_back)?,
+            (3, "older than --look-back 0s (created 0s ago)\n".into())
+        );
+        Ok(())
+    }
+}
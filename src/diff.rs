@@ -0,0 +1,209 @@
+use regex::Regex;
+
+/// The number of unchanged lines shown around each change.
+const CONTEXT: usize = 3;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Edit<'a> {
+    Equal(&'a str),
+    Delete(&'a str),
+    Insert(&'a str),
+}
+
+/// Finds the shortest set of edits turning `a` into `b`, using Myers' algorithm. Memory use
+/// grows with the number of differences, rather than the size of the inputs.
+pub fn diff<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<Edit<'a>> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = n + m;
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * max as usize + 3];
+    let mut trace: Vec<Vec<isize>> = vec![];
+
+    'search: for d in 0..=max {
+        trace.push(v[(offset - d) as usize..=(offset + d) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let i = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    let mut edits = vec![];
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().skip(1).rev() {
+        // `trace[d]` holds the furthest points reached after d - 1 edits, on diagonals -d..=d
+        let d = d as isize;
+        let at = |k: isize| v[(k + d) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            edits.push(Edit::Equal(a[(x - 1) as usize]));
+            x -= 1;
+            y -= 1;
+        }
+
+        if x == prev_x {
+            edits.push(Edit::Insert(b[(y - 1) as usize]));
+        } else {
+            edits.push(Edit::Delete(a[(x - 1) as usize]));
+        }
+
+        x = prev_x;
+        y = prev_y;
+    }
+
+    while x > 0 && y > 0 {
+        edits.push(Edit::Equal(a[(x - 1) as usize]));
+        x -= 1;
+        y -= 1;
+    }
+
+    edits.reverse();
+    edits
+}
+
+/// Formats the differences between two texts as a unified diff, or returns `None` when they're
+/// the same. Lines matching `ignore` that are added or removed don't count as changes, though
+/// they're still shown when they're next to other changes.
+pub fn unified(
+    a: &str,
+    b: &str,
+    a_name: &str,
+    b_name: &str,
+    ignore: Option<&Regex>,
+) -> Option<String> {
+    let a_lines = a.lines().collect::<Vec<_>>();
+    let b_lines = b.lines().collect::<Vec<_>>();
+    let edits = diff(&a_lines, &b_lines);
+
+    let changes = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, edit)| match edit {
+            Edit::Equal(_) => false,
+            Edit::Delete(line) | Edit::Insert(line) => {
+                ignore.is_none_or(|ignore| !ignore.is_match(line))
+            }
+        })
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    if changes.is_empty() {
+        return None;
+    }
+
+    // Group changes close enough together that their context would overlap
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for change in changes {
+        match hunks.last_mut() {
+            Some((_, end)) if change <= *end + 2 * CONTEXT => *end = change,
+            _ => hunks.push((change, change)),
+        }
+    }
+
+    // The line in each text at which each edit starts
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut a_line, mut b_line) = (0, 0);
+    for edit in &edits {
+        positions.push((a_line, b_line));
+        match edit {
+            Edit::Equal(_) => {
+                a_line += 1;
+                b_line += 1;
+            }
+            Edit::Delete(_) => a_line += 1,
+            Edit::Insert(_) => b_line += 1,
+        }
+    }
+    positions.push((a_line, b_line));
+
+    let mut result = format!("--- {}\n+++ {}\n", a_name, b_name);
+    for (first, last) in hunks {
+        let start = first.saturating_sub(CONTEXT);
+        let end = (last + CONTEXT + 1).min(edits.len());
+        let (a_start, b_start) = positions[start];
+        let (a_end, b_end) = positions[end];
+
+        result.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            a_start + 1,
+            a_end - a_start,
+            b_start + 1,
+            b_end - b_start
+        ));
+
+        for edit in &edits[start..end] {
+            match edit {
+                Edit::Equal(line) => result.push_str(&format!(" {}\n", line)),
+                Edit::Delete(line) => result.push_str(&format!("-{}\n", line)),
+                Edit::Insert(line) => result.push_str(&format!("+{}\n", line)),
+            }
+        }
+    }
+
+    Some(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        assert_eq!(diff(&[], &[]), vec![]);
+        assert_eq!(
+            diff(&["a", "b", "c"], &["a", "c", "d"]),
+            vec![
+                Edit::Equal("a"),
+                Edit::Delete("b"),
+                Edit::Equal("c"),
+                Edit::Insert("d")
+            ]
+        );
+        assert_eq!(
+            diff(&["a"], &["b"]),
+            vec![Edit::Delete("a"), Edit::Insert("b")]
+        );
+        assert_eq!(diff(&[], &["a"]), vec![Edit::Insert("a")]);
+    }
+
+    #[test]
+    fn test_unified() {
+        assert_eq!(unified("a\nb\n", "a\nb\n", "old", "new", None), None);
+
+        let a = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let b = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n10\n";
+        assert_eq!(
+            unified(a, b, "old", "new", None).unwrap(),
+            "--- old\n+++ new\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n"
+        );
+
+        let ignore = Regex::new("^at ").unwrap();
+        let (a, b) = ("at 10:00\nok\n", "at 10:05\nok\n");
+        assert_eq!(unified(a, b, "old", "new", Some(&ignore)), None);
+        assert_eq!(
+            unified(a, "at 10:05\nfailed\n", "old", "new", Some(&ignore)).unwrap(),
+            "--- old\n+++ new\n@@ -1,2 +1,2 @@\n-at 10:00\n-ok\n+at 10:05\n+failed\n",
+            "ignored lines are shown alongside other changes"
+        );
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::cache::{
+    is_not_found, unable_to_read_cache_entry_error, unable_to_write_to_cache_error, CacheEntry,
+    CorruptOutput, DiskCache, DiskCacheEntry,
+};
+use crate::deja::describe_size;
+use crate::output::Output;
+
+/// Files changed this recently might belong to a result that's still being recorded, so are
+/// never treated as orphaned.
+const ORPHAN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// A problem found in a disk cache.
+enum Problem {
+    /// An entry whose file can't be parsed.
+    Unreadable(PathBuf),
+    /// An entry whose captured output is missing.
+    MissingOutput(DiskCacheEntry),
+    /// An entry whose captured output doesn't match the checksums recorded with it.
+    CorruptOutput(DiskCacheEntry, CorruptOutput),
+    /// Output that no entry refers to, or a file left behind by an interrupted write, with the
+    /// space it takes.
+    Orphaned(Vec<PathBuf>, usize),
+    /// A file or directory without the mode the cache gives those it creates, and that mode.
+    Permissions(PathBuf, u32, u32),
+}
+
+/// Names an entry by its command, or by its path when it's encrypted and can't be decrypted.
+fn name(entry: &DiskCacheEntry) -> String {
+    if entry.is_locked() {
+        entry.path().display().to_string()
+    } else {
+        entry.command().to_string()
+    }
+}
+
+impl Problem {
+    fn describe(&self) -> String {
+        match self {
+            Problem::Unreadable(path) => format!("unreadable entry {}", path.display()),
+            Problem::MissingOutput(entry) => format!("{}: output is missing", name(entry)),
+            Problem::CorruptOutput(entry, corrupt) => format!("{}: {}", name(entry), corrupt),
+            Problem::Orphaned(paths, size) => {
+                format!("orphaned {} ({})", paths[0].display(), describe_size(*size))
+            }
+            Problem::Permissions(path, mode, expected) => format!(
+                "{} has mode {:o}, expected {:o}",
+                path.display(),
+                mode,
+                expected
+            ),
+        }
+    }
+
+    /// Fixes the problem, returning what was done.
+    fn fix(&self) -> anyhow::Result<String> {
+        match self {
+            Problem::Unreadable(path) => {
+                std::fs::rename(path, DiskCache::corrupt_path(path))
+                    .map_err(|_| unable_to_write_to_cache_error(path))?;
+                Ok("set aside".into())
+            }
+            Problem::MissingOutput(entry) => {
+                entry
+                    .set_aside(None)
+                    .map_err(|_| unable_to_write_to_cache_error(entry.path()))?;
+                Ok("set aside".into())
+            }
+            Problem::CorruptOutput(entry, corrupt) => {
+                entry
+                    .set_aside(Some(corrupt.path()))
+                    .map_err(|_| unable_to_write_to_cache_error(entry.path()))?;
+                Ok("set aside".into())
+            }
+            Problem::Orphaned(paths, _) => {
+                for path in paths {
+                    match std::fs::remove_file(path) {
+                        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                            return Err(unable_to_write_to_cache_error(path))
+                        }
+                        _ => (),
+                    }
+                }
+                Ok("removed".into())
+            }
+            Problem::Permissions(path, _, expected) => {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(*expected))
+                    .map_err(|_| unable_to_write_to_cache_error(path))?;
+                Ok(format!("changed to {:o}", expected))
+            }
+        }
+    }
+}
+
+/// What was found when checking a cache.
+#[derive(Default)]
+struct Diagnosis {
+    problems: Vec<Problem>,
+    /// How many entries were checked, including previous results kept with `--keep-history`.
+    checked: usize,
+    /// How many entries had no checksums, so their output's contents couldn't be checked.
+    unverified: usize,
+}
+
+/// Checks a disk cache for problems, writing a line for each one found and then a summary.
+///
+/// Each entry must be readable, and its output must exist and match the length recorded with
+/// it (and with `verify`, its contents must match the recorded checksum too). Output that no
+/// entry refers to, or left behind by an interrupted write, is orphaned, and files and
+/// directories must have the modes the cache gives those it creates. With `fix`, entries with
+/// problems are set aside, orphaned files removed and modes changed. Returns 1 if problems were
+/// found and not fixed.
+pub fn doctor(
+    cache: &DiskCache,
+    output: &mut Output,
+    verify: bool,
+    fix: bool,
+) -> anyhow::Result<i32> {
+    if fix && cache.is_read_only() {
+        return Err(cache.read_only_error());
+    }
+
+    let diagnosis = diagnose(cache, verify)?;
+    let mut reclaimable = 0;
+    for problem in &diagnosis.problems {
+        if let Problem::Orphaned(_, size) = problem {
+            reclaimable += size;
+        }
+        if fix {
+            writeln!(output.stdout, "{}, {}", problem.describe(), problem.fix()?)?;
+        } else {
+            writeln!(output.stdout, "{}", problem.describe())?;
+        }
+    }
+
+    writeln!(
+        output.stdout,
+        "checked {} results, {} {} problems, {} {}",
+        diagnosis.checked,
+        if fix { "fixed" } else { "found" },
+        diagnosis.problems.len(),
+        describe_size(reclaimable),
+        if fix { "reclaimed" } else { "reclaimable" }
+    )?;
+    if diagnosis.unverified > 0 {
+        writeln!(
+            output.stdout,
+            "{} results recorded without checksums were only checked for missing output",
+            diagnosis.unverified
+        )?;
+    }
+    Ok(if fix || diagnosis.problems.is_empty() {
+        0
+    } else {
+        1
+    })
+}
+
+fn diagnose(cache: &DiskCache, verify: bool) -> anyhow::Result<Diagnosis> {
+    let mut diagnosis = Diagnosis::default();
+    let modes = cache.modes();
+    let root = cache.root();
+    let blobs = root.join("blobs");
+    let recent = SystemTime::now() - ORPHAN_AGE;
+
+    for dir in [root, &blobs] {
+        check_mode(&mut diagnosis, dir, modes.dir)?;
+    }
+
+    // Output can only be found to be orphaned once every entry referring to it has been read
+    let mut referenced = BTreeSet::new();
+    let mut outputs = vec![];
+    for path in read_dir(root)? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.ends_with(".tmp") {
+            if !changed_since(&path, recent) {
+                orphan(&mut diagnosis, vec![path]);
+            }
+            continue;
+        } else if name.ends_with(".out") || name.ends_with(".err") {
+            check_mode(&mut diagnosis, &path, modes.file)?;
+            outputs.push(path);
+            continue;
+        } else if !name.ends_with(".ron") {
+            continue;
+        }
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            // Removed by another process since listing
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(_) => return Err(unable_to_read_cache_entry_error(&path)),
+        };
+        diagnosis.checked += 1;
+        check_mode(&mut diagnosis, &path, modes.file)?;
+
+        let entry = match cache.parse_entry(path.clone(), contents) {
+            Ok(entry) => entry,
+            Err(_) => {
+                diagnosis.problems.push(Problem::Unreadable(path));
+                continue;
+            }
+        };
+        referenced.extend(entry.output_paths().map(Path::to_path_buf));
+        match entry.verify_output(verify) {
+            Ok(true) => (),
+            Ok(false) => diagnosis.unverified += 1,
+            Err(e) if is_not_found(&e) => diagnosis.problems.push(Problem::MissingOutput(entry)),
+            Err(e) => match e.downcast::<CorruptOutput>() {
+                Ok(corrupt) => diagnosis
+                    .problems
+                    .push(Problem::CorruptOutput(entry, corrupt)),
+                Err(e) => return Err(e),
+            },
+        }
+    }
+
+    for path in outputs {
+        if !referenced.contains(&path) && !changed_since(&path, recent) {
+            orphan(&mut diagnosis, vec![path]);
+        }
+    }
+
+    if !blobs.exists() {
+        return Ok(diagnosis);
+    }
+    for path in read_dir(&blobs)? {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if let Some(blob) = name.strip_suffix(".refs") {
+            check_mode(&mut diagnosis, &path, modes.file)?;
+            // A count left behind by a blob that's been removed by hand
+            if !blobs.join(blob).exists() && !changed_since(&path, recent) {
+                orphan(&mut diagnosis, vec![path]);
+            }
+        } else if name.len() == 40 && name.chars().all(|c| c.is_ascii_hexdigit()) {
+            check_mode(&mut diagnosis, &path, modes.file)?;
+            // Blobs are stored, and their counts updated, just before their entry is written
+            let refs = blobs.join(format!("{}.refs", name));
+            if !referenced.contains(&path)
+                && !changed_since(&path, recent)
+                && !changed_since(&refs, recent)
+            {
+                orphan(&mut diagnosis, vec![path, refs]);
+            }
+        }
+    }
+
+    Ok(diagnosis)
+}
+
+/// The paths of everything in a directory, in order.
+fn read_dir(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(dir)
+        .and_then(|entries| entries.map(|entry| Ok(entry?.path())).collect())
+        .map_err(|_| unable_to_read_cache_entry_error(dir))?;
+    paths.sort();
+    Ok(paths)
+}
+
+fn changed_since(path: &Path, time: SystemTime) -> bool {
+    path.metadata()
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified > time)
+}
+
+/// Adds orphaned files, which are removed together when fixed.
+fn orphan(diagnosis: &mut Diagnosis, paths: Vec<PathBuf>) {
+    let size = paths
+        .iter()
+        .filter_map(|path| path.metadata().ok())
+        .map(|metadata| metadata.len() as usize)
+        .sum();
+    diagnosis.problems.push(Problem::Orphaned(paths, size));
+}
+
+fn check_mode(diagnosis: &mut Diagnosis, path: &Path, expected: u32) -> anyhow::Result<()> {
+    let mode = match path.metadata() {
+        Ok(metadata) => metadata.permissions().mode() & 0o7777,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(_) => return Err(unable_to_read_cache_entry_error(path)),
+    };
+    if mode != expected {
+        diagnosis
+            .problems
+            .push(Problem::Permissions(path.to_path_buf(), mode, expected));
+    }
+    Ok(())
+}
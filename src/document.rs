@@ -0,0 +1,149 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The format of a document containing a watched value.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentFormat {
+    /// A JSON document.
+    Json,
+    /// A YAML document.
+    Yaml,
+}
+
+impl std::fmt::Display for DocumentFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DocumentFormat::Json => write!(f, "json"),
+            DocumentFormat::Yaml => write!(f, "yaml"),
+        }
+    }
+}
+
+/// A value extracted from a JSON or YAML document using a JSON pointer. The
+/// value is stored in a canonical form (compact JSON with sorted keys), so
+/// formatting changes and key order in the document don't affect it.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WatchedValue {
+    /// The format of the document.
+    pub format: DocumentFormat,
+    /// The document the value is read from.
+    #[serde(with = "crate::command::stored_path")]
+    pub path: PathBuf,
+    /// The JSON pointer to the value within the document.
+    pub pointer: String,
+    /// The value, in canonical form.
+    pub value: String,
+}
+
+impl WatchedValue {
+    /// Parses a `<path>:<pointer>` argument, and extracts the value from the document.
+    pub fn parse(format: DocumentFormat, arg: &str) -> anyhow::Result<Self> {
+        let (path, pointer) = split_path_and_pointer(arg).ok_or_else(|| {
+            anyhow!(
+                "invalid --watch-{} '{}', use <path>:<pointer> like package.json:/dependencies",
+                format,
+                arg
+            )
+        })?;
+
+        let path = std::fs::canonicalize(path)
+            .map_err(|_| anyhow!("watch {} path '{}' not found", format, path))?;
+
+        Self::extract(format, &path, pointer)
+    }
+
+    /// Extracts the value at `pointer` from the document at `path`.
+    pub fn extract(format: DocumentFormat, path: &Path, pointer: &str) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("unable to read '{}': {}", path.display(), e))?;
+
+        let document: serde_json::Value = match format {
+            DocumentFormat::Json => serde_json::from_str(&contents)
+                .map_err(|e| anyhow!("unable to parse json in '{}': {}", path.display(), e))?,
+            DocumentFormat::Yaml => serde_yaml::from_str(&contents)
+                .map_err(|e| anyhow!("unable to parse yaml in '{}': {}", path.display(), e))?,
+        };
+
+        let value = document
+            .pointer(pointer)
+            .ok_or_else(|| anyhow!("pointer '{}' not found in '{}'", pointer, path.display()))?;
+
+        Ok(WatchedValue {
+            format,
+            path: path.to_path_buf(),
+            pointer: pointer.to_string(),
+            value: serde_json::to_string(value)?,
+        })
+    }
+}
+
+/// Splits `<path>:<pointer>` where the pointer is either empty (the whole
+/// document) or starts with `/`.
+fn split_path_and_pointer(arg: &str) -> Option<(&str, &str)> {
+    if let Some(index) = arg.find(":/") {
+        Some((&arg[..index], &arg[index + 1..]))
+    } else {
+        arg.strip_suffix(':').map(|path| (path, ""))
+    }
+    .filter(|(path, _)| !path.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixture(name: &str, contents: &str) -> anyhow::Result<PathBuf> {
+        let dir = std::env::temp_dir().join(format!("deja-document-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(name);
+        std::fs::write(&path, contents)?;
+        Ok(path)
+    }
+
+    #[test]
+    fn test_split_path_and_pointer() {
+        assert_eq!(
+            split_path_and_pointer("package.json:/dependencies"),
+            Some(("package.json", "/dependencies"))
+        );
+        assert_eq!(
+            split_path_and_pointer("package.json:"),
+            Some(("package.json", ""))
+        );
+        assert_eq!(split_path_and_pointer("package.json"), None);
+        assert_eq!(split_path_and_pointer(":/a"), None);
+    }
+
+    #[test]
+    fn test_extract_json() -> anyhow::Result<()> {
+        let a = fixture(
+            "a.json",
+            r#"{"deps": {"b": "2", "a": "1"}, "description": "x"}"#,
+        )?;
+        let b = fixture(
+            "b.json",
+            "{\"description\": \"y\",\n \"deps\": {\"a\": \"1\", \"b\": \"2\"}}",
+        )?;
+
+        let a_value = WatchedValue::extract(DocumentFormat::Json, &a, "/deps")?;
+        let b_value = WatchedValue::extract(DocumentFormat::Json, &b, "/deps")?;
+
+        assert_eq!(a_value.value, r#"{"a":"1","b":"2"}"#, "value is canonical");
+        assert_eq!(a_value.value, b_value.value, "key order doesn't matter");
+
+        assert!(WatchedValue::extract(DocumentFormat::Json, &a, "/missing").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_yaml() -> anyhow::Result<()> {
+        let a = fixture("a.yml", "deps:\n  a: 1\n  b: [1, 2]\n")?;
+
+        let value = WatchedValue::extract(DocumentFormat::Yaml, &a, "/deps/b")?;
+        assert_eq!(value.value, "[1,2]");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,392 @@
+use anyhow::anyhow;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The fewest bytes a key file can hold, so keys are never weak enough to guess.
+const MIN_KEY_LENGTH: usize = 32;
+
+/// The length of the random nonce stored before each encrypted value.
+const NONCE_LENGTH: usize = 24;
+
+/// The length of the tag stored after each encrypted value, which authenticates it.
+const TAG_LENGTH: usize = 16;
+
+/// How much output is encrypted at a time. Each chunk is encrypted separately, with its own
+/// nonce and tag, so output never has to be held in memory all at once.
+const CHUNK_LENGTH: usize = 64 * 1024;
+
+/// The length of a chunk of output once encrypted. Only the last chunk can be shorter.
+const ENCRYPTED_CHUNK_LENGTH: usize = NONCE_LENGTH + CHUNK_LENGTH + TAG_LENGTH;
+
+/// The key used by `--encrypt` to encrypt entries and their output with XChaCha20-Poly1305,
+/// derived from the contents of a key file.
+pub struct EncryptionKey {
+    cipher: XChaCha20Poly1305,
+    /// A separate key for naming encrypted output, so blob names don't reveal what's in them.
+    names: [u8; 32],
+}
+
+impl EncryptionKey {
+    /// Reads a key from a file holding at least 32 bytes, like one made by
+    /// `head -c 32 /dev/urandom`.
+    pub fn from_file(path: &Path) -> anyhow::Result<EncryptionKey> {
+        let contents = std::fs::read(path)
+            .map_err(|e| anyhow!("unable to read encryption key {}: {}", path.display(), e))?;
+        if contents.len() < MIN_KEY_LENGTH {
+            return Err(anyhow!(
+                "encryption key {} is too short, it needs at least {} bytes",
+                path.display(),
+                MIN_KEY_LENGTH
+            ));
+        }
+        Ok(EncryptionKey::from_bytes(&contents))
+    }
+
+    /// Derives a key from any bytes, which should be random.
+    pub fn from_bytes(bytes: &[u8]) -> EncryptionKey {
+        let cipher = blake3::derive_key("deja 2026-10-16 cache encryption", bytes);
+        EncryptionKey {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&cipher)),
+            names: blake3::derive_key("deja 2026-10-16 output names", bytes),
+        }
+    }
+
+    /// Encrypts a value with a new random nonce, which is stored ahead of it.
+    pub(crate) fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| anyhow!("unable to encrypt"))?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypts a value made by `encrypt`, or returns `None` if it was encrypted with another
+    /// key (or has been changed since).
+    pub(crate) fn decrypt(&self, encrypted: &[u8]) -> Option<Vec<u8>> {
+        if encrypted.len() < NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LENGTH);
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .ok()
+    }
+
+    /// Encrypts a chunk of output. Its position, and whether it's the last chunk, are
+    /// authenticated along with it, so chunks can't be reordered, removed or cut short unnoticed.
+    fn encrypt_chunk(&self, index: u64, last: bool, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = chunk_aad(index, last);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: chunk,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| std::io::Error::other("unable to encrypt output"))?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypts a chunk made by `encrypt_chunk`, or returns `None` if it was encrypted with
+    /// another key, or isn't the chunk expected.
+    fn decrypt_chunk(&self, index: u64, last: bool, encrypted: &[u8]) -> Option<Vec<u8>> {
+        if encrypted.len() < NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LENGTH);
+        let aad = chunk_aad(index, last);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .ok()
+    }
+
+    /// The name encrypted output is stored under, given the hash of its contents. Names are
+    /// the same length as unencrypted ones, but only match for output encrypted with this key.
+    pub(crate) fn blob_name(&self, hash: &str) -> String {
+        blake3::keyed_hash(&self.names, hash.as_bytes()).to_hex()[..40].to_string()
+    }
+}
+
+fn chunk_aad(index: u64, last: bool) -> [u8; 9] {
+    let mut aad = [0; 9];
+    aad[..8].copy_from_slice(&index.to_be_bytes());
+    aad[8] = last as u8;
+    aad
+}
+
+/// Encrypts output as it's written, a chunk at a time. Once everything has been written,
+/// `finish` writes the last chunk, which is written even when empty, so output can't be cut
+/// short at the end of a chunk unnoticed.
+pub(crate) struct OutputEncryptor<'a, W: Write> {
+    key: &'a EncryptionKey,
+    writer: W,
+    chunk: Vec<u8>,
+    index: u64,
+}
+
+impl<'a, W: Write> OutputEncryptor<'a, W> {
+    pub(crate) fn new(key: &'a EncryptionKey, writer: W) -> Self {
+        OutputEncryptor {
+            key,
+            writer,
+            chunk: Vec::with_capacity(CHUNK_LENGTH),
+            index: 0,
+        }
+    }
+
+    fn write_chunk(&mut self, last: bool) -> std::io::Result<()> {
+        let encrypted = self.key.encrypt_chunk(self.index, last, &self.chunk)?;
+        self.writer.write_all(&encrypted)?;
+        self.chunk.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    /// Writes the last chunk, returning the writer.
+    pub(crate) fn finish(mut self) -> std::io::Result<W> {
+        self.write_chunk(true)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for OutputEncryptor<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // A full chunk is only written once there's more to follow, as until then it could be
+        // the last
+        if self.chunk.len() == CHUNK_LENGTH && !buf.is_empty() {
+            self.write_chunk(false)?;
+        }
+        let length = buf.len().min(CHUNK_LENGTH - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..length]);
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decrypts output made by `OutputEncryptor` as it's read, a chunk at a time. Output that was
+/// encrypted with another key, or has been changed or cut short since, fails to read.
+pub(crate) struct OutputDecryptor<R: Read> {
+    key: Arc<EncryptionKey>,
+    reader: R,
+    index: u64,
+    /// The chunk being read, and how much of it has been read.
+    chunk: Vec<u8>,
+    position: usize,
+    /// The first byte of the next chunk, read to find out whether the last chunk was the last.
+    next: Option<u8>,
+    finished: bool,
+}
+
+impl<R: Read> OutputDecryptor<R> {
+    pub(crate) fn new(key: Arc<EncryptionKey>, reader: R) -> Self {
+        OutputDecryptor {
+            key,
+            reader,
+            index: 0,
+            chunk: vec![],
+            position: 0,
+            next: None,
+            finished: false,
+        }
+    }
+
+    fn read_chunk(&mut self) -> std::io::Result<()> {
+        let mut encrypted = Vec::with_capacity(ENCRYPTED_CHUNK_LENGTH);
+        encrypted.extend(self.next.take());
+        let remaining = ENCRYPTED_CHUNK_LENGTH - encrypted.len();
+        (&mut self.reader)
+            .take(remaining as u64)
+            .read_to_end(&mut encrypted)?;
+
+        // Only a full chunk can be followed by another
+        let last = encrypted.len() < ENCRYPTED_CHUNK_LENGTH || {
+            let mut next = [0];
+            let read = loop {
+                match self.reader.read(&mut next) {
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    read => break read?,
+                }
+            };
+            self.next = (read == 1).then_some(next[0]);
+            read == 0
+        };
+
+        self.chunk = self
+            .key
+            .decrypt_chunk(self.index, last, &encrypted)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "unable to decrypt output: wrong key, or changed since it was written",
+                )
+            })?;
+        self.position = 0;
+        self.index += 1;
+        self.finished = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for OutputDecryptor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.finished || buf.is_empty() {
+                return Ok(0);
+            }
+            self.read_chunk()?;
+        }
+        let length = buf.len().min(self.chunk.len() - self.position);
+        buf[..length].copy_from_slice(&self.chunk[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+/// Keys are never printed, even in debug output.
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// The error when an entry can't be decrypted with the key given, if any.
+#[derive(Debug)]
+struct WrongOrMissingKey(PathBuf);
+
+impl std::fmt::Display for WrongOrMissingKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unable to decrypt cache entry {}: wrong or missing encryption key",
+            self.0.display()
+        )
+    }
+}
+
+impl std::error::Error for WrongOrMissingKey {}
+
+pub(crate) fn wrong_or_missing_key_error(path: &Path) -> anyhow::Error {
+    WrongOrMissingKey(path.to_path_buf()).into()
+}
+
+/// Whether an error is from reading an entry that can't be decrypted with the key given.
+pub(crate) fn is_wrong_or_missing_key(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<WrongOrMissingKey>().is_some()
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let key = EncryptionKey::from_bytes(b"0123456789abcdef0123456789abcdef");
+        let encrypted = key.encrypt(b"secret").unwrap();
+        assert!(!encrypted.windows(6).any(|window| window == b"secret"));
+        assert_eq!(key.decrypt(&encrypted).unwrap(), b"secret");
+        assert_ne!(
+            key.encrypt(b"secret").unwrap(),
+            encrypted,
+            "each value has its own nonce"
+        );
+
+        let other = EncryptionKey::from_bytes(b"fedcba9876543210fedcba9876543210");
+        assert!(other.decrypt(&encrypted).is_none(), "wrong key");
+        assert!(key.decrypt(&encrypted[..10]).is_none(), "truncated");
+
+        assert_eq!(key.blob_name("abc").len(), 40);
+        assert_ne!(key.blob_name("abc"), other.blob_name("abc"));
+    }
+
+    fn encrypt_output(key: &EncryptionKey, output: &[u8]) -> Vec<u8> {
+        let mut encryptor = OutputEncryptor::new(key, vec![]);
+        encryptor.write_all(output).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt_output(key: EncryptionKey, encrypted: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut output = vec![];
+        OutputDecryptor::new(Arc::new(key), encrypted).read_to_end(&mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt_output() {
+        let key = || EncryptionKey::from_bytes(b"0123456789abcdef0123456789abcdef");
+        for length in [0, 1, CHUNK_LENGTH - 1, CHUNK_LENGTH, 3 * CHUNK_LENGTH + 7] {
+            let output: Vec<u8> = (0..length).map(|i| (i % 251) as u8).collect();
+            let encrypted = encrypt_output(&key(), &output);
+            let chunks = length.div_ceil(CHUNK_LENGTH).max(1);
+            assert_eq!(
+                encrypted.len(),
+                length + chunks * (NONCE_LENGTH + TAG_LENGTH),
+                "{length}: one chunk per {CHUNK_LENGTH} bytes"
+            );
+            assert_eq!(
+                decrypt_output(key(), &encrypted).unwrap(),
+                output,
+                "{length}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_decrypt_changed_output() {
+        let key = || EncryptionKey::from_bytes(b"0123456789abcdef0123456789abcdef");
+        let output = vec![7; 2 * CHUNK_LENGTH];
+        let encrypted = encrypt_output(&key(), &output);
+        let (first, rest) = encrypted.split_at(ENCRYPTED_CHUNK_LENGTH);
+
+        let other = EncryptionKey::from_bytes(b"fedcba9876543210fedcba9876543210");
+        assert!(decrypt_output(other, &encrypted).is_err(), "wrong key");
+        assert!(decrypt_output(key(), first).is_err(), "missing last chunk");
+        assert!(decrypt_output(key(), rest).is_err(), "missing first chunk");
+        assert!(
+            decrypt_output(key(), &[rest, first].concat()).is_err(),
+            "reordered"
+        );
+        assert!(
+            decrypt_output(key(), &encrypted[..encrypted.len() - 1]).is_err(),
+            "truncated"
+        );
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff").unwrap(), vec![0, 15, 255]);
+        assert!(from_hex("0g").is_none());
+        assert!(from_hex("abc").is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+
+/// Variables with names matching these patterns have their values redacted when recorded.
+pub const DEFAULT_REDACT_PATTERNS: [&str; 7] = [
+    "*SECRET*",
+    "*TOKEN*",
+    "*PASSWORD*",
+    "*PASSWD*",
+    "*KEY*",
+    "*CREDENTIAL*",
+    "*AUTH*",
+];
+
+/// The most variables recorded in a snapshot.
+const MAX_VARIABLES: usize = 64;
+
+/// The longest value recorded in a snapshot, in bytes. Longer values are truncated.
+const MAX_VALUE_LENGTH: usize = 1024;
+
+const REDACTED: &str = "[redacted]";
+
+/// Which environment variables are recorded alongside a result, and which are redacted.
+#[derive(Debug, Default, Clone)]
+pub struct EnvSnapshotOptions {
+    /// Patterns (using `*` as a wildcard) for the names of variables to record.
+    pub patterns: Vec<String>,
+    /// Patterns for the names of variables whose values are redacted.
+    pub redact: Vec<String>,
+}
+
+impl EnvSnapshotOptions {
+    /// Records the current values of matching variables. The snapshot is bounded in size, so a
+    /// large environment can't bloat every entry.
+    pub fn snapshot(&self) -> BTreeMap<String, String> {
+        let mut snapshot = BTreeMap::new();
+
+        let mut vars = std::env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value)))
+            .filter(|(name, _)| self.patterns.iter().any(|p| matches(p, name)))
+            .collect::<Vec<_>>();
+        vars.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        for (name, value) in vars.into_iter().take(MAX_VARIABLES) {
+            let value = if self.redact.iter().any(|p| matches(p, &name)) {
+                REDACTED.to_string()
+            } else {
+                truncate(value.to_string_lossy().to_string())
+            };
+            snapshot.insert(name, value);
+        }
+
+        snapshot
+    }
+}
+
+fn truncate(mut value: String) -> String {
+    if value.len() > MAX_VALUE_LENGTH {
+        let mut end = MAX_VALUE_LENGTH;
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        value.truncate(end);
+        value.push('…');
+    }
+    value
+}
+
+/// Matches a name against a pattern, where `*` matches any sequence of characters. Matching
+/// ignores case, so `*token*` matches `GITHUB_TOKEN`.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_uppercase();
+    let name = name.to_uppercase();
+    let mut parts = pattern.split('*');
+
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts = parts.collect::<Vec<_>>();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.ends_with(last)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("PATH", "PATH"));
+        assert!(!matches("PATH", "MANPATH"));
+        assert!(matches("*PATH", "MANPATH"));
+        assert!(matches("AWS_*", "AWS_REGION"));
+        assert!(!matches("AWS_*", "MY_AWS_REGION"));
+        assert!(matches("*token*", "GITHUB_TOKEN_V2"));
+        assert!(matches("A*B*C", "AXXBYYC"));
+        assert!(!matches("A*B*C", "AXXCYYB"));
+        assert!(matches("*", "ANYTHING"));
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(truncate("short".into()), "short");
+        let long = "é".repeat(MAX_VALUE_LENGTH);
+        assert!(truncate(long).len() <= MAX_VALUE_LENGTH + '…'.len_utf8());
+    }
+}
@@ -0,0 +1,479 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Finds the root of the git repository containing `path`, by walking up the
+/// directory tree looking for a `.git` directory (or file, in the case of
+/// worktrees and submodules).
+pub fn find_root(path: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .map(|dir| dir.to_path_buf())
+}
+
+/// What part of the repository state is included in the cache key.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum GitWatchMode {
+    /// The commit checked out.
+    Head,
+    /// The commit checked out, and whether tracked files have been changed.
+    HeadDirty,
+    /// The tag pointing at the commit checked out (or the abbreviated commit when
+    /// there isn't one), and whether tracked files have been changed.
+    Describe,
+}
+
+impl std::str::FromStr for GitWatchMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "head" => Ok(GitWatchMode::Head),
+            "head-dirty" => Ok(GitWatchMode::HeadDirty),
+            "describe" => Ok(GitWatchMode::Describe),
+            _ => Err(anyhow!(
+                "invalid git watch mode '{}', use one of head, head-dirty or describe",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for GitWatchMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GitWatchMode::Head => write!(f, "head"),
+            GitWatchMode::HeadDirty => write!(f, "head-dirty"),
+            GitWatchMode::Describe => write!(f, "describe"),
+        }
+    }
+}
+
+/// The state of a git repository, as included in the cache key.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct GitState {
+    /// What of the repository's state is included.
+    pub mode: GitWatchMode,
+    /// The commit checked out, or `None` on a branch with no commits.
+    pub commit: Option<String>,
+    /// The tag pointing at the commit checked out (only when describing).
+    pub tag: Option<String>,
+    /// Whether tracked files have been changed (only when checking dirty state).
+    pub dirty: Option<bool>,
+}
+
+impl GitState {
+    /// Reads the state of the repository containing `path`, without running git.
+    pub fn read(path: &Path, mode: GitWatchMode) -> anyhow::Result<Self> {
+        let root = find_root(path)
+            .ok_or_else(|| anyhow!("not in a git repository: {}", path.display()))?;
+        let repository = Repository::open(&root)?;
+        let commit = repository.head()?;
+
+        let tag = match (mode, &commit) {
+            (GitWatchMode::Describe, Some(commit)) => repository.tag_for(commit)?,
+            _ => None,
+        };
+
+        let dirty = match mode {
+            GitWatchMode::Head => None,
+            _ => Some(repository.is_dirty()?),
+        };
+
+        Ok(GitState {
+            mode,
+            commit,
+            tag,
+            dirty,
+        })
+    }
+}
+
+impl std::fmt::Display for GitState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let commit = self.commit.as_deref().unwrap_or("unborn");
+        match (&self.mode, &self.tag) {
+            (GitWatchMode::Describe, Some(tag)) => write!(f, "{}", tag)?,
+            (GitWatchMode::Describe, None) => write!(f, "{:.7}", commit)?,
+            _ => write!(f, "{}", commit)?,
+        }
+        if self.dirty == Some(true) {
+            write!(f, "-dirty")?;
+        }
+        Ok(())
+    }
+}
+
+struct Repository {
+    /// The directory containing the working tree.
+    root: PathBuf,
+    /// The git directory for this working tree (holding `HEAD` and `index`).
+    git_dir: PathBuf,
+    /// The git directory shared between worktrees (holding refs).
+    common_dir: PathBuf,
+}
+
+impl Repository {
+    fn open(root: &Path) -> anyhow::Result<Self> {
+        let dot_git = root.join(".git");
+        let git_dir = if dot_git.is_file() {
+            let contents = read_to_string(&dot_git)?;
+            let path = contents.trim().strip_prefix("gitdir: ").ok_or_else(|| {
+                anyhow!("unable to read git directory from {}", dot_git.display())
+            })?;
+            root.join(path)
+        } else {
+            dot_git
+        };
+
+        let common_dir = match std::fs::read_to_string(git_dir.join("commondir")) {
+            Ok(path) => git_dir.join(path.trim()),
+            Err(_) => git_dir.clone(),
+        };
+
+        Ok(Repository {
+            root: root.to_path_buf(),
+            git_dir,
+            common_dir,
+        })
+    }
+
+    /// Resolves `HEAD` to a commit, following symbolic refs.
+    fn head(&self) -> anyhow::Result<Option<String>> {
+        let mut reference = read_to_string(&self.git_dir.join("HEAD"))?
+            .trim()
+            .to_string();
+
+        // Symbolic refs can point at other symbolic refs, but not indefinitely
+        for _ in 0..10 {
+            match reference.strip_prefix("ref: ") {
+                Some(name) => match self.resolve_ref(name)? {
+                    Some(target) => reference = target,
+                    None => return Ok(None),
+                },
+                None => return Ok(Some(reference)),
+            }
+        }
+
+        Err(anyhow!("unable to resolve git HEAD"))
+    }
+
+    /// Reads a ref, either from its own file or from `packed-refs`.
+    fn resolve_ref(&self, name: &str) -> anyhow::Result<Option<String>> {
+        for dir in [&self.git_dir, &self.common_dir] {
+            if let Ok(contents) = std::fs::read_to_string(dir.join(name)) {
+                return Ok(Some(contents.trim().to_string()));
+            }
+        }
+
+        Ok(self
+            .packed_refs()?
+            .into_iter()
+            .find(|(_, ref_name, _)| ref_name == name)
+            .map(|(commit, _, _)| commit))
+    }
+
+    /// Reads `packed-refs`, returning the object, name and peeled commit (for
+    /// annotated tags) of each ref.
+    fn packed_refs(&self) -> anyhow::Result<Vec<(String, String, Option<String>)>> {
+        let path = self.common_dir.join("packed-refs");
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut refs: Vec<(String, String, Option<String>)> = vec![];
+        for line in read_to_string(&path)?.lines() {
+            if let Some(peeled) = line.strip_prefix('^') {
+                if let Some(last) = refs.last_mut() {
+                    last.2 = Some(peeled.to_string());
+                }
+            } else if let Some((object, name)) = line.split_once(' ') {
+                if !line.starts_with('#') {
+                    refs.push((object.to_string(), name.to_string(), None));
+                }
+            }
+        }
+        Ok(refs)
+    }
+
+    /// Finds a lightweight or (packed) annotated tag pointing at the given commit.
+    /// When more than one tag matches, the first by name is used.
+    fn tag_for(&self, commit: &str) -> anyhow::Result<Option<String>> {
+        let mut tags = vec![];
+
+        for (object, name, peeled) in self.packed_refs()? {
+            if let Some(tag) = name.strip_prefix("refs/tags/") {
+                if object == commit || peeled.as_deref() == Some(commit) {
+                    tags.push(tag.to_string());
+                }
+            }
+        }
+
+        let tags_dir = self.common_dir.join("refs").join("tags");
+        if let Ok(entries) = std::fs::read_dir(&tags_dir) {
+            for entry in entries.flatten() {
+                let object = std::fs::read_to_string(entry.path()).unwrap_or_default();
+                if object.trim() == commit {
+                    tags.push(entry.file_name().to_string_lossy().to_string());
+                }
+            }
+        }
+
+        tags.sort();
+        Ok(tags.into_iter().next())
+    }
+
+    /// Checks whether any tracked file has been modified or deleted, by comparing
+    /// the working tree with the index. Files whose size and modification time
+    /// match the index are assumed unchanged (as git does), otherwise their
+    /// contents are hashed and compared. Untracked files aren't considered.
+    fn is_dirty(&self) -> anyhow::Result<bool> {
+        let index_path = self.git_dir.join("index");
+        if !index_path.exists() {
+            return Ok(false);
+        }
+
+        let index = std::fs::read(&index_path)
+            .map_err(|e| anyhow!("unable to read {}: {}", index_path.display(), e))?;
+
+        for entry in parse_index(&index)
+            .ok_or_else(|| anyhow!("unable to parse git index {}", index_path.display()))?
+        {
+            if entry.skip_worktree {
+                continue;
+            }
+
+            let path = self.root.join(std::ffi::OsStr::from_bytes(&entry.path));
+            let metadata = match path.symlink_metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => return Ok(true),
+            };
+
+            if metadata.size() as u32 != entry.size {
+                return Ok(true);
+            }
+
+            if metadata.mtime() as u32 == entry.mtime
+                && metadata.mtime_nsec() as u32 == entry.mtime_nsec
+            {
+                continue;
+            }
+
+            let contents = if metadata.is_symlink() {
+                std::fs::read_link(&path)?.as_os_str().as_bytes().to_vec()
+            } else {
+                std::fs::read(&path)?
+            };
+
+            if blob_id(&contents) != entry.id {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+fn read_to_string(path: &Path) -> anyhow::Result<String> {
+    std::fs::read_to_string(path).map_err(|e| anyhow!("unable to read {}: {}", path.display(), e))
+}
+
+/// The object id git gives to a blob with the given contents.
+fn blob_id(contents: &[u8]) -> [u8; 20] {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(format!("blob {}\0", contents.len()).as_bytes());
+    hasher.update(contents);
+    hasher.digest().bytes()
+}
+
+struct IndexEntry {
+    mtime: u32,
+    mtime_nsec: u32,
+    size: u32,
+    id: [u8; 20],
+    skip_worktree: bool,
+    path: Vec<u8>,
+}
+
+/// Parses the entries from a git index file (versions 2, 3 and 4).
+fn parse_index(data: &[u8]) -> Option<Vec<IndexEntry>> {
+    let u32_at = |offset: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(
+            data.get(offset..offset + 4)?.try_into().ok()?,
+        ))
+    };
+    let u16_at = |offset: usize| -> Option<u16> {
+        Some(u16::from_be_bytes(
+            data.get(offset..offset + 2)?.try_into().ok()?,
+        ))
+    };
+
+    if data.get(0..4)? != b"DIRC" {
+        return None;
+    }
+    let version = u32_at(4)?;
+    if !(2..=4).contains(&version) {
+        return None;
+    }
+    let count = u32_at(8)? as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    let mut offset = 12;
+    let mut previous_path: Vec<u8> = vec![];
+
+    for _ in 0..count {
+        let start = offset;
+        let mtime = u32_at(offset + 8)?;
+        let mtime_nsec = u32_at(offset + 12)?;
+        let size = u32_at(offset + 36)?;
+        let id: [u8; 20] = data.get(offset + 40..offset + 60)?.try_into().ok()?;
+        let flags = u16_at(offset + 60)?;
+        offset += 62;
+
+        let mut skip_worktree = false;
+        if flags & 0x4000 != 0 {
+            skip_worktree = u16_at(offset)? & 0x4000 != 0;
+            offset += 2;
+        }
+
+        let path = if version == 4 {
+            // Paths are prefix compressed: a varint giving the number of bytes to
+            // remove from the previous path, followed by the suffix to append
+            let mut strip: usize = 0;
+            loop {
+                let byte = *data.get(offset)?;
+                offset += 1;
+                strip = (strip << 7) | (byte & 0x7f) as usize;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+                strip += 1;
+            }
+            let end = offset + data.get(offset..)?.iter().position(|b| *b == 0)?;
+            let mut path = previous_path
+                .get(..previous_path.len().checked_sub(strip)?)?
+                .to_vec();
+            path.extend_from_slice(&data[offset..end]);
+            offset = end + 1;
+            path
+        } else {
+            let end = offset + data.get(offset..)?.iter().position(|b| *b == 0)?;
+            let path = data[offset..end].to_vec();
+            // Entries are padded with 1-8 nul bytes to a multiple of 8 bytes
+            offset = start + (end - start + 8) / 8 * 8;
+            path
+        };
+
+        previous_path = path.clone();
+        entries.push(IndexEntry {
+            mtime,
+            mtime_nsec,
+            size,
+            id,
+            skip_worktree,
+            path,
+        });
+    }
+
+    Some(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ulid::Ulid;
+
+    #[test]
+    fn test_find_root() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-git-{}", Ulid::new()));
+        let nested = root.join("a").join("b");
+        std::fs::create_dir_all(&nested)?;
+
+        assert_eq!(find_root(&nested), None, "no root outside a repository");
+
+        std::fs::create_dir(root.join(".git"))?;
+
+        assert_eq!(
+            find_root(&nested),
+            Some(root.clone()),
+            "finds root from nested directory"
+        );
+        assert_eq!(
+            find_root(&root),
+            Some(root.clone()),
+            "finds root from root directory"
+        );
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> anyhow::Result<()> {
+        let status = std::process::Command::new("git")
+            .args(["-c", "user.name=deja", "-c", "user.email=deja@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()?;
+        assert!(status.success(), "git {:?} failed", args);
+        Ok(())
+    }
+
+    #[test]
+    fn test_git_state() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-git-{}", Ulid::new()));
+        std::fs::create_dir_all(&root)?;
+
+        git(&root, &["init", "-q"])?;
+        let unborn = GitState::read(&root, GitWatchMode::HeadDirty)?;
+        assert_eq!(unborn.commit, None, "no commit on unborn branch");
+
+        std::fs::write(root.join("file"), "a")?;
+        git(&root, &["add", "file"])?;
+        git(&root, &["commit", "-q", "-m", "first"])?;
+
+        let clean = GitState::read(&root, GitWatchMode::HeadDirty)?;
+        assert_eq!(
+            clean.commit.as_ref().map(|c| c.len()),
+            Some(40),
+            "resolves commit"
+        );
+        assert_eq!(clean.dirty, Some(false), "clean after commit");
+
+        std::fs::write(root.join("file"), "b")?;
+        let dirty = GitState::read(&root, GitWatchMode::HeadDirty)?;
+        assert_eq!(dirty.dirty, Some(true), "dirty after change");
+        assert_eq!(
+            dirty.to_string(),
+            format!("{}-dirty", clean.commit.clone().unwrap())
+        );
+
+        std::fs::write(root.join("file"), "a")?;
+        let reverted = GitState::read(&root, GitWatchMode::HeadDirty)?;
+        assert_eq!(reverted.dirty, Some(false), "clean when contents reverted");
+
+        git(&root, &["tag", "-a", "v1", "-m", "v1"])?;
+        git(&root, &["pack-refs", "--all"])?;
+        let described = GitState::read(&root, GitWatchMode::Describe)?;
+        assert_eq!(
+            described.to_string(),
+            "v1",
+            "describes packed annotated tag"
+        );
+
+        std::fs::write(root.join("file"), "c")?;
+        git(&root, &["commit", "-q", "-a", "-m", "second"])?;
+        let untagged = GitState::read(&root, GitWatchMode::Describe)?;
+        assert_ne!(
+            untagged.commit, clean.commit,
+            "resolves new commit from packed ref"
+        );
+        assert_eq!(untagged.to_string().len(), 7, "abbreviates untagged commit");
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
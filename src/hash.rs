@@ -1,15 +1,58 @@
 use std::{
     collections::{HashMap, HashSet},
-    error::Error,
     ffi::OsString,
-    os::unix::ffi::OsStrExt,
-    path::PathBuf,
+    fs::FileType,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
-use merkle_hash::{Algorithm, MerkleTree};
+use anyhow::anyhow;
+use merkle_hash::Algorithm;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// How symlinks are treated when hashing watched paths.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SymlinkMode {
+    /// Hash the contents of the file or directory the link points to.
+    #[default]
+    Follow,
+    /// Hash the link target, without reading what it points to.
+    Target,
+    /// Leave symlinks out of the hash entirely.
+    Skip,
+}
+
+impl std::fmt::Display for SymlinkMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SymlinkMode::Follow => write!(f, "follow"),
+            SymlinkMode::Target => write!(f, "target"),
+            SymlinkMode::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+impl FromStr for SymlinkMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "follow" => Ok(SymlinkMode::Follow),
+            "target" => Ok(SymlinkMode::Target),
+            "skip" => Ok(SymlinkMode::Skip),
+            _ => Err(anyhow!(
+                "invalid symlink mode '{}', use one of follow, target or skip",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Hash {
     hash: Vec<u8>,
 }
@@ -78,25 +121,221 @@ impl From<&Option<OsString>> for Hash {
     }
 }
 
+impl Hash {
+    /// Hashes the contents of a file or directory. Directories are hashed as a
+    /// merkle tree of their children, with the name of each file and directory
+    /// included in its hash.
+    pub fn try_from_path(path: &Path, symlinks: SymlinkMode) -> anyhow::Result<Self> {
+        Hash::try_from_path_with_progress(path, symlinks, &mut |_, _| ())
+    }
+
+    /// Hashes the contents of a file or directory like `try_from_path`, calling `progress`
+    /// with the path and size in bytes of each file once it has been read.
+    pub fn try_from_path_with_progress(
+        path: &Path,
+        symlinks: SymlinkMode,
+        progress: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<Self> {
+        let hash = hash_path(path, symlinks, progress, &mut HashSet::new())?
+            .unwrap_or(Algorithm::Blake3.compute_hash(b""));
+        Ok(Hash { hash })
+    }
+
+    pub fn try_from_paths(paths: &[PathBuf], symlinks: SymlinkMode) -> anyhow::Result<Self> {
+        let hashes = paths
+            .iter()
+            .map(|path| Hash::try_from_path(path, symlinks))
+            .collect::<Result<Vec<Hash>, anyhow::Error>>();
+
+        Ok(Hash::from(&hashes?))
+    }
+}
+
+/// Builds a hash from a sequence of fields in a single pass, without hashing each field
+/// separately first. Every field is written with its length (and optional fields and lists
+/// with a marker or count), so different sequences of fields never give the same input.
+pub struct HashBuilder {
+    hasher: blake3::Hasher,
+}
+
+impl HashBuilder {
+    /// Starts a hash for the given domain, such as the name of a scope component, so values
+    /// hashed for different purposes never collide.
+    pub fn new(domain: &str) -> Self {
+        let mut builder = HashBuilder {
+            hasher: blake3::Hasher::new(),
+        };
+        builder.bytes(domain.as_bytes());
+        builder
+    }
+
+    fn len(&mut self, len: usize) -> &mut Self {
+        self.hasher.update(&(len as u64).to_le_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.len(bytes.len());
+        self.hasher.update(bytes);
+        self
+    }
+
+    pub fn str(&mut self, s: &str) -> &mut Self {
+        self.bytes(s.as_bytes())
+    }
+
+    pub fn bool(&mut self, b: bool) -> &mut Self {
+        self.hasher.update(&[b as u8]);
+        self
+    }
+
+    pub fn hash(&mut self, hash: &Hash) -> &mut Self {
+        self.bytes(&hash.hash)
+    }
+
+    /// Writes a marker for whether the value is present, followed by the value itself.
+    pub fn optional(&mut self, bytes: Option<&[u8]>) -> &mut Self {
+        self.bool(bytes.is_some());
+        if let Some(bytes) = bytes {
+            self.bytes(bytes);
+        }
+        self
+    }
+
+    /// Writes the number of strings, followed by each in turn.
+    pub fn strs<'a>(&mut self, strings: impl ExactSizeIterator<Item = &'a str>) -> &mut Self {
+        self.len(strings.len());
+        for s in strings {
+            self.str(s);
+        }
+        self
+    }
+
+    /// Writes the number of items, which should be followed by writing each one.
+    pub fn count(&mut self, count: usize) -> &mut Self {
+        self.len(count)
+    }
+
+    pub fn finish(&self) -> Hash {
+        Hash {
+            hash: self.hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+fn unable_to_hash_path_error(path: &Path, e: std::io::Error) -> anyhow::Error {
+    anyhow!("unable to read watch path '{}': {}", path.display(), e)
+}
+
+/// Returns the hash of the given path, or `None` if it should be left out of the hash
+/// (when it's a symlink and symlinks are skipped). `visiting` holds the device and inode of
+/// each directory being hashed further up, so a symlink back to one is never followed forever.
+fn hash_path(
+    path: &Path,
+    symlinks: SymlinkMode,
+    progress: &mut dyn FnMut(&Path, u64),
+    visiting: &mut HashSet<(u64, u64)>,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let algorithm = Algorithm::Blake3;
+    let metadata = path
+        .symlink_metadata()
+        .map_err(|e| unable_to_hash_path_error(path, e))?;
+
+    let contents = if metadata.is_symlink() && symlinks != SymlinkMode::Follow {
+        if symlinks == SymlinkMode::Skip {
+            return Ok(None);
+        }
+        let target = std::fs::read_link(path).map_err(|e| unable_to_hash_path_error(path, e))?;
+        algorithm.compute_hash_from_slices(b"symlink", target.as_os_str().as_bytes())
+    } else {
+        // Where a followed symlink leads
+        let metadata = path
+            .metadata()
+            .map_err(|e| unable_to_hash_path_error(path, e))?;
+        let file_type = metadata.file_type();
+
+        if file_type.is_dir() {
+            let id = (metadata.dev(), metadata.ino());
+            if visiting.insert(id) {
+                let hash = hash_directory(path, symlinks, progress, visiting);
+                visiting.remove(&id);
+                hash?
+            } else {
+                algorithm.compute_hash_from_slices(b"loop", b"")
+            }
+        } else if file_type.is_file() {
+            let mut file =
+                std::fs::File::open(path).map_err(|e| unable_to_hash_path_error(path, e))?;
+            let mut hasher = blake3::Hasher::new();
+            let bytes = std::io::copy(&mut file, &mut hasher)
+                .map_err(|e| unable_to_hash_path_error(path, e))?;
+            progress(path, bytes);
+            hasher.finalize().as_bytes().to_vec()
+        } else {
+            // Reading a FIFO or device could block or never end, so they're hashed by type
+            algorithm.compute_hash_from_slices(b"special", special_file_type(file_type).as_bytes())
+        }
+    };
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("unable to read name of watch path '{}'", path.display()))?;
+
+    Ok(Some(
+        algorithm.compute_hash_from_slices(name.as_bytes(), &contents),
+    ))
+}
+
+/// Hashes a directory as a merkle tree of its children, in name order.
+fn hash_directory(
+    path: &Path,
+    symlinks: SymlinkMode,
+    progress: &mut dyn FnMut(&Path, u64),
+    visiting: &mut HashSet<(u64, u64)>,
+) -> anyhow::Result<Vec<u8>> {
+    let algorithm = Algorithm::Blake3;
+    let mut entries = std::fs::read_dir(path)
+        .map_err(|e| unable_to_hash_path_error(path, e))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<PathBuf>, std::io::Error>>()
+        .map_err(|e| unable_to_hash_path_error(path, e))?;
+    entries.sort();
+
+    let mut children = Vec::new();
+    for entry in entries {
+        if let Some(hash) = hash_path(&entry, symlinks, progress, visiting)? {
+            children.push(hash);
+        }
+    }
+    let slices = children
+        .iter()
+        .map(|h| h.as_slice())
+        .collect::<Vec<&[u8]>>();
+    Ok(algorithm
+        .compute_merkle_hash(&slices)
+        .unwrap_or(algorithm.compute_hash(b"")))
+}
+
+/// Names the type of a file that's neither a regular file, directory nor symlink.
+fn special_file_type(file_type: FileType) -> &'static str {
+    if file_type.is_fifo() {
+        "fifo"
+    } else if file_type.is_socket() {
+        "socket"
+    } else if file_type.is_char_device() {
+        "character device"
+    } else if file_type.is_block_device() {
+        "block device"
+    } else {
+        "unknown"
+    }
+}
+
 impl TryFrom<&PathBuf> for Hash {
     type Error = anyhow::Error;
 
     fn try_from(path: &PathBuf) -> anyhow::Result<Self> {
-        Ok(Hash {
-            hash: MerkleTree::builder(path.to_str().unwrap())
-                .hash_names(true)
-                .build()
-                .map_err(|e| {
-                    println!("A {:?}", e);
-                    if let Some(e) = e.source() {
-                        println!("Error: {:?}", e);
-                    }
-                    e
-                })?
-                .root
-                .item
-                .hash,
-        })
+        Hash::try_from_path(path, SymlinkMode::Follow)
     }
 }
 
@@ -134,12 +373,7 @@ impl TryFrom<&Vec<PathBuf>> for Hash {
     type Error = anyhow::Error;
 
     fn try_from(paths: &Vec<PathBuf>) -> anyhow::Result<Self> {
-        let hashes = paths
-            .iter()
-            .map(Hash::try_from)
-            .collect::<Result<Vec<Hash>, anyhow::Error>>();
-
-        Ok(Hash::from(&hashes?))
+        Hash::try_from_paths(paths, SymlinkMode::Follow)
     }
 }
 
@@ -164,23 +398,33 @@ impl From<&HashMap<String, String>> for Hash {
     fn from(map: &HashMap<String, String>) -> Self {
         let mut entries = map.iter().collect::<Vec<(&String, &String)>>();
         entries.sort();
-        let hashes = entries
-            .iter()
-            .map(|(k, v)| Hash::from(&vec![Hash::from(k.as_bytes()), Hash::from(v.as_bytes())]))
-            .collect::<Vec<Hash>>();
-        Hash::from(&hashes)
+        let mut builder = HashBuilder::new("map");
+        builder.count(entries.len());
+        for (k, v) in entries {
+            builder.str(k).str(v);
+        }
+        builder.finish()
+    }
+}
+
+impl From<&HashMap<String, bool>> for Hash {
+    fn from(map: &HashMap<String, bool>) -> Self {
+        let mut entries = map.iter().collect::<Vec<(&String, &bool)>>();
+        entries.sort();
+        let mut builder = HashBuilder::new("map");
+        builder.count(entries.len());
+        for (k, v) in entries {
+            builder.str(k).bool(*v);
+        }
+        builder.finish()
     }
 }
 
 impl From<&HashSet<String>> for Hash {
-    fn from(map: &HashSet<String>) -> Self {
-        let mut entries = map.iter().collect::<Vec<&String>>();
+    fn from(set: &HashSet<String>) -> Self {
+        let mut entries = set.iter().map(String::as_str).collect::<Vec<&str>>();
         entries.sort();
-        let hashes = entries
-            .iter()
-            .map(|e| Hash::from(e.as_bytes()))
-            .collect::<Vec<Hash>>();
-        Hash::from(&hashes)
+        HashBuilder::new("set").strs(entries.into_iter()).finish()
     }
 }
 
@@ -238,6 +482,168 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_hash_builder() {
+        let hash = |f: fn(&mut HashBuilder)| {
+            let mut builder = HashBuilder::new("test");
+            f(&mut builder);
+            builder.finish().hex()
+        };
+
+        assert_eq!(
+            "0fb62de14a36a9af62d736ab5caf36cb58780ef84fc87fb85c46e1a8a5139bf8",
+            hash(|b| {
+                b.str("hello");
+            })
+        );
+        assert_eq!(
+            hash(|b| {
+                b.str("hello");
+            }),
+            hash(|b| {
+                b.bytes(b"hello");
+            })
+        );
+        assert_ne!(
+            hash(|b| {
+                b.str("ab").str("c");
+            }),
+            hash(|b| {
+                b.str("a").str("bc");
+            }),
+            "fields are length prefixed"
+        );
+        assert_ne!(
+            hash(|b| {
+                b.optional(None).str("");
+            }),
+            hash(|b| {
+                b.optional(Some(b""));
+            }),
+            "missing and empty values differ"
+        );
+        assert_ne!(
+            HashBuilder::new("a").finish().hex(),
+            HashBuilder::new("b").finish().hex(),
+            "domains differ"
+        );
+    }
+
+    #[test]
+    fn test_try_from_path_symlinks() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-hash-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&root)?;
+        std::fs::write(root.join("file"), "contents")?;
+
+        let without_link = Hash::try_from_path(&root, SymlinkMode::Follow)?.hex();
+
+        std::os::unix::fs::symlink(root.join("file"), root.join("link"))?;
+        let follow = Hash::try_from_path(&root, SymlinkMode::Follow)?.hex();
+        let target = Hash::try_from_path(&root, SymlinkMode::Target)?.hex();
+        let skip = Hash::try_from_path(&root, SymlinkMode::Skip)?.hex();
+
+        assert_ne!(follow, target, "following and hashing target are different");
+        assert_ne!(follow, without_link, "followed link is included in hash");
+        assert_eq!(skip, without_link, "skipped link is excluded from hash");
+
+        std::fs::remove_file(root.join("link"))?;
+        std::os::unix::fs::symlink(root.join("missing"), root.join("link"))?;
+
+        assert!(
+            Hash::try_from_path(&root, SymlinkMode::Follow).is_err(),
+            "following broken link fails"
+        );
+        assert_eq!(
+            Hash::try_from_path(&root, SymlinkMode::Target)?.hex(),
+            Hash::try_from_path(&root, SymlinkMode::Target)?.hex(),
+            "hashing broken link target succeeds"
+        );
+        assert_eq!(
+            Hash::try_from_path(&root, SymlinkMode::Skip)?.hex(),
+            without_link,
+            "skipping broken link succeeds"
+        );
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_from_path_symlink_loop() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-hash-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(root.join("dir"))?;
+        std::fs::write(root.join("dir/file"), "contents")?;
+        std::os::unix::fs::symlink(&root, root.join("dir/loop"))?;
+
+        let hash = Hash::try_from_path(&root, SymlinkMode::Follow)?.hex();
+        assert_eq!(
+            hash,
+            Hash::try_from_path(&root, SymlinkMode::Follow)?.hex(),
+            "hashing a loop is stable"
+        );
+
+        std::fs::write(root.join("dir/file"), "changed")?;
+        assert_ne!(
+            hash,
+            Hash::try_from_path(&root, SymlinkMode::Follow)?.hex(),
+            "files inside a loop are still hashed"
+        );
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_from_path_special_files() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-hash-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(&root)?;
+        let without_fifo = Hash::try_from_path(&root, SymlinkMode::Follow)?.hex();
+
+        let fifo = std::ffi::CString::new(root.join("fifo").as_os_str().as_bytes())?;
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        let _socket = std::os::unix::net::UnixListener::bind(root.join("socket"))?;
+
+        let hash = Hash::try_from_path(&root, SymlinkMode::Follow)?.hex();
+        assert_ne!(hash, without_fifo, "special files are included in hash");
+
+        std::fs::remove_file(root.join("fifo"))?;
+        std::fs::write(root.join("fifo"), "")?;
+        assert_ne!(
+            hash,
+            Hash::try_from_path(&root, SymlinkMode::Follow)?.hex(),
+            "special files are hashed by type"
+        );
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_from_path_with_progress() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-hash-{}", ulid::Ulid::new()));
+        std::fs::create_dir_all(root.join("dir"))?;
+        std::fs::write(root.join("a"), "a")?;
+        std::fs::write(root.join("dir/b"), "bb")?;
+
+        let mut hashed = vec![];
+        let hash =
+            Hash::try_from_path_with_progress(&root, SymlinkMode::Follow, &mut |path, bytes| {
+                hashed.push((path.strip_prefix(&root).unwrap().to_path_buf(), bytes))
+            })?;
+        assert_eq!(
+            hash.hex(),
+            Hash::try_from_path(&root, SymlinkMode::Follow)?.hex()
+        );
+        assert_eq!(
+            hashed,
+            vec![(PathBuf::from("a"), 1), (PathBuf::from("dir/b"), 2)],
+            "reports each file as it's hashed"
+        );
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
     #[test]
     fn test_try_from_path() {
         assert_eq!(
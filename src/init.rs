@@ -0,0 +1,180 @@
+use anyhow::anyhow;
+use std::io::Write;
+use std::path::Path;
+
+use crate::output::Output;
+
+/// A shell that `init` can write integration code for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    /// Bash.
+    Bash,
+    /// Zsh.
+    Zsh,
+    /// Fish.
+    Fish,
+}
+
+impl std::str::FromStr for Shell {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(anyhow!(
+                "unsupported shell '{}', use one of bash, zsh or fish",
+                s
+            )),
+        }
+    }
+}
+
+impl Shell {
+    /// Quotes a word so the shell passes it through unchanged.
+    fn quote(&self, word: &str) -> String {
+        match self {
+            Shell::Bash | Shell::Zsh => shell_words::quote(word).to_string(),
+            Shell::Fish if is_plain(word) => word.to_string(),
+            // Inside single quotes fish only treats backslashes and quotes specially
+            Shell::Fish => format!("'{}'", word.replace('\\', "\\\\").replace('\'', "\\'")),
+        }
+    }
+
+    /// A function that runs the given words followed by its own arguments, keeping each
+    /// argument intact and returning the exit status of what it runs.
+    fn function(&self, name: &str, words: &[String]) -> String {
+        let words = words
+            .iter()
+            .map(|word| self.quote(word))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        match self {
+            Shell::Bash | Shell::Zsh => format!("{name}() {{\n  {words} \"$@\"\n}}\n"),
+            Shell::Fish => format!("function {name}\n    {words} $argv\nend\n"),
+        }
+    }
+}
+
+/// Whether a word needs no quoting in any supported shell.
+fn is_plain(word: &str) -> bool {
+    !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+}
+
+/// Whether a name can be used for a function in every supported shell.
+pub(crate) fn is_function_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Writes shell code defining a `deja-memo` function, which runs a command through `deja run`
+/// with the given options, and a function for each alias so the command it's named after always
+/// runs through `deja-memo`. The code is meant to be evaluated by the shell on startup, like
+/// `eval "$(deja init bash)"`.
+pub fn init(
+    output: &mut Output,
+    shell: Shell,
+    deja: &Path,
+    options: &[String],
+    aliases: &[String],
+) -> anyhow::Result<i32> {
+    if let Some(alias) = aliases.iter().find(|alias| !is_function_name(alias)) {
+        return Err(anyhow!(
+            "invalid alias '{}', aliases must be command names",
+            alias
+        ));
+    }
+
+    let mut memo = vec![
+        "command".to_string(),
+        deja.to_string_lossy().to_string(),
+        "run".to_string(),
+    ];
+    memo.extend(options.iter().cloned());
+    memo.push("--".to_string());
+
+    write!(output.stdout, "{}", shell.function("deja-memo", &memo))?;
+    for alias in aliases {
+        let words = ["deja-memo".to_string(), alias.clone()];
+        write!(output.stdout, "{}", shell.function(alias, &words))?;
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::SharedBuffer;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    fn generate(shell: Shell, options: &[&str], aliases: &[&str]) -> anyhow::Result<String> {
+        let stdout = SharedBuffer::default();
+        let mut output = Output::new(stdout.clone(), std::io::sink());
+        let strings = |words: &[&str]| words.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        init(
+            &mut output,
+            shell,
+            &PathBuf::from("/usr/local/bin/deja"),
+            &strings(options),
+            &strings(aliases),
+        )?;
+        Ok(String::from_utf8(stdout.take())?)
+    }
+
+    #[test]
+    fn test_init_bash() -> anyhow::Result<()> {
+        assert_eq!(
+            generate(Shell::Bash, &["--cache-for", "1 hour"], &["cargo"])?,
+            "deja-memo() {\n  command /usr/local/bin/deja run --cache-for '1 hour' -- \"$@\"\n}\n\
+             cargo() {\n  deja-memo cargo \"$@\"\n}\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_fish() -> anyhow::Result<()> {
+        assert_eq!(
+            generate(Shell::Fish, &["--watch-path", "it's\\here"], &[])?,
+            "function deja-memo\n    command /usr/local/bin/deja run --watch-path \
+             'it\\'s\\\\here' -- $argv\nend\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_init_invalid_alias() {
+        assert!(generate(Shell::Bash, &[], &["rm -rf"]).is_err());
+        assert!(generate(Shell::Bash, &[], &["--help"]).is_err());
+    }
+
+    #[test]
+    fn test_init_runs_in_bash() -> anyhow::Result<()> {
+        // Stands in for deja, printing its arguments and exiting with an unusual status
+        let stand_in = std::env::temp_dir().join(format!("deja-init-{}", ulid::Ulid::new()));
+        std::fs::write(&stand_in, "#!/bin/sh\nprintf '%s|' \"$@\"\nexit 3\n")?;
+        std::fs::set_permissions(&stand_in, std::fs::Permissions::from_mode(0o755))?;
+
+        let code = generate(Shell::Bash, &["--cache-for", "1 hour"], &["false"])?
+            .replace("/usr/local/bin/deja", &stand_in.to_string_lossy());
+        let script =
+            format!("{code}deja-memo 'two words' \"it's\"; echo \" $?\"; false; echo \" $?\"");
+        let result = Command::new("bash").arg("-c").arg(script).output();
+        std::fs::remove_file(&stand_in)?;
+
+        assert_eq!(
+            String::from_utf8(result?.stdout)?,
+            "run|--cache-for|1 hour|--|two words|it's| 3\nrun|--cache-for|1 hour|--|false| 3\n"
+        );
+        Ok(())
+    }
+}
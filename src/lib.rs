@@ -0,0 +1,100 @@
+//! Caches the results of commands, replaying their output and exit code when the same command
+//! is run again.
+//!
+//! This is the library behind the `deja` command line tool, for using its caching from other
+//! tools. Build a [`Scope`] with a [`ScopeBuilder`], wrap it in a [`Command`], and pass it to
+//! entry points like [`run`] or [`read`] along with a [`Cache`] such as [`DiskCache`]. Entry
+//! points write replayed output and reports to an [`Output`], which can capture them rather
+//! than writing to the process's stdout and stderr.
+//!
+//! The results of Rust code can be cached in the same way, with [`memoize`].
+
+mod cache;
+mod command;
+mod config;
+mod deja;
+mod diff;
+mod doctor;
+mod document;
+mod encryption;
+mod env;
+mod git;
+mod hash;
+mod init;
+mod log;
+mod memoize;
+mod output;
+mod progress;
+mod retention;
+mod stats;
+mod timestamp;
+mod warm;
+mod watch_cache;
+mod wrap;
+
+use std::sync::OnceLock;
+
+pub use crate::cache::layered::LayeredCache;
+pub use crate::cache::memory::{MemoryCache, MemoryCacheEntry};
+pub use crate::cache::redis::{is_redis_url, RedisCache};
+pub use crate::cache::sqlite::SqliteCache;
+pub use crate::cache::{
+    fallback_cache_dir, parse_mode, Cache, CacheEntry, CacheModes, DiskCache, FindOptions,
+    LockOptions, OutputRequired, RecordOptions, TagFilter,
+};
+pub use crate::command::{
+    parse_signal, BinaryWatchMode, Command, CommandBinary, Scope, ScopeBuilder, Timeout,
+    CLEAR_ENV_KEEPS,
+};
+pub use crate::config::{user_config_path, Config, ConfigOption, ConfigValue, Preset};
+pub use crate::deja::{
+    diff, diff_fresh, dry_run, explain, force, gc, hash, hash_lines, history, import, list,
+    list_presets, pin, pull, push, read, refresh, remove, remove_interactive, remove_tagged,
+    revalidate, run, show, test, verify, Gc, Hooks, OnMiss, Verify,
+};
+pub use crate::doctor::doctor;
+pub use crate::document::{DocumentFormat, WatchedValue};
+pub use crate::encryption::EncryptionKey;
+pub use crate::env::{EnvSnapshotOptions, DEFAULT_REDACT_PATTERNS};
+pub use crate::git::{find_root as find_git_root, GitState, GitWatchMode};
+pub use crate::hash::SymlinkMode;
+pub use crate::init::{init, Shell};
+pub use crate::log::{enabled as log_enabled, log, logs_to_file, set_logger, Level, Logger};
+pub use crate::memoize::{memoize, memoize_with};
+pub use crate::output::Output;
+pub use crate::retention::{find_policy, RetentionPolicy};
+pub use crate::stats::{reset_stats, stats, Counters, Outcome, COUNTERS_FILE};
+pub use crate::timestamp::{parse_expire_at, parse_time};
+pub use crate::warm::{read_manifest, warm, WarmCommand};
+pub use crate::watch_cache::WatchCache;
+pub use crate::wrap::wrap;
+
+/// Logs a key decision, like the hash of a command or whether its result was recorded.
+pub(crate) fn info(string: String) {
+    log::log(log::Level::Info, string);
+}
+
+/// Logs a warning about something that didn't stop a command, like output that couldn't be
+/// captured.
+pub(crate) fn warn(string: String) {
+    log::log(log::Level::Warn, string);
+}
+
+/// Logs a debug message, with detail on how a decision was made.
+pub(crate) fn debug(string: String) {
+    log::log(log::Level::Debug, string);
+}
+
+/// Whether caching is turned off. Can only be set once, before deja is used.
+static DISABLED: OnceLock<bool> = OnceLock::new();
+
+/// Turns caching off (or leaves it on), so commands run as if deja weren't there. Can only be
+/// set once, before deja is used, returning the value given if it's already been set.
+pub fn set_disabled(disabled: bool) -> Result<(), bool> {
+    DISABLED.set(disabled)
+}
+
+/// Whether caching has been turned off, so commands run as if deja weren't there.
+pub fn disabled() -> bool {
+    DISABLED.get_or_init(|| false).to_owned()
+}
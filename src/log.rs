@@ -0,0 +1,196 @@
+use anyhow::anyhow;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// How much detail is logged. Each level includes those before it, so `Info` also logs
+/// warnings and errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    /// Errors that stop a command.
+    Error,
+    /// Problems that don't stop a command, shown on stderr as well as any log file.
+    Warn,
+    /// What deja decided and why.
+    Info,
+    /// Detail useful when debugging.
+    Debug,
+    /// Even more detail than `Debug`.
+    Trace,
+}
+
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(anyhow!("unknown log level: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        f.pad(name)
+    }
+}
+
+/// Where log messages are written, and how much detail is included. Nothing is logged until
+/// it's set.
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Sets where log messages are written. Can only be set once, before deja is used, returning
+/// the logger given if one has already been set.
+pub fn set_logger(logger: Logger) -> Result<(), Logger> {
+    LOGGER.set(logger)
+}
+
+/// Whether log messages are written to a file, rather than stderr or nowhere.
+pub fn logs_to_file() -> bool {
+    LOGGER.get().is_some_and(Logger::is_file)
+}
+
+/// Where log messages are written, and up to which level.
+#[derive(Debug)]
+pub struct Logger {
+    level: Option<Level>,
+    file: Option<Mutex<File>>,
+}
+
+impl Logger {
+    /// Logs nothing.
+    pub fn off() -> Logger {
+        Logger {
+            level: None,
+            file: None,
+        }
+    }
+
+    /// Writes messages up to `level` to stderr, each on a line starting with `- `.
+    pub fn stderr(level: Level) -> Logger {
+        Logger {
+            level: Some(level),
+            file: None,
+        }
+    }
+
+    /// Appends messages up to `level` to the file at `path`, creating it if needed. Each line
+    /// starts with the time, process id and level, so lines from concurrent processes can be
+    /// told apart.
+    pub fn file(level: Level, path: &Path) -> anyhow::Result<Logger> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| anyhow!("unable to open log file '{}': {}", path.display(), e))?;
+        Ok(Logger {
+            level: Some(level),
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    /// Whether messages are written to a file, rather than stderr.
+    pub fn is_file(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Whether messages at `level` are logged.
+    pub fn enabled(&self, level: Level) -> bool {
+        self.level.is_some_and(|enabled| level <= enabled)
+    }
+
+    fn write(&self, level: Level, message: &str) {
+        // Warnings are for the user rather than for debugging, so are always shown on stderr
+        if level == Level::Warn {
+            eprintln!("deja: warning: {}", message);
+        }
+        match &self.file {
+            Some(file) => {
+                let line = format!(
+                    "{} [{}] {:<5} {}\n",
+                    humantime::format_rfc3339_millis(SystemTime::now()),
+                    std::process::id(),
+                    level,
+                    message
+                );
+                // Each line is a single write to a file opened for appending, so lines from
+                // other processes aren't interleaved with it
+                if let Ok(mut file) = file.lock() {
+                    let _ = file.write_all(line.as_bytes());
+                }
+            }
+            None if level == Level::Warn => {}
+            None => eprintln!("- {}", message),
+        }
+    }
+}
+
+/// Whether messages at the given level are logged.
+pub fn enabled(level: Level) -> bool {
+    LOGGER.get_or_init(Logger::off).enabled(level)
+}
+
+/// Logs a message at the given level, if that level is enabled.
+pub fn log(level: Level, message: String) {
+    let logger = LOGGER.get_or_init(Logger::off);
+    if logger.enabled(level) {
+        logger.write(level, &message);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ulid::Ulid;
+
+    #[test]
+    fn test_levels() -> anyhow::Result<()> {
+        assert_eq!("debug".parse::<Level>()?, Level::Debug);
+        assert!("verbose".parse::<Level>().is_err());
+
+        let logger = Logger::stderr(Level::Info);
+        assert!(logger.enabled(Level::Error));
+        assert!(logger.enabled(Level::Info));
+        assert!(!logger.enabled(Level::Debug));
+        assert!(!Logger::off().enabled(Level::Error));
+        Ok(())
+    }
+
+    #[test]
+    fn test_file() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("deja-log-{}", Ulid::new()));
+        std::fs::write(&path, "existing\n")?;
+
+        let logger = Logger::file(Level::Debug, &path)?;
+        logger.write(Level::Info, "first");
+        logger.write(Level::Debug, "second");
+
+        let contents = std::fs::read_to_string(&path)?;
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "existing", "appends to the file");
+        assert!(
+            lines[1].ends_with(&format!("[{}] INFO  first", std::process::id())),
+            "{}",
+            lines[1]
+        );
+        assert!(lines[2].ends_with("DEBUG second"), "{}", lines[2]);
+
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
@@ -1,59 +1,261 @@
-mod cache;
-mod command;
-mod deja;
-mod hash;
-
-use crate::cache::{DiskCache, FindOptions, RecordOptions};
-use crate::command::Command;
 use anyhow::anyhow;
+use clap::parser::ValueSource;
 use clap::value_parser;
 use clap::Arg;
 use clap::ValueHint;
-use command::ScopeBuilder;
+use deja::{
+    fallback_cache_dir, find_git_root, find_policy, is_redis_url, parse_expire_at, parse_mode,
+    parse_signal, parse_time, user_config_path, BinaryWatchMode, Cache, CacheEntry, CacheModes,
+    Command, CommandBinary, Config, ConfigOption, ConfigValue, Counters, DiskCache, DocumentFormat,
+    EncryptionKey, EnvSnapshotOptions, FindOptions, GitState, GitWatchMode, Hooks, LayeredCache,
+    Level, LockOptions, Logger, OnMiss, Output, OutputRequired, Preset, RecordOptions, RedisCache,
+    RetentionPolicy, ScopeBuilder, SqliteCache, SymlinkMode, TagFilter, Timeout, Verify,
+    WatchCache, WatchedValue, COUNTERS_FILE, DEFAULT_REDACT_PATTERNS,
+};
+use regex::Regex;
 use std::collections::HashMap;
-use std::io;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Write};
+use std::mem::ManuallyDrop;
+use std::os::fd::FromRawFd;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use std::sync::OnceLock;
-use std::time::Duration;
-
-pub static DEBUG: OnceLock<bool> = OnceLock::new();
-
-pub fn debug(string: String) {
-    if DEBUG.get_or_init(|| false).to_owned() {
-        eprintln!("- {}", string);
-    };
+fn cache_long_help(default_cache: &str) -> String {
+    format!(r#"
+Directory to store cache files (default: {default_cache}). Can also be set via the DEJA_CACHE variable. Files are stored in this directory with the hash as the filename, only readable by the current user. A redis:// or rediss:// URL stores results in a Redis server instead, so they can be shared between machines.
+"#).trim().to_owned()
 }
 
-fn subcommand(
-    name: &str,
-    about: &str,
-    include_cache_miss_exit_code_param: bool,
-    include_record_exit_codes_param: bool,
-) -> clap::Command {
+fn cache_arg() -> Arg {
     let env = "DEJA_CACHE";
-    let mut cache = Arg::new("cache")
+    let cache = Arg::new("cache")
         .long("cache")
         .value_name("path")
         .help("Path used as cache")
         .env(env)
         .value_parser(value_parser!(PathBuf));
 
-    cache = if let Some(cache_dir) = dirs::cache_dir() {
+    if let Some(cache_dir) = dirs::cache_dir() {
         let default_cache = cache_dir.join("deja").into_os_string();
-        let default_cache_string = default_cache.to_string_lossy();
-        let long_help = format!(r#"
-Directory to store cache files (default: {default_cache_string}). Can also be set via the {env} variable. Files are stored in this directory with the hash as the filename, only readable by the current user.
-"#).trim().to_owned();
         cache
-            .long_help(long_help)
+            .long_help(cache_long_help(&default_cache.to_string_lossy()))
             .default_value(&default_cache)
             .hide_default_value(true)
             .hide_env(true)
     } else {
         cache
+    }
+}
+
+fn secondary_cache_arg() -> Arg {
+    Arg::new("secondary-cache")
+        .long("secondary-cache")
+        .value_name("path")
+        .help("Path or URL of a cache to fall back to")
+        .long_help(r#"
+Path or URL of a cache to fall back to, such as a team cache on a network share. Results are looked up in the main cache first, then this one, with results found here copied into the main cache. New results are only recorded in the main cache, unless --populate-secondary is used. The backend is chosen from the path, as with --cache. Can also be set via the DEJA_SECONDARY_CACHE variable.
+"#.trim())
+        .env("DEJA_SECONDARY_CACHE")
+        .hide_env(true)
+        .value_parser(value_parser!(PathBuf))
+}
+
+/// Options for the permissions of what's created in the cache.
+fn cache_mode_args() -> [Arg; 3] {
+    let share_cache = Arg::new("share-cache")
+        .long("share-cache")
+        .help("Use a shared cache")
+        .help_heading("Caching options")
+        .long_help(r#"Use a shared cache. By default, each user has their own cache. This flag changes this behaviour, so all users share the same cache. This can be useful when running the same command as different users, as the cache will be shared between them."#.trim())
+        .action(clap::ArgAction::SetTrue);
+
+    let cache_mode = Arg::new("cache-mode")
+        .long("cache-mode")
+        .value_name("octal")
+        .help("Permissions for files created in the cache")
+        .help_heading("Caching options")
+        .long_help(r#"
+Permissions for files created in the cache, in octal (e.g. 660). Defaults to 600, or 666 with --share-cache. Can also be set via the DEJA_CACHE_MODE variable.
+"#.trim())
+        .env("DEJA_CACHE_MODE")
+        .hide_env(true)
+        .value_parser(|s: &str| parse_mode(s).map_err(|e| e.to_string()));
+
+    let cache_dir_mode = Arg::new("cache-dir-mode")
+        .long("cache-dir-mode")
+        .value_name("octal")
+        .help("Permissions for directories created in the cache")
+        .help_heading("Caching options")
+        .long_help(r#"
+Permissions for directories created for the cache, in octal (e.g. 2770 for a directory shared with its group, where new files keep the group). Defaults to 700, or 777 with --share-cache. Can also be set via the DEJA_CACHE_DIR_MODE variable.
+"#.trim())
+        .env("DEJA_CACHE_DIR_MODE")
+        .hide_env(true)
+        .value_parser(|s: &str| parse_mode(s).map_err(|e| e.to_string()));
+
+    [share_cache, cache_mode, cache_dir_mode]
+}
+
+fn encryption_key_arg() -> Arg {
+    Arg::new("encryption-key")
+        .long("encryption-key")
+        .value_name("path")
+        .value_hint(ValueHint::FilePath)
+        .help("Key file for reading and writing encrypted results")
+        .help_heading("Caching options")
+        .long_help(r#"
+Path to a file holding the key for results recorded with --encrypt, at least 32 random bytes (like from `head -c 32 /dev/urandom`). Encrypted results can't be replayed, shown or removed without the key. Can also be set via the DEJA_ENCRYPTION_KEY variable.
+"#.trim())
+        .env("DEJA_ENCRYPTION_KEY")
+        .hide_env(true)
+        .value_parser(value_parser!(PathBuf))
+}
+
+fn encrypt_arg() -> Arg {
+    Arg::new("encrypt")
+        .long("encrypt")
+        .help("Encrypt recorded results with the --encryption-key")
+        .help_heading("Caching options")
+        .long_help(r#"
+Encrypt recorded results, including the command and its output, with the key given by --encryption-key, so secrets in the output aren't left readable in the cache. Only supported by the disk backend.
+"#.trim())
+        .action(clap::ArgAction::SetTrue)
+}
+
+fn backend_arg() -> Arg {
+    Arg::new("backend")
+        .long("backend")
+        .value_name("backend")
+        .help("How results are stored [disk, sqlite]")
+        .long_help(r#"
+How results are stored. With disk (the default), each result is stored as separate files in the cache directory. With sqlite, results are stored in a single SQLite database at the cache path, which copes better with very large numbers of entries. The sqlite backend is also used when the cache path ends with .db. Can also be set via the DEJA_BACKEND variable.
+"#.trim())
+        .env("DEJA_BACKEND")
+        .hide_env(true)
+        .value_parser(["disk", "sqlite"])
+        .hide_possible_values(true)
+}
+
+fn cache_fallback_arg() -> Arg {
+    Arg::new("cache-fallback")
+        .long("cache-fallback")
+        .value_name("fallback")
+        .help("Where to cache results when the cache can't be used [tmp]")
+        .long_help(r#"
+What to do when the cache can't be used, for example because the cache path is a file or a directory that can't be written to. By default deja fails with an error. With tmp, deja prints a warning and uses a private cache directory in the system's temporary directory instead, so the command still runs. Can also be set via the DEJA_CACHE_FALLBACK variable.
+"#.trim())
+        .env("DEJA_CACHE_FALLBACK")
+        .hide_env(true)
+        .value_parser(["tmp"])
+        .hide_possible_values(true)
+}
+
+fn print_status_arg() -> Arg {
+    Arg::new("print-status")
+        .long("print-status")
+        .help("Print whether the result came from the cache to stderr")
+        .long_help(r#"
+Once the command completes, print a line to stderr saying where its result came from, like 'deja: hit (age 4m12s)', 'deja: miss (recorded, 8.3s)' or 'deja: miss (not recorded, 8.3s)'. The line is always last, after any replayed output. Can also be set via the DEJA_PRINT_STATUS variable.
+"#.trim())
+        .env("DEJA_PRINT_STATUS")
+        .hide_env(true)
+        .value_parser(clap::builder::FalseyValueParser::new())
+        .action(clap::ArgAction::SetTrue)
+}
+
+fn print_hash_arg() -> Arg {
+    Arg::new("print-hash")
+        .long("print-hash")
+        .value_name("sink")
+        .help("Print the command's hash before running or replaying it [stderr, fd:N, file:PATH]")
+        .long_help(r#"
+Print the hash used to cache the command's result once it's been worked out, before looking it up, running it or replaying it, so it appears even if those fail. It saves calling deja hash separately (which would hash any watched paths again), for example to find a command's result in the logs of another system. Nothing is written to stdout. By default a line like 'deja: hash <hash>' is written to stderr. With fd:N, the hash alone is written on a line to file descriptor N (which must already be open, like 3 in `deja run --print-hash=fd:3 -- make 3>hash.txt`), and with file:PATH it's appended on a line to the file.
+"#.trim())
+        .num_args(0..=1)
+        .require_equals(true)
+        .default_missing_value("stderr")
+        .value_parser(|s: &str| parse_hash_sink(s).map_err(|e| e.to_string()))
+}
+
+fn renew_on_hit_arg() -> Arg {
+    Arg::new("renew-on-hit")
+        .long("renew-on-hit")
+        .help("Renew a result's --cache-for expiry each time it's replayed")
+        .help_heading("Caching options")
+        .long_help(r#"
+Each time a fresh result is replayed, move its expiry to --cache-for from now, so results expire once they've gone unused for that long rather than that long after they were recorded. Only the result's metadata is rewritten. Renewal is skipped when the cache is read-only, or another process is recording the same command. Needs --cache-for.
+"#.trim())
+        .action(clap::ArgAction::SetTrue)
+}
+
+fn tag_filter_args() -> [Arg; 3] {
+    [
+        Arg::new("tag")
+            .long("tag")
+            .value_name("tag")
+            .help("Only include results recorded with the tag")
+            .long_help(r#"
+Only include results recorded with the given tag (see --tag on run). When given multiple times, results must have every tag, unless --any-tag is also given.
+"#.trim())
+            .value_parser(|s: &str| parse_tag(s).map_err(|e| e.to_string()))
+            .action(clap::ArgAction::Append),
+        Arg::new("any-tag")
+            .long("any-tag")
+            .help("Include results with any of the tags given, rather than all of them")
+            .requires("tag")
+            .action(clap::ArgAction::SetTrue),
+        Arg::new("id")
+            .long("id")
+            .value_name("pattern")
+            .help("Only include results recorded with an id matching the pattern")
+            .long_help(r#"
+Only include results recorded with an id (see --id on run) matching the given pattern, where * matches any sequence of characters, ignoring case. For example `--id 'JIRA-*'` includes every result recorded with an id starting JIRA-.
+"#.trim()),
+    ]
+}
+
+fn hook_args() -> [Arg; 2] {
+    let hook = |name: &'static str, env: &'static str, when: &'static str| {
+        Arg::new(name)
+            .long(name)
+            .value_name("command line")
+            .help(format!("Run the command line with sh -c {}", when))
+            .long_help(format!(r#"
+Run the command line with sh -c {}, for example to count hits and misses. The hook is given DEJA_HASH, DEJA_COMMAND and DEJA_STATUS (the exit status deja exits with) in its environment, along with DEJA_ENTRY_AGE (the age of the replayed result in seconds) on a hit. Its output is written to stderr and never cached, and if it fails deja prints a warning but exits as it would have anyway. Hooks aren't run when deja is run from within a hook. Can also be set via the {} variable.
+"#, when, env).trim().to_string())
+            .env(env)
+            .hide_env(true)
     };
+    [
+        hook("on-hit", "DEJA_ON_HIT", "after a cached result is replayed"),
+        hook(
+            "on-miss",
+            "DEJA_ON_MISS",
+            "when no cached result is found, after the command runs",
+        ),
+    ]
+}
+
+fn dry_run_arg() -> Arg {
+    Arg::new("dry-run")
+        .long("dry-run")
+        .help("Report what would happen, without running the command")
+        .long_help(r#"
+Report what would happen, without running the command or writing anything to the cache (which isn't created if it doesn't exist): the hash, whether a usable result is cached and how old it is, whether the command would run, and which exit codes would be recorded. Exits with 0 when a cached result would be replayed, or 1 when the command would run. With --json, the report is written as JSON.
+"#.trim())
+        .action(clap::ArgAction::SetTrue)
+}
+
+fn subcommand(
+    name: &str,
+    about: &str,
+    include_cache_miss_exit_code_param: bool,
+    include_record_exit_codes_param: bool,
+) -> clap::Command {
+    let cache = cache_arg();
 
     let watch_path = Arg::new("watch-path")
         .long("watch-path")
@@ -69,6 +271,55 @@ This option can be given multiple times to watch multiple paths.
         .value_parser(value_parser!(PathBuf))
         .action(clap::ArgAction::Append);
 
+    let watch_symlinks = Arg::new("watch-symlinks")
+        .long("watch-symlinks")
+        .value_name("mode")
+        .help_heading("Caching options")
+        .help("How to handle symlinks in watched paths [follow, target, skip]")
+        .long_help(r#"
+How to handle symlinks when hashing watched paths. With `follow` (the default), the contents of the file or directory the link points to are included in the hash. With `target`, only the link target is hashed, which is cheaper and works with broken links. With `skip`, symlinks are left out of the hash entirely.
+"#.trim())
+        .value_parser(["follow", "target", "skip"])
+        .default_value("follow")
+        .hide_default_value(true)
+        .hide_possible_values(true);
+
+    let watch_cache = Arg::new("watch-cache")
+        .long("watch-cache")
+        .help_heading("Caching options")
+        .help("Reuse hashes of watched paths that haven't changed")
+        .long_help(r#"
+Remember the hash of each watched path in the cache, along with a fingerprint made from the size and modification time of everything it contains. While the fingerprint matches, the remembered hash is reused rather than reading every file again, which makes watching large directories much cheaper. Not supported with a Redis cache. Can also be set via the DEJA_WATCH_CACHE variable.
+"#.trim())
+        .env("DEJA_WATCH_CACHE")
+        .hide_env(true)
+        .value_parser(clap::builder::FalseyValueParser::new())
+        .action(clap::ArgAction::SetTrue);
+
+    let watch_json = Arg::new("watch-json")
+        .long("watch-json")
+        .help_heading("Caching options")
+        .value_name("path:pointer")
+        .help("Include value from JSON file in cache key")
+        .long_help(r#"
+Include a value from a JSON file in cache key. The value is given as a path and a JSON pointer, separated by a colon. For example `--watch-json package.json:/dependencies` will only include the `dependencies` object, so other changes to the file won't result in a cache miss. An empty pointer (e.g. `package.json:`) includes the whole document, ignoring formatting.
+
+This option can be given multiple times to watch multiple values.
+"#.trim())
+        .action(clap::ArgAction::Append);
+
+    let watch_yaml = Arg::new("watch-yaml")
+        .long("watch-yaml")
+        .help_heading("Caching options")
+        .value_name("path:pointer")
+        .help("Include value from YAML file in cache key")
+        .long_help(r#"
+Include a value from a YAML file in cache key. This works in the same way as --watch-json, for example `--watch-yaml config.yml:/database/host`.
+
+This option can be given multiple times to watch multiple values.
+"#.trim())
+        .action(clap::ArgAction::Append);
+
     let watch_scope = Arg::new("watch-scope")
         .long("watch-scope")
         .value_name("scope")
@@ -77,7 +328,7 @@ This option can be given multiple times to watch multiple paths.
         .long_help(r#"
 Include scope string in cache key. Any string can be given as a scope, which when combined with shell substitution can be extremely flexible. For example `--watch-scope "$(date +%Y-%m-%d)"` will include the current date.
 
-This option can be given multiple times to watch multiple scopes.
+This option can be given multiple times to watch multiple scopes. When set via the DEJA_WATCH_SCOPE variable, each line is a separate scope, or the scopes can be separated by the string in DEJA_WATCH_SCOPE_DELIM instead.
 "#.trim())
         .env("DEJA_WATCH_SCOPE")
         .hide_env(true)
@@ -95,6 +346,98 @@ This option can be given multiple times to watch multiple variables.
 "#.trim())
         .action(clap::ArgAction::Append);
 
+    let exclude_args = Arg::new("exclude-args")
+        .long("exclude-args")
+        .help("Remove all arguments from cache key")
+        .help_heading("Caching options")
+        .long_help(r#"
+Remove all arguments from the cache key, so the command shares a single cached result whatever arguments it's given. The arguments are still passed to the command when it runs, and recorded alongside the result.
+"#.trim())
+        .conflicts_with_all(["ignore-arg", "ignore-arg-with-value"])
+        .action(clap::ArgAction::SetTrue);
+
+    let key = Arg::new("key")
+        .long("key")
+        .value_name("key")
+        .help_heading("Caching options")
+        .help("Use the given string as the cache key")
+        .long_help(r#"
+Use the given string as the cache key, instead of generating one from the command and other options. When set, the command, arguments, working directory, user and all watched values are ignored, so any command given the same key will share a cached result. For example `--key "$(git describe --tags)"` caches a single result per release.
+"#.trim());
+
+    let ignore_arg = Arg::new("ignore-arg")
+        .long("ignore-arg")
+        .value_name("arg")
+        .help_heading("Caching options")
+        .help("Remove argument from cache key")
+        .long_help(r#"
+Remove an argument from the cache key. The argument can be given either by value (e.g. `--ignore-arg --verbose`) or by `#` and its position, starting from 1 (e.g. `--ignore-arg '#2'`). A number without `#` is matched by value, so `--ignore-arg 2` only removes arguments that are `2`. Ignored arguments are still passed to the command when it runs, but changing them won't result in a cache miss.
+
+This option can be given multiple times to ignore multiple arguments.
+"#.trim())
+        .allow_hyphen_values(true)
+        .action(clap::ArgAction::Append);
+
+    let ignore_arg_with_value = Arg::new("ignore-arg-with-value")
+        .long("ignore-arg-with-value")
+        .value_name("option")
+        .help_heading("Caching options")
+        .help("Remove option and its value from cache key")
+        .long_help(r#"
+Remove an option and its value from the cache key. For example `--ignore-arg-with-value --log-file` ignores both `--log-file /tmp/a` and `--log-file=/tmp/a`. Ignored options are still passed to the command when it runs, but changing them won't result in a cache miss.
+
+This option can be given multiple times to ignore multiple options.
+"#.trim())
+        .allow_hyphen_values(true)
+        .action(clap::ArgAction::Append);
+
+    let watch_env_exists = Arg::new("watch-env-exists")
+        .long("watch-env-exists")
+        .value_name("env")
+        .help_heading("Caching options")
+        .help("Include whether variable is set in cache key")
+        .long_help(r#"
+Include whether a variable is set in cache key, ignoring its value. For example `--watch-env-exists CI` will give different results depending on whether the `CI` environment variable is set, but the same result whatever value it's set to.
+
+This option can be given multiple times to watch multiple variables.
+"#.trim())
+        .action(clap::ArgAction::Append);
+
+    let set_env = Arg::new("env")
+        .long("env")
+        .value_name("name=value")
+        .help_heading("Caching options")
+        .help("Set variable for the command, and include it in cache key")
+        .long_help(r#"
+Set an environment variable for the command when it runs, and include it in the cache key, as it can change the command's output. For example `--env TZ=UTC --env LANG=C` runs the command in UTC with the C locale. When the same variable is given more than once, the last value is used. Variables given to --watch-env see the value set here.
+
+This option can be given multiple times to set multiple variables.
+"#.trim())
+        .value_parser(|s: &str| parse_env_var(s).map_err(|e| e.to_string()))
+        .action(clap::ArgAction::Append);
+
+    let clear_env = Arg::new("clear-env")
+        .long("clear-env")
+        .help_heading("Caching options")
+        .help("Run the command with only PATH, HOME, TERM and chosen variables set")
+        .long_help(r#"
+Run the command with a cleared environment, so its output can't depend on whatever variables happen to be set. Only PATH, HOME and TERM are passed through, along with variables given to --keep-env, and those set with --env. Which variables are passed through is part of the cache key, though their values aren't (use --watch-env for that). A warning is printed for variables given to --watch-env that the command won't see.
+"#.trim())
+        .action(clap::ArgAction::SetTrue);
+
+    let keep_env = Arg::new("keep-env")
+        .long("keep-env")
+        .value_name("env")
+        .help_heading("Caching options")
+        .help("Pass variable through to the command with --clear-env")
+        .long_help(r#"
+Pass a variable through to the command when the environment is cleared with --clear-env. Its value isn't part of the cache key, unless it's also given to --watch-env.
+
+This option can be given multiple times to keep multiple variables.
+"#.trim())
+        .requires("clear-env")
+        .action(clap::ArgAction::Append);
+
     let exclude_pwd = Arg::new("exclude-pwd")
         .long("exclude-pwd")
         .help("Remove current directory from cache key")
@@ -106,13 +449,130 @@ Remove the current working directory from the cache key. By default, the current
         .hide_env(true)
         .action(clap::ArgAction::SetTrue);
 
-    let share_cache = Arg::new("share-cache")
-        .long("share-cache")
-        .help("Use a shared cache")
+    let exclude_user = Arg::new("exclude-user")
+        .long("exclude-user")
+        .help("Remove current user from cache key")
         .help_heading("Caching options")
-        .long_help(r#"Use a shared cache. By default, each user has their own cache. This flag changes this behaviour, so all users share the same cache. This can be useful when running the same command as different users, as the cache will be shared between them."#.trim())
+        .long_help(r#"
+Remove the current user from the cache key. By default, the current user is always included in the cache key. Unlike --share-cache, this doesn't change the permissions of the cache, so files remain only readable by the current user. This can be useful when several accounts share a cache directory through group permissions.
+"#.trim())
+        .action(clap::ArgAction::SetTrue);
+
+    let pwd = Arg::new("pwd")
+        .long("pwd")
+        .value_name("path")
+        .value_hint(ValueHint::DirPath)
+        .help("Use given directory in cache key instead of current directory")
+        .help_heading("Caching options")
+        .long_help(r#"
+Use the given directory in the cache key, instead of the current directory. This is useful when deja is run from a temporary directory (for example by a wrapper script), but the command conceptually belongs to another directory. The command itself still runs in the current directory.
+"#.trim())
+        .value_parser(value_parser!(PathBuf))
+        .conflicts_with("exclude-pwd");
+
+    let chdir = Arg::new("chdir")
+        .long("chdir")
+        .value_name("path")
+        .value_hint(ValueHint::DirPath)
+        .help("Run the command in the given directory")
+        .help_heading("Caching options")
+        .long_help(r#"
+Run the command in the given directory, rather than the current directory. The directory replaces the current directory in the cache key too (unless --pwd is given), and is used to find the repository for --watch-git and --pwd-from-git-root. With --exclude-pwd, the command still runs in the directory, but it's left out of the cache key.
+"#.trim())
+        .value_parser(value_parser!(PathBuf));
+
+    let pwd_from_git_root = Arg::new("pwd-from-git-root")
+        .long("pwd-from-git-root")
+        .help("Use git repository root as directory in cache key")
+        .help_heading("Caching options")
+        .long_help(r#"
+Use the root of the current git repository as the directory in the cache key, rather than the current directory. This allows the same command to be run from any subdirectory of a repository and hit the cache. The path of the subdirectory within the repository isn't included, as the root plus that path is just the current directory: leave out --pwd-from-git-root to cache results for each subdirectory separately. Outside a git repository, the current directory is used as normal.
+"#.trim())
+        .action(clap::ArgAction::SetTrue);
+
+    let watch_command_binary = Arg::new("watch-command-binary")
+        .long("watch-command-binary")
+        .value_name("mode")
+        .help_heading("Caching options")
+        .help("Include command binary in cache key [content, metadata]")
+        .long_help(r#"
+Include the binary the command resolves to in the cache key, so upgrading a tool results in a cache miss. The command is resolved using PATH. With `content` (the default) the contents of the binary are hashed. With `metadata` only its path, size and modification time are hashed, which is much faster for large binaries. Commands that can't be resolved to a binary (such as shell builtins) result in an error.
+"#.trim())
+        .value_parser(["content", "metadata"])
+        .num_args(0..=1)
+        .require_equals(true)
+        .default_missing_value("content")
+        .hide_possible_values(true);
+
+    let watch_git = Arg::new("watch-git")
+        .long("watch-git")
+        .value_name("mode")
+        .help_heading("Caching options")
+        .help("Include git commit in cache key [head, head-dirty, describe]")
+        .long_help(r#"
+Include the state of the current git repository in the cache key, so a new commit results in a cache miss. With `head` (the default) the commit checked out is used. With `head-dirty` whether any tracked file has been changed is also included. With `describe` the tag pointing at the commit is used when there is one (so rebuilding a tagged release hits the cache), along with whether tracked files have been changed. The repository is read directly, without running git. Outside a git repository this is an error, unless --watch-git-optional is also given.
+"#.trim())
+        .value_parser(["head", "head-dirty", "describe"])
+        .num_args(0..=1)
+        .require_equals(true)
+        .default_missing_value("head")
+        .hide_possible_values(true);
+
+    let watch_git_optional = Arg::new("watch-git-optional")
+        .long("watch-git-optional")
+        .help("Ignore --watch-git outside a git repository")
+        .help_heading("Caching options")
+        .long_help(r#"
+When used with --watch-git, run normally outside a git repository rather than failing. The git state is simply left out of the cache key.
+"#.trim())
+        .requires("watch-git")
+        .action(clap::ArgAction::SetTrue);
+
+    let user_key = Arg::new("user-key")
+        .long("user-key")
+        .value_name("identity")
+        .help_heading("Caching options")
+        .help("How the current user is identified in cache key [name, uid, uid-host]")
+        .long_help(r#"
+How the current user is identified in the cache key. With `name` (the default) the username is used. With `uid` the effective user id is used instead, which is more reliable in containers where the same account can report different usernames. With `uid-host` the hostname is added to the user id, so identical user ids on different machines sharing a cache don't collide.
+
+Changing this option changes the cache key, so results cached with one identity won't be returned with another. Existing results will be recorded again the first time each command is run with the new identity.
+"#.trim())
+        .value_parser(["name", "uid", "uid-host"])
+        .default_value("name")
+        .hide_default_value(true)
+        .hide_possible_values(true);
+
+    let watch_hostname = Arg::new("watch-hostname")
+        .long("watch-hostname")
+        .help("Include hostname in cache key")
+        .help_heading("Caching options")
+        .long_help(r#"
+Include the hostname in the cache key. By default, the hostname isn't part of the cache key, so a cache directory shared between machines (for example in a synced home directory) returns the same results everywhere. This flag gives each machine its own cached results, which is useful for commands with machine-specific output.
+"#.trim())
+        .env("DEJA_WATCH_HOSTNAME")
+        .hide_env(true)
+        .action(clap::ArgAction::SetTrue);
+
+    let watch_platform = Arg::new("watch-platform")
+        .long("watch-platform")
+        .help("Include platform (OS and architecture) in cache key")
+        .help_heading("Caching options")
+        .long_help(r#"
+Include the platform (operating system and architecture, e.g. linux-x86_64) in the cache key. This prevents results recorded on one platform being returned on another, when a cache directory is shared between different machines. This is enabled automatically when --share-cache is used.
+"#.trim())
+        .overrides_with("no-watch-platform")
+        .action(clap::ArgAction::SetTrue);
+
+    let no_watch_platform = Arg::new("no-watch-platform")
+        .long("no-watch-platform")
+        .help("Remove platform from cache key when using a shared cache")
+        .help_heading("Caching options")
+        .overrides_with("watch-platform")
         .action(clap::ArgAction::SetTrue);
 
+    let [share_cache, cache_mode, cache_dir_mode] = cache_mode_args();
+
     let look_back = Arg::new("look-back")
         .long("look-back")
         .value_name("duration")
@@ -135,26 +595,102 @@ How far back in time to look for cached results. When this option is set, deja w
 How long a cached result should be valid. When this option is set, any cached result will only ever be used for the given duration. After the duration has passed, the result will be considered stale and never returned. The duration should be provided in a format like 5s, 30m, 2h, 1d, etc.
 "#.trim());
 
+    let expire_at = Arg::new("expire-at")
+        .long("expire-at")
+        .value_name("time")
+        .help("When a cached result should stop being valid")
+        .help_heading("Caching options")
+        .long_help(r#"
+When a cached result should stop being valid, as an absolute time rather than a duration. Accepts RFC3339 timestamps like 2024-06-01T17:00:00Z, or local times like 17:00, today 17:00, tomorrow 03:00 or just tomorrow (meaning midnight). Times in the past are rejected.
+"#.trim())
+        .conflicts_with("cache-for");
+
+    // The command and its arguments are a single argument, so once the command is seen
+    // everything after it is passed to the command, even if it looks like an option to deja
     let command = Arg::new("command")
         .value_name("COMMAND")
-        .value_hint(ValueHint::CommandName)
+        .value_hint(ValueHint::CommandWithArguments)
         .required(true)
-        .help("Command to run");
+        .num_args(1..)
+        .trailing_var_arg(true)
+        .help("Command to run, followed by its arguments")
+        .long_help(r#"
+Command to run, followed by its arguments. Everything after COMMAND is passed to it unchanged, even if it looks like an option to deja, so options for deja must come before COMMAND.
+"#.trim());
 
-    let arguments = Arg::new("arguments")
-        .value_name("ARGUMENTS")
-        .help("Arguments to pass to command")
-        .action(clap::ArgAction::Append);
+    let shell = Arg::new("shell")
+        .long("shell")
+        .short('c')
+        .help("Run COMMAND as a command line through a shell")
+        .long_help(r#"
+Run COMMAND as a command line through a shell, so it can use pipes, redirects and other shell syntax, for example `deja run --shell 'dig +short example.com | sort'`. The command line must be given as a single argument, and is passed unchanged to `$SHELL -c` (or `/bin/sh -c` when SHELL isn't set), with the whole command line used as the command in the cache key. The shell is also part of the cache key, so `deja run --shell 'echo hi'` and `deja run echo hi` are cached separately.
+"#.trim())
+        .action(clap::ArgAction::SetTrue);
+
+    let shell_path = Arg::new("shell-path")
+        .long("shell-path")
+        .value_name("path")
+        .help("Shell used by --shell (default: $SHELL)")
+        .requires("shell")
+        .value_parser(value_parser!(PathBuf));
+
+    let quiet = Arg::new("quiet")
+        .long("quiet")
+        .help("Don't print notices or progress to stderr")
+        .long_help(r#"
+Don't print notices or progress to stderr. Without it, progress is shown when hashing --watch-path takes more than a moment and stderr is a terminal, and a notice is printed when --allow-expired replays an expired result.
+"#.trim())
+        .action(clap::ArgAction::SetTrue);
 
     let mut cache_args = vec![
+        shell,
+        shell_path,
         watch_path,
+        watch_symlinks,
+        watch_cache,
+        watch_json,
+        watch_yaml,
         watch_scope,
         watch_env,
+        watch_env_exists,
+        set_env,
+        clear_env,
+        keep_env,
+        ignore_arg,
+        ignore_arg_with_value,
+        exclude_args,
+        key,
+        watch_command_binary,
+        watch_git,
+        watch_git_optional,
+        watch_hostname,
+        watch_platform,
+        no_watch_platform,
         share_cache,
+        cache_mode,
+        cache_dir_mode,
         exclude_pwd,
+        pwd,
+        chdir,
+        pwd_from_git_root,
+        exclude_user,
+        user_key,
         look_back,
         cache_for,
+        expire_at,
         cache,
+        backend_arg(),
+        cache_fallback_arg(),
+        secondary_cache_arg(),
+        encrypt_arg(),
+        encryption_key_arg(),
+        quiet,
+        Arg::new("populate-secondary")
+            .long("populate-secondary")
+            .help("Also record results in the secondary cache")
+            .help_heading("Caching options")
+            .requires("secondary-cache")
+            .action(clap::ArgAction::SetTrue),
     ];
 
     if include_cache_miss_exit_code_param {
@@ -177,31 +713,192 @@ How long a cached result should be valid. When this option is set, any cached re
                 .env("DEJA_RECORD_EXIT_CODES")
                 .hide_env(true)
                 .help("Exit codes to record in the cache (default: 0)")
+                .value_parser(|s: &str| parse_exit_codes(s).map_err(|e| e.to_string()))
                 .help_heading("Caching options")
                 .hide_default_value(true)
                 .default_value("0"),
         );
     }
 
-    cache_args.push(command);
-    cache_args.push(arguments);
+    if include_record_exit_codes_param {
+        cache_args.push(
+            Arg::new("tag")
+                .long("tag")
+                .value_name("tag")
+                .help("Label the recorded result with a tag")
+                .help_heading("Caching options")
+                .long_help(r#"
+Label the recorded result with a tag, so related results can be listed with `deja list --tag` or removed together with `deja remove --tag`. Tags don't affect the cache key. A tag can't be empty, or contain whitespace or commas.
 
-    clap::Command::new(name.to_string())
-        .about(about.to_string())
-        .args(cache_args)
-}
+This option can be given multiple times to add multiple tags.
+"#.trim())
+                .value_parser(|s: &str| parse_tag(s).map_err(|e| e.to_string()))
+                .action(clap::ArgAction::Append),
+        );
 
-pub fn styles() -> clap::builder::Styles {
-    clap::builder::Styles::styled()
-        .usage(
-            anstyle::Style::new()
-                .underline()
-                .fg_color(Some(anstyle::Color::Ansi(anstyle::AnsiColor::BrightYellow))),
-        )
-        .header(
-            anstyle::Style::new()
-                .underline()
-                .fg_color(Some(anstyle::Color::Ansi(anstyle::AnsiColor::BrightYellow))),
+        cache_args.push(
+            Arg::new("id")
+                .long("id")
+                .value_name("id")
+                .help("Record an identifier with the result, like a ticket number")
+                .help_heading("Caching options")
+                .long_help(r#"
+Record an identifier with the result, like a ticket number or a pipeline run, which `deja list`, `deja show` and `deja explain` display. Results can be listed or removed by id with `deja list --id` and `deja remove --id`. Unlike --tag, a result has a single id, which recording the result again replaces. The id doesn't affect the cache key. It can't be empty, contain control characters or be longer than 128 characters.
+"#.trim())
+                .value_parser(|s: &str| parse_id(s).map_err(|e| e.to_string())),
+        );
+
+        cache_args.push(
+            Arg::new("keep-history")
+                .long("keep-history")
+                .value_name("count")
+                .value_parser(clap::value_parser!(usize))
+                .help("Keep the given number of previous results")
+                .help_heading("Caching options")
+                .long_help(r#"
+Keep the given number of previous results when recording a new one, rather than replacing the current result. Previous results are never returned by run or read, but can be listed with `deja history`, shown with `deja show --generation` and compared with `deja diff --generations`.
+"#.trim()),
+        );
+
+        cache_args.push(
+            Arg::new("record-env")
+                .long("record-env")
+                .value_name("pattern")
+                .help("Record environment variables alongside the result")
+                .help_heading("Caching options")
+                .long_help(r#"
+Record environment variables alongside the result, to help work out what produced it. They're shown by `deja show`, but don't affect the cache key. Without a pattern, PATH and any variables given to --watch-env are recorded. A pattern can use `*` as a wildcard, for example `--record-env=AWS_*`.
+
+Values of variables that look secret (with names containing SECRET, TOKEN, PASSWORD, PASSWD, KEY, CREDENTIAL or AUTH) are never recorded. More can be added with --redact-env.
+
+This option can be given multiple times to record more variables.
+"#.trim())
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("")
+                .action(clap::ArgAction::Append),
+        );
+
+        cache_args.push(
+            Arg::new("redact-env")
+                .long("redact-env")
+                .value_name("pattern")
+                .help("Redact values of matching variables recorded with --record-env")
+                .help_heading("Caching options")
+                .requires("record-env")
+                .action(clap::ArgAction::Append),
+        );
+
+        cache_args.push(
+            Arg::new("record-if-output-matches")
+                .long("record-if-output-matches")
+                .value_name("regex")
+                .help("Only record result when a line of output matches pattern")
+                .help_heading("Caching options")
+                .long_help(r#"
+Only record the result when a line of the command's stdout matches the given regular expression. Each line is checked as the command runs, so the output is never held in memory. The exit status of the command is returned whether or not the result is recorded.
+"#.trim()),
+        );
+
+        cache_args.push(
+            Arg::new("skip-record-if-output-matches")
+                .long("skip-record-if-output-matches")
+                .value_name("regex")
+                .help("Don't record result when a line of output matches pattern")
+                .help_heading("Caching options")
+                .long_help(r#"
+Don't record the result when a line of the command's stdout matches the given regular expression. For example `--skip-record-if-output-matches "rate limited"` avoids caching an error from a command that exits successfully anyway.
+"#.trim()),
+        );
+    }
+
+    if include_record_exit_codes_param {
+        cache_args.push(
+            Arg::new("record-only-if-output")
+                .long("record-only-if-output")
+                .help("Only record result when the command prints to stdout")
+                .help_heading("Caching options")
+                .long_help(r#"
+Only record the result when the command prints something to stdout, whatever its exit code. Commands that print nothing when there's nothing to report won't hide later output for the lifetime of a cached result.
+"#.trim())
+                .action(clap::ArgAction::SetTrue),
+        );
+
+        cache_args.push(
+            Arg::new("record-only-if-any-output")
+                .long("record-only-if-any-output")
+                .help("Only record result when the command prints to stdout or stderr")
+                .help_heading("Caching options")
+                .conflicts_with("record-only-if-output")
+                .action(clap::ArgAction::SetTrue),
+        );
+    }
+
+    if include_record_exit_codes_param {
+        cache_args.push(
+            Arg::new("timeout")
+                .long("timeout")
+                .value_name("duration")
+                .help("Stop the command if it runs longer than duration")
+                .help_heading("Caching options")
+                .long_help(r#"
+Stop the command if it runs for longer than the given duration, so a hung command can't block forever. The command is sent --timeout-signal (TERM by default), and killed if it's still running 5 seconds later. The result of a command that times out is never recorded, and deja exits with --timeout-exit-code (124 by default). The duration should be provided in a format like 5s, 30m, 2h, 1d, etc.
+"#.trim()),
+        );
+
+        cache_args.push(
+            Arg::new("record-signals")
+                .long("record-signals")
+                .help("Record results of commands killed by a signal")
+                .help_heading("Caching options")
+                .long_help(r#"
+Record the result of a command killed by a signal (such as SIGSEGV or SIGKILL). By default these results are never recorded, whatever --record-exit-codes is set to. A command killed by a signal exits with 128 plus the signal number, as in a shell.
+"#.trim())
+                .action(clap::ArgAction::SetTrue),
+        );
+
+        cache_args.push(
+            Arg::new("timeout-signal")
+                .long("timeout-signal")
+                .value_name("signal")
+                .help("Signal sent when the command times out (default: TERM)")
+                .help_heading("Caching options")
+                .requires("timeout")
+                .default_value("TERM")
+                .hide_default_value(true),
+        );
+
+        cache_args.push(
+            Arg::new("timeout-exit-code")
+                .long("timeout-exit-code")
+                .value_name("code")
+                .value_parser(clap::value_parser!(i32).range(0..256))
+                .help("Exit code when the command times out (default: 124)")
+                .help_heading("Caching options")
+                .requires("timeout")
+                .default_value("124")
+                .hide_default_value(true),
+        );
+    }
+
+    cache_args.push(command);
+
+    clap::Command::new(name.to_string())
+        .about(about.to_string())
+        .args(cache_args)
+}
+
+pub fn styles() -> clap::builder::Styles {
+    clap::builder::Styles::styled()
+        .usage(
+            anstyle::Style::new()
+                .underline()
+                .fg_color(Some(anstyle::Color::Ansi(anstyle::AnsiColor::BrightYellow))),
+        )
+        .header(
+            anstyle::Style::new()
+                .underline()
+                .fg_color(Some(anstyle::Color::Ansi(anstyle::AnsiColor::BrightYellow))),
         )
         .literal(
             anstyle::Style::new()
@@ -232,226 +929,2483 @@ fn cli() -> anyhow::Result<clap::Command> {
         "Return cached result or run and cache command",
         false,
         true,
+    )
+    .arg(
+        Arg::new("stale-while-revalidate")
+            .long("stale-while-revalidate")
+            .value_name("duration")
+            .help("Return stale results while updating them in the background")
+            .help_heading("Retrieval options")
+            .long_help(r#"
+Return a stale result immediately, and run the command again in the background to update the cache. A result is stale once it's older than --look-back or has passed its --cache-for expiry, and will be returned for up to the given duration after that. Only one background update runs at a time for each cache key, and its output is never shown. The duration should be provided in a format like 5s, 30m, 2h, 1d, etc.
+"#.trim()),
+    )
+    .arg(
+        Arg::new("refresh")
+            .long("refresh")
+            .help("Ignore any cached result, running and caching command")
+            .help_heading("Retrieval options")
+            .long_help(r#"
+Ignore any cached result, and behave as if the cache was missed: the command is run, its result recorded (following --cache-for and --record-exit-codes) and its exit status returned.
+"#.trim())
+            .conflicts_with("stale-while-revalidate")
+            .action(clap::ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("lock-timeout")
+            .long("lock-timeout")
+            .value_name("duration")
+            .help("How long to wait for another process running the same command")
+            .help_heading("Retrieval options")
+            .long_help(r#"
+When another process is already running the same command, deja waits for it to finish and replays its result rather than running the command again. This sets how long to wait before running the command anyway. By default, deja waits for as long as it takes. The duration should be provided in a format like 5s, 30m, 2h, 1d, etc.
+"#.trim()),
+    )
+    .arg(
+        Arg::new("no-wait")
+            .long("no-wait")
+            .help("Don't wait for another process running the same command")
+            .help_heading("Retrieval options")
+            .conflicts_with("lock-timeout")
+            .action(clap::ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("verify")
+            .long("verify")
+            .value_name("probability")
+            .help("Check replayed results against a fresh run (a probability, or always)")
+            .long_help(r#"
+Check that cached results are still right, by running the command again after a fresh result is replayed and comparing its stdout and exit status with the cached result. Given a probability from 0 to 1 (like 0.05), only that fraction of replays are checked, or every replay with `always`. The fresh output isn't shown. When the results differ, a warning is printed to stderr and the cached result is marked as diverged, which `deja list` and `deja show` report. This suggests the cache key is missing something, like a path that should be watched.
+"#.trim())
+            .value_parser(|s: &str| parse_probability(s).map_err(|e| e.to_string())),
+    )
+    .arg(
+        Arg::new("verify-update")
+            .long("verify-update")
+            .help("Record the fresh result made by --verify in place of the cached one")
+            .requires("verify")
+            .action(clap::ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("verify-background")
+            .long("verify-background")
+            .help("Make --verify checks in a detached process, rather than before exiting")
+            .long_help(r#"
+Make --verify checks in a detached process, so deja exits as soon as the cached result has been replayed. The process has no access to the terminal, so differences are only reported by marking the cached result as diverged.
+"#.trim())
+            .requires("verify")
+            .action(clap::ArgAction::SetTrue),
+    )
+    .arg(renew_on_hit_arg())
+    .arg(print_status_arg())
+    .arg(print_hash_arg())
+    .args(hook_args())
+    .arg(dry_run_arg().conflicts_with("revalidate"));
+
+    let read = subcommand("read", "Return cached result or exit", true, false)
+        .arg(
+            Arg::new("on-miss-exec")
+                .long("on-miss-exec")
+                .value_name("cmdline")
+                .help("Command to run (without caching) on cache miss")
+                .help_heading("Retrieval options")
+                .long_help(r#"
+Run the given command line when no cached result is found, returning its exit status instead of --cache-miss-exit-code. Its output isn't cached. The command line is split into words like a shell would (e.g. `--on-miss-exec "echo 'pending…'"`), but isn't run by a shell unless --on-miss-shell is also given.
+"#.trim())
+                .conflicts_with("cache-miss-exit-code"),
+        )
+        .arg(
+            Arg::new("wait")
+                .long("wait")
+                .value_name("duration")
+                .help("Wait for a result to be cached by another process")
+                .help_heading("Retrieval options")
+                .long_help(r#"
+When no result is cached, wait up to the given duration for another process to cache one, replaying it as soon as it appears. If no result appears in time, deja behaves as on any other cache miss. Results must still meet --look-back and not have expired. The duration should be provided in a format like 5s, 30m, 2h, 1d, etc.
+"#.trim()),
+        )
+        .arg(
+            Arg::new("allow-expired")
+                .long("allow-expired")
+                .help("Replay an expired result when no fresh result is cached")
+                .help_heading("Retrieval options")
+                .long_help(r#"
+When no fresh result is cached, replay a result that has passed its --cache-for expiry rather than missing the cache. A notice giving the age of the result is printed to stderr, unless --quiet is given. Results must still meet --look-back.
+"#.trim())
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("allow-expired-for")
+                .long("allow-expired-for")
+                .value_name("duration")
+                .help("Replay results up to the given duration past their expiry")
+                .help_heading("Retrieval options")
+                .long_help(r#"
+Like --allow-expired, but only replays results that expired within the given duration. The duration should be provided in a format like 5s, 30m, 2h, 1d, etc.
+"#.trim()),
+        )
+        .arg(renew_on_hit_arg())
+        .arg(print_status_arg())
+        .arg(print_hash_arg())
+        .args(hook_args())
+        .arg(
+            Arg::new("on-miss-shell")
+                .long("on-miss-shell")
+                .help("Run --on-miss-exec command line with sh -c")
+                .help_heading("Retrieval options")
+                .requires("on-miss-exec")
+                .action(clap::ArgAction::SetTrue),
+        );
+    let force = subcommand("force", "Run and cache command", false, true)
+        .arg(
+            Arg::new("exit-zero")
+                .long("exit-zero")
+                .help("Exit with 0 whatever the command's exit status")
+                .long_help(r#"
+Exit with 0 whatever the command's exit status, rather than returning it. Useful when warming a cache in a script using `set -e`, where a failing command shouldn't stop the script.
+"#.trim())
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(print_hash_arg())
+        .arg(dry_run_arg().conflicts_with("exit-zero"));
+    let remove = subcommand("remove", "Remove command from cache", false, false)
+        .arg(
+            Arg::new("all-layers")
+                .long("all-layers")
+                .help("Also remove the result from the secondary cache")
+                .requires("secondary-cache")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("interactive")
+                .long("interactive")
+                .short('i')
+                .help("Choose cached results to remove from a list, instead of giving COMMAND")
+                .long_help(r#"
+Choose cached results to remove from a list, instead of giving COMMAND. Each result is listed with its command, age, status and size, then the numbers of the results to remove are read from the terminal, as numbers and ranges like `1 3-5`, or `all`. Needs stdin to be a terminal.
+"#.trim())
+                .conflicts_with("command")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("matching")
+                .long("matching")
+                .value_name("pattern")
+                .help("Only list results whose command matches the regular expression")
+                .requires("interactive")
+                .conflicts_with("command"),
+        )
+        .args(tag_filter_args().map(|arg| arg.conflicts_with("command")))
+        .mut_arg("command", |arg| {
+            arg.required(false)
+                .required_unless_present_any(["interactive", "tag", "id"])
+        });
+    let pin = subcommand("pin", "Pin cached result so it never expires", false, false)
+        .long_about(r#"
+Pin the cached result for a command, so it's fresh whenever it expires and gc keeps it (unless given --include-pinned). Recording a new result for the command, as with force, replaces the pinned result with one that isn't pinned. Exits with 1 if no result is cached.
+"#.trim());
+    let unpin = subcommand("unpin", "Unpin cached result", false, false).long_about(
+        r#"
+Unpin the cached result for a command, so it expires and is removed by gc as usual. Exits with 1 if no result is cached.
+"#
+        .trim(),
+    );
+    let show = subcommand("show", "Show details of cached result", false, false).arg(
+        Arg::new("generation")
+            .long("generation")
+            .value_name("generation")
+            .value_parser(clap::value_parser!(usize))
+            .help("Show a previous result kept with --keep-history (0 is the current result)"),
+    );
+    let remote = Arg::new("remote")
+        .long("remote")
+        .value_name("path")
+        .help("Path or URL of the cache to sync with")
+        .long_help(r#"
+Path or URL of the cache to sync with, such as a cache directory on a network share. The backend is chosen from the path, as with --cache. Can also be set via the DEJA_REMOTE variable.
+"#.trim())
+        .env("DEJA_REMOTE")
+        .hide_env(true)
+        .required(true)
+        .value_parser(value_parser!(PathBuf));
+    let push = subcommand("push", "Copy cached result to a remote cache", false, false)
+        .arg(remote.clone());
+    let pull = subcommand(
+        "pull",
+        "Copy cached result from a remote cache",
+        false,
+        false,
+    )
+    .arg(remote);
+    let history = subcommand(
+        "history",
+        "List current and previous results kept with --keep-history",
+        false,
+        false,
+    );
+    let diff = subcommand(
+        "diff",
+        "Compare output of results kept with --keep-history, or with a fresh run",
+        false,
+        true,
+    )
+    .arg(
+        Arg::new("generations")
+            .long("generations")
+            .value_name("from..to")
+            .help("Generations to compare (default: 1..0)")
+            .long_help(r#"
+The generations to compare, where 0 is the current result, 1 the result before it, and so on. For example `--generations 2..0` shows how the output has changed over the last two runs. Exits with 0 if the output is the same, or 1 if it's different.
+"#.trim())
+            .default_value("1..0")
+            .hide_default_value(true),
+    )
+    .arg(
+        Arg::new("fresh")
+            .long("fresh")
+            .help("Compare the cached result with the output of running the command now")
+            .long_help(r#"
+Run the command now and compare its output with the cached result, to see whether the cached result has drifted. The fresh output isn't printed, and isn't recorded unless --update is given. A note is added when the exit status differs too. Exits with 0 if the output and exit status are the same, 1 if they're different, or 2 if there's no cached result to compare with.
+"#.trim())
+            .conflicts_with("generations")
+            .action(clap::ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("update")
+            .long("update")
+            .help("Record the fresh result, as deja force would")
+            .requires("fresh")
+            .action(clap::ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("ignore-matching-lines")
+            .long("ignore-matching-lines")
+            .value_name("pattern")
+            .help("Ignore changed lines matching the regular expression, like timestamps")
+            .long_help(r#"
+Ignore added and removed lines that match the regular expression, such as lines holding timestamps. When every changed line matches, the output counts as the same. Matching lines are still shown when they're next to other changes.
+"#.trim()),
+    )
+    .arg(
+        Arg::new("stderr")
+            .long("stderr")
+            .help("Compare stderr rather than stdout")
+            .action(clap::ArgAction::SetTrue),
     );
+    let test = subcommand("test", "Test if command is cached", false, false)
+        .arg(
+            Arg::new("why")
+                .long("why")
+                .help("Print why there's no usable result to stderr")
+                .long_help(r#"
+When no usable result is cached, print the reason to stderr on a single line: "missing", "expired 3m ago" or "older than --look-back 1h (created 2h ago)". Nothing is printed to stdout, and the exit status is unchanged.
+"#.trim())
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("exit-with-status")
+                .long("exit-with-status")
+                .help("Exit with the cached command's exit status")
+                .long_help(r#"
+When a usable result is cached, exit with the exit status the command had when it was recorded, rather than 0. When there's no usable result (because it's missing, expired or older than --look-back), exit with --cache-miss-exit-code instead, 125 by default, so a miss can't be mistaken for a cached failure. Only results with exit codes given to --record-exit-codes are ever cached, so the status is 0 unless other codes were recorded.
+"#.trim())
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("cache-miss-exit-code")
+                .long("cache-miss-exit-code")
+                .value_name("code")
+                .value_parser(clap::value_parser!(i32).range(1..256))
+                .help("Exit code with --exit-with-status when no usable result is cached (default: 125)")
+                .requires("exit-with-status")
+                .default_value("125")
+                .hide_default_value(true),
+        )
+        .after_long_help(
+        r#"
+Exit status:
+  0  A usable result is cached
+  1  No result is cached
+  2  A result is cached, but has expired (see --cache-for)
+  3  A result is cached, but is older than --look-back
 
-    let read = subcommand("read", "Return cached result or exit", true, false);
-    let force = subcommand("force", "Run and cache command", false, true);
-    let remove = subcommand("remove", "Remove command from cache", false, false);
-    let test = subcommand("test", "Test if command is cached", false, false);
+With --exit-with-status, a usable result's recorded exit status is used instead, and --cache-miss-exit-code (125 by default) in every other case.
+"#
+        .trim(),
+    );
     let explain = subcommand("explain", "Explain cache key for command", false, false).hide(true);
     let hash = subcommand(
         "hash",
         "Print hash generated for command and options",
         false,
         false,
-    );
+    )
+    .arg(
+        Arg::new("components")
+            .long("components")
+            .help("Print the hash of each component of the cache key")
+            .long_help(r#"
+Print the hash of each component of the cache key on its own line (e.g. `cmd: <hash>`), followed by the final hash (`hash: <hash>`). Each watched path is also listed individually. Comparing the output of two invocations shows which component changed.
+"#.trim())
+            .action(clap::ArgAction::SetTrue),
+    )
+    .arg(
+        Arg::new("stdin")
+            .long("stdin")
+            .help("Hash each command line read from stdin")
+            .long_help(r#"
+Read a command line from each line of stdin instead of taking COMMAND, split into words as the shell would, and print its hash and the line separated by a tab. Every other option applies to each command. Blank lines and those starting with # are skipped. A line that can't be parsed or hashed is reported with its line number and the rest are still hashed, but deja exits with 1. With --json, a {"hash": "...", "command": "..."} object is printed for each line.
+"#.trim())
+            .conflicts_with_all(["command", "components"])
+            .action(clap::ArgAction::SetTrue),
+    )
+    .mut_arg("command", |command| {
+        command.required(false).required_unless_present("stdin")
+    });
+
+    let list = clap::Command::new("list")
+        .about("List cached results")
+        .arg(cache_arg())
+        .arg(backend_arg())
+        .arg(secondary_cache_arg())
+        .arg(encryption_key_arg())
+        .arg(
+            Arg::new("long")
+                .long("long")
+                .short('l')
+                .action(clap::ArgAction::SetTrue)
+                .help("Include the CPU time and memory used by each command"),
+        )
+        .args(tag_filter_args());
+
+    let gc = clap::Command::new("gc")
+        .about("Remove expired and old results from the cache")
+        .long_about(r#"
+Remove results that have expired from the cache, along with their output. Results are otherwise only removed when they're next looked up, so a cache can fill with results for commands that are never run again. With --older-than or --created-before, results recorded before a cutoff are removed too, whether or not they've expired. Results pinned with `deja pin` are kept, unless --include-pinned is given. Each result removed is printed, followed by how many were removed and the space their output took.
+
+A retention policy in retention.toml, in the cache directory or else deja's configuration directory (next to config.toml), is applied too. Rules are given as [rules.<name>] tables, each matching results by their command (with * matching anything) and tags, and saying how long to keep them. The first rule matching a result decides, and keep outside any rule applies to results no rule matches. Once results have been removed by age, the oldest are removed until the rest fit in max-size:
+
+  keep = "30d"
+  max-size = "2GB"
+
+  [rules.terraform]
+  command = "terraform *"
+  keep = "7d"
+
+  [rules.releases]
+  tags = ["release"]
+  keep = "forever"
+"#.trim())
+        .arg(cache_arg())
+        .arg(backend_arg())
+        .arg(encryption_key_arg())
+        .arg(
+            Arg::new("older-than")
+                .long("older-than")
+                .value_name("duration")
+                .help("Also remove results recorded longer ago than duration")
+                .long_help(r#"
+Also remove results recorded longer ago than the given duration, whether or not they've expired. The duration should be provided in a format like 5s, 30m, 2h, 1d, etc.
+"#.trim()),
+        )
+        .arg(
+            Arg::new("created-before")
+                .long("created-before")
+                .value_name("time")
+                .help("Also remove results recorded before time")
+                .long_help(r#"
+Also remove results recorded before the given time, whether or not they've expired. Accepts RFC3339 timestamps like 2024-06-01T17:00:00Z, or local times like 17:00, yesterday 09:00 or just yesterday (meaning midnight).
+"#.trim()),
+        )
+        .arg(
+            Arg::new("include-pinned")
+                .long("include-pinned")
+                .help("Also remove pinned results that have expired or are too old")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("ignore-policy")
+                .long("ignore-policy")
+                .help("Don't apply the retention policy in retention.toml")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verbose")
+                .long("verbose")
+                .short('v')
+                .help("Say which retention rule removed each result")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .help("Report which results would be removed, without removing them")
+                .action(clap::ArgAction::SetTrue),
+        );
+
+    let doctor = clap::Command::new("doctor")
+        .about("Check the cache for problems, and optionally fix them")
+        .long_about(r#"
+Check every entry in a disk cache, including previous results kept with --keep-history, and the files they refer to. Reports entries that can't be read, entries whose output is missing or isn't the length recorded with it, output no entry refers to (or left behind by an interrupted write, once it's an hour old), and files and directories without the permissions set by --share-cache, --cache-mode and --cache-dir-mode. Each problem is printed, followed by a summary including the space orphaned output takes. Exits with 1 if any problems are found, unless --fix is given.
+"#.trim())
+        .arg(cache_arg())
+        .arg(backend_arg())
+        .args(cache_mode_args())
+        .arg(
+            Arg::new("verify")
+                .long("verify")
+                .help("Also check the contents of output against its checksums")
+                .long_help(r#"
+Also check the contents of each result's output against the checksums recorded with it, which means reading all of it. Results recorded by older versions have no checksums, so can't be checked.
+"#.trim())
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("fix")
+                .long("fix")
+                .help("Fix the problems found")
+                .long_help(r#"
+Fix the problems found: entries that can't be read or have missing or corrupt output are set aside with a .corrupt suffix (along with the corrupt output), orphaned output is removed, and permissions are changed to the expected mode.
+"#.trim())
+                .action(clap::ArgAction::SetTrue),
+        );
+
+    let stats = clap::Command::new("stats")
+        .about("Show how often cached results have been used")
+        .long_about(r#"
+Show how many times `run` and `read` found a cached result (a hit) or didn't (a miss), and the time hits saved by not running their commands, in total and for each of the most recent days. Hits and misses are counted in a file in the cache directory (or alongside a sqlite database), which is compacted into daily totals as it grows. Lookups against a --read-only cache aren't counted.
+"#.trim())
+        .arg(cache_arg())
+        .arg(backend_arg())
+        .arg(
+            Arg::new("days")
+                .long("days")
+                .help("How many recent days to show totals for")
+                .value_name("N")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("7"),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Write the totals as JSON")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("reset")
+                .long("reset")
+                .help("Clear the counters, after confirming")
+                .conflicts_with_all(["days", "json"])
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("yes")
+                .long("yes")
+                .short('y')
+                .help("Clear the counters without confirming")
+                .requires("reset")
+                .action(clap::ArgAction::SetTrue),
+        );
+
+    let list_presets = clap::Command::new("list-presets")
+        .about("List presets from configuration files")
+        .long_about(r#"
+List the presets defined in configuration files, with the command each one runs and the options it sets. A preset is run by giving its name, prefixed with @, in place of the command, like `deja run @metadata`. `deja run @` also lists presets.
+"#.trim());
+
+    let import = clap::Command::new("import")
+        .about("Import cached results from a disk cache into a SQLite cache")
+        .arg(cache_arg())
+        .arg(backend_arg())
+        .arg(encryption_key_arg())
+        .arg(
+            Arg::new("from")
+                .long("from")
+                .value_name("path")
+                .value_hint(ValueHint::DirPath)
+                .value_parser(value_parser!(PathBuf))
+                .required(true)
+                .help("Disk cache directory to import from"),
+        );
+
+    let init = clap::Command::new("init")
+        .about("Print shell functions for running commands through deja")
+        .long_about(r#"
+Print shell code defining a deja-memo function, which runs the command given to it with `deja run` and the options given after --. Add it to your shell's startup file, like `eval "$(deja init bash -- --cache-for 1h)"` for bash or zsh, or `deja init fish | source` for fish.
+"#.trim())
+        .arg(
+            Arg::new("shell")
+                .value_name("SHELL")
+                .value_parser(["bash", "zsh", "fish"])
+                .required(true)
+                .help("Shell to print functions for"),
+        )
+        .arg(
+            Arg::new("alias")
+                .long("alias")
+                .value_name("COMMAND")
+                .action(clap::ArgAction::Append)
+                .help("Always run the given command through deja-memo (can be repeated)"),
+        )
+        .arg(
+            Arg::new("options")
+                .value_name("OPTIONS")
+                .num_args(1..)
+                .last(true)
+                .allow_hyphen_values(true)
+                .help("Options for deja run, used by deja-memo"),
+        );
+
+    let wrap = clap::Command::new("wrap")
+        .about("Write an executable that runs a command through deja")
+        .long_about(r#"
+Write an executable shell script called NAME, which runs COMMAND through `deja run` with the options given before it, adding any arguments it's given and exiting with the command's exit status. For example, `deja wrap tfplan --cache-for 10m --watch-path . -- terraform plan` writes a `tfplan` script to ~/.local/bin. When the script is named after the command it runs and comes first on PATH, it runs the next command of that name on PATH rather than itself.
+"#.trim())
+        .arg(
+            Arg::new("name")
+                .value_name("NAME")
+                .required(true)
+                .help("Name of the executable to write"),
+        )
+        .arg(
+            Arg::new("output-dir")
+                .long("output-dir")
+                .value_name("path")
+                .value_hint(ValueHint::DirPath)
+                .value_parser(value_parser!(PathBuf))
+                .help("Directory to write the executable to (default: ~/.local/bin)"),
+        )
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .help("Replace an existing file")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("command")
+                .value_name("COMMAND")
+                .value_hint(ValueHint::CommandWithArguments)
+                .required(true)
+                .num_args(1..)
+                .trailing_var_arg(true)
+                .allow_hyphen_values(true)
+                .help("Options for deja run, then the command and its arguments"),
+        );
+
+    let warm = clap::Command::new("warm")
+        .about("Record results for a list of commands")
+        .long_about(r#"
+Record results for each command in a manifest that doesn't already have a fresh result, so they're cached before they're needed. Each command is run with `deja run`, with any options given before `warm` (like --cache-dir), and its output isn't shown, except stderr from commands that fail. A line is printed as each command is recorded, skipped or fails, followed by a summary. A failing command doesn't stop the others, but deja exits with 1 if any failed.
+
+A manifest has a command line on each row, with blank rows and rows starting with # ignored. A manifest ending .toml has a [commands.<name>] table for each command, with a `command` key and any options for deja run, like presets in configuration files. Options outside tables apply to every command:
+
+  cache-for = "12h"
+
+  [commands.metadata]
+  command = ["cargo", "metadata"]
+  watch-path = "Cargo.lock"
+"#.trim())
+        .arg(
+            Arg::new("file")
+                .short('f')
+                .long("file")
+                .value_name("path")
+                .value_hint(ValueHint::FilePath)
+                .value_parser(value_parser!(PathBuf))
+                .required(true)
+                .help("Manifest of commands to record"),
+        )
+        .arg(
+            Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .value_name("count")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .default_value("1")
+                .help("Number of commands to run at once"),
+        );
+
+    let generate_man = clap::Command::new("generate-man")
+        .about("Generate manual pages")
+        .long_about(r#"
+Generate a manual page for deja and each of its subcommands, or with --format markdown a single markdown document. The output only depends on the version of deja, not the machine it's generated on, so it can be vendored by packagers.
+"#.trim())
+        .hide(true)
+        .arg(
+            Arg::new("output-dir")
+                .long("output-dir")
+                .value_name("DIR")
+                .value_hint(ValueHint::DirPath)
+                .value_parser(value_parser!(PathBuf))
+                .default_value(".")
+                .help("Directory to write pages to"),
+        )
+        .arg(
+            Arg::new("format")
+                .long("format")
+                .value_parser(["man", "markdown"])
+                .default_value("man")
+                .help("Format of the pages"),
+        );
+
+    let completions = clap::command!()
+        .name("completions")
+        .args(vec![Arg::new("shell")
+            .long("shell")
+            .value_name("SHELL")
+            .value_parser(["bash", "fish", "zsh", "powershell"])
+            .required(true)
+            .help("Shell to generate completions for")]);
+
+    Ok(clap::command!()
+        .name("deja")
+        .arg_required_else_help(true)
+        .styles(styles())
+        // Added by deja when it runs `run` again in the background, ahead of the subcommand so
+        // they can't be mistaken for another option's value
+        .arg(
+            Arg::new("revalidate")
+                .long("revalidate")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .hide(true),
+        )
+        .arg(
+            Arg::new("verify-now")
+                .long("verify-now")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .hide(true),
+        )
+        .arg(
+            Arg::new("debug")
+                .long("debug")
+                .help("Log debug messages to stderr, like --log-level debug")
+                .action(clap::ArgAction::SetTrue)
+                .global(true)
+                .hide(true),
+        )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .value_name("level")
+                .help("How much to log [error, warn, info, debug, trace]")
+                .long_help(r#"
+How much to log, from only errors to everything: error, warn, info, debug or trace. At info, key decisions are logged, like the hash of the command, whether a cached result was found (and if not, why), and whether the result was recorded. At debug, timings and the files written are logged too. Messages are written to stderr, or to the file given with --log-file. Warnings are always shown on stderr, unless the level is error. Can also be set via the DEJA_LOG_LEVEL variable.
+"#.trim())
+                .env("DEJA_LOG_LEVEL")
+                .hide_env(true)
+                .value_parser(["error", "warn", "info", "debug", "trace"])
+                .hide_possible_values(true)
+                .global(true),
+        )
+        .arg(
+            Arg::new("log-file")
+                .long("log-file")
+                .value_name("path")
+                .help("Append log messages to a file, rather than stderr")
+                .long_help(r#"
+Append log messages to the given file rather than writing them to stderr, so they don't mix with the command's own output. Each line starts with the time, the process id and the level. Messages are logged at debug level, unless --log-level is given. Can also be set via the DEJA_LOG variable.
+"#.trim())
+                .env("DEJA_LOG")
+                .hide_env(true)
+                .value_hint(ValueHint::FilePath)
+                .value_parser(value_parser!(PathBuf))
+                .global(true),
+        )
+        .arg(
+            Arg::new("disable")
+                .long("disable")
+                .help("Run commands without caching")
+                .long_help(r#"
+Run commands as if deja weren't there, without looking up or recording results. run and force just run the command, read behaves as if no result is cached, and test exits with 1. Can also be set via the DEJA_DISABLE variable.
+"#.trim())
+                .env("DEJA_DISABLE")
+                .hide_env(true)
+                .value_parser(clap::builder::FalseyValueParser::new())
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("read-only")
+                .long("read-only")
+                .help("Replay cached results without writing to the cache")
+                .long_help(r#"
+Replay results from the cache, but never write to it, for caches on read-only storage. Commands without a cached result are run without recording their result, and remove fails. Only supported by the disk backend. Can also be set via the DEJA_READ_ONLY variable.
+"#.trim())
+                .env("DEJA_READ_ONLY")
+                .hide_env(true)
+                .value_parser(clap::builder::FalseyValueParser::new())
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("color")
+                .long("color")
+                .value_name("WHEN")
+                .value_parser(["auto", "always", "never"])
+                .default_value("auto")
+                .help("When to use colors in deja's own output")
+                .long_help(r#"
+When to use colors in help and error messages: always, never, or auto (the default) to use them only when writing to a terminal. In auto mode, colors are turned off by setting NO_COLOR, or on by setting CLICOLOR_FORCE. Output replayed from the cache is never changed.
+"#.trim())
+                .global(true),
+        )
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Print output of explain, hash, list, test and --dry-run as JSON")
+                .long_help(r#"
+Print the output of explain, hash, list, test and run or force --dry-run as a single line of JSON, for use in scripts. Times are RFC 3339 timestamps, durations are in seconds, and fields without a value are null. A result's age is the whole seconds since it was recorded, and expires_in the whole seconds until it expires (negative once it has). Other subcommands are unaffected.
+
+  hash     {"hash": "..."}
+  hash --components
+           {"format_version": "...", "components": {"<name>": "<hash>", ...},
+            "watch_paths": {"<path>": "<hash>", ...}, "watch_env": {"<name>": "<hash>", ...},
+            "watch_scope": {"<scope>": "<hash>", ...}, "hash": "..."}
+  hash --stdin
+           {"hash": "...", "command": "..."}, one line for each command
+  test     {"status": "hit|miss|expired|stale", "created": ..., "expires": ..., "age": ...,
+            "expires_in": ...}
+  explain  {"scope": {"format", "key", "cmd", "args", "shell", "ignored_args", "user",
+            "pwd", "hostname", "platform", "binary", "git", "scope", "paths", "symlinks",
+            "values", "env", "env_exists", "set_env", "clear_env", "chdir"},
+            "hashes": {as for hash --components}, "hash": "...", "status": ...,
+            "created": ..., "expires": ..., "age": ..., "expires_in": ..., "duration": ...,
+            "env": {...}, "id": ...}
+  list     [{"hash", "command", "created", "expires", "age", "expires_in", "duration", "status", "signal",
+            "user_time", "system_time", "max_rss", "diverged", "tags", "id",
+            "pinned"}, ...]
+  --dry-run
+           {"hash": "...", "status": ..., "created": ..., "expires": ..., "age": ...,
+            "expires_in": ..., "run": true|false, "record": true|false,
+            "record_exit_codes": [0, ...]}
+
+test and --dry-run exit with the same status as without --json.
+"#.trim())
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .subcommands(vec![
+            run,
+            read,
+            force,
+            remove,
+            pin,
+            unpin,
+            push,
+            pull,
+            list,
+            gc,
+            doctor,
+            stats,
+            list_presets,
+            show,
+            history,
+            diff,
+            test,
+            explain,
+            hash,
+            import,
+            init,
+            wrap,
+            warm,
+            generate_man,
+            completions,
+        ]))
+}
+
+/// The command line definition for generated documentation, describing the default cache
+/// directory in general terms rather than with the current user's path.
+fn documented_cli() -> anyhow::Result<clap::Command> {
+    let default_cache = "~/.cache/deja";
+    let mut cli = cli()?;
+    let names = cli
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect::<Vec<_>>();
+
+    // Every subcommand is visited in order, as each one changed is moved to the end
+    for name in names {
+        cli = cli.mut_subcommand(name, |subcommand| {
+            subcommand.mut_args(|arg| match arg.get_id().as_str() {
+                "cache" => arg
+                    .long_help(cache_long_help(default_cache))
+                    .default_value(default_cache),
+                _ => arg,
+            })
+        });
+    }
+    Ok(cli)
+}
+
+fn parse_exit_codes(param: &str) -> anyhow::Result<[bool; 256]> {
+    let mut exit_codes = [false; 256];
+    for part in param.split(',').map(|s| s.trim()) {
+        let parse_code = |code: &str| -> anyhow::Result<usize> {
+            code.parse::<u8>()
+                .map(usize::from)
+                .map_err(|_| anyhow!("invalid exit code '{}', use values from 0 to 255", part))
+        };
+
+        let (start, end) = if let Some(start) = part.strip_suffix('+') {
+            (parse_code(start)?, 255)
+        } else if let Some((start, end)) = part.split_once('-') {
+            let (start, end) = (parse_code(start)?, parse_code(end)?);
+            if start > end {
+                return Err(anyhow!(
+                    "invalid exit code range '{}', start is after end",
+                    part
+                ));
+            }
+            (start, end)
+        } else {
+            let code = parse_code(part)?;
+            (code, code)
+        };
+
+        exit_codes[start..=end].fill(true);
+    }
+    Ok(exit_codes)
+}
+
+/// Splits the scopes given in a variable. An empty delimiter leaves the value whole.
+fn split_scopes(value: &str, delimiter: &str) -> Vec<String> {
+    if delimiter.is_empty() {
+        return vec![value.to_string()];
+    }
+    value
+        .split(delimiter)
+        .map(|scope| scope.to_string())
+        .collect()
+}
+
+fn command(matches: &clap::ArgMatches) -> anyhow::Result<Command> {
+    let words = matches
+        .get_many::<String>("command")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+    command_from(matches, words)
+}
+
+/// Builds the command, first writing its hash to wherever `--print-hash` says, if given.
+fn hashed_command(matches: &clap::ArgMatches) -> anyhow::Result<Command> {
+    let command = command(matches)?;
+    if let Some(sink) = matches.get_one::<HashSink>("print-hash") {
+        sink.write(command.hash())?;
+    }
+    Ok(command)
+}
+
+/// Builds the command for the given words (the command and its arguments, or the words of a
+/// command line with `--shell`), with the scope options given on the command line.
+fn command_from(matches: &clap::ArgMatches, words: Vec<String>) -> anyhow::Result<Command> {
+    let started = Instant::now();
+    let line = shell_words::join(&words);
+    let mut words = words.into_iter();
+    let cmd = words
+        .next()
+        .ok_or(anyhow!("unexpected failure to parse arguments"))?;
+    let args = words.collect::<Vec<String>>();
+
+    // In shell mode the command is a single command line, run as `shell -c 'command line'`.
+    // Joining separate words would lose how they were quoted, so only one is accepted
+    let shell = optional_flag(matches, "shell").then(|| shell_path(matches));
+    if shell.is_some() && !args.is_empty() {
+        return Err(anyhow!(
+            "--shell takes a single command line, but was given {} arguments, \
+             quote the whole command line instead",
+            args.len() + 1
+        ));
+    }
+    let cmd = &cmd;
+    let watch_path_bufs = matches
+        .get_many::<PathBuf>("watch-path")
+        .unwrap_or_default()
+        .map(|s| s.into())
+        .collect::<Vec<PathBuf>>();
+
+    let watch_paths = watch_path_bufs
+        .iter()
+        .map(|path| {
+            std::fs::canonicalize(path)
+                .map_err(|_| anyhow!("watch path '{}' not found", path.display()))
+        })
+        .collect::<Result<Vec<PathBuf>, anyhow::Error>>()?;
+
+    let watch_symlinks = matches
+        .get_one::<String>("watch-symlinks")
+        .map(|mode| SymlinkMode::from_str(mode))
+        .transpose()?
+        .unwrap_or_default();
+
+    let watch_json = matches
+        .get_many::<String>("watch-json")
+        .unwrap_or_default()
+        .map(|arg| WatchedValue::parse(DocumentFormat::Json, arg));
+    let watch_yaml = matches
+        .get_many::<String>("watch-yaml")
+        .unwrap_or_default()
+        .map(|arg| WatchedValue::parse(DocumentFormat::Yaml, arg));
+    let watch_values = watch_json
+        .chain(watch_yaml)
+        .collect::<Result<Vec<WatchedValue>, anyhow::Error>>()?;
+
+    let mut watch_scope = matches
+        .get_many::<String>("watch-scope")
+        .unwrap_or_default()
+        .map(|s| s.into())
+        .collect::<Vec<String>>();
+    // A variable only holds one string, so can hold several scopes separated by a delimiter
+    if matches.value_source("watch-scope") == Some(ValueSource::EnvVariable) {
+        let delimiter =
+            std::env::var("DEJA_WATCH_SCOPE_DELIM").unwrap_or_else(|_| "\n".to_string());
+        watch_scope = watch_scope
+            .iter()
+            .flat_map(|scope| split_scopes(scope, &delimiter))
+            .collect();
+    }
+
+    let watch_env_names = matches
+        .get_many::<String>("watch-env")
+        .unwrap_or_default()
+        .map(|s| s.into())
+        .collect::<Vec<String>>();
+
+    // Later values replace earlier ones for the same variable
+    let set_env: HashMap<String, String> = matches
+        .get_many::<(String, String)>("env")
+        .unwrap_or_default()
+        .cloned()
+        .collect();
+
+    let watch_env: HashMap<String, String> =
+        HashMap::from_iter(watch_env_names.iter().map(|name| {
+            let value = set_env
+                .get(name)
+                .cloned()
+                .or_else(|| std::env::var(name).ok());
+            (name.clone(), value.unwrap_or_default())
+        }));
+
+    let watch_env_exists: HashMap<String, bool> = HashMap::from_iter(
+        matches
+            .get_many::<String>("watch-env-exists")
+            .unwrap_or_default()
+            .map(|name| (name.clone(), std::env::var_os(name).is_some())),
+    );
+
+    let ignore_args = matches
+        .get_many::<String>("ignore-arg")
+        .unwrap_or_default()
+        .map(|s| s.into())
+        .collect::<Vec<String>>();
+
+    let ignore_args_with_value = matches
+        .get_many::<String>("ignore-arg-with-value")
+        .unwrap_or_default()
+        .map(|s| s.into())
+        .collect::<Vec<String>>();
+
+    let exclude_pwd = matches.get_flag("exclude-pwd");
+
+    let watch_hostname = matches.get_flag("watch-hostname");
+
+    let share_cache = matches.get_flag("share-cache");
+
+    let exclude_user = matches.get_flag("exclude-user");
+
+    let watch_platform = matches.get_flag("watch-platform")
+        || (share_cache && !matches.get_flag("no-watch-platform"));
+
+    let mut scope = ScopeBuilder::new()
+        .cmd(cmd.to_string())
+        .args(args)
+        .ignore_args(ignore_args)
+        .ignore_args_with_value(ignore_args_with_value)
+        .exclude_args(matches.get_flag("exclude-args"))
+        .watch_paths(watch_paths)
+        .watch_symlinks(watch_symlinks)
+        .watch_values(watch_values)
+        .watch_scope(watch_scope)
+        .watch_env(watch_env)
+        .watch_env_exists(watch_env_exists)
+        .set_env(set_env.clone())
+        .watch_progress(!matches.get_flag("quiet"));
+
+    if matches.get_flag("clear-env") {
+        let keep_env = matches
+            .get_many::<String>("keep-env")
+            .unwrap_or_default()
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in &watch_env_names {
+            let kept = deja::CLEAR_ENV_KEEPS.contains(&name.as_str()) || keep_env.contains(name);
+            if !kept && !set_env.contains_key(name) {
+                eprintln!(
+                    "deja: warning: --watch-env {} isn't passed to the command with --clear-env, \
+                     add --keep-env {} to pass it",
+                    name, name
+                );
+            }
+        }
+        scope = scope.clear_env(keep_env);
+    }
+
+    if matches.get_flag("watch-cache") {
+        if let Some(path) = watch_cache_path(matches) {
+            let read_only = matches.get_flag("read-only") || optional_flag(matches, "dry-run");
+            scope = scope.watch_cache(WatchCache::new(path, read_only));
+        }
+    }
+
+    let chdir = match matches.get_one::<PathBuf>("chdir") {
+        Some(path) => {
+            let chdir = std::fs::canonicalize(path)
+                .map_err(|_| anyhow!("directory '{}' not found", path.display()))?;
+            if !chdir.is_dir() {
+                return Err(anyhow!("'{}' isn't a directory", path.display()));
+            }
+            scope = scope.chdir(chdir.clone());
+            Some(chdir)
+        }
+        None => None,
+    };
+
+    let pwd = match (matches.get_one::<PathBuf>("pwd"), chdir) {
+        (Some(path), _) => std::fs::canonicalize(path)
+            .map_err(|_| anyhow!("pwd '{}' not found", path.display()))?,
+        (None, Some(chdir)) => chdir,
+        (None, None) => std::env::current_dir()?,
+    };
+
+    if let Some(mode) = matches.get_one::<String>("watch-git") {
+        let mode = GitWatchMode::from_str(mode)?;
+        if find_git_root(&pwd).is_some() || !matches.get_flag("watch-git-optional") {
+            scope = scope.git(GitState::read(&pwd, mode)?);
+        }
+    }
+
+    if !exclude_pwd {
+        let pwd = if matches.get_flag("pwd-from-git-root") {
+            find_git_root(&pwd).unwrap_or(pwd)
+        } else {
+            pwd
+        };
+        scope = scope.pwd(pwd);
+    }
+
+    if let Some(key) = matches.get_one::<String>("key") {
+        scope = scope.key(key);
+    }
+
+    if let Some(shell) = &shell {
+        scope = scope.shell(shell);
+    }
+
+    if let Some(mode) = matches.get_one::<String>("watch-command-binary") {
+        let mode = BinaryWatchMode::from_str(mode)?;
+        let binary = shell.as_ref().unwrap_or(cmd);
+        scope = scope.command_binary(CommandBinary::resolve(binary, mode)?);
+    }
+
+    if watch_hostname {
+        let hostname = whoami::fallible::hostname()
+            .map_err(|e| anyhow!("unable to determine hostname: {}", e))?;
+        scope = scope.hostname(hostname);
+    }
+
+    if watch_platform {
+        let platform = format!("{}-{}", std::env::consts::OS, std::env::consts::ARCH);
+        scope = scope.platform(platform);
+    }
+
+    if share_cache {
+        scope = scope.shared(true);
+    } else if !exclude_user {
+        scope = scope.user(user_identity(matches)?);
+    }
+
+    let command = Command::new(scope.build()?);
+    deja::log(
+        Level::Info,
+        format!(
+            "hash {} for {} (in {:.3}s)",
+            command.hash(),
+            line,
+            started.elapsed().as_secs_f64()
+        ),
+    );
+    Ok(command)
+}
+
+fn shell_path(matches: &clap::ArgMatches) -> String {
+    match matches.get_one::<PathBuf>("shell-path") {
+        Some(path) => path.to_string_lossy().into_owned(),
+        None => std::env::var("SHELL")
+            .ok()
+            .filter(|shell| !shell.is_empty())
+            .unwrap_or_else(|| "/bin/sh".to_string()),
+    }
+}
+
+fn user_identity(matches: &clap::ArgMatches) -> anyhow::Result<String> {
+    let uid = || unsafe { libc::geteuid() };
+    match matches.get_one::<String>("user-key").map(|s| s.as_str()) {
+        Some("uid") => Ok(uid().to_string()),
+        Some("uid-host") => {
+            let hostname = whoami::fallible::hostname()
+                .map_err(|e| anyhow!("unable to determine hostname: {}", e))?;
+            Ok(format!("{}@{}", uid(), hostname))
+        }
+        _ => Ok(whoami::username()),
+    }
+}
+
+/// Where results are stored, chosen with `--backend` or from the cache path.
+enum Backend {
+    Disk(DiskCache),
+    Sqlite(SqliteCache),
+    Redis(RedisCache),
+}
+
+/// Whether a flag is set, for flags only some subcommands have.
+fn optional_flag(matches: &clap::ArgMatches, name: &str) -> bool {
+    matches
+        .try_get_one::<bool>(name)
+        .ok()
+        .flatten()
+        .copied()
+        .unwrap_or(false)
+}
+
+fn cache(matches: &clap::ArgMatches) -> anyhow::Result<Backend> {
+    let path = matches.get_one::<PathBuf>("cache").unwrap();
+    let backend = matches.get_one::<String>("backend").map(String::as_str);
+    let fallback = matches
+        .try_get_one::<String>("cache-fallback")
+        .ok()
+        .flatten();
+
+    match open_cache(path, backend, matches) {
+        Err(e) if fallback.is_some() => {
+            let fallback = fallback_cache_dir()?;
+            eprintln!("deja: warning: {e}, using {} instead", fallback.display());
+            open_cache(&fallback, Some("disk"), matches)
+        }
+        result => result,
+    }
+}
+
+/// The cache given with `--secondary-cache`, if any. Its backend is chosen from its path.
+fn secondary_cache(matches: &clap::ArgMatches) -> anyhow::Result<Option<Backend>> {
+    match matches
+        .try_get_one::<PathBuf>("secondary-cache")
+        .ok()
+        .flatten()
+    {
+        Some(path) => Ok(Some(open_cache(path, None, matches)?)),
+        None => Ok(None),
+    }
+}
+
+fn open_cache(
+    cache: &Path,
+    backend: Option<&str>,
+    matches: &clap::ArgMatches,
+) -> anyhow::Result<Backend> {
+    let modes = cache_modes(matches);
+    let cache_dir = cache.to_path_buf();
+    let read_only = matches.get_flag("read-only");
+    let dry_run = optional_flag(matches, "dry-run");
+    let encrypt = optional_flag(matches, "encrypt");
+    let key = encryption_key(matches)?;
+    if encrypt && key.is_none() {
+        return Err(anyhow!(
+            "--encrypt needs a key, given with --encryption-key or DEJA_ENCRYPTION_KEY"
+        ));
+    }
+
+    if let Some(url) = cache.to_str().filter(|cache| is_redis_url(cache)) {
+        if read_only {
+            return Err(read_only_unsupported_error());
+        }
+        if encrypt {
+            return Err(encrypt_unsupported_error());
+        }
+        return Ok(Backend::Redis(RedisCache::open(url)));
+    }
+
+    if is_sqlite(cache, backend) {
+        if read_only {
+            return Err(read_only_unsupported_error());
+        }
+        if encrypt {
+            return Err(encrypt_unsupported_error());
+        }
+        if dry_run {
+            return Ok(Backend::Sqlite(SqliteCache::open_read_only(
+                cache_dir, modes,
+            )?));
+        }
+        Ok(Backend::Sqlite(SqliteCache::open(cache_dir, modes)?))
+    } else {
+        let mut cache = DiskCache::new(cache_dir, modes, read_only || dry_run)?;
+        cache.set_encryption(key, encrypt);
+        Ok(Backend::Disk(cache))
+    }
+}
+
+/// The key given with `--encryption-key`, if any. Not every subcommand has this option.
+fn encryption_key(matches: &clap::ArgMatches) -> anyhow::Result<Option<EncryptionKey>> {
+    match matches
+        .try_get_one::<PathBuf>("encryption-key")
+        .ok()
+        .flatten()
+    {
+        Some(path) => Ok(Some(EncryptionKey::from_file(path)?)),
+        None => Ok(None),
+    }
+}
+
+/// The permissions used for files and directories created in the cache, from `--share-cache`
+/// and any modes given explicitly. Not every subcommand has these options.
+fn cache_modes(matches: &clap::ArgMatches) -> CacheModes {
+    let mode = |name| matches.try_get_one::<u32>(name).ok().flatten().copied();
+    let defaults = CacheModes::new(optional_flag(matches, "share-cache"));
+    CacheModes {
+        file: mode("cache-mode").unwrap_or(defaults.file),
+        dir: mode("cache-dir-mode").unwrap_or(defaults.dir),
+    }
+}
+
+/// Whether a cache path should use the sqlite backend.
+fn is_sqlite(cache: &Path, backend: Option<&str>) -> bool {
+    match backend {
+        Some(backend) => backend == "sqlite",
+        None => cache.extension().is_some_and(|extension| extension == "db"),
+    }
+}
+
+/// Where `--watch-cache` remembers hashes: inside a disk cache, or alongside a sqlite database.
+/// Redis caches aren't on this machine, so have nowhere to keep them.
+fn watch_cache_path(matches: &clap::ArgMatches) -> Option<PathBuf> {
+    let cache = matches.get_one::<PathBuf>("cache")?;
+    let backend = matches.get_one::<String>("backend").map(String::as_str);
+    if cache.to_str().is_some_and(is_redis_url) {
+        None
+    } else if is_sqlite(cache, backend) {
+        Some(cache.with_extension("watch-hashes"))
+    } else {
+        Some(cache.join("watch-hashes"))
+    }
+}
+
+/// Where `run` and `read` count hits and misses: inside a disk cache, or alongside a sqlite
+/// database. Redis caches are shared between machines, so have nowhere to keep them.
+fn counters_path(matches: &clap::ArgMatches) -> Option<PathBuf> {
+    let cache = matches.get_one::<PathBuf>("cache")?;
+    let backend = matches.get_one::<String>("backend").map(String::as_str);
+    if cache.to_str().is_some_and(is_redis_url) {
+        None
+    } else if is_sqlite(cache, backend) {
+        Some(cache.with_extension(COUNTERS_FILE))
+    } else {
+        Some(cache.join(COUNTERS_FILE))
+    }
+}
+
+/// The counters `run` and `read` add hits and misses to, unless the cache is read only.
+fn counters(matches: &clap::ArgMatches) -> Option<Counters> {
+    if optional_flag(matches, "read-only") {
+        return None;
+    }
+    counters_path(matches).map(|path| Counters::new(path, cache_modes(matches)))
+}
+
+fn encrypt_unsupported_error() -> anyhow::Error {
+    anyhow!("--encrypt is only supported by the disk backend")
+}
+
+fn read_only_unsupported_error() -> anyhow::Error {
+    anyhow!("--read-only is only supported by the disk backend")
+}
+
+fn parse_duration(d: &str) -> anyhow::Result<Duration> {
+    humantime::parse_duration(d).map_err(|_| {
+        anyhow!(
+            "invalid duration '{}', use values like 15s, 30m, 3h, 4d etc",
+            d
+        )
+    })
+}
+
+fn parse_probability(p: &str) -> anyhow::Result<f64> {
+    match p {
+        "always" => Ok(1.0),
+        _ => p
+            .parse::<f64>()
+            .ok()
+            .filter(|p| (0.0..=1.0).contains(p))
+            .ok_or_else(|| {
+                anyhow!(
+                    "invalid probability '{}', use a number from 0 to 1, or always",
+                    p
+                )
+            }),
+    }
+}
+
+/// Parses a variable given to `--env`, like `TZ=UTC`. The value can be empty, or contain `=`.
+fn parse_env_var(var: &str) -> anyhow::Result<(String, String)> {
+    match var.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.to_string(), value.to_string())),
+        _ => Err(anyhow!(
+            "invalid variable '{}', use values like NAME=value",
+            var
+        )),
+    }
+}
+
+/// Parses a tag given to --tag, which can't be empty or contain whitespace or commas.
+fn parse_tag(tag: &str) -> anyhow::Result<String> {
+    if tag.is_empty() || tag.contains(|c: char| c.is_whitespace() || c == ',') {
+        return Err(anyhow!(
+            "invalid tag '{}', tags can't be empty or contain whitespace or commas",
+            tag
+        ));
+    }
+    Ok(tag.to_string())
+}
+
+/// The retention policy for the cache, unless `--ignore-policy` is given.
+fn retention_policy(matches: &clap::ArgMatches) -> anyhow::Result<Option<RetentionPolicy>> {
+    if matches.get_flag("ignore-policy") {
+        return Ok(None);
+    }
+    let cache = matches.get_one::<PathBuf>("cache").unwrap();
+    find_policy(cache)
+        .map(|path| RetentionPolicy::read(&path))
+        .transpose()
+}
+
+/// The tags and id given to list or remove, to choose which results to include.
+fn tag_filter(matches: &clap::ArgMatches) -> TagFilter {
+    TagFilter {
+        tags: matches
+            .get_many::<String>("tag")
+            .unwrap_or_default()
+            .cloned()
+            .collect(),
+        any: matches.get_flag("any-tag"),
+        id: matches.get_one::<String>("id").cloned(),
+    }
+}
+
+/// The longest id that can be given to `--id`, in characters.
+const MAX_ID_LENGTH: usize = 128;
+
+/// Parses an id given to `--id`, which can't be empty, contain control characters or be longer
+/// than `MAX_ID_LENGTH`.
+fn parse_id(id: &str) -> anyhow::Result<String> {
+    if id.is_empty() || id.contains(char::is_control) {
+        return Err(anyhow!(
+            "invalid id '{}', ids can't be empty or contain control characters",
+            id.escape_debug()
+        ));
+    }
+    let length = id.chars().count();
+    if length > MAX_ID_LENGTH {
+        return Err(anyhow!(
+            "id is {} characters long, ids can be at most {} characters",
+            length,
+            MAX_ID_LENGTH
+        ));
+    }
+    Ok(id.to_string())
+}
+
+/// Where `--print-hash` writes the hash.
+#[derive(Debug, Clone, PartialEq)]
+enum HashSink {
+    Stderr,
+    Fd(i32),
+    File(PathBuf),
+}
+
+impl HashSink {
+    fn write(&self, hash: &str) -> anyhow::Result<()> {
+        let written = match self {
+            HashSink::Stderr => writeln!(std::io::stderr(), "deja: hash {}", hash),
+            HashSink::Fd(fd) => {
+                // The descriptor was opened by whatever started deja, so is left open
+                let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(*fd) });
+                writeln!(file, "{}", hash)
+            }
+            HashSink::File(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", hash)),
+        };
+        written.map_err(|e| {
+            let sink = match self {
+                HashSink::Stderr => "stderr".to_string(),
+                HashSink::Fd(fd) => format!("fd {}", fd),
+                HashSink::File(path) => format!("'{}'", path.display()),
+            };
+            anyhow!("unable to write hash to {}: {}", sink, e)
+        })
+    }
+}
+
+/// Parses a sink given to `--print-hash`, like `stderr`, `fd:3` or `file:hashes.log`.
+fn parse_hash_sink(sink: &str) -> anyhow::Result<HashSink> {
+    let invalid = || anyhow!("invalid sink '{}', use stderr, fd:N or file:PATH", sink);
+    match sink.split_once(':') {
+        None if sink == "stderr" => Ok(HashSink::Stderr),
+        Some(("fd", fd)) => fd
+            .parse::<u16>()
+            .map(|fd| HashSink::Fd(fd.into()))
+            .map_err(|_| invalid()),
+        Some(("file", path)) if !path.is_empty() => Ok(HashSink::File(path.into())),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_regex(r: &str) -> anyhow::Result<Regex> {
+    Regex::new(r).map_err(|_| anyhow!("invalid regular expression '{}'", r))
+}
+
+fn record_options(matches: &clap::ArgMatches) -> anyhow::Result<RecordOptions> {
+    let mut options = RecordOptions::default();
+
+    if let Some(exit_codes) = matches.get_one::<[bool; 256]>("record-exit-codes") {
+        options.set_exit_codes(*exit_codes);
+    };
+
+    if let Some(s) = matches.get_one::<String>("cache-for") {
+        options.set_cache_for(Some(parse_duration(s)?));
+    };
+
+    if let Some(s) = matches.get_one::<String>("expire-at") {
+        options.set_expire_at(Some(parse_expire_at(s, std::time::SystemTime::now())?));
+    };
+
+    if let Some(s) = matches.get_one::<String>("timeout") {
+        let timeout = Timeout {
+            duration: parse_duration(s)?,
+            signal: parse_signal(matches.get_one::<String>("timeout-signal").unwrap())?,
+        };
+        let exit_code = *matches.get_one::<i32>("timeout-exit-code").unwrap();
+        options.set_timeout(Some(timeout), exit_code);
+    };
+
+    options.set_record_signals(matches.get_flag("record-signals"));
+
+    if let Some(tags) = matches.get_many::<String>("tag") {
+        options.set_tags(tags.cloned().collect());
+    }
+
+    options.set_id(matches.get_one::<String>("id").cloned());
+
+    if let Some(keep_history) = matches.get_one::<usize>("keep-history") {
+        options.set_keep_history(*keep_history);
+    }
+
+    if let Some(patterns) = matches.get_many::<String>("record-env") {
+        let mut snapshot = EnvSnapshotOptions::default();
+        for pattern in patterns {
+            if pattern.is_empty() {
+                snapshot.patterns.push("PATH".into());
+                snapshot.patterns.extend(
+                    matches
+                        .get_many::<String>("watch-env")
+                        .unwrap_or_default()
+                        .cloned(),
+                );
+            } else {
+                snapshot.patterns.push(pattern.clone());
+            }
+        }
+        snapshot.redact = DEFAULT_REDACT_PATTERNS
+            .iter()
+            .map(|p| p.to_string())
+            .chain(
+                matches
+                    .get_many::<String>("redact-env")
+                    .unwrap_or_default()
+                    .cloned(),
+            )
+            .collect();
+        options.set_env_snapshot(Some(snapshot));
+    }
+
+    if matches.get_flag("record-only-if-output") {
+        options.set_output_required(OutputRequired::Stdout);
+    } else if matches.get_flag("record-only-if-any-output") {
+        options.set_output_required(OutputRequired::Any);
+    }
+
+    if let Some(s) = matches.get_one::<String>("record-if-output-matches") {
+        options.set_record_if_output_matches(Some(parse_regex(s)?));
+    };
+
+    if let Some(s) = matches.get_one::<String>("skip-record-if-output-matches") {
+        options.set_skip_record_if_output_matches(Some(parse_regex(s)?));
+    };
+
+    Ok(options)
+}
+
+fn read_options(matches: &clap::ArgMatches) -> anyhow::Result<FindOptions> {
+    let mut options = FindOptions::default();
+
+    if let Some(s) = matches.get_one::<String>("look-back") {
+        options.set_max_age(Some(parse_duration(s)?));
+    };
+
+    if let Ok(Some(s)) = matches.try_get_one::<String>("stale-while-revalidate") {
+        options.set_stale_while_revalidate(Some(parse_duration(s)?));
+    };
+
+    if let Ok(Some(&allow_expired)) = matches.try_get_one::<bool>("allow-expired") {
+        let allow_expired_for = matches
+            .get_one::<String>("allow-expired-for")
+            .map(|s| parse_duration(s))
+            .transpose()?;
+        options.set_allow_expired(
+            allow_expired || allow_expired_for.is_some(),
+            allow_expired_for,
+        );
+    };
+
+    if optional_flag(matches, "renew-on-hit") {
+        let cache_for = matches
+            .get_one::<String>("cache-for")
+            .ok_or_else(|| anyhow!("--renew-on-hit needs --cache-for"))?;
+        options.set_renew_for(Some(parse_duration(cache_for)?));
+    }
+
+    Ok(options)
+}
+
+fn verify(matches: &clap::ArgMatches) -> Option<Verify> {
+    matches.get_one::<f64>("verify").map(|probability| Verify {
+        probability: *probability,
+        update: matches.get_flag("verify-update"),
+        background: matches.get_flag("verify-background"),
+    })
+}
+
+fn hooks(matches: &clap::ArgMatches) -> Hooks {
+    Hooks {
+        on_hit: matches.get_one::<String>("on-hit").cloned(),
+        on_miss: matches.get_one::<String>("on-miss").cloned(),
+    }
+}
+
+fn on_miss(matches: &clap::ArgMatches) -> anyhow::Result<OnMiss> {
+    let Some(cmdline) = matches.get_one::<String>("on-miss-exec") else {
+        return Ok(OnMiss::Exit(
+            *matches.get_one::<i32>("cache-miss-exit-code").unwrap_or(&1),
+        ));
+    };
+
+    let words = if matches.get_flag("on-miss-shell") {
+        vec!["sh".to_string(), "-c".to_string(), cmdline.to_string()]
+    } else {
+        shell_words::split(cmdline)
+            .map_err(|_| anyhow!("unable to parse --on-miss-exec '{}'", cmdline))?
+    };
+
+    if words.is_empty() {
+        return Err(anyhow!("--on-miss-exec must not be empty"));
+    }
+
+    Ok(OnMiss::Exec(words))
+}
+
+fn parse_generations(generations: &str) -> anyhow::Result<(usize, usize)> {
+    generations
+        .split_once("..")
+        .and_then(|(from, to)| Some((from.parse().ok()?, to.parse().ok()?)))
+        .ok_or_else(|| {
+            anyhow!(
+                "invalid generations '{}', use a range like 1..0",
+                generations
+            )
+        })
+}
+
+fn lock_options(matches: &clap::ArgMatches) -> anyhow::Result<LockOptions> {
+    Ok(LockOptions {
+        no_wait: matches.get_flag("no-wait"),
+        timeout: matches
+            .get_one::<String>("lock-timeout")
+            .map(|s| parse_duration(s))
+            .transpose()?,
+    })
+}
+
+/// Logs to the file given with `--log-file`, or stderr, at the level given with `--log-level`.
+/// `--debug` is the same as `--log-level debug`, and a log file without a level logs at debug.
+/// Without either, warnings are still shown on stderr.
+fn logger(matches: &clap::ArgMatches) -> anyhow::Result<Logger> {
+    let file = matches.get_one::<PathBuf>("log-file");
+    let level = match matches.get_one::<String>("log-level") {
+        _ if matches.get_flag("debug") => Some(Level::Debug),
+        Some(level) => Some(level.parse()?),
+        None => file.map(|_| Level::Debug),
+    };
+    match (level, file) {
+        (Some(level), Some(file)) => Logger::file(level, file),
+        (Some(level), None) => Ok(Logger::stderr(level)),
+        (None, _) => Ok(Logger::stderr(Level::Warn)),
+    }
+}
+
+/// The `--color` choice, found before parsing arguments for real, so help and errors from
+/// parsing them use it.
+fn color_choice() -> anyhow::Result<clap::ColorChoice> {
+    let matches = cli()?
+        .ignore_errors(true)
+        .disable_help_flag(true)
+        .disable_version_flag(true)
+        .try_get_matches();
 
-    let completions = clap::command!()
-        .name("completions")
-        .args(vec![Arg::new("shell")
-            .long("shell")
-            .value_name("SHELL")
-            .value_parser(["bash", "fish", "zsh", "powershell"])
-            .required(true)
-            .help("Shell to generate completions for")]);
+    let color = matches
+        .ok()
+        .and_then(|matches| matches.get_one::<String>("color").cloned());
+    Ok(match color.as_deref() {
+        Some("always") => clap::ColorChoice::Always,
+        Some("never") => clap::ColorChoice::Never,
+        _ => clap::ColorChoice::Auto,
+    })
+}
 
-    Ok(clap::command!()
-        .name("deja")
-        .arg_required_else_help(true)
-        .styles(styles())
+/// The helper used by completion scripts to list cached commands. It's left out of `cli()`, so
+/// it isn't included in generated completions and documentation.
+fn complete_subcommand() -> clap::Command {
+    clap::Command::new("__complete")
+        .about("List cached commands matching the words being completed")
+        .hide(true)
         .arg(
-            Arg::new("debug")
-                .long("debug")
-                .action(clap::ArgAction::SetTrue)
-                .global(true)
-                .hide(true),
+            Arg::new("words")
+                .value_name("WORDS")
+                .num_args(0..)
+                .trailing_var_arg(true)
+                .allow_hyphen_values(true)
+                .help("Words after deja, ending with the word being completed"),
         )
-        .subcommands(vec![
-            run,
-            read,
-            force,
-            remove,
-            test,
-            explain,
-            hash,
-            completions,
-        ]))
 }
 
-fn parse_exit_codes(param: &str) -> [bool; 256] {
-    let parts = param.split(',').map(|s| s.trim());
+/// Subcommands whose COMMAND argument is completed with commands found in the cache.
+const CACHED_COMMAND_SUBCOMMANDS: [&str; 6] = ["remove", "show", "read", "test", "pin", "unpin"];
 
-    let mut exit_codes = [false; 256];
-    for part in parts {
-        if part.ends_with('+') {
-            let start = part.trim_end_matches('+').parse::<i32>().unwrap();
-            for i in start..=255 {
-                exit_codes[i as usize] = true;
-            }
-        } else if part.contains('-') {
-            let mut parts = part.split('-');
-            let start = parts.next().unwrap().parse::<i32>().unwrap();
-            let end = parts.next().unwrap().parse::<i32>().unwrap();
-            for i in start..=end {
-                exit_codes[i as usize] = true;
-            }
-        } else {
-            let code = part.parse::<i32>().unwrap();
-            exit_codes[code as usize] = true;
-        }
-    }
-    exit_codes
+const BASH_CACHED_COMMANDS: &str = r#"
+_deja_cached_commands() {
+    local IFS=$'\n'
+    local candidates=($(deja __complete -- "$@" 2>/dev/null))
+    COMPREPLY=()
+    if [[ ${#candidates[@]} -gt 0 ]]; then
+        COMPREPLY=($(printf '%q\n' "${candidates[@]}"))
+    fi
 }
 
-fn command(matches: &clap::ArgMatches) -> anyhow::Result<Command> {
-    let cmd = matches
-        .get_one::<String>("command")
-        .ok_or(anyhow!("unexpected failure to parse arguments"))?;
-    let args = matches
-        .get_many::<String>("arguments")
-        .unwrap_or_default()
-        .map(|s| s.into())
-        .collect::<Vec<String>>();
-    let watch_path_bufs = matches
-        .get_many::<PathBuf>("watch-path")
-        .unwrap_or_default()
-        .map(|s| s.into())
-        .collect::<Vec<PathBuf>>();
+_deja() {
+    case "${COMP_WORDS[1]}" in
+        SUBCOMMANDS)
+            _deja_cached_commands "${COMP_WORDS[@]:1:COMP_CWORD}"
+            if [[ ${#COMPREPLY[@]} -gt 0 ]]; then
+                return 0
+            fi
+            ;;
+    esac
+    _deja_generated "$@"
+}
+"#;
 
-    let watch_paths = watch_path_bufs
-        .iter()
-        .map(|path| {
-            std::fs::canonicalize(path)
-                .map_err(|_| anyhow!("watch path '{}' not found", path.display()))
-        })
-        .collect::<Result<Vec<PathBuf>, anyhow::Error>>()?;
+const ZSH_CACHED_COMMANDS: &str = r#"
+_deja() {
+    if (( CURRENT > 2 )) && [[ ${words[2]} == (SUBCOMMANDS) ]]; then
+        local -a candidates
+        candidates=(${(f)"$(deja __complete -- "${(@Q)words[2,CURRENT]}" 2>/dev/null)"})
+        if (( ${#candidates} )); then
+            compadd -a candidates
+            return
+        fi
+    fi
+    _deja_generated "$@"
+}
+"#;
 
-    let watch_scope = matches
-        .get_many::<String>("watch-scope")
-        .unwrap_or_default()
-        .map(|s| s.into())
-        .collect::<Vec<String>>();
+const FISH_CACHED_COMMANDS: &str = r#"
+complete -c deja -n "__fish_deja_using_subcommand SUBCOMMANDS" -f -a "(deja __complete -- (commandline -opc)[2..-1] (commandline -ct) 2>/dev/null)"
+"#;
 
-    let watch_env_names = matches
-        .get_many::<String>("watch-env")
+/// The completion script for a shell. For bash, zsh and fish, the COMMAND argument of
+/// subcommands that look up results is completed with commands from the cache, listed by
+/// `deja __complete`. The generated completion function is renamed, and called by one that
+/// tries the cache first.
+fn completions(shell: clap_complete::Shell) -> anyhow::Result<String> {
+    let mut script = vec![];
+    clap_complete::generate(shell, &mut cli()?, "deja", &mut script);
+    let script = String::from_utf8(script)?;
+
+    let cached_commands = |template: &str, separator: &str| {
+        template
+            .trim_start()
+            .replace("SUBCOMMANDS", &CACHED_COMMAND_SUBCOMMANDS.join(separator))
+    };
+    let wrap = |template: &str| {
+        script.replacen(
+            "_deja() {",
+            &format!("{}\n_deja_generated() {{", cached_commands(template, "|")),
+            1,
+        )
+    };
+    Ok(match shell {
+        clap_complete::Shell::Bash => wrap(BASH_CACHED_COMMANDS),
+        clap_complete::Shell::Zsh => wrap(ZSH_CACHED_COMMANDS),
+        clap_complete::Shell::Fish => script + &cached_commands(FISH_CACHED_COMMANDS, " "),
+        _ => script,
+    })
+}
+
+/// The next word of each cached command that matches the words being completed. The first
+/// word is the subcommand, and the last the word being completed. Options before the command
+/// are used to find the cache, and completing an option gives nothing, leaving it to the
+/// shell's usual completion.
+fn complete(cli: &clap::Command, words: &[String]) -> Vec<String> {
+    let Some((name, words)) = words.split_first() else {
+        return vec![];
+    };
+    let Some((prefix, words)) = words.split_last() else {
+        return vec![];
+    };
+    let Some(subcommand) = cli
+        .find_subcommand(name)
+        .filter(|_| CACHED_COMMAND_SUBCOMMANDS.contains(&name.as_str()))
+    else {
+        return vec![];
+    };
+
+    // Options come before the command, along with the values of those that take one
+    let mut words = words.iter();
+    let mut options = vec![];
+    let mut typed: Vec<String> = vec![];
+    let mut in_command = false;
+    let mut needs_value = false;
+    while let Some(word) = words.next() {
+        if needs_value {
+            needs_value = false;
+            options.push(word.as_str());
+        } else if word == "--" || !word.starts_with('-') {
+            in_command = true;
+            typed.extend(
+                std::iter::once(word)
+                    .filter(|word| *word != "--")
+                    .chain(words)
+                    .cloned(),
+            );
+            break;
+        } else {
+            options.push(word.as_str());
+            needs_value = option_takes_value(cli, subcommand, word);
+        }
+    }
+    if !in_command && (needs_value || prefix.starts_with('-')) {
+        return vec![];
+    }
+
+    let Ok(matches) = cli
+        .clone()
+        .try_get_matches_from(["deja", name].into_iter().chain(options).chain(["-"]))
+    else {
+        return vec![];
+    };
+    let Some((_, matches)) = matches.subcommand() else {
+        return vec![];
+    };
+    let shell = optional_flag(matches, "shell");
+    let commands = match cache(matches) {
+        Ok(Backend::Disk(cache)) => cache.commands(),
+        Ok(Backend::Sqlite(cache)) => cache.commands(),
+        Ok(Backend::Redis(cache)) => cache.commands(),
+        Err(e) => Err(e),
+    };
+
+    let mut candidates = commands
         .unwrap_or_default()
-        .map(|s| s.into())
-        .collect::<Vec<String>>();
+        .into_iter()
+        .filter(|command| command.shell == shell && command.words.len() > typed.len())
+        .filter(|command| command.words.starts_with(&typed))
+        .map(|command| command.words[typed.len()].clone())
+        .filter(|word| word.starts_with(prefix.as_str()))
+        .collect::<Vec<_>>();
+    candidates.sort();
+    candidates.dedup();
+    candidates
+}
 
-    let watch_env: HashMap<String, String> = HashMap::from_iter(
-        watch_env_names
-            .iter()
-            .map(|name| (name.clone(), std::env::var(name).unwrap_or_default())),
-    );
+/// Whether the option given in a word takes its value from the next word.
+fn option_takes_value(cli: &clap::Command, subcommand: &clap::Command, word: &str) -> bool {
+    let matches = |arg: &&Arg| match word.strip_prefix("--") {
+        Some(long) => arg.get_long() == Some(long),
+        None => word.len() == 2 && word.chars().nth(1) == arg.get_short(),
+    };
+    cli.get_arguments()
+        .chain(subcommand.get_arguments())
+        .find(matches)
+        .is_some_and(|arg| arg.get_action().takes_values() && !arg.is_require_equals_set())
+}
 
-    let exclude_pwd = matches.get_flag("exclude-pwd");
+/// Makes options set in configuration files the defaults for the matching arguments, so
+/// options given on the command line or via variables still take precedence. Values are
+/// checked up front, so errors name the file they came from.
+fn configure(mut cli: clap::Command, config: &Config) -> anyhow::Result<clap::Command> {
+    let mut defaults = HashMap::new();
+    for option in config.options() {
+        let args = std::iter::once(&cli)
+            .chain(cli.get_subcommands())
+            .flat_map(|command| command.get_arguments())
+            .filter(|arg| arg.get_long() == Some(&option.key))
+            .collect::<Vec<_>>();
 
-    let share_cache = matches.get_flag("share-cache");
+        if args.is_empty() {
+            eprintln!(
+                "deja: warning: unknown option '{}' in {}",
+                option.key,
+                option.file.display()
+            );
+            continue;
+        }
 
-    let mut scope = ScopeBuilder::new()
-        .cmd(cmd.to_string())
-        .args(args)
-        .watch_paths(watch_paths)
-        .watch_scope(watch_scope)
-        .watch_env(watch_env);
+        defaults.insert(option.key.clone(), option_values(&args, option)?);
+    }
 
-    if !exclude_pwd {
-        scope = scope.pwd(std::env::current_dir().unwrap());
+    let names = cli
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().to_string())
+        .collect::<Vec<_>>();
+    cli = cli.mut_args(|arg| set_default(arg, &defaults));
+    for name in names {
+        cli = cli.mut_subcommand(name, |subcommand| {
+            subcommand.mut_args(|arg| set_default(arg, &defaults))
+        });
     }
+    Ok(cli)
+}
 
-    if share_cache {
-        scope = scope.shared(true);
-    } else {
-        scope = scope.user(whoami::username());
+/// Makes a preset's options the defaults for the subcommand it's run with, taking precedence
+/// over configuration files but not over the command line. Options the subcommand doesn't
+/// have, like --record-exit-codes for test, are left out.
+fn configure_preset(
+    mut cli: clap::Command,
+    name: &str,
+    preset: &Preset,
+) -> anyhow::Result<clap::Command> {
+    let subcommand = cli
+        .find_subcommand(name)
+        .ok_or(anyhow!("unexpected failure to parse arguments"))?;
+    let mut defaults = HashMap::new();
+    for option in &preset.options {
+        let args = cli
+            .get_arguments()
+            .chain(subcommand.get_arguments())
+            .filter(|arg| arg.get_long() == Some(&option.key))
+            .collect::<Vec<_>>();
+
+        if args.is_empty() {
+            let known = cli
+                .get_subcommands()
+                .flat_map(|command| command.get_arguments())
+                .any(|arg| arg.get_long() == Some(&option.key));
+            if !known {
+                eprintln!(
+                    "deja: warning: unknown option '{}' in preset '@{}' in {}",
+                    option.key,
+                    preset.name,
+                    option.file.display()
+                );
+            }
+            continue;
+        }
+        defaults.insert(option.key.clone(), option_values(&args, option)?);
     }
 
-    Ok(Command::new(scope.build()?))
+    cli = cli.mut_args(|arg| set_default(arg, &defaults));
+    Ok(cli.mut_subcommand(name, |subcommand| {
+        subcommand.mut_args(|arg| set_default(arg, &defaults))
+    }))
 }
 
-fn cache(matches: &clap::ArgMatches) -> anyhow::Result<DiskCache> {
-    let share_cache = matches.get_flag("share-cache");
-    let cache = matches.get_one::<PathBuf>("cache").unwrap();
-    let cache_dir = cache.clone();
+fn set_default(arg: Arg, defaults: &HashMap<String, Vec<String>>) -> Arg {
+    match arg.get_long().and_then(|long| defaults.get(long)) {
+        Some(values) => arg.default_values(values),
+        None => arg,
+    }
+}
 
-    let cache = cache::DiskCache::new(cache_dir, share_cache)?;
+/// The values an option gives its arguments, checked against each argument's parser.
+fn option_values(args: &[&Arg], option: &ConfigOption) -> anyhow::Result<Vec<String>> {
+    let values = config_values(args[0], option)?;
+    for arg in args {
+        let check = clap::Command::new("config").no_binary_name(true).arg(
+            Arg::new("values")
+                .value_parser(arg.get_value_parser().clone())
+                .num_args(0..)
+                .allow_hyphen_values(true),
+        );
+        check.try_get_matches_from(&values).map_err(|_| {
+            anyhow!(
+                "invalid value {} for '{}' in {}",
+                option.value,
+                option.key,
+                option.file.display()
+            )
+        })?;
+    }
+    Ok(values)
+}
 
-    Ok(cache)
+/// The name of the preset given in place of the command, like `metadata` for
+/// `deja run @metadata`.
+fn preset_name(matches: &clap::ArgMatches) -> Option<String> {
+    let (_, matches) = matches.subcommand()?;
+    let mut words = matches.try_get_many::<String>("command").ok().flatten()?;
+    words.next()?.strip_prefix('@').map(str::to_string)
 }
 
-fn parse_duration(d: &str) -> anyhow::Result<Duration> {
-    humantime::parse_duration(d).map_err(|_| {
-        anyhow!(
-            "invalid duration '{}', use values like 15s, 30m, 3h, 4d etc",
-            d
-        )
-    })
+/// The values an option from a configuration file gives its argument. Flags take `true` or
+/// `false`, and only options that can be repeated take arrays.
+fn config_values(arg: &Arg, option: &ConfigOption) -> anyhow::Result<Vec<String>> {
+    let file = option.file.display();
+    match (arg.get_action(), &option.value) {
+        (clap::ArgAction::SetTrue, ConfigValue::Boolean(value)) => Ok(vec![value.to_string()]),
+        (clap::ArgAction::SetTrue, _) => Err(anyhow!(
+            "invalid value {} for '{}' in {}, expected true or false",
+            option.value,
+            option.key,
+            file
+        )),
+        (clap::ArgAction::Append, value) => Ok(value.strings()),
+        (_, ConfigValue::Array(_)) => Err(anyhow!(
+            "invalid value {} for '{}' in {}, expected a single value",
+            option.value,
+            option.key,
+            file
+        )),
+        (_, value) => Ok(value.strings()),
+    }
 }
 
-fn record_options(matches: &clap::ArgMatches) -> anyhow::Result<RecordOptions> {
-    let mut options = RecordOptions::default();
+/// The options for a subcommand that were set by configuration files or a preset, rather than
+/// given on the command line or via variables.
+fn configured_options<'a>(
+    cli: &clap::Command,
+    name: &str,
+    matches: &clap::ArgMatches,
+    config: &'a Config,
+    preset: Option<&'a Preset>,
+) -> Vec<&'a ConfigOption> {
+    let Some(subcommand) = cli.find_subcommand(name) else {
+        return vec![];
+    };
+    let preset_options = preset
+        .map(|preset| preset.options.as_slice())
+        .unwrap_or_default();
+    config
+        .options()
+        .iter()
+        .filter(|option| !preset_options.iter().any(|preset| preset.key == option.key))
+        .chain(preset_options)
+        .filter(|option| {
+            subcommand.get_arguments().any(|arg| {
+                arg.get_long() == Some(&option.key)
+                    && matches.value_source(arg.get_id().as_str())
+                        == Some(ValueSource::DefaultValue)
+            })
+        })
+        .collect()
+}
 
-    if let Some(exit_codes) = matches.get_one::<String>("record-exit-codes") {
-        options.set_exit_codes(parse_exit_codes(exit_codes));
+fn run() -> anyhow::Result<i32> {
+    let config = Config::load(
+        user_config_path(),
+        &std::env::current_dir().unwrap_or_default(),
+    )?;
+    let mut args = std::env::args_os().collect::<Vec<_>>();
+    let mut configured_cli = configure(cli()?.subcommand(complete_subcommand()), &config)?;
+    let mut matches = configured_cli
+        .clone()
+        .color(color_choice()?)
+        .get_matches_from(&args);
+
+    // A command like `@metadata` is replaced by the preset's command, keeping any arguments
+    // after it, and the preset's options become defaults
+    let preset = match preset_name(&matches) {
+        Some(preset) if preset.is_empty() => {
+            return deja::list_presets(&config, &mut Output::stdio());
+        }
+        Some(preset) => {
+            let preset = config.preset(&preset).ok_or(anyhow!(
+                "unknown preset '@{}', see deja list-presets",
+                preset
+            ))?;
+            let Some((name, matches)) = matches.subcommand() else {
+                unreachable!("missing subcommand not caught by clap")
+            };
+            let words = matches.get_many::<String>("command").unwrap_or_default();
+            let position = args.len() - words.len();
+            args.splice(
+                position..=position,
+                preset.command.iter().map(OsString::from),
+            );
+            configured_cli = configure_preset(configured_cli, name, preset)?;
+            Some(preset)
+        }
+        None => None,
     };
+    if preset.is_some() {
+        matches = configured_cli
+            .clone()
+            .color(color_choice()?)
+            .get_matches_from(&args);
+    }
 
-    if let Some(s) = matches.get_one::<String>("cache-for") {
-        options.set_cache_for(Some(parse_duration(s)?));
+    deja::set_logger(logger(&matches)?).unwrap();
+    deja::set_disabled(matches.get_flag("disable")).unwrap();
+
+    let Some((name, matches)) = matches.subcommand() else {
+        unreachable!("missing subcommand not caught by clap")
     };
 
-    Ok(options)
-}
+    if name == "init" {
+        let strings = |name: &str| {
+            matches
+                .get_many::<String>(name)
+                .unwrap_or_default()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        return deja::init(
+            &mut Output::stdio(),
+            matches.get_one::<String>("shell").unwrap().parse()?,
+            &std::env::current_exe()?,
+            &strings("options"),
+            &strings("alias"),
+        );
+    }
 
-fn read_options(matches: &clap::ArgMatches) -> anyhow::Result<FindOptions> {
-    let mut options = FindOptions::default();
+    if name == "wrap" {
+        let words = matches
+            .get_many::<String>("command")
+            .unwrap_or_default()
+            .cloned()
+            .collect::<Vec<_>>();
+        // Options are separated from the command as deja run would, which checks them too
+        let run = cli()?.get_matches_from(
+            ["deja", "run"]
+                .into_iter()
+                .map(String::from)
+                .chain(words.clone()),
+        );
+        let (_, run) = run.subcommand().unwrap();
+        let command = run
+            .get_many::<String>("command")
+            .unwrap()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut options = words[..words.len() - command.len()].to_vec();
+        if options.last().is_some_and(|option| option == "--") {
+            options.pop();
+        }
+        let dir = match matches.get_one::<PathBuf>("output-dir") {
+            Some(dir) => dir.clone(),
+            None => dirs::home_dir()
+                .ok_or_else(|| anyhow!("unable to find home directory, use --output-dir"))?
+                .join(".local/bin"),
+        };
+        return deja::wrap(
+            &mut Output::stdio(),
+            matches.get_one::<String>("name").unwrap(),
+            &dir,
+            &std::env::current_exe()?,
+            &options,
+            &command,
+            matches.get_flag("force"),
+        );
+    }
 
-    if let Some(s) = matches.get_one::<String>("look-back") {
-        options.set_max_age(Some(parse_duration(s)?));
-    };
+    if name == "warm" {
+        // Options given before warm, like --cache-dir, are given to each run too
+        let global_args = std::env::args_os()
+            .skip(1)
+            .take_while(|arg| arg != "warm")
+            .collect::<Vec<_>>();
+        let commands = deja::read_manifest(matches.get_one::<PathBuf>("file").unwrap())?;
+        return deja::warm(
+            &mut Output::stdio(),
+            &std::env::current_exe()?,
+            &global_args,
+            &commands,
+            *matches.get_one::<u32>("jobs").unwrap() as usize,
+        );
+    }
 
-    Ok(options)
+    if name == "generate-man" {
+        let dir = matches.get_one::<PathBuf>("output-dir").unwrap();
+        std::fs::create_dir_all(dir)?;
+        let cli = documented_cli()?;
+        match matches.get_one::<String>("format").unwrap().as_str() {
+            "markdown" => std::fs::write(
+                dir.join("deja.md"),
+                clap_markdown::help_markdown_command(&cli),
+            )?,
+            _ => clap_mangen::generate_to(cli, dir)?,
+        }
+        return Ok(0);
+    }
+
+    if name == "list-presets" {
+        return deja::list_presets(&config, &mut Output::stdio());
+    }
+
+    if name == "completions" {
+        let shell_name = matches.get_one::<String>("shell").unwrap();
+        let shell = clap_complete::Shell::from_str(shell_name).unwrap();
+        print!("{}", completions(shell)?);
+        return Ok(0);
+    }
+
+    if name == "__complete" {
+        let words = matches.get_many::<String>("words").unwrap_or_default();
+        for candidate in complete(&configured_cli, &words.cloned().collect::<Vec<_>>()) {
+            println!("{}", candidate);
+        }
+        return Ok(0);
+    }
+
+    if name == "stats" {
+        let counters = counters_path(matches)
+            .map(|path| Counters::new(path, cache_modes(matches)))
+            .ok_or_else(|| anyhow!("stats needs a disk or sqlite cache"))?;
+        let output = &mut Output::stdio();
+        if !matches.get_flag("reset") {
+            return deja::stats(
+                &counters,
+                output,
+                *matches.get_one::<usize>("days").unwrap(),
+                matches.get_flag("json"),
+            );
+        }
+        let confirmed = matches.get_flag("yes");
+        if !confirmed && !std::io::stdin().is_terminal() {
+            return Err(anyhow!(
+                "stats --reset needs stdin to be a terminal to confirm, or --yes"
+            ));
+        }
+        return deja::reset_stats(&counters, output, &mut std::io::stdin().lock(), confirmed);
+    }
+
+    let status = match (name, cache(matches)?) {
+        ("import", Backend::Sqlite(cache)) => {
+            let mut from = DiskCache::new(
+                matches.get_one::<PathBuf>("from").unwrap().clone(),
+                CacheModes::PRIVATE,
+                true,
+            )?;
+            from.set_encryption(encryption_key(matches)?, false);
+            deja::import(&cache, &mut Output::stdio(), &from)
+        }
+        ("import", Backend::Disk(_) | Backend::Redis(_)) => Err(anyhow!(
+            "import needs a sqlite cache, use --backend sqlite or a cache path ending in .db"
+        )),
+        ("doctor", Backend::Disk(cache)) => deja::doctor(
+            &cache,
+            &mut Output::stdio(),
+            matches.get_flag("verify"),
+            matches.get_flag("fix"),
+        ),
+        ("doctor", Backend::Sqlite(_) | Backend::Redis(_)) => {
+            Err(anyhow!("doctor needs a disk cache"))
+        }
+        (name, Backend::Disk(cache)) => execute_layered(name, matches, cache),
+        (name, Backend::Sqlite(cache)) => execute_layered(name, matches, cache),
+        (name, Backend::Redis(cache)) => execute_layered(name, matches, cache),
+    }?;
+
+    let configured = configured_options(&configured_cli, name, matches, &config, preset);
+    if name == "explain" && !matches.get_flag("json") && !configured.is_empty() {
+        println!("options from config:");
+        for option in configured {
+            println!(
+                "  {} = {} ({})",
+                option.key,
+                option.value,
+                option.file.display()
+            );
+        }
+    }
+    Ok(status)
 }
 
-fn run() -> anyhow::Result<i32> {
-    let matches = cli()?.get_matches();
+/// Runs `push` or `pull` between the given cache and the `--remote` cache.
+fn sync<C, E>(name: &str, matches: &clap::ArgMatches, cache: &C) -> anyhow::Result<i32>
+where
+    C: Cache<E>,
+    E: CacheEntry,
+{
+    let remote = matches.get_one::<PathBuf>("remote").unwrap();
+    match open_cache(remote, None, matches)? {
+        Backend::Disk(remote) => sync_with(name, matches, cache, &remote),
+        Backend::Sqlite(remote) => sync_with(name, matches, cache, &remote),
+        Backend::Redis(remote) => sync_with(name, matches, cache, &remote),
+    }
+}
+
+fn sync_with<C, E, R, F>(
+    name: &str,
+    matches: &clap::ArgMatches,
+    cache: &C,
+    remote: &R,
+) -> anyhow::Result<i32>
+where
+    C: Cache<E>,
+    E: CacheEntry,
+    R: Cache<F>,
+    F: CacheEntry,
+{
+    let mut command = command(matches)?;
+    let output = &mut Output::stdio();
+    match name {
+        "push" => deja::push(&mut command, cache, output, remote),
+        _ => deja::pull(&mut command, cache, output, remote),
+    }
+}
+
+fn layered<P, S>(matches: &clap::ArgMatches, primary: P, secondary: S) -> LayeredCache<P, S> {
+    LayeredCache::new(
+        primary,
+        secondary,
+        optional_flag(matches, "populate-secondary"),
+        optional_flag(matches, "all-layers"),
+    )
+}
 
-    DEBUG.set(matches.get_flag("debug")).unwrap();
+/// Runs a subcommand against the given cache, layered in front of any `--secondary-cache`.
+fn execute_layered<C, E>(name: &str, matches: &clap::ArgMatches, cache: C) -> anyhow::Result<i32>
+where
+    C: Cache<E>,
+    E: CacheEntry,
+{
+    match secondary_cache(matches)? {
+        None => execute(name, matches, &cache),
+        Some(Backend::Disk(secondary)) => {
+            execute(name, matches, &layered(matches, cache, secondary))
+        }
+        Some(Backend::Sqlite(secondary)) => {
+            execute(name, matches, &layered(matches, cache, secondary))
+        }
+        Some(Backend::Redis(secondary)) => {
+            execute(name, matches, &layered(matches, cache, secondary))
+        }
+    }
+}
 
-    match matches.subcommand() {
-        Some(("run", matches)) => deja::run(
+/// Runs a subcommand against the given cache.
+fn execute<C, E>(name: &str, matches: &clap::ArgMatches, cache: &C) -> anyhow::Result<i32>
+where
+    C: Cache<E>,
+    E: CacheEntry,
+{
+    let output = &mut Output::stdio();
+    match name {
+        "run" | "force" if matches.get_flag("dry-run") => deja::dry_run(
+            &mut hashed_command(matches)?,
+            cache,
+            output,
+            read_options(matches)?,
+            &record_options(matches)?,
+            name == "force" || optional_flag(matches, "refresh"),
+            matches.get_flag("read-only"),
+            matches.get_flag("json"),
+        ),
+        "run" if matches.get_flag("verify-now") => deja::verify(
             &mut command(matches)?,
-            &cache(matches)?,
+            cache,
+            output,
             record_options(matches)?,
-            read_options(matches)?,
+            matches.get_flag("verify-update"),
         ),
-        Some(("read", matches)) => deja::read(
+        "run" if matches.get_flag("revalidate") => deja::revalidate(
             &mut command(matches)?,
-            &cache(matches)?,
+            cache,
+            record_options(matches)?,
             read_options(matches)?,
-            *matches.get_one::<i32>("cache-miss-exit-code").unwrap_or(&1),
         ),
-        Some(("force", matches)) => deja::force(
-            &mut command(matches)?,
-            &cache(matches)?,
+        "run" if matches.get_flag("refresh") => deja::refresh(
+            &mut hashed_command(matches)?,
+            cache,
+            output,
             record_options(matches)?,
+            matches.get_flag("print-status"),
+            &hooks(matches),
         ),
-        Some(("remove", matches)) => deja::remove(&mut command(matches)?, &cache(matches)?),
-        Some(("test", matches)) => deja::test(
+        "run" => deja::run(
+            &mut hashed_command(matches)?,
+            cache,
+            output,
+            record_options(matches)?,
+            read_options(matches)?,
+            lock_options(matches)?,
+            matches.get_flag("print-status"),
+            &hooks(matches),
+            verify(matches).as_ref(),
+            counters(matches).as_ref(),
+        ),
+        "read" => deja::read(
+            &mut hashed_command(matches)?,
+            cache,
+            output,
+            read_options(matches)?,
+            matches
+                .get_one::<String>("wait")
+                .map(|s| parse_duration(s))
+                .transpose()?,
+            on_miss(matches)?,
+            matches.get_flag("quiet"),
+            matches.get_flag("print-status"),
+            &hooks(matches),
+            counters(matches).as_ref(),
+        ),
+        "force" => deja::force(
+            &mut hashed_command(matches)?,
+            cache,
+            record_options(matches)?,
+            matches.get_flag("exit-zero"),
+        ),
+        "remove" if matches.get_flag("interactive") => {
+            if !std::io::stdin().is_terminal() {
+                return Err(anyhow!("remove --interactive needs stdin to be a terminal"));
+            }
+            deja::remove_interactive(
+                cache,
+                output,
+                &mut std::io::stdin().lock(),
+                matches
+                    .get_one::<String>("matching")
+                    .map(|s| parse_regex(s))
+                    .transpose()?
+                    .as_ref(),
+                &tag_filter(matches),
+            )
+        }
+        "remove" if matches.contains_id("tag") || matches.contains_id("id") => {
+            deja::remove_tagged(cache, output, &tag_filter(matches))
+        }
+        "remove" => deja::remove(&mut command(matches)?, cache),
+        "pin" | "unpin" => deja::pin(&mut command(matches)?, cache, output, name == "pin"),
+        "push" | "pull" => sync(name, matches, cache),
+        "list" => deja::list(
+            cache,
+            output,
+            matches.get_flag("long"),
+            matches.get_flag("json"),
+            &tag_filter(matches),
+        ),
+        "gc" => deja::gc(
+            cache,
+            output,
+            &deja::Gc {
+                older_than: matches
+                    .get_one::<String>("older-than")
+                    .map(|s| parse_duration(s))
+                    .transpose()?,
+                created_before: matches
+                    .get_one::<String>("created-before")
+                    .map(|s| parse_time(s, std::time::SystemTime::now()))
+                    .transpose()?,
+                include_pinned: matches.get_flag("include-pinned"),
+                policy: retention_policy(matches)?,
+                verbose: matches.get_flag("verbose"),
+            },
+            matches.get_flag("dry-run"),
+        ),
+        "show" => deja::show(
+            &mut command(matches)?,
+            cache,
+            output,
+            *matches.get_one::<usize>("generation").unwrap_or(&0),
+        ),
+        "history" => deja::history(&mut command(matches)?, cache, output),
+        "diff" => {
+            let ignore = matches
+                .get_one::<String>("ignore-matching-lines")
+                .map(|s| parse_regex(s))
+                .transpose()?;
+            if matches.get_flag("fresh") {
+                deja::diff_fresh(
+                    &mut command(matches)?,
+                    cache,
+                    output,
+                    record_options(matches)?,
+                    matches.get_flag("update"),
+                    ignore.as_ref(),
+                    matches.get_flag("stderr"),
+                )
+            } else {
+                deja::diff(
+                    &mut command(matches)?,
+                    cache,
+                    output,
+                    parse_generations(matches.get_one::<String>("generations").unwrap())?,
+                    ignore.as_ref(),
+                    matches.get_flag("stderr"),
+                )
+            }
+        }
+        "test" => deja::test(
             &mut command(matches)?,
-            &cache(matches)?,
+            cache,
+            output,
             read_options(matches)?,
+            matches.get_flag("json"),
+            matches.get_flag("why"),
+            matches
+                .get_flag("exit-with-status")
+                .then(|| *matches.get_one::<i32>("cache-miss-exit-code").unwrap()),
         ),
-        Some(("explain", matches)) => deja::explain(
+        "explain" => deja::explain(
             &mut command(matches)?,
-            &cache(matches)?,
+            cache,
+            output,
             read_options(matches)?,
+            matches.get_flag("json"),
+        ),
+        "hash" if matches.get_flag("stdin") => deja::hash_lines(
+            &mut std::io::stdin().lock(),
+            output,
+            matches.get_flag("json"),
+            |line| {
+                let words = if optional_flag(matches, "shell") {
+                    vec![line.to_string()]
+                } else {
+                    shell_words::split(line)?
+                };
+                command_from(matches, words)
+            },
+        ),
+        "hash" => deja::hash(
+            &mut command(matches)?,
+            cache,
+            output,
+            matches.get_flag("components"),
+            matches.get_flag("json"),
         ),
-        Some(("hash", matches)) => deja::hash(&mut command(matches)?, &cache(matches)?),
-        Some(("completions", matches)) => {
-            let shell_name = matches.get_one::<String>("shell").unwrap();
-            let shell = clap_complete::Shell::from_str(shell_name).unwrap();
-            clap_complete::generate(shell, &mut cli().unwrap(), "deja", &mut io::stdout());
-            Ok(0)
-        }
         _ => unreachable!("unknown subcommand not caught by clap"),
     }
 }
@@ -462,8 +3416,179 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             std::process::exit(status);
         }
         Err(e) => {
+            // Errors are always written to stderr, so are only logged when that's elsewhere
+            if deja::logs_to_file() {
+                deja::log(Level::Error, format!("{:#}", e));
+            }
             eprintln!("deja: {:?}", e);
             std::process::exit(1);
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn recorded(param: &str) -> Vec<usize> {
+        let exit_codes = parse_exit_codes(param).unwrap();
+        (0..256).filter(|code| exit_codes[*code]).collect()
+    }
+
+    fn error(param: &str) -> String {
+        parse_exit_codes(param).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn test_parse_exit_codes() {
+        assert_eq!(recorded("0"), vec![0]);
+        assert_eq!(recorded("0,1"), vec![0, 1]);
+        assert_eq!(recorded("2-5"), vec![2, 3, 4, 5]);
+        assert_eq!(recorded("253+"), vec![253, 254, 255]);
+        assert_eq!(recorded("0, 10-11, 255"), vec![0, 10, 11, 255]);
+    }
+
+    #[test]
+    fn test_parse_exit_codes_errors() {
+        assert_eq!(
+            error("abc"),
+            "invalid exit code 'abc', use values from 0 to 255"
+        );
+        assert_eq!(
+            error("300"),
+            "invalid exit code '300', use values from 0 to 255"
+        );
+        assert_eq!(
+            error("-1"),
+            "invalid exit code '-1', use values from 0 to 255"
+        );
+        assert_eq!(
+            error("5-"),
+            "invalid exit code '5-', use values from 0 to 255"
+        );
+        assert_eq!(
+            error("256+"),
+            "invalid exit code '256+', use values from 0 to 255"
+        );
+        assert_eq!(
+            error("0,"),
+            "invalid exit code '', use values from 0 to 255"
+        );
+        assert_eq!(
+            error("9-3"),
+            "invalid exit code range '9-3', start is after end"
+        );
+    }
+
+    #[test]
+    fn test_split_scopes() {
+        assert_eq!(split_scopes("a", "\n"), ["a"]);
+        assert_eq!(split_scopes("a\nb", "\n"), ["a", "b"]);
+        assert_eq!(split_scopes("a:b:c", ":"), ["a", "b", "c"]);
+        assert_eq!(split_scopes("17:00", "\n"), ["17:00"]);
+        assert_eq!(split_scopes("", "\n"), [""]);
+        assert_eq!(split_scopes("a\nb", ""), ["a\nb"]);
+    }
+
+    fn parse_run(args: &[&str]) -> (clap::ArgMatches, Vec<String>) {
+        let matches = cli()
+            .unwrap()
+            .try_get_matches_from(["deja", "run"].iter().chain(args))
+            .unwrap();
+        let (_, run) = matches.subcommand().unwrap();
+        let words = run
+            .get_many::<String>("command")
+            .unwrap()
+            .cloned()
+            .collect();
+        (run.clone(), words)
+    }
+
+    #[test]
+    fn test_command_arguments_are_passed_through() {
+        let (run, words) = parse_run(&["--cache", "/tmp/c", "grep", "--cache", "foo"]);
+        assert_eq!(
+            run.get_one::<PathBuf>("cache"),
+            Some(&PathBuf::from("/tmp/c"))
+        );
+        assert_eq!(words, ["grep", "--cache", "foo"]);
+
+        let (run, words) = parse_run(&["--", "cmd", "--debug"]);
+        assert!(!run.get_flag("debug"));
+        assert_eq!(words, ["cmd", "--debug"]);
+
+        let (run, words) = parse_run(&["--debug", "cmd", "--debug", "--", "-x", "--help"]);
+        assert!(run.get_flag("debug"));
+        assert_eq!(words, ["cmd", "--debug", "--", "-x", "--help"]);
+    }
+
+    #[test]
+    fn test_options_must_come_before_command() {
+        let error = cli()
+            .unwrap()
+            .try_get_matches_from(["deja", "run", "--unknown", "cmd"])
+            .unwrap_err();
+        assert_eq!(error.kind(), clap::error::ErrorKind::UnknownArgument);
+    }
+
+    #[test]
+    fn test_completions_list_cached_commands() -> anyhow::Result<()> {
+        for shell in [clap_complete::Shell::Bash, clap_complete::Shell::Zsh] {
+            let script = completions(shell)?;
+            assert!(script.contains("remove|show|read|test"), "{}", shell);
+            assert!(script.contains("deja __complete --"), "{}", shell);
+            assert!(script.contains("_deja_generated() {"), "{}", shell);
+            assert_eq!(script.matches("_deja() {").count(), 1, "{}", shell);
+        }
+
+        let script = completions(clap_complete::Shell::Fish)?;
+        assert!(script.contains("__fish_deja_using_subcommand remove show read test"));
+        assert!(script.contains("deja __complete --"));
+
+        let script = completions(clap_complete::Shell::PowerShell)?;
+        assert!(!script.contains("__complete"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(parse_id("JIRA-123").unwrap(), "JIRA-123");
+        assert_eq!(parse_id(&"é".repeat(128)).unwrap(), "é".repeat(128));
+        assert_eq!(
+            parse_id(&"a".repeat(129)).unwrap_err().to_string(),
+            "id is 129 characters long, ids can be at most 128 characters"
+        );
+        assert_eq!(
+            parse_id("run\n2").unwrap_err().to_string(),
+            "invalid id 'run\\n2', ids can't be empty or contain control characters"
+        );
+        assert!(parse_id("").is_err(), "empty");
+    }
+
+    #[test]
+    fn test_parse_hash_sink() {
+        assert_eq!(parse_hash_sink("stderr").unwrap(), HashSink::Stderr);
+        assert_eq!(parse_hash_sink("fd:3").unwrap(), HashSink::Fd(3));
+        assert_eq!(
+            parse_hash_sink("file:/tmp/hashes").unwrap(),
+            HashSink::File("/tmp/hashes".into())
+        );
+        assert_eq!(
+            parse_hash_sink("fd:-1").unwrap_err().to_string(),
+            "invalid sink 'fd:-1', use stderr, fd:N or file:PATH"
+        );
+        assert!(parse_hash_sink("file:").is_err(), "no path");
+        assert!(parse_hash_sink("stdout").is_err(), "unknown sink");
+    }
+
+    #[test]
+    fn test_option_takes_value() -> anyhow::Result<()> {
+        let cli = cli()?;
+        let remove = cli.find_subcommand("remove").unwrap();
+        assert!(option_takes_value(&cli, remove, "--cache"));
+        assert!(!option_takes_value(&cli, remove, "--cache=/tmp/c"));
+        assert!(!option_takes_value(&cli, remove, "--shell"));
+        assert!(!option_takes_value(&cli, remove, "--unknown"));
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cache::{replay_output, Cache, CacheEntry, FindOptions, OutputReader, RecordOptions};
+use crate::command::{Command, ResourceUsage, Scope};
+use crate::disabled;
+use crate::info;
+use crate::output::Output;
+
+/// Returns the cached result of `f` for the given scope, or runs `f` and records its result.
+/// Results are recorded with the default `RecordOptions`, so are kept until removed.
+///
+/// The scope identifies the result in the same way as for a command, so should include
+/// anything the result depends on, for example with `ScopeBuilder::key`.
+pub fn memoize<E, F>(cache: &impl Cache<E>, scope: Scope, f: F) -> anyhow::Result<Vec<u8>>
+where
+    E: CacheEntry,
+    F: FnOnce() -> anyhow::Result<Vec<u8>>,
+{
+    memoize_with(cache, scope, &RecordOptions::default(), f)
+}
+
+/// Like `memoize`, recording results with the given options. Options about exit codes and
+/// signals don't apply, as results are only recorded when `f` succeeds.
+pub fn memoize_with<E, F>(
+    cache: &impl Cache<E>,
+    scope: Scope,
+    options: &RecordOptions,
+    f: F,
+) -> anyhow::Result<Vec<u8>>
+where
+    E: CacheEntry,
+    F: FnOnce() -> anyhow::Result<Vec<u8>>,
+{
+    if disabled() {
+        return f();
+    }
+
+    let command = Command::new(scope);
+    let hash = &command.hash().to_string();
+
+    if let Some(result) = cache.find(hash, &FindOptions::default())? {
+        return decode(&result.raw_output()?.0);
+    }
+
+    // As with commands, only one process computes the result at once
+    let _lock = cache.lock(hash, None)?;
+    if let Some(result) = cache.find(hash, &FindOptions::default())? {
+        return decode(&result.raw_output()?.0);
+    }
+
+    let start = Instant::now();
+    let result = f()?;
+    let duration = start.elapsed();
+
+    let text = String::from_utf8_lossy(&result);
+    let matches = options
+        .run_options()
+        .stdout_patterns
+        .iter()
+        .map(|pattern| text.lines().any(|line| pattern.is_match(line)))
+        .collect::<Vec<_>>();
+    if let Some(reason) = options.skip_reason(0, None, &matches, result.len() as u64, 0) {
+        info(format!("not recording result: {}", reason));
+        return Ok(result);
+    }
+
+    let now = SystemTime::now();
+    let entry = MemoizedResult {
+        expires: options.expires_at(now),
+        env: options.env(),
+        tags: options.tags().clone(),
+        id: options.id(),
+        created: now,
+        duration,
+        output: encode(&result),
+        command,
+    };
+    info(format!("recording memoized result for {}", hash));
+    cache.store(hash, &entry)?;
+    Ok(result)
+}
+
+/// A result returned by a closure, stored in the same format as a command's output so any
+/// cache can hold it.
+struct MemoizedResult {
+    command: Command,
+    created: SystemTime,
+    expires: Option<SystemTime>,
+    env: BTreeMap<String, String>,
+    tags: BTreeSet<String>,
+    id: Option<String>,
+    duration: Duration,
+    output: Vec<u8>,
+}
+
+impl CacheEntry for MemoizedResult {
+    fn created_at(&self) -> SystemTime {
+        self.created
+    }
+
+    fn expires_at(&self) -> Option<SystemTime> {
+        self.expires
+    }
+
+    fn command_status(&self) -> i32 {
+        0
+    }
+
+    fn command_signal(&self) -> Option<i32> {
+        None
+    }
+
+    fn command(&self) -> &Command {
+        &self.command
+    }
+
+    fn env(&self) -> &BTreeMap<String, String> {
+        &self.env
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        Some(self.duration)
+    }
+
+    fn usage(&self) -> Option<ResourceUsage> {
+        None
+    }
+
+    fn diverged_at(&self) -> Option<SystemTime> {
+        None
+    }
+
+    fn tags(&self) -> &BTreeSet<String> {
+        &self.tags
+    }
+
+    fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    fn pinned(&self) -> bool {
+        false
+    }
+
+    fn replay_command_output(&self, output: &mut Output) -> anyhow::Result<()> {
+        replay_output(&self.output[..], &[][..], output)?;
+        Ok(())
+    }
+
+    fn stdout(&self) -> anyhow::Result<String> {
+        let reader = OutputReader {
+            reader: std::io::BufReader::new(&self.output[..]),
+        };
+        Ok(reader.into_string())
+    }
+
+    fn stdout_reader(&self) -> anyhow::Result<Box<dyn std::io::Read + Send>> {
+        Ok(Box::new(std::io::Cursor::new(self.output.clone())))
+    }
+
+    fn raw_output(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        Ok((self.output.clone(), vec![]))
+    }
+
+    fn output_sizes(&self) -> Vec<(String, usize)> {
+        vec![(format!("{}.out", self.command.ulid), self.output.len())]
+    }
+}
+
+/// Stores bytes as captured output, with each line following a (zero) timestamp.
+fn encode(bytes: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(bytes.len());
+    for line in bytes.split_inclusive(|byte| *byte == b'\n') {
+        output.extend_from_slice(&0u128.to_be_bytes());
+        output.extend_from_slice(line);
+    }
+    output
+}
+
+/// Recovers the bytes from captured output, leaving out timestamps.
+fn decode(output: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let reader = OutputReader {
+        reader: std::io::BufReader::new(output),
+    };
+    Ok(reader.flat_map(|(_, line)| line).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::memory::MemoryCache;
+    use crate::cache::{CacheModes, DiskCache};
+    use crate::command::ScopeBuilder;
+    use std::cell::Cell;
+    use ulid::Ulid;
+
+    fn check_memoize<E: CacheEntry>(cache: &impl Cache<E>) -> anyhow::Result<()> {
+        let scope = || ScopeBuilder::new().cmd("memoize").args("test").build();
+        let calls = Cell::new(0);
+        let compute = || {
+            calls.set(calls.get() + 1);
+            Ok(b"first line\nsecond \xff line".to_vec())
+        };
+
+        let result = memoize(cache, scope()?, compute)?;
+        assert_eq!(result, b"first line\nsecond \xff line");
+        assert_eq!(
+            memoize(cache, scope()?, compute)?,
+            result,
+            "returns cached bytes"
+        );
+        assert_eq!(calls.get(), 1, "only computes once");
+
+        let failing = ScopeBuilder::new().cmd("memoize").args("failing").build()?;
+        assert!(memoize(cache, failing.clone(), || anyhow::bail!("failed")).is_err());
+        assert_eq!(
+            memoize(cache, failing, || Ok(vec![]))?,
+            b"",
+            "errors aren't cached"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_memoize_with_memory_cache() -> anyhow::Result<()> {
+        check_memoize(&MemoryCache::new())
+    }
+
+    #[test]
+    fn test_memoize_with_disk_cache() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-cache-{}", Ulid::new()));
+        check_memoize(&DiskCache::new(root.clone(), CacheModes::PRIVATE, false)?)?;
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_memoize_with_options() -> anyhow::Result<()> {
+        let cache = MemoryCache::new();
+        let scope = ScopeBuilder::new().cmd("memoize").args("empty").build()?;
+        let mut options = RecordOptions::default();
+        options.set_output_required(crate::cache::OutputRequired::Stdout);
+
+        let hash = Command::new(scope.clone()).hash().to_string();
+        memoize_with(&cache, scope, &options, || Ok(vec![]))?;
+        assert!(cache.read(&hash)?.is_none(), "skips empty result");
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_decode() -> anyhow::Result<()> {
+        for bytes in [&b""[..], b"one", b"one\n", b"one\ntwo", b"\n\n\xff"] {
+            assert_eq!(decode(&encode(bytes))?, bytes);
+        }
+        Ok(())
+    }
+}
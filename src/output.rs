@@ -0,0 +1,30 @@
+use std::io::Write;
+
+/// Where deja writes replayed results, reports and warnings. The deja binary writes to the
+/// process's stdout and stderr, while library users can capture output by passing their own
+/// writers.
+pub struct Output {
+    pub(crate) stdout: Box<dyn Write>,
+    pub(crate) stderr: Box<dyn Write>,
+}
+
+impl Output {
+    /// Writes to the given writers in place of stdout and stderr.
+    pub fn new(stdout: impl Write + 'static, stderr: impl Write + 'static) -> Output {
+        Output {
+            stdout: Box::new(stdout),
+            stderr: Box::new(stderr),
+        }
+    }
+
+    /// Writes to the process's stdout and stderr.
+    pub fn stdio() -> Output {
+        Output::new(std::io::stdout(), std::io::stderr())
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::stdio()
+    }
+}
@@ -0,0 +1,109 @@
+use std::io::{IsTerminal, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How long hashing runs before progress is shown, so quick hashing never flickers.
+const DELAY: Duration = Duration::from_millis(300);
+
+/// How often progress is redrawn.
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shows progress on stderr while watched paths are hashed, once hashing has taken long enough
+/// to notice. The line is redrawn in place and erased when hashing is done, so it never ends
+/// up in logs.
+pub(crate) struct Progress {
+    started: Instant,
+    drawn: Option<Instant>,
+    files: u64,
+    bytes: u64,
+}
+
+impl Progress {
+    /// Progress for hashing that's just starting, or `None` when stderr isn't a terminal.
+    pub fn stderr() -> Option<Progress> {
+        std::io::stderr().is_terminal().then(|| Progress {
+            started: Instant::now(),
+            drawn: None,
+            files: 0,
+            bytes: 0,
+        })
+    }
+
+    /// Counts a file of the given size hashed within the watched path, redrawing the line if
+    /// it's due.
+    pub fn file_hashed(&mut self, watched: &Path, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+
+        let now = Instant::now();
+        let due = match self.drawn {
+            Some(drawn) => now.duration_since(drawn) >= INTERVAL,
+            None => now.duration_since(self.started) >= DELAY,
+        };
+        if due {
+            self.drawn = Some(now);
+            let line = self.line(watched, terminal_width());
+            let _ = write!(std::io::stderr(), "\r\x1b[2K{}", line);
+        }
+    }
+
+    /// The progress line, cut to fit the terminal so it never wraps onto a line that can't
+    /// be erased.
+    fn line(&self, watched: &Path, width: usize) -> String {
+        let line = format!(
+            "deja: hashing {} ({} files, {:.1} MiB)",
+            watched.display(),
+            self.files,
+            self.bytes as f64 / (1024.0 * 1024.0)
+        );
+        line.chars().take(width.saturating_sub(1)).collect()
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if self.drawn.is_some() {
+            let _ = write!(std::io::stderr(), "\r\x1b[2K");
+        }
+    }
+}
+
+/// The width of the terminal on stderr, or 80 columns if it can't be found.
+fn terminal_width() -> usize {
+    let mut size = libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let fd = std::io::stderr().as_raw_fd();
+    match unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut size) } {
+        0 if size.ws_col > 0 => size.ws_col as usize,
+        _ => 80,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        let progress = Progress {
+            started: Instant::now(),
+            drawn: None,
+            files: 1200,
+            bytes: 5 * 1024 * 1024 + 512 * 1024,
+        };
+        assert_eq!(
+            progress.line(Path::new("node_modules"), 80),
+            "deja: hashing node_modules (1200 files, 5.5 MiB)"
+        );
+        assert_eq!(
+            progress.line(Path::new("node_modules"), 20),
+            "deja: hashing node_",
+            "fits the terminal"
+        );
+    }
+}
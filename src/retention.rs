@@ -0,0 +1,333 @@
+use anyhow::anyhow;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::cache::CacheEntry;
+use crate::config::{read_parsed, user_config_path, ConfigValue};
+use crate::timestamp::describe_duration;
+
+/// The name of a retention policy file, found in the cache directory or the user's
+/// configuration directory.
+pub const RETENTION_FILE: &str = "retention.toml";
+
+/// How long `gc` keeps results, read from a retention policy file. Files are written like
+/// configuration files, with `[rules.<name>]` tables for each rule, like:
+///
+/// ```toml
+/// keep = "30d"
+/// max-size = "2GB"
+///
+/// [rules.terraform]
+/// command = "terraform *"
+/// keep = "7d"
+/// ```
+///
+/// Each result is kept for as long as the first rule matching it says, or the `keep` given
+/// outside any rule when none match. Once results have been removed by age, the oldest are
+/// removed until the rest fit in `max-size`.
+#[derive(Debug)]
+pub struct RetentionPolicy {
+    rules: Vec<RetentionRule>,
+    /// How long to keep results no rule matches, or `None` to keep them.
+    keep: Option<Duration>,
+    /// The most space results' output can take, in bytes.
+    max_size: Option<usize>,
+    /// The file the policy was read from.
+    file: PathBuf,
+}
+
+/// A rule in a retention policy, saying how long to keep the results it matches.
+#[derive(Debug)]
+struct RetentionRule {
+    name: String,
+    /// Results' commands must match this pattern, where `*` matches any sequence of characters.
+    command: Option<Regex>,
+    /// Results must have every one of these tags.
+    tags: Vec<String>,
+    /// How long to keep results, or `None` to keep them forever.
+    keep: Option<Duration>,
+}
+
+impl RetentionRule {
+    fn matches(&self, entry: &impl CacheEntry) -> bool {
+        self.command
+            .as_ref()
+            .is_none_or(|command| command.is_match(&entry.command().to_string()))
+            && self.tags.iter().all(|tag| entry.tags().contains(tag))
+    }
+}
+
+impl RetentionPolicy {
+    /// Reads a policy from a file, failing with the rule at fault if any are invalid.
+    pub fn read(path: &Path) -> anyhow::Result<RetentionPolicy> {
+        let parsed = read_parsed(path, "rules")?;
+        let invalid = |rule: Option<&str>, e: anyhow::Error| match rule {
+            Some(rule) => anyhow!("rule '{}' in '{}': {}", rule, path.display(), e),
+            None => anyhow!("invalid retention policy '{}': {}", path.display(), e),
+        };
+
+        let mut policy = RetentionPolicy {
+            rules: vec![],
+            keep: None,
+            max_size: None,
+            file: path.to_path_buf(),
+        };
+        for (key, value) in parsed.options {
+            match key.as_str() {
+                "keep" => policy.keep = parse_keep(&value).map_err(|e| invalid(None, e))?,
+                "max-size" => {
+                    policy.max_size = Some(parse_size(&value).map_err(|e| invalid(None, e))?)
+                }
+                _ => return Err(invalid(None, anyhow!("unknown key '{}'", key))),
+            }
+        }
+
+        for (name, options) in parsed.tables {
+            let mut rule = RetentionRule {
+                name,
+                command: None,
+                tags: vec![],
+                keep: None,
+            };
+            let mut keep = None;
+            for (key, value) in options {
+                let result = match key.as_str() {
+                    "command" => {
+                        string(&value).map(|pattern| rule.command = Some(pattern_regex(&pattern)))
+                    }
+                    "tags" => {
+                        rule.tags = value.strings();
+                        Ok(())
+                    }
+                    "keep" => parse_keep(&value).map(|duration| keep = Some(duration)),
+                    _ => Err(anyhow!("unknown key '{}'", key)),
+                };
+                result.map_err(|e| invalid(Some(&rule.name), e))?;
+            }
+            if rule.command.is_none() && rule.tags.is_empty() {
+                return Err(invalid(
+                    Some(&rule.name),
+                    anyhow!("needs a command or tags to match"),
+                ));
+            }
+            rule.keep =
+                keep.ok_or_else(|| invalid(Some(&rule.name), anyhow!("needs a keep duration")))?;
+            policy.rules.push(rule);
+        }
+        Ok(policy)
+    }
+
+    /// How long to keep a result, and what decided it (a rule, or the default), or `None` when
+    /// it's kept however old it is.
+    pub fn keep(&self, entry: &impl CacheEntry) -> Option<(Duration, String)> {
+        match self.rules.iter().find(|rule| rule.matches(entry)) {
+            Some(rule) => rule.keep.map(|keep| {
+                (
+                    keep,
+                    format!("rule '{}' in {}", rule.name, self.file.display()),
+                )
+            }),
+            None => self
+                .keep
+                .map(|keep| (keep, format!("default keep in {}", self.file.display()))),
+        }
+    }
+
+    /// The most space results' output can take, in bytes, if limited.
+    pub fn max_size(&self) -> Option<usize> {
+        self.max_size
+    }
+
+    /// The file the policy was read from.
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+}
+
+/// The retention policy file for a cache, in its directory or else the user's configuration
+/// directory, if there is one.
+pub fn find_policy(cache: &Path) -> Option<PathBuf> {
+    let config_dir = user_config_path().and_then(|path| path.parent().map(Path::to_path_buf));
+    std::iter::once(cache.to_path_buf())
+        .chain(config_dir)
+        .map(|dir| dir.join(RETENTION_FILE))
+        .find(|path| path.is_file())
+}
+
+fn string(value: &ConfigValue) -> anyhow::Result<String> {
+    match value {
+        ConfigValue::String(value) => Ok(value.clone()),
+        value => Err(anyhow!("expected a string, not {}", value)),
+    }
+}
+
+/// A regular expression matching the whole of a string against a pattern, where `*` matches
+/// any sequence of characters.
+fn pattern_regex(pattern: &str) -> Regex {
+    let parts = pattern.split('*').map(regex::escape).collect::<Vec<_>>();
+    Regex::new(&format!("^{}$", parts.join(".*"))).expect("escaped pattern is a valid regex")
+}
+
+/// Parses how long to keep results, like `7d`, or `forever`.
+fn parse_keep(value: &ConfigValue) -> anyhow::Result<Option<Duration>> {
+    let keep = string(value)?;
+    if keep == "forever" {
+        return Ok(None);
+    }
+    humantime::parse_duration(&keep).map(Some).map_err(|_| {
+        anyhow!(
+            "invalid duration '{}', use values like 12h, 7d or forever",
+            keep
+        )
+    })
+}
+
+/// Parses a size in bytes, given as a number of bytes or with a unit like `500MB` or `2GB`
+/// (where each unit is 1024 of the one before).
+fn parse_size(value: &ConfigValue) -> anyhow::Result<usize> {
+    let size = match value {
+        ConfigValue::Integer(bytes) if *bytes >= 0 => return Ok(*bytes as usize),
+        value => string(value)?,
+    };
+    let invalid = || anyhow!("invalid size '{}', use values like 500MB or 2GB", size);
+
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number = number.parse::<f64>().map_err(|_| invalid())?;
+    let power = match unit.trim().to_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 1,
+        "M" | "MB" | "MIB" => 2,
+        "G" | "GB" | "GIB" => 3,
+        "T" | "TB" | "TIB" => 4,
+        _ => return Err(invalid()),
+    };
+    Ok((number * 1024f64.powi(power)) as usize)
+}
+
+/// Describes why a result is removed for being older than a policy keeps it.
+pub(crate) fn describe_keep(keep: Duration) -> String {
+    format!("older than {}", describe_duration(keep))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::memory::MemoryCache;
+    use crate::cache::{Cache, RecordOptions};
+    use crate::command::{Command, ScopeBuilder};
+    use ulid::Ulid;
+
+    fn write_policy(contents: &str) -> anyhow::Result<PathBuf> {
+        let path = std::env::temp_dir().join(format!("deja-retention-{}.toml", Ulid::new()));
+        std::fs::write(&path, contents)?;
+        Ok(path)
+    }
+
+    #[test]
+    fn test_read_policy() -> anyhow::Result<()> {
+        let path = write_policy(
+            r#"
+keep = "30d"
+max-size = "1.5GB"
+
+[rules.terraform]
+command = "echo terraform *"
+keep = "7d"
+
+[rules.infra]
+tags = ["infra", "prod"]
+keep = "forever"
+"#,
+        )?;
+        let policy = RetentionPolicy::read(&path);
+        std::fs::remove_file(&path)?;
+        let policy = policy?;
+
+        let cache = MemoryCache::new();
+        let record = |cmd: &str, args: &str, tags: &[&str]| -> anyhow::Result<_> {
+            let mut command = Command::new(ScopeBuilder::new().cmd(cmd).args(args).build()?);
+            let mut options = RecordOptions::default();
+            options.set_tags(tags.iter().map(|tag| tag.to_string()).collect());
+            cache.record(&mut command, &options)?;
+            Ok(cache.read(command.hash())?.unwrap())
+        };
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        let plan = record("echo", "terraform plan", &["infra", "prod"])?;
+        let (keep, rule) = policy.keep(&plan).unwrap();
+        assert_eq!(keep, 7 * day, "first matching rule wins");
+        assert!(rule.starts_with("rule 'terraform' in "), "{}", rule);
+
+        let deploy = record("echo", "deploy", &["infra", "prod"])?;
+        assert_eq!(policy.keep(&deploy), None, "kept forever");
+
+        let ls = record("true", "", &["infra"])?;
+        let (keep, rule) = policy.keep(&ls).unwrap();
+        assert_eq!(keep, 30 * day, "needs every tag");
+        assert!(rule.starts_with("default keep in "), "{}", rule);
+
+        assert_eq!(policy.max_size(), Some(1610612736));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_policy_errors() -> anyhow::Result<()> {
+        let error = |contents: &str| -> anyhow::Result<String> {
+            let path = write_policy(contents)?;
+            let error = RetentionPolicy::read(&path).unwrap_err().to_string();
+            std::fs::remove_file(&path)?;
+            Ok(error.replace(&path.display().to_string(), "<path>"))
+        };
+
+        assert_eq!(
+            error("[rules.terraform]\ncommand = \"terraform *\"\nkeep = \"7x\"\n")?,
+            "rule 'terraform' in '<path>': invalid duration '7x', use values like 12h, 7d or forever"
+        );
+        assert_eq!(
+            error("[rules.all]\nkeep = \"7d\"\n")?,
+            "rule 'all' in '<path>': needs a command or tags to match"
+        );
+        assert_eq!(
+            error("[rules.ls]\ncommand = \"ls\"\n")?,
+            "rule 'ls' in '<path>': needs a keep duration"
+        );
+        assert_eq!(
+            error("[rules.ls]\ncommand = \"ls\"\nkeep = \"1d\"\nmatch = \"x\"\n")?,
+            "rule 'ls' in '<path>': unknown key 'match'"
+        );
+        assert_eq!(
+            error("max-size = \"lots\"\n")?,
+            "invalid retention policy '<path>': invalid size 'lots', use values like 500MB or 2GB"
+        );
+        assert_eq!(
+            error("[presets.ls]\n")?,
+            "unable to parse '<path>': line 1: only [rules.<name>] tables are supported"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_size() {
+        let size = |s: &str| parse_size(&ConfigValue::String(s.into()));
+        assert_eq!(size("512").unwrap(), 512);
+        assert_eq!(size("2KB").unwrap(), 2048);
+        assert_eq!(size("500 MB").unwrap(), 500 * 1024 * 1024);
+        assert_eq!(size("2GiB").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size(&ConfigValue::Integer(100)).unwrap(), 100);
+        assert!(size("2XB").is_err(), "unknown unit");
+        assert!(size("GB").is_err(), "no number");
+    }
+
+    #[test]
+    fn test_pattern_regex() {
+        assert!(pattern_regex("terraform *").is_match("terraform plan -out x"));
+        assert!(!pattern_regex("terraform *").is_match("terraform"));
+        assert!(pattern_regex("*.rb").is_match("ruby a.rb"));
+        assert!(!pattern_regex("a.b").is_match("axb"), "dots are literal");
+        assert!(!pattern_regex("Terraform *").is_match("terraform plan"));
+    }
+}
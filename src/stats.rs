@@ -0,0 +1,498 @@
+use anyhow::anyhow;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use ulid::Ulid;
+
+use crate::cache::CacheModes;
+use crate::debug;
+use crate::output::Output;
+use crate::timestamp::describe_duration;
+
+/// The name of the file hits and misses are counted in, kept in the cache directory.
+pub const COUNTERS_FILE: &str = "counters";
+
+/// Once the counters file grows beyond this, its events are compacted into totals for each day.
+const MAX_COUNTERS_SIZE: u64 = 256 * 1024;
+
+/// How many days are kept separately when compacting, with those before added together.
+const MAX_DAYS: usize = 366;
+
+/// Whether a lookup by `run` or `read` found a result, and for a hit, how long the command
+/// took when it was recorded (the time the hit saved).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// A result was found.
+    Hit(Duration),
+    /// No result was found.
+    Miss,
+}
+
+/// Counts cache hits and misses between invocations, so `deja stats` can say how effective a
+/// cache has been over time.
+///
+/// Each lookup appends a line to the counters file, written with a single write to a file
+/// opened for appending, so lines from concurrent processes are never interleaved. A line cut
+/// short by a crash is skipped when reading. Appends hold a shared lock, and once the file
+/// grows beyond a limit, it's compacted under an exclusive lock into totals for each day,
+/// written to a temporary file that's renamed into place.
+#[derive(Debug, Clone)]
+pub struct Counters {
+    path: PathBuf,
+    modes: CacheModes,
+}
+
+/// Hits and misses counted over a period, and the time the hits saved.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Totals {
+    hits: u64,
+    misses: u64,
+    saved: Duration,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.saved += other.saved;
+    }
+
+    /// The share of lookups that were hits, as a percentage.
+    fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            lookups => self.hits as f64 * 100.0 / lookups as f64,
+        }
+    }
+
+    fn to_line(self) -> String {
+        format!("{} {} {}", self.hits, self.misses, self.saved.as_millis())
+    }
+}
+
+/// Everything counted, with totals for each day (in UTC) and for days before those kept.
+#[derive(Debug, Default, PartialEq)]
+struct Counted {
+    days: BTreeMap<String, Totals>,
+    earlier: Totals,
+}
+
+impl Counted {
+    fn total(&self) -> Totals {
+        let mut total = self.earlier;
+        for totals in self.days.values() {
+            total.add(totals);
+        }
+        total
+    }
+
+    /// Parses the counters file, skipping lines that can't be parsed, like one cut short by
+    /// a crash. Events look like `<time> <hash> hit <saved ms>`, totals for a day left by
+    /// compacting like `day <date> <hits> <misses> <saved ms>`, and totals for the days before
+    /// those kept like `earlier <hits> <misses> <saved ms>`.
+    fn parse(contents: &str) -> Counted {
+        let mut counted = Counted::default();
+        for line in contents.lines() {
+            let words = line.split(' ').collect::<Vec<_>>();
+            match words.as_slice() {
+                ["day", date, totals @ ..] => {
+                    if let Some(totals) = parse_totals(totals) {
+                        counted
+                            .days
+                            .entry(date.to_string())
+                            .or_default()
+                            .add(&totals);
+                    }
+                }
+                ["earlier", totals @ ..] => {
+                    if let Some(totals) = parse_totals(totals) {
+                        counted.earlier.add(&totals);
+                    }
+                }
+                [time, _hash, outcome, saved] => {
+                    let (Ok(_), Ok(saved)) = (humantime::parse_rfc3339(time), saved.parse::<u64>())
+                    else {
+                        continue;
+                    };
+                    let (hits, misses, saved) = match *outcome {
+                        "hit" => (1, 0, Duration::from_millis(saved)),
+                        "miss" => (0, 1, Duration::ZERO),
+                        _ => continue,
+                    };
+                    let date = time[..10].to_string();
+                    counted.days.entry(date).or_default().add(&Totals {
+                        hits,
+                        misses,
+                        saved,
+                    });
+                }
+                _ => continue,
+            }
+        }
+        counted
+    }
+
+    /// Writes the totals for each day, adding together those before the most recent
+    /// `MAX_DAYS`.
+    fn to_compacted(&self) -> String {
+        let mut earlier = self.earlier;
+        let folded = self.days.len().saturating_sub(MAX_DAYS);
+        let mut contents = String::new();
+        for (index, (date, totals)) in self.days.iter().enumerate() {
+            if index < folded {
+                earlier.add(totals);
+            } else {
+                contents.push_str(&format!("day {} {}\n", date, totals.to_line()));
+            }
+        }
+        if earlier != Totals::default() {
+            contents.insert_str(0, &format!("earlier {}\n", earlier.to_line()));
+        }
+        contents
+    }
+}
+
+fn parse_totals(words: &[&str]) -> Option<Totals> {
+    match words {
+        [hits, misses, saved] => Some(Totals {
+            hits: hits.parse().ok()?,
+            misses: misses.parse().ok()?,
+            saved: Duration::from_millis(saved.parse().ok()?),
+        }),
+        _ => None,
+    }
+}
+
+impl Counters {
+    /// Uses the counters file at the given path, creating files with the cache's modes.
+    pub fn new(path: PathBuf, modes: CacheModes) -> Self {
+        Counters { path, modes }
+    }
+
+    /// Appends a hit or miss for the command with the given hash, compacting the file once it
+    /// has grown too large.
+    pub fn record(&self, hash: &str, outcome: Outcome) -> anyhow::Result<()> {
+        let line = match outcome {
+            Outcome::Hit(saved) => format!("{} {} hit {}\n", now(), hash, saved.as_millis()),
+            Outcome::Miss => format!("{} {} miss 0\n", now(), hash),
+        };
+        let size = {
+            let _lock = self.lock(libc::LOCK_SH)?;
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+            self.set_mode(&self.path);
+            file.write_all(line.as_bytes())?;
+            file.metadata()?.len()
+        };
+        if size > MAX_COUNTERS_SIZE {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the events in the file with totals for each day.
+    fn compact(&self) -> anyhow::Result<()> {
+        let _lock = self.lock(libc::LOCK_EX)?;
+        // Another process may have compacted the file while waiting for the lock
+        if self.path.metadata()?.len() <= MAX_COUNTERS_SIZE {
+            return Ok(());
+        }
+        debug(format!("compacting {}", self.path.display()));
+        let counted = Counted::parse(&std::fs::read_to_string(&self.path)?);
+
+        let temp = self.path.with_extension(format!("{}.tmp", Ulid::new()));
+        std::fs::write(&temp, counted.to_compacted())?;
+        self.set_mode(&temp);
+        std::fs::rename(&temp, &self.path).inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp);
+        })?;
+        Ok(())
+    }
+
+    fn read(&self) -> anyhow::Result<Counted> {
+        // Nothing has been counted, and the cache may not exist yet to hold a lock
+        if !self.path.exists() {
+            return Ok(Counted::default());
+        }
+        let _lock = self.lock(libc::LOCK_SH)?;
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => Ok(Counted::parse(&contents)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Counted::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn reset(&self) -> anyhow::Result<()> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+        let _lock = self.lock(libc::LOCK_EX)?;
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Locks the file beside the counters, released when the returned file is closed.
+    fn lock(&self, operation: i32) -> anyhow::Result<File> {
+        let path = self.path.with_extension("lock");
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        self.set_mode(&path);
+        if unsafe { libc::flock(lock.as_raw_fd(), operation) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(lock)
+    }
+
+    /// Gives a file the cache's mode. Files are shared between processes (and users, with a
+    /// shared cache), so may already exist with permissions that can't be changed.
+    fn set_mode(&self, path: &Path) {
+        let _ = std::fs::set_permissions(path, std::fs::Permissions::from_mode(self.modes.file));
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+#[derive(Serialize)]
+struct JsonTotals<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date: Option<&'a str>,
+    hits: u64,
+    misses: u64,
+    hit_rate: f64,
+    saved_secs: f64,
+}
+
+impl<'a> JsonTotals<'a> {
+    fn new(date: Option<&'a str>, totals: &Totals) -> Self {
+        JsonTotals {
+            date,
+            hits: totals.hits,
+            misses: totals.misses,
+            hit_rate: totals.hit_rate(),
+            saved_secs: totals.saved.as_secs_f64(),
+        }
+    }
+}
+
+/// Writes how many lookups by `run` and `read` were hits and misses, with the time hits saved,
+/// in total and then for each of the most recent `days` with any lookups.
+pub fn stats(
+    counters: &Counters,
+    output: &mut Output,
+    days: usize,
+    json: bool,
+) -> anyhow::Result<i32> {
+    let counted = counters
+        .read()
+        .map_err(|e| anyhow!("unable to read '{}': {}", counters.path().display(), e))?;
+    let total = counted.total();
+    let recent = counted.days.iter().rev().take(days).rev();
+
+    if json {
+        #[derive(Serialize)]
+        struct Stats<'a> {
+            #[serde(flatten)]
+            total: JsonTotals<'a>,
+            days: Vec<JsonTotals<'a>>,
+        }
+        serde_json::to_writer(
+            &mut output.stdout,
+            &Stats {
+                total: JsonTotals::new(None, &total),
+                days: recent
+                    .map(|(date, totals)| JsonTotals::new(Some(date), totals))
+                    .collect(),
+            },
+        )?;
+        writeln!(output.stdout)?;
+        return Ok(0);
+    }
+
+    writeln!(output.stdout, "{}", describe_totals("total     ", &total))?;
+    for (date, totals) in recent {
+        writeln!(output.stdout, "{}", describe_totals(date, totals))?;
+    }
+    Ok(0)
+}
+
+fn describe_totals(label: &str, totals: &Totals) -> String {
+    format!(
+        "{}  {:>6} hits ({:>5.1}%)  {:>6} misses  {} saved",
+        label,
+        totals.hits,
+        totals.hit_rate(),
+        totals.misses,
+        describe_duration(totals.saved)
+    )
+}
+
+/// Clears the counters, once confirmed on `input` (unless `confirmed` already). The prompt is
+/// written to stderr. Returns 1 when the counters aren't cleared.
+pub fn reset_stats(
+    counters: &Counters,
+    output: &mut Output,
+    input: &mut impl BufRead,
+    confirmed: bool,
+) -> anyhow::Result<i32> {
+    if !confirmed {
+        let total = counters.read().map(|counted| counted.total())?;
+        write!(
+            output.stderr,
+            "Reset counters of {} hits and {} misses? [y/N] ",
+            total.hits, total.misses
+        )?;
+        output.stderr.flush()?;
+        let mut line = String::new();
+        input.read_line(&mut line)?;
+        if !matches!(line.trim().to_lowercase().as_str(), "y" | "yes") {
+            writeln!(output.stderr, "counters not reset")?;
+            return Ok(1);
+        }
+    }
+    counters
+        .reset()
+        .map_err(|e| anyhow!("unable to reset '{}': {}", counters.path().display(), e))?;
+    writeln!(output.stdout, "reset counters")?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_counters() -> anyhow::Result<Counters> {
+        let dir = std::env::temp_dir().join(format!("deja-stats-{}", Ulid::new()));
+        std::fs::create_dir(&dir)?;
+        Ok(Counters::new(dir.join(COUNTERS_FILE), CacheModes::PRIVATE))
+    }
+
+    #[test]
+    fn test_parse() {
+        let counted = Counted::parse(
+            "earlier 5 1 60000\n\
+             day 2024-06-01 2 1 3000\n\
+             2024-06-01T10:00:00Z abc hit 1500\n\
+             2024-06-02T09:30:00Z abc miss 0\n\
+             2024-06-02T09:31:00Z abc hit 250\n\
+             2024-06-02T09:32:00Z abc hi",
+        );
+        assert_eq!(
+            counted.days["2024-06-01"],
+            Totals {
+                hits: 3,
+                misses: 1,
+                saved: Duration::from_millis(4500)
+            }
+        );
+        assert_eq!(
+            counted.days["2024-06-02"],
+            Totals {
+                hits: 1,
+                misses: 1,
+                saved: Duration::from_millis(250)
+            },
+            "skips a line cut short"
+        );
+        assert_eq!(
+            counted.total(),
+            Totals {
+                hits: 9,
+                misses: 3,
+                saved: Duration::from_millis(64750)
+            }
+        );
+        assert_eq!(Counted::parse(&counted.to_compacted()), counted);
+    }
+
+    #[test]
+    fn test_compacted_days() {
+        let mut counted = Counted::default();
+        for day in 0..MAX_DAYS + 2 {
+            let date = format!("{:04}-01-01", 2000 + day);
+            let totals = Totals {
+                hits: 1,
+                misses: 0,
+                saved: Duration::from_secs(1),
+            };
+            counted.days.insert(date, totals);
+        }
+        let compacted = Counted::parse(&counted.to_compacted());
+        assert_eq!(compacted.days.len(), MAX_DAYS);
+        assert_eq!(compacted.earlier.hits, 2, "oldest days added together");
+        assert_eq!(compacted.total(), counted.total());
+    }
+
+    #[test]
+    fn test_record_and_compact() -> anyhow::Result<()> {
+        let counters = temp_counters()?;
+        counters.record("abc", Outcome::Hit(Duration::from_millis(1200)))?;
+        counters.record("abc", Outcome::Miss)?;
+        assert_eq!(
+            counters.read()?.total(),
+            Totals {
+                hits: 1,
+                misses: 1,
+                saved: Duration::from_millis(1200)
+            }
+        );
+
+        // Each event takes around 32 bytes, so this is enough to be compacted
+        let events = (MAX_COUNTERS_SIZE / 16) as usize;
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..events / 4 {
+                        counters.record("abc", Outcome::Miss).unwrap();
+                    }
+                });
+            }
+        });
+        let size = counters.path().metadata()?.len();
+        assert!(size < MAX_COUNTERS_SIZE, "compacted to {} bytes", size);
+        assert_eq!(
+            counters.read()?.total().misses,
+            1 + (events / 4 * 4) as u64,
+            "no events lost"
+        );
+
+        counters.reset()?;
+        assert_eq!(counters.read()?, Counted::default());
+        std::fs::remove_dir_all(counters.path().parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset_stats() -> anyhow::Result<()> {
+        let counters = temp_counters()?;
+        counters.record("abc", Outcome::Miss)?;
+        let mut output = Output::new(std::io::sink(), std::io::sink());
+
+        let status = reset_stats(&counters, &mut output, &mut "n\n".as_bytes(), false)?;
+        assert_eq!(status, 1);
+        assert_eq!(counters.read()?.total().misses, 1, "kept unless confirmed");
+
+        let status = reset_stats(&counters, &mut output, &mut "y\n".as_bytes(), false)?;
+        assert_eq!(status, 0);
+        assert_eq!(counters.read()?.total().misses, 0);
+        std::fs::remove_dir_all(counters.path().parent().unwrap())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+use anyhow::anyhow;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Parses an absolute time, given either as an RFC3339 timestamp (like
+/// `2024-06-01T17:00:00Z` or `2024-06-01T17:00:00+01:00`), or as a local time of day
+/// (like `17:00`, `today 17:00` or `tomorrow 03:00`). Times in the past are rejected.
+pub fn parse_expire_at(s: &str, now: SystemTime) -> anyhow::Result<SystemTime> {
+    let time = parse_time(s, now)?;
+
+    if time <= now {
+        return Err(anyhow!("time '{}' is in the past", s));
+    }
+
+    Ok(time)
+}
+
+/// Parses an absolute time in the same forms as `parse_expire_at`, or `yesterday` with an
+/// optional time of day, whether it's in the past or the future.
+pub fn parse_time(s: &str, now: SystemTime) -> anyhow::Result<SystemTime> {
+    let invalid = || {
+        anyhow!(
+            "invalid time '{}', use values like 17:00, tomorrow 03:00 or 2024-06-01T17:00:00Z",
+            s
+        )
+    };
+
+    if s.contains('-') && s.contains(':') && !s.starts_with(['t', 'T', 'y', 'Y']) {
+        return parse_rfc3339(s).ok_or_else(invalid);
+    }
+
+    let (days, time) = match s.split_once(' ') {
+        Some(("yesterday", time)) => (-1, time),
+        Some(("today", time)) => (0, time),
+        Some(("tomorrow", time)) => (1, time),
+        None if s == "yesterday" => (-1, "00:00"),
+        None if s == "tomorrow" => (1, "00:00"),
+        None => (0, s),
+        _ => return Err(invalid()),
+    };
+    local_time(now, days, time).ok_or_else(invalid)
+}
+
+fn parse_rfc3339(s: &str) -> Option<SystemTime> {
+    if s.ends_with(['Z', 'z']) {
+        return humantime::parse_rfc3339_weak(&s[..s.len() - 1]).ok();
+    }
+
+    // humantime only understands UTC, so apply any offset ourselves
+    let (time, sign, offset) = match s.len().checked_sub(6).map(|i| s.split_at(i)) {
+        Some((time, offset)) if offset.starts_with('+') => (time, 1, &offset[1..]),
+        Some((time, offset)) if offset.starts_with('-') => (time, -1, &offset[1..]),
+        _ => return None,
+    };
+
+    let (hours, minutes) = offset.split_once(':')?;
+    let offset =
+        Duration::from_secs(hours.parse::<u64>().ok()? * 3600 + minutes.parse::<u64>().ok()? * 60);
+    let time = humantime::parse_rfc3339_weak(time).ok()?;
+
+    if sign > 0 {
+        time.checked_sub(offset)
+    } else {
+        time.checked_add(offset)
+    }
+}
+
+/// The given local time of day (`HH:MM` or `HH:MM:SS`), `days` after the day of `now`.
+fn local_time(now: SystemTime, days: i32, time: &str) -> Option<SystemTime> {
+    let mut parts = time.split(':').map(|part| part.parse::<i32>().ok());
+    let hour = parts.next()??;
+    let minute = parts.next()??;
+    let second = parts.next().unwrap_or(Some(0))?;
+    if parts.next().is_some()
+        || !(0..24).contains(&hour)
+        || !(0..60).contains(&minute)
+        || !(0..60).contains(&second)
+    {
+        return None;
+    }
+
+    let now = now.duration_since(UNIX_EPOCH).ok()?.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return None;
+    }
+
+    tm.tm_mday += days;
+    tm.tm_hour = hour;
+    tm.tm_min = minute;
+    tm.tm_sec = second;
+    tm.tm_isdst = -1;
+
+    let time = unsafe { libc::mktime(&mut tm) };
+    if time < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(time as u64))
+}
+
+/// Describes a span of time to the second, in its largest units (like `1d 2h 3m` or `45m`),
+/// keeping at most three of them. Spans under a second are `0s`.
+pub fn describe_duration(duration: Duration) -> String {
+    const UNITS: [(u64, &str); 4] = [(24 * 60 * 60, "d"), (60 * 60, "h"), (60, "m"), (1, "s")];
+
+    let mut remaining = duration.as_secs();
+    let parts: Vec<String> = UNITS
+        .iter()
+        .filter_map(|(size, unit)| {
+            let count = remaining / size;
+            remaining %= size;
+            (count > 0).then(|| format!("{}{}", count, unit))
+        })
+        .take(3)
+        .collect();
+
+    if parts.is_empty() {
+        "0s".to_string()
+    } else {
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_expire_at_rfc3339() -> anyhow::Result<()> {
+        let now = humantime::parse_rfc3339("2024-06-01T12:00:00Z")?;
+
+        assert_eq!(
+            parse_expire_at("2024-06-01T17:00:00Z", now)?,
+            humantime::parse_rfc3339("2024-06-01T17:00:00Z")?
+        );
+        assert_eq!(
+            parse_expire_at("2024-06-01T17:00:00+01:00", now)?,
+            humantime::parse_rfc3339("2024-06-01T16:00:00Z")?
+        );
+        assert_eq!(
+            parse_expire_at("2024-06-01T17:00:00-01:30", now)?,
+            humantime::parse_rfc3339("2024-06-01T18:30:00Z")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_expire_at_local_time() -> anyhow::Result<()> {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        let tomorrow = parse_expire_at("tomorrow 03:00", now)?;
+        assert!(tomorrow > now && tomorrow < now + 2 * day, "is tomorrow");
+
+        let midnight = parse_expire_at("tomorrow", now)?;
+        assert!(midnight > now && midnight <= now + day, "is next midnight");
+        assert!(midnight < tomorrow, "midnight is before 03:00");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_time_in_the_past() -> anyhow::Result<()> {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+
+        assert_eq!(
+            parse_time("2024-06-01T11:00:00Z", now)?,
+            humantime::parse_rfc3339("2024-06-01T11:00:00Z")?
+        );
+        let yesterday = parse_time("yesterday", now)?;
+        assert!(
+            yesterday < now - day && yesterday >= now - 2 * day,
+            "is midnight at the start of yesterday"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_expire_at_errors() -> anyhow::Result<()> {
+        let now = humantime::parse_rfc3339("2024-06-01T12:00:00Z")?;
+
+        assert_eq!(
+            parse_expire_at("2024-06-01T11:00:00Z", now)
+                .unwrap_err()
+                .to_string(),
+            "time '2024-06-01T11:00:00Z' is in the past"
+        );
+        assert!(parse_expire_at("25:00", now).is_err(), "invalid hour");
+        assert!(
+            parse_expire_at("yesterday 10:00", now).is_err(),
+            "unknown day"
+        );
+        assert!(parse_expire_at("2024-06-01", now).is_err(), "date only");
+        assert!(parse_expire_at("soon", now).is_err(), "nonsense");
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_duration() {
+        let describe = |secs: f64| describe_duration(Duration::from_secs_f64(secs));
+
+        assert_eq!(describe(0.0), "0s");
+        assert_eq!(describe(0.9), "0s", "sub-second");
+        assert_eq!(describe(1.0), "1s");
+        assert_eq!(describe(60.0), "1m", "exactly one minute");
+        assert_eq!(describe(3600.0), "1h", "exactly one hour");
+        assert_eq!(describe(86400.0), "1d", "exactly one day");
+        assert_eq!(describe(2700.5), "45m");
+        assert_eq!(
+            describe(93784.0),
+            "1d 2h 3m",
+            "seconds beyond three units dropped"
+        );
+        assert_eq!(describe(86401.0), "1d 1s");
+        assert_eq!(describe(259259.0), "3d 59s");
+    }
+}
@@ -0,0 +1,288 @@
+use anyhow::anyhow;
+use std::ffi::OsString;
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::config::{read_commands, ConfigOption, ConfigValue};
+use crate::output::Output;
+
+/// A command to record before it's needed, with the options to run it with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WarmCommand {
+    /// How the command is described in what `warm` prints.
+    pub name: String,
+    /// The arguments for `deja run`: any options, then `--`, the command and its arguments.
+    pub args: Vec<String>,
+}
+
+/// Reads the commands to warm from a manifest. Files ending `.toml` have a `[commands.<name>]`
+/// table for each command, with a `command` key and any options for it, and options outside
+/// tables apply to every command. Other files have a command line on each row, with blank rows
+/// and those starting with `#` skipped.
+pub fn read_manifest(path: &Path) -> anyhow::Result<Vec<WarmCommand>> {
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "toml")
+    {
+        let (shared, commands) = read_commands(path)?;
+        return Ok(commands
+            .into_iter()
+            .map(|command| {
+                // A command's own options replace those given for every command
+                let shared = shared.iter().filter(|shared| {
+                    !command
+                        .options
+                        .iter()
+                        .any(|option| option.key == shared.key)
+                });
+                let mut args: Vec<String> = shared
+                    .chain(&command.options)
+                    .flat_map(option_args)
+                    .collect();
+                args.push("--".to_string());
+                args.extend(command.command);
+                WarmCommand {
+                    name: command.name,
+                    args,
+                }
+            })
+            .collect());
+    }
+
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("unable to read '{}': {}", path.display(), e))?;
+    parse_command_lines(&contents)
+        .map_err(|e| anyhow!("unable to parse '{}': {}", path.display(), e))
+}
+
+/// Parses a command line on each row, split into words as the shell would.
+fn parse_command_lines(contents: &str) -> anyhow::Result<Vec<WarmCommand>> {
+    let mut commands = vec![];
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let words = shell_words::split(line).map_err(|e| anyhow!("line {}: {}", index + 1, e))?;
+        commands.push(WarmCommand {
+            name: line.to_string(),
+            args: std::iter::once("--".to_string()).chain(words).collect(),
+        });
+    }
+    Ok(commands)
+}
+
+/// An option as it's given on the command line, with `true` giving a flag alone and `false`
+/// leaving it out.
+fn option_args(option: &ConfigOption) -> Vec<String> {
+    match &option.value {
+        ConfigValue::Boolean(true) => vec![format!("--{}", option.key)],
+        ConfigValue::Boolean(false) => vec![],
+        value => value
+            .strings()
+            .into_iter()
+            .map(|value| format!("--{}={}", option.key, value))
+            .collect(),
+    }
+}
+
+/// What happened to a command being warmed.
+enum Warmed {
+    Recorded(Duration),
+    Skipped,
+    /// The command (or deja) failed, with a description and what it wrote to stderr.
+    Failed(String, Vec<u8>),
+}
+
+/// Records each command that doesn't already have a fresh result, by running it with
+/// `deja run`, up to `jobs` at a time. Commands' output isn't passed through, except stderr
+/// from those that fail, with each line prefixed by the command. A line is printed for each
+/// command as it finishes, then a summary. Returns 1 if any command failed.
+pub fn warm(
+    output: &mut Output,
+    deja: &Path,
+    global_args: &[OsString],
+    commands: &[WarmCommand],
+    jobs: usize,
+) -> anyhow::Result<i32> {
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    let (mut recorded, mut skipped, mut failed) = (0, 0, 0);
+
+    std::thread::scope(|scope| -> anyhow::Result<()> {
+        for _ in 0..jobs.clamp(1, commands.len().max(1)) {
+            let sender = sender.clone();
+            let next = &next;
+            scope.spawn(move || {
+                while let Some(command) = commands.get(next.fetch_add(1, Ordering::SeqCst)) {
+                    let warmed = warm_command(deja, global_args, command);
+                    if sender.send((command, warmed)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        for (command, warmed) in receiver {
+            match warmed {
+                Warmed::Recorded(duration) => {
+                    recorded += 1;
+                    writeln!(
+                        output.stdout,
+                        "recorded  {} ({:.1}s)",
+                        command.name,
+                        duration.as_secs_f64()
+                    )?;
+                }
+                Warmed::Skipped => {
+                    skipped += 1;
+                    writeln!(output.stdout, "skipped   {} (fresh)", command.name)?;
+                }
+                Warmed::Failed(description, stderr) => {
+                    failed += 1;
+                    for line in String::from_utf8_lossy(&stderr).lines() {
+                        let line = format!("[{}] {}", command.name, line);
+                        writeln!(output.stderr, "{}", line.trim_end())?;
+                    }
+                    writeln!(
+                        output.stdout,
+                        "failed    {} ({})",
+                        command.name, description
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    writeln!(
+        output.stdout,
+        "{} recorded, {} skipped, {} failed",
+        recorded, skipped, failed
+    )?;
+    Ok(if failed > 0 { 1 } else { 0 })
+}
+
+/// Warms a single command, first checking with `run --dry-run` whether a fresh result is
+/// already cached.
+fn warm_command(deja: &Path, global_args: &[OsString], command: &WarmCommand) -> Warmed {
+    let run = |extra: &[&str]| {
+        std::process::Command::new(deja)
+            .args(global_args)
+            .arg("run")
+            .args(extra)
+            .args(&command.args)
+            .stdin(Stdio::null())
+            .output()
+    };
+
+    match run(&["--dry-run"]) {
+        Ok(dry_run) if dry_run.status.code() == Some(0) => return Warmed::Skipped,
+        Ok(dry_run) if dry_run.status.code() != Some(1) => {
+            return Warmed::Failed(describe(dry_run.status), dry_run.stderr)
+        }
+        Ok(_) => (),
+        Err(e) => return Warmed::Failed(format!("unable to run deja: {}", e), vec![]),
+    }
+
+    let started = Instant::now();
+    match run(&[]) {
+        Ok(result) if result.status.success() => Warmed::Recorded(started.elapsed()),
+        Ok(result) => Warmed::Failed(describe(result.status), result.stderr),
+        Err(e) => Warmed::Failed(format!("unable to run deja: {}", e), vec![]),
+    }
+}
+
+fn describe(status: std::process::ExitStatus) -> String {
+    match status.code() {
+        Some(code) => format!("exit code {}", code),
+        None => "killed by a signal".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(words: &[&str]) -> Vec<String> {
+        words.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_command_lines() -> anyhow::Result<()> {
+        let commands = parse_command_lines(
+            "# Slow commands\n\ncargo metadata\n  git log --oneline 'main..HEAD'  \n",
+        )?;
+        assert_eq!(
+            commands,
+            vec![
+                WarmCommand {
+                    name: "cargo metadata".into(),
+                    args: args(&["--", "cargo", "metadata"]),
+                },
+                WarmCommand {
+                    name: "git log --oneline 'main..HEAD'".into(),
+                    args: args(&["--", "git", "log", "--oneline", "main..HEAD"]),
+                },
+            ]
+        );
+        assert_eq!(
+            parse_command_lines("ls\necho 'unterminated")
+                .unwrap_err()
+                .to_string(),
+            "line 2: missing closing quote"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_manifest() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join(format!("deja-warm-{}.toml", ulid::Ulid::new()));
+        std::fs::write(
+            &path,
+            r#"
+cache-for = "12h"
+exclude-pwd = true
+
+[commands.metadata]
+command = ["cargo", "metadata"]
+watch-path = ["Cargo.toml", "Cargo.lock"]
+
+[commands.plan]
+command = ["terraform", "plan"]
+cache-for = "1h"
+exclude-pwd = false
+"#,
+        )?;
+        let commands = read_manifest(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(
+            commands,
+            vec![
+                WarmCommand {
+                    name: "metadata".into(),
+                    args: args(&[
+                        "--cache-for=12h",
+                        "--exclude-pwd",
+                        "--watch-path=Cargo.toml",
+                        "--watch-path=Cargo.lock",
+                        "--",
+                        "cargo",
+                        "metadata"
+                    ]),
+                },
+                WarmCommand {
+                    name: "plan".into(),
+                    args: args(&["--cache-for=1h", "--", "terraform", "plan"]),
+                },
+            ]
+        );
+        Ok(())
+    }
+}
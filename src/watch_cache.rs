@@ -0,0 +1,267 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use merkle_hash::Algorithm;
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::debug;
+use crate::hash::{Hash, SymlinkMode};
+
+/// Remembers the hashes of watched paths between invocations, so a path that hasn't changed
+/// isn't read again. Alongside each hash is a fingerprint of the path, built only from file
+/// metadata (sizes, modification and change times, inode numbers), which is much cheaper to
+/// compute than reading every file. When the fingerprint still matches, the remembered hash is
+/// used.
+///
+/// Hashes are kept in a single index file. Updates are made under a lock and written to a
+/// temporary file that's renamed into place, so concurrent processes never see a partial index.
+#[derive(Debug, Clone)]
+pub struct WatchCache {
+    path: PathBuf,
+    read_only: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    entries: BTreeMap<String, IndexEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    fingerprint: String,
+    hash: Hash,
+}
+
+impl WatchCache {
+    /// Uses the index file at the given path. With `read_only`, remembered hashes are used but
+    /// the index is never written.
+    pub fn new(path: PathBuf, read_only: bool) -> Self {
+        WatchCache { path, read_only }
+    }
+
+    /// Hashes each path, as `Hash::try_from_path_with_progress` would, reusing remembered
+    /// hashes for paths whose fingerprint hasn't changed. Only files that are read are passed
+    /// to `progress`.
+    pub fn hash_paths(
+        &self,
+        paths: &[PathBuf],
+        symlinks: SymlinkMode,
+        progress: &mut dyn FnMut(&Path, u64),
+    ) -> anyhow::Result<Vec<(PathBuf, Hash)>> {
+        let index = self.read_index();
+        let mut updates = BTreeMap::new();
+        let mut hashes = Vec::with_capacity(paths.len());
+
+        for path in paths {
+            // Paths that aren't valid UTF-8 can't be told apart in the index, so aren't kept
+            let Some(key) = path.to_str().map(|path| format!("{}:{}", symlinks, path)) else {
+                hashes.push((
+                    path.clone(),
+                    Hash::try_from_path_with_progress(path, symlinks, progress)?,
+                ));
+                continue;
+            };
+            let started = SystemTime::now();
+            let fingerprint = fingerprint(path, symlinks);
+
+            if let (Some((fingerprint, _)), Some(entry)) = (&fingerprint, index.entries.get(&key)) {
+                if *fingerprint == entry.fingerprint {
+                    debug(format!("reusing hash of {}", path.display()));
+                    hashes.push((path.clone(), entry.hash.clone()));
+                    continue;
+                }
+            }
+
+            let hash = Hash::try_from_path_with_progress(path, symlinks, progress)?;
+            // Anything changed within the same second as hashing might change again without
+            // its fingerprint changing, so isn't remembered until it has settled
+            if let Some((fingerprint, newest)) = fingerprint {
+                if newest < seconds(started) {
+                    updates.insert(
+                        key,
+                        IndexEntry {
+                            fingerprint,
+                            hash: hash.clone(),
+                        },
+                    );
+                }
+            }
+            hashes.push((path.clone(), hash));
+        }
+
+        if !updates.is_empty() && !self.read_only {
+            if let Err(e) = self.update_index(updates) {
+                debug(format!("unable to update watch cache: {}", e));
+            }
+        }
+        Ok(hashes)
+    }
+
+    /// Reads the index, treating a missing or unreadable index as empty.
+    fn read_index(&self) -> Index {
+        std::fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|index| ron::from_str(&index).ok())
+            .unwrap_or_default()
+    }
+
+    /// Merges entries into the index, holding a lock so updates from other processes aren't
+    /// lost.
+    fn update_index(&self, updates: BTreeMap<String, IndexEntry>) -> anyhow::Result<()> {
+        let lock_path = self.path.with_extension("lock");
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&lock_path)?;
+        // The lock is released when the file is closed
+        if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let mut index = self.read_index();
+        index.entries.extend(updates);
+
+        let temp = self.path.with_extension(format!("{}.tmp", Ulid::new()));
+        std::fs::write(&temp, ron::to_string(&index)?)?;
+        std::fs::rename(&temp, &self.path).inspect_err(|_| {
+            let _ = std::fs::remove_file(&temp);
+        })?;
+        Ok(())
+    }
+}
+
+fn seconds(time: SystemTime) -> i64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// A fingerprint of a path built from the metadata of everything it contains, walked in the
+/// same way as when hashing, along with the newest modification or change time seen (in
+/// seconds). Returns `None` if anything can't be read, leaving hashing to report the error.
+fn fingerprint(path: &Path, symlinks: SymlinkMode) -> Option<(String, i64)> {
+    let mut record = Vec::new();
+    let mut newest = 0;
+    walk(path, symlinks, &mut record, &mut newest).ok()?;
+    let hash = Algorithm::Blake3.compute_hash(&record);
+    Some((merkle_hash::bytes_to_hex(&hash), newest))
+}
+
+fn walk(
+    path: &Path,
+    symlinks: SymlinkMode,
+    record: &mut Vec<u8>,
+    newest: &mut i64,
+) -> std::io::Result<()> {
+    let mut metadata = path.symlink_metadata()?;
+    if metadata.is_symlink() {
+        match symlinks {
+            SymlinkMode::Skip => return Ok(()),
+            SymlinkMode::Target => {
+                record.extend_from_slice(std::fs::read_link(path)?.as_os_str().as_bytes())
+            }
+            SymlinkMode::Follow => metadata = path.metadata()?,
+        }
+    }
+
+    record.extend_from_slice(path.as_os_str().as_bytes());
+    for value in [
+        metadata.ino() as i64,
+        metadata.mode() as i64,
+        metadata.size() as i64,
+        metadata.mtime(),
+        metadata.mtime_nsec(),
+        metadata.ctime(),
+        metadata.ctime_nsec(),
+    ] {
+        record.extend_from_slice(&value.to_be_bytes());
+    }
+    *newest = (*newest).max(metadata.mtime()).max(metadata.ctime());
+
+    if metadata.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<PathBuf>>>()?;
+        entries.sort();
+        for entry in entries {
+            walk(&entry, symlinks, record, newest)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hash_paths() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-watch-cache-{}", Ulid::new()));
+        let watched = root.join("watched");
+        std::fs::create_dir_all(&watched)?;
+        std::fs::write(watched.join("file"), "contents")?;
+
+        // Backdate the file, so it's old enough to be remembered
+        let old = SystemTime::now() - std::time::Duration::from_secs(60);
+        std::fs::File::options()
+            .write(true)
+            .open(watched.join("file"))?
+            .set_modified(old)?;
+
+        let cache = WatchCache::new(root.join("watch-hashes"), false);
+        let paths = vec![watched.clone()];
+        let expected = Hash::try_from_path(&watched, SymlinkMode::Follow)?.hex();
+
+        let hash = |cache: &WatchCache| -> anyhow::Result<String> {
+            Ok(
+                cache.hash_paths(&paths, SymlinkMode::Follow, &mut |_, _| ())?[0]
+                    .1
+                    .hex(),
+            )
+        };
+
+        // Directory change times are recent, so nothing is remembered yet
+        assert_eq!(hash(&cache)?, expected);
+        assert!(cache.read_index().entries.is_empty(), "recent changes");
+
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        assert_eq!(hash(&cache)?, expected);
+        assert_eq!(cache.read_index().entries.len(), 1, "remembers hash");
+
+        // A remembered hash is used while the fingerprint matches
+        let mut index = cache.read_index();
+        for entry in index.entries.values_mut() {
+            entry.hash = Hash::from("remembered");
+        }
+        std::fs::write(root.join("watch-hashes"), ron::to_string(&index)?)?;
+        assert_eq!(hash(&cache)?, Hash::from("remembered").hex());
+
+        std::fs::write(watched.join("file"), "changed")?;
+        assert_ne!(hash(&cache)?, Hash::from("remembered").hex(), "rehashes");
+        assert_eq!(
+            hash(&cache)?,
+            Hash::try_from_path(&watched, SymlinkMode::Follow)?.hex()
+        );
+
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only() -> anyhow::Result<()> {
+        let root = std::env::temp_dir().join(format!("deja-watch-cache-{}", Ulid::new()));
+        let cache = WatchCache::new(root.join("watch-hashes"), true);
+        let paths = vec![PathBuf::from("test/fixtures/empty-a.txt")];
+
+        cache.hash_paths(&paths, SymlinkMode::Follow, &mut |_, _| ())?;
+        assert!(!root.join("watch-hashes").exists(), "never writes index");
+        Ok(())
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::anyhow;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::init::is_function_name;
+use crate::output::Output;
+
+/// Finds the command when the wrapper is on PATH ahead of it. Without this, deja would find
+/// the wrapper again and run it forever.
+const RESOLVE_COMMAND: &str = r#"
+if [ "$(command -v "$command")" -ef "$0" ]; then
+  found=
+  set -f
+  IFS=:
+  for dir in $PATH; do
+    if [ -f "${dir:-.}/$command" ] && [ -x "${dir:-.}/$command" ] && ! [ "${dir:-.}/$command" -ef "$0" ]; then
+      found="${dir:-.}/$command"
+      break
+    fi
+  done
+  unset IFS
+  set +f
+  if [ -z "$found" ]; then
+    echo "$0: $command not found" >&2
+    exit 127
+  fi
+  command=$found
+fi
+"#;
+
+/// Writes an executable shell script called `name` to `dir`, which runs the given command
+/// through `deja run` with the given options. Arguments given to the script are added to the
+/// command, and it exits with the command's exit status. An existing file is only replaced
+/// with `force`.
+pub fn wrap(
+    output: &mut Output,
+    name: &str,
+    dir: &Path,
+    deja: &Path,
+    options: &[String],
+    command: &[String],
+    force: bool,
+) -> anyhow::Result<i32> {
+    if !is_function_name(name) {
+        return Err(anyhow!(
+            "invalid name '{}', names must be command names",
+            name
+        ));
+    }
+    let Some((cmd, args)) = command.split_first() else {
+        return Err(anyhow!("no command given to wrap"));
+    };
+
+    let path = dir.join(name);
+    if path.symlink_metadata().is_ok() && !force {
+        return Err(anyhow!(
+            "'{}' already exists, use --force to replace it",
+            path.display()
+        ));
+    }
+
+    let mut run = vec![deja.to_string_lossy().to_string(), "run".to_string()];
+    run.extend(options.iter().cloned());
+    run.push("--".to_string());
+    // Comments end at a newline, so none can be left in what they show
+    let comment = |words: &str| words.replace('\n', " ");
+    let script = format!(
+        "#!/bin/sh\n\
+         # Runs {} through deja. Generated by:\n\
+         # deja wrap {}\n\
+         command={}\n\
+         {}\n\
+         exec {} \"$command\" {}\"$@\"\n",
+        comment(&shell_words::join(command)),
+        comment(&shell_words::join(
+            std::iter::once(name.to_string())
+                .chain(options.iter().cloned())
+                .chain(std::iter::once("--".to_string()))
+                .chain(command.iter().cloned())
+        )),
+        shell_words::quote(cmd),
+        RESOLVE_COMMAND.trim(),
+        shell_words::join(run),
+        args.iter()
+            .map(|arg| format!("{} ", shell_words::quote(arg)))
+            .collect::<String>(),
+    );
+
+    std::fs::create_dir_all(dir)
+        .map_err(|e| anyhow!("unable to create '{}': {}", dir.display(), e))?;
+    // Written alongside and renamed, so the wrapper is never run half written
+    let temp = dir.join(format!(".{}.{}.tmp", name, ulid::Ulid::new()));
+    std::fs::write(&temp, script)
+        .and_then(|_| std::fs::set_permissions(&temp, std::fs::Permissions::from_mode(0o755)))
+        .and_then(|_| std::fs::rename(&temp, &path))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&temp);
+            anyhow!("unable to write '{}': {}", path.display(), e)
+        })?;
+
+    let on_path = std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|path| path == dir));
+    if !on_path {
+        writeln!(
+            output.stderr,
+            "deja: warning: '{}' isn't on PATH",
+            dir.display()
+        )?;
+    }
+    writeln!(output.stdout, "{}", path.display())?;
+    Ok(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::SharedBuffer;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    fn strings(words: &[&str]) -> Vec<String> {
+        words.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn generate(
+        dir: &Path,
+        name: &str,
+        options: &[&str],
+        command: &[&str],
+        force: bool,
+    ) -> anyhow::Result<String> {
+        let stdout = SharedBuffer::default();
+        let mut output = Output::new(stdout.clone(), std::io::sink());
+        wrap(
+            &mut output,
+            name,
+            dir,
+            &PathBuf::from("/usr/local/bin/deja"),
+            &strings(options),
+            &strings(command),
+            force,
+        )?;
+        Ok(String::from_utf8(stdout.take())?)
+    }
+
+    #[test]
+    fn test_wrap() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("deja-wrap-{}", ulid::Ulid::new()));
+        let printed = generate(
+            &dir,
+            "tf",
+            &["--cache-for", "1 hour"],
+            &["terraform", "plan"],
+            false,
+        )?;
+        assert_eq!(printed, format!("{}\n", dir.join("tf").display()));
+
+        let script = std::fs::read_to_string(dir.join("tf"))?;
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("# deja wrap tf --cache-for '1 hour' -- terraform plan\n"));
+        assert!(script.contains("command=terraform\n"));
+        assert!(script.ends_with(
+            "exec /usr/local/bin/deja run --cache-for '1 hour' -- \"$command\" plan \"$@\"\n"
+        ));
+        let mode = std::fs::metadata(dir.join("tf"))?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        assert!(
+            generate(&dir, "tf", &[], &["terraform"], false).is_err(),
+            "doesn't replace without force"
+        );
+        generate(&dir, "tf", &[], &["terraform"], true)?;
+        assert!(std::fs::read_to_string(dir.join("tf"))?.contains("-- \"$command\" \"$@\""));
+
+        assert!(generate(&dir, "a/b", &[], &["terraform"], false).is_err());
+        assert!(generate(&dir, "--help", &[], &["terraform"], false).is_err());
+        assert!(generate(&dir, "empty", &[], &[], false).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_wrap_runs_in_sh() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("deja-wrap-{}", ulid::Ulid::new()));
+        let bin = dir.join("bin");
+        std::fs::create_dir_all(&bin)?;
+
+        // Stands in for deja, printing its arguments and exiting with an unusual status
+        let stand_in = dir.join("deja");
+        std::fs::write(&stand_in, "#!/bin/sh\nprintf '%s|' \"$@\"\nexit 3\n")?;
+        std::fs::set_permissions(&stand_in, std::fs::Permissions::from_mode(0o755))?;
+        let mut output = Output::new(std::io::sink(), std::io::sink());
+        let words = strings(&["--watch-path", "it's here", "--", "shadowed", "two words"]);
+        wrap(
+            &mut output,
+            "shadowed",
+            &bin,
+            &stand_in,
+            &words[..2],
+            &words[3..],
+            false,
+        )?;
+
+        let run = |path: &str| -> anyhow::Result<(String, Option<i32>)> {
+            let result = Command::new(bin.join("shadowed"))
+                .arg("extra arg")
+                .env("PATH", path)
+                .output()?;
+            Ok((String::from_utf8(result.stdout)?, result.status.code()))
+        };
+
+        // The wrapper shadows the command it wraps, so runs the next one on PATH
+        let real = dir.join("real");
+        std::fs::create_dir_all(&real)?;
+        std::fs::write(real.join("shadowed"), "")?;
+        std::fs::set_permissions(
+            real.join("shadowed"),
+            std::fs::Permissions::from_mode(0o755),
+        )?;
+        let path = format!("{}:{}:/usr/bin:/bin", bin.display(), real.display());
+        assert_eq!(
+            run(&path)?,
+            (
+                format!(
+                    "run|--watch-path|it's here|--|{}|two words|extra arg|",
+                    real.join("shadowed").display()
+                ),
+                Some(3)
+            )
+        );
+
+        // Otherwise the command is left for deja to find
+        let path = format!("{}:{}:/usr/bin:/bin", real.display(), bin.display());
+        assert_eq!(
+            run(&path)?,
+            (
+                "run|--watch-path|it's here|--|shadowed|two words|extra arg|".to_string(),
+                Some(3)
+            )
+        );
+
+        // And it fails like the shell when there's nothing else to run
+        let path = format!("{}:/usr/bin:/bin", bin.display());
+        assert_eq!(run(&path)?, (String::new(), Some(127)));
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
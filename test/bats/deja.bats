@@ -20,6 +20,28 @@ setup() {
   assert_success
 }
 
+@test "--color" {
+  esc=$'\e\\['
+
+  deja run --invalid
+  refute_regex "$stderr" "$esc"
+
+  CLICOLOR_FORCE=1 deja run --invalid
+  assert_regex "$stderr" "$esc"
+
+  NO_COLOR=1 CLICOLOR_FORCE=1 deja run --invalid
+  refute_regex "$stderr" "$esc"
+
+  CLICOLOR_FORCE=1 deja --color never run --invalid
+  refute_regex "$stderr" "$esc"
+
+  NO_COLOR=1 deja run --color always --invalid
+  assert_regex "$stderr" "$esc"
+
+  deja run --color never -- printf '\e[31mred\e[0m\n'
+  assert_output $'\e[31mred\e[0m'
+}
+
 @test "run" {
   deja run -- mock-command
   assert_success_with_mock_command_output "runs command and returns result"
@@ -30,6 +52,85 @@ setup() {
   assert_success_with_mock_command_output_matching $first_output "returns previous result"
 }
 
+@test "run --dry-run" {
+  deja run --dry-run -- mock-command
+  assert_failure 1
+  assert_line --index 1 "cached: missing"
+  assert_line --index 2 "run: yes"
+  assert_line --index 3 "record: if it exits with 0"
+  refute [ -e "$DEJA_CACHE" ]
+
+  deja run -- mock-command
+  first_output=$output
+
+  deja run --dry-run --record-exit-codes 0-2 -- mock-command
+  assert_success
+  assert_line --index 1 --regexp "^cached: fresh, created [0-9]+s ago$"
+  assert_line --index 2 "run: no, the cached result would be replayed"
+
+  deja force --dry-run --record-exit-codes 0-2,5 -- mock-command
+  assert_failure 1
+  assert_line --index 3 "record: if it exits with 0-2, 5"
+
+  deja run --dry-run --json -- mock-command
+  assert_success
+  assert_output --regexp '"status":"hit".*"run":false'
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "dry runs change nothing"
+}
+
+@test "run --dry-run (check: sqlite cache isn't created)" {
+  deja run --dry-run --cache "$WORKSPACE/cache.db" -- mock-command
+  assert_failure 1
+  refute [ -e "$WORKSPACE/cache.db" ]
+}
+
+@test "run (check: options after the command are passed to it)" {
+  deja run echo --cache /tmp --debug -- x
+  assert_success
+  assert_output "--cache /tmp --debug -- x"
+  assert_equal "$stderr" ""
+}
+
+@test "run --shell" {
+  deja run --shell 'echo a | tr a b'
+  assert_success
+  assert_output "b"
+
+  deja run -c "mock-command > /dev/null; mock-command"
+  assert_success
+  first_output=$output
+
+  deja run -c "mock-command > /dev/null; mock-command"
+  assert_success
+  assert_equal "$output" "$first_output"
+
+  deja explain --shell-path /bin/sh -c "mock-command > /dev/null; mock-command"
+  assert_output --partial "shell: /bin/sh -c"
+}
+
+@test "run --shell (check: quoting and metacharacters are passed to the shell unchanged)" {
+  deja run --shell "echo 'a  b'; echo 'x;y'"
+  assert_success
+  assert_line --index 0 "a  b"
+  assert_line --index 1 "x;y"
+}
+
+@test "run --shell (error: more than one argument)" {
+  deja run --shell -- echo "a  b" "x;y"
+  assert_handled_failure
+  assert_equal "$stderr" "deja: --shell takes a single command line, but was given 3 arguments, quote the whole command line instead"
+}
+
+@test "run --shell (check: shell and direct commands are cached separately)" {
+  deja hash --shell 'echo hi'
+  shell_hash=$output
+
+  deja hash echo hi
+  assert_not_equal "$output" "$shell_hash"
+}
+
 @test "run (doesn't cache result if return status is non-zero)" {
   set_next_mock_command_return_status 1
   deja run -- mock-command
@@ -75,6 +176,128 @@ setup() {
   assert_success_with_mock_command_output_not_matching $first_output "returns fresh result when watched path changes"
 }
 
+@test "run --watch-path (check: progress is never written when stderr isn't a terminal)" {
+  folder=$(folder_fixture folder)
+  head -c 50000000 /dev/zero > "$folder/large"
+
+  deja run --watch-path $folder -- mock-command
+  assert_success_with_mock_command_output
+  assert_equal "$stderr" ""
+
+  deja run --quiet --watch-path $folder -- mock-command
+  assert_success_with_mock_command_output
+  assert_equal "$stderr" ""
+}
+
+@test "run --watch-path (non-UTF-8 path)" {
+  folder=$(folder_fixture folder)
+  file="$folder/$(printf 'not\xffutf8')"
+  touch "$file"
+
+  deja run --watch-path "$file" -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  first_output=$output
+
+  deja run --watch-path "$file" -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns previous result"
+
+  echo "changed" > "$file"
+  deja run --watch-path "$file" -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "returns fresh result when watched path changes"
+}
+
+@test "run --watch-symlinks" {
+  folder=$(folder_fixture folder)
+  echo "a" > $WORKSPACE/a
+  echo "b" > $WORKSPACE/b
+  ln -s $WORKSPACE/a $folder/link
+
+  deja run --watch-path $folder -- mock-command
+  first_output=$output
+
+  deja run --watch-symlinks target --watch-path $folder -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "symlink mode is part of the cache key"
+
+  target_output=$output
+
+  echo "changed" > $WORKSPACE/a
+  deja run --watch-symlinks target --watch-path $folder -- mock-command
+  assert_success_with_mock_command_output_matching $target_output "returns previous result when link target contents change"
+
+  ln -sf $WORKSPACE/b $folder/link
+  deja run --watch-symlinks target --watch-path $folder -- mock-command
+  assert_success_with_mock_command_output_not_matching $target_output "returns fresh result when link target changes"
+
+  ln -sf $WORKSPACE/missing $folder/link
+  deja run --watch-symlinks skip --watch-path $folder -- mock-command
+  assert_success
+
+  deja run --watch-path $folder -- mock-command
+  assert_handled_failure "fails following a broken link"
+
+  deja explain --watch-symlinks skip --watch-path $folder -- mock-command
+  assert_output --partial "symlinks: skip"
+}
+
+@test "run --watch-cache" {
+  folder=$(folder_fixture folder)
+
+  deja run --watch-path $folder -- mock-command
+  first_output=$output
+
+  sleep 1
+  deja run --watch-cache --watch-path $folder -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "cache key is unchanged"
+  assert [ -f $DEJA_CACHE/watch-hashes ]
+
+  deja run --watch-cache --watch-path $folder -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "reuses remembered hash"
+
+  echo "changed" > $folder/new
+  deja run --watch-cache --watch-path $folder -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "notices changes"
+}
+
+@test "run --watch-json" {
+  echo '{"description": "a", "dependencies": {"x": "1"}}' > $WORKSPACE/package.json
+
+  deja run --watch-json $WORKSPACE/package.json:/dependencies -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  first_output=$output
+
+  echo '{"description": "b", "dependencies": {"x": "1"}}' > $WORKSPACE/package.json
+  deja run --watch-json $WORKSPACE/package.json:/dependencies -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns previous result when other fields change"
+
+  echo '{"description": "b", "dependencies": {"x": "2"}}' > $WORKSPACE/package.json
+  deja run --watch-json $WORKSPACE/package.json:/dependencies -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "returns fresh result when watched field changes"
+
+  deja explain --watch-json $WORKSPACE/package.json:/dependencies -- mock-command
+  assert_output --partial "package.json:/dependencies (json): {\"x\":\"2\"}"
+
+  deja run --watch-json $WORKSPACE/package.json:/missing -- mock-command
+  assert_handled_failure
+  assert_equal "$stderr" "deja: pointer '/missing' not found in '$WORKSPACE/package.json'"
+
+  deja run --watch-json $WORKSPACE/missing.json:/a -- mock-command
+  assert_handled_failure
+  assert_equal "$stderr" "deja: watch json path '$WORKSPACE/missing.json' not found"
+}
+
+@test "run --watch-yaml" {
+  printf 'name: a\ndatabase:\n  host: db1\n' > $WORKSPACE/config.yml
+
+  deja run --watch-yaml $WORKSPACE/config.yml:/database -- mock-command
+  first_output=$output
+
+  printf 'name: b\ndatabase: {host: db1}\n' > $WORKSPACE/config.yml
+  deja run --watch-yaml $WORKSPACE/config.yml:/database -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns previous result when other fields change"
+}
+
 @test "run --watch-scope" {
   deja run --watch-scope a -- mock-command
   assert_success_with_mock_command_output "runs command and returns result"
@@ -94,6 +317,24 @@ setup() {
   assert_success_with_mock_command_output_matching $first_output "still returns result when called with original scope"
 }
 
+@test "run --watch-scope (check: DEJA_WATCH_SCOPE holds multiple scopes)" {
+  deja hash --watch-scope a --watch-scope b -- mock-command
+  flag_hash=$output
+
+  DEJA_WATCH_SCOPE=$'a\nb' deja hash -- mock-command
+  assert_equal "$output" "$flag_hash"
+
+  DEJA_WATCH_SCOPE_DELIM=: DEJA_WATCH_SCOPE=a:b deja hash -- mock-command
+  assert_equal "$output" "$flag_hash"
+
+  DEJA_WATCH_SCOPE=a:b deja hash -- mock-command
+  assert_not_equal "$output" "$flag_hash"
+  env_hash=$output
+
+  deja hash --watch-scope a:b -- mock-command
+  assert_equal "$output" "$env_hash"
+}
+
 @test "run --watch-env" {
   ENV_A=1 deja run --watch-env ENV_A -- mock-command
   assert_success_with_mock_command_output "runs command and returns result"
@@ -113,6 +354,93 @@ setup() {
   assert_success_with_mock_command_output_not_matching $first_output "returns fresh result when env not set"
 }
 
+@test "run --env" {
+  deja run --env GREETING=hello --env GREETING=bonjour -- sh -c 'echo "$GREETING"'
+  assert_success
+  assert_output "bonjour"
+
+  GREETING=hola deja run --env GREETING=hello -- sh -c 'echo "$GREETING"'
+  assert_output "hello"
+
+  deja test --env GREETING=bonjour -- sh -c 'echo "$GREETING"'
+  assert_success "later values replace earlier ones"
+
+  deja test -- sh -c 'echo "$GREETING"'
+  assert_failure 1 "variables are part of the cache key"
+
+  deja explain --env TZ=UTC --env LANG=C -- date
+  assert_output --partial $'set env:\n  LANG: C\n  TZ: UTC'
+}
+
+@test "run --env (error: invalid variable)" {
+  deja run --env TZ -- date
+  assert_failure 2
+  assert_regex "$stderr" "invalid variable 'TZ', use values like NAME=value"
+}
+
+@test "run --clear-env" {
+  SECRET=1 deja run --clear-env -- sh -c 'echo "${SECRET:-unset} ${PATH:+path}"'
+  assert_success
+  assert_output "unset path"
+
+  SECRET=1 OTHER=2 deja run --clear-env --keep-env SECRET --env OTHER=3 -- sh -c 'echo "$SECRET $OTHER"'
+  assert_output "1 3"
+
+  SECRET=2 deja test --clear-env --keep-env SECRET --env OTHER=3 -- sh -c 'echo "$SECRET $OTHER"'
+  assert_success "values of kept variables aren't part of the cache key"
+
+  deja test --clear-env --env OTHER=3 -- sh -c 'echo "$SECRET $OTHER"'
+  assert_failure 1 "which variables are kept is part of the cache key"
+
+  deja test -- sh -c 'echo "${SECRET:-unset} ${PATH:+path}"'
+  assert_failure 1 "clearing the environment is part of the cache key"
+
+  deja run --clear-env --watch-env SECRET --watch-env PATH -- mock-command
+  assert_success
+  assert_equal "$stderr" "deja: warning: --watch-env SECRET isn't passed to the command with --clear-env, add --keep-env SECRET to pass it"
+}
+
+@test "run --chdir" {
+  mkdir -p "$WORKSPACE/project"
+  deja run --chdir "$WORKSPACE/project" -- pwd
+  assert_success
+  assert_output "$(cd "$WORKSPACE/project" && pwd -P)"
+
+  (cd "$WORKSPACE/project" && deja test -- pwd)
+  assert_success "the directory replaces the current directory in the cache key"
+
+  deja explain --chdir "$WORKSPACE/project" -- pwd
+  assert_line "runs in: $(cd "$WORKSPACE/project" && pwd -P)"
+
+  deja run --chdir "$WORKSPACE/project" --exclude-pwd -- sh -c 'echo "in $PWD"'
+  assert_output "in $(cd "$WORKSPACE/project" && pwd -P)"
+  deja test --exclude-pwd -- sh -c 'echo "in $PWD"'
+  assert_success "with --exclude-pwd the directory isn't part of the cache key"
+}
+
+@test "run --chdir (error: missing directory)" {
+  deja run --chdir "$WORKSPACE/missing" -- pwd
+  assert_handled_failure
+  assert_equal "$stderr" "deja: directory '$WORKSPACE/missing' not found"
+}
+
+@test "run --watch-env-exists" {
+  ENV_A=1 deja run --watch-env-exists ENV_A -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  first_output=$output
+
+  ENV_A=2 deja run --watch-env-exists ENV_A -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns previous result when value changes"
+
+  deja run --watch-env-exists ENV_A -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "returns fresh result when env not set"
+
+  ENV_A=1 deja explain --watch-env-exists ENV_A -- mock-command
+  assert_output --partial "env exists:"
+  assert_output --partial "  ENV_A: true"
+}
+
 @test "run --look-back" {
   deja run -- mock-command
   assert_success_with_mock_command_output "runs command and returns result"
@@ -151,127 +479,1977 @@ setup() {
   assert_success_with_mock_command_output_not_matching $first_output "returns fresh result if cached result has expired"
 }
 
-@test "run --exclude-pwd" {
-  folder=$(folder_fixture folder)
-
-  deja run -- mock-command
-  assert_success_with_mock_command_output "runs command and returns result"
+@test "run --renew-on-hit" {
+  deja run --cache-for 2s --renew-on-hit -- mock-command
+  assert_success_with_mock_command_output
+  first_output=$output
 
-  output_without_flag=$output
+  for _ in 1 2 3; do
+    sleep 1
+    deja read --cache-for 2s --renew-on-hit -- mock-command
+    assert_success_with_mock_command_output_matching $first_output "renewed while in use"
+  done
 
-  deja run --debug --exclude-pwd -- mock-command
-  assert_success_with_mock_command_output_not_matching $output_without_flag "generates different result when --exclude-pwd flag is set"
+  sleep 2.5
+  deja run --cache-for 2s --renew-on-hit -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "expires once idle"
+}
 
-  output_with_flag=$output
+@test "run --renew-on-hit (error: needs cache-for)" {
+  deja run --renew-on-hit -- mock-command
+  assert_handled_failure
+  assert_equal "$stderr" "deja: --renew-on-hit needs --cache-for"
+}
 
-  deja run --debug --exclude-pwd -- mock-command
-  assert_success_with_mock_command_output_matching $output_with_flag "returns previous result when --exclude-pwd flag is set"
+@test "run --expire-at" {
+  expires=$(date -u -d "+1 hour" +%Y-%m-%dT%H:%M:%SZ)
+  deja run --expire-at $expires -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
 
-  cd $folder
+  first_output=$output
 
   deja run -- mock-command
-  assert_success_with_mock_command_output_not_matching $output_without_flag "returns different result when called without flag from different folder"
-  assert_not_equal "$output" "$output_with_flag" "doesn't return output generated with flag when called without flag"
+  assert_success_with_mock_command_output_matching $first_output "returns result before expiry"
 
-  deja run --debug --exclude-pwd -- mock-command
-  assert_success_with_mock_command_output_matching $output_with_flag "returns previous result from when called with flag from different folder"
+  deja explain -- mock-command
+  assert_regex "$output" "entry expires at $expires \(in (1h|59m [0-9]+s)\)"
 }
 
-@test "run (check: private cache files and folders only read and writable by owner)" {
-  deja run -- mock-command
-  command find $DEJA_CACHE -type f -perm 600 | grep .
-  command find $DEJA_CACHE -type d -perm 700 | grep .
-}
+@test "explain (check: ages of expired and stale results)" {
+  deja run --cache-for 1s -- mock-command
+  sleep 1
 
-@test "run (check: shared cache files and folders read and writable by everyone)" {
-  deja run --share-cache -- mock-command
-  ls -al $DEJA_CACHE
-  command find $DEJA_CACHE -type f -perm 666 | grep .
-  command find $DEJA_CACHE -type d -perm 777 | grep .
-}
+  deja explain -- mock-command
+  assert_line --regexp "^Expired: entry in cache expired [0-9]+s ago$"
+  assert_line --regexp "^entry created [0-9]+s ago$"
 
-@test "run (error: command not found)" {
-  deja run -- unknown
-  assert_handled_failure "fails when unknown command"
-  assert_equal "$stderr" "deja: command not found: unknown"
+  deja force -- mock-command
+  sleep 1
+
+  deja explain --look-back 1s -- mock-command
+  assert_line "Stale: entry in cache created longer than 1s ago"
 }
 
-@test "run (error: permission denied to run command)" {
-  deja run -- ./README.md
-  assert_handled_failure "fails when unknown command"
-  assert_equal "$stderr" "deja: permission denied running command: ./README.md"
+@test "run --expire-at (error: time in the past)" {
+  deja run --expire-at 2001-01-01T00:00:00Z -- mock-command
+  assert_handled_failure "fails when time is in the past"
+  assert_equal "$stderr" "deja: time '2001-01-01T00:00:00Z' is in the past"
 }
 
-@test "run (error: unable to write to cache)" {
-  deja run --cache /missing/folder -- mock-command
-  assert_handled_failure "fails when unknown command"
-  assert_equal "$stderr" "deja: unable to write file to cache /missing/folder"
+@test "run --expire-at (error: conflicts with cache-for)" {
+  deja run --expire-at tomorrow --cache-for 1h -- mock-command
+  assert_failure
+  assert_regex "$stderr" "cannot be used with"
 }
 
-@test "run (error: unable to read from cache)" {
+@test "run --refresh" {
   deja run -- mock-command
+  first_output=$output
+
+  deja run --refresh -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "runs command despite cached result"
 
-  chmod -R 300 $DEJA_CACHE/*
+  refreshed_output=$output
 
   deja run -- mock-command
+  assert_success_with_mock_command_output_matching $refreshed_output "records refreshed result"
 
-  assert_handled_failure "fails when unable to read cache entry"
-  assert_regex "$stderr" "deja: unable to read file from cache $DEJA_CACHE/.*"
-}
+  MOCK_COMMAND_STATUS=3 deja run --refresh -- mock-command
+  assert_failure 3
 
-@test "run --look-back (error: invalid duration)" {
-  deja run --look-back 1xyz -- mock-command
-  assert_handled_failure "fails when duration can't be parsed"
-  assert_equal "$stderr" "deja: invalid duration '1xyz', use values like 15s, 30m, 3h, 4d etc"
-}
+  deja run -- mock-command
+  assert_success_with_mock_command_output_matching $refreshed_output "doesn't record unsuccessful result"
 
-@test "run --cache-for (error: invalid duration)" {
-  deja run --cache-for 1xyz -- mock-command
-  assert_handled_failure "fails when duration can't be parsed"
-  assert_equal "$stderr" "deja: invalid duration '1xyz', use values like 15s, 30m, 3h, 4d etc"
-}
+  MOCK_COMMAND_STATUS=3 deja run --refresh --record-exit-codes 3 -- mock-command
+  assert_failure 3
 
-@test "run --cache-for (error: watch-path not found)" {
-  deja run --watch-path missing -- mock-command
-  assert_handled_failure "fails when --watch-path is missing"
-  assert_equal "$stderr" "deja: watch path 'missing' not found"
+  failed_output=$output
+
+  deja run -- mock-command
+  assert_equal "$output" "$failed_output"
+  assert_failure 3
 }
 
-@test "read" {
-  deja read -- mock-command
-  assert_handled_failure "fails when no result cached"
+@test "run --record-if-output-matches" {
+  deja run --record-if-output-matches "^ok" -- echo "rate limited"
+  assert_success
+  assert_output "rate limited"
 
-  deja run -- mock-command
-  first_output=$output
+  deja test -- echo "rate limited"
+  assert_failure 1
 
-  deja read -- mock-command
-  assert_success_with_mock_command_output_matching $first_output "returns cached result"
-}
+  deja run --record-if-output-matches "^ok" -- echo "ok"
+  assert_success
 
-@test "read --cache-miss-exit-code" {
-  deja read --cache-miss-exit-code 123 -- mock-command
-  assert_handled_failure "fails when no result cached"
-  assert_equal "$status" "123" "returns exit code specified when no result cached"
+  deja test -- echo "ok"
+  assert_success
 }
 
-@test "force" {
-  deja run -- mock-command
-
-  first_output=$output
+@test "run --skip-record-if-output-matches" {
+  deja run --debug --skip-record-if-output-matches "rate limited" -- echo "rate limited"
+  assert_success
+  assert_output "rate limited"
+  assert_equal "$(echo "$stderr" | grep "not recording")" "- not recording result: output matches 'rate limited'"
 
-  deja force -- mock-command
-  assert_success_with_mock_command_output_not_matching $first_output "forces new result"
+  deja test -- echo "rate limited"
+  assert_failure 1
 
-  forced_output=$output
+  deja run --skip-record-if-output-matches "rate limited" -- echo "ok"
+  deja test -- echo "ok"
+  assert_success
+}
 
-  deja run -- mock-command
-  assert_success_with_mock_command_output_matching $forced_output "forced result now cached"
+@test "run --record-if-output-matches (error: invalid regex)" {
+  deja run --record-if-output-matches "(" -- mock-command
+  assert_handled_failure "fails when regex can't be parsed"
+  assert_equal "$stderr" "deja: invalid regular expression '('"
 }
 
-@test "remove" {
-  deja run -- mock-command
+@test "test --json" {
+  deja test --json -- echo "json"
+  assert_failure 1
+  assert_output '{"status":"miss","created":null,"expires":null,"age":null,"expires_in":null}'
 
-  first_output=$output
+  deja run --cache-for 1h -- echo "json"
+  deja test --json -- echo "json"
+  assert_success
+  assert_output --regexp '^\{"status":"hit","created":"[0-9T:-]+Z","expires":"[0-9T:-]+Z","age":[0-9]+,"expires_in":(3600|359[0-9])\}$'
+
+  deja hash -- echo "json"
+  hash="$output"
+  deja hash --json -- echo "json"
+  assert_output "{\"hash\":\"$hash\"}"
+}
+
+@test "test --why" {
+  deja test --why -- echo "why"
+  assert_failure 1
+  assert_output ""
+  assert_equal "$stderr" "missing"
+
+  deja run --cache-for 1s -- echo "why"
+  sleep 1
+  deja test --why -- echo "why"
+  assert_failure 2
+  assert_output ""
+  assert_regex "$stderr" "^expired [0-9]+s ago$"
+
+  deja run -- echo "why"
+  deja test --why --look-back 1s -- echo "why"
+  assert_success
+  assert_equal "$stderr" ""
+
+  sleep 1
+  deja test --why --look-back 1s -- echo "why"
+  assert_failure 3
+  assert_output ""
+  assert_regex "$stderr" "^older than --look-back 1s \(created [0-9]+s ago\)$"
+}
+
+@test "run --print-status" {
+  deja run --print-status -- echo "status"
+  assert_success
+  assert_output "status"
+  assert_regex "$stderr" "^deja: miss \(recorded, [0-9]+\.[0-9]s\)$"
+
+  deja run --print-status -- echo "status"
+  assert_success
+  assert_output "status"
+  assert_regex "$stderr" "^deja: hit \(age [0-9]+s\)$"
+
+  deja run --print-status -- bash -c "echo failed; exit 1"
+  assert_failure 1
+  assert_output "failed"
+  assert_regex "$stderr" "^deja: miss \(not recorded, [0-9]+\.[0-9]s\)$"
+}
+
+@test "run --print-hash" {
+  deja hash -- echo "hashed"
+  hash=$output
+
+  deja run --print-hash -- echo "hashed"
+  assert_success
+  assert_output "hashed"
+  assert_equal "$stderr" "deja: hash $hash"
+
+  deja read --print-hash -- echo "hashed"
+  assert_success
+  assert_output "hashed"
+  assert_equal "$stderr" "deja: hash $hash"
+}
+
+@test "run --print-hash (check: fd and file sinks)" {
+  deja hash -- mock-command
+  hash=$output
+
+  deja run --print-hash=fd:4 -- mock-command 4> "$WORKSPACE/fd"
+  assert_success_with_mock_command_output "hash isn't written to stdout"
+  assert_equal "$stderr" ""
+  assert_equal "$(cat "$WORKSPACE/fd")" "$hash"
+
+  deja force --print-hash=file:$WORKSPACE/hashes -- mock-command
+  deja read --print-hash=file:$WORKSPACE/hashes -- mock-command
+  assert_equal "$(cat "$WORKSPACE/hashes")" "$hash"$'\n'"$hash"
+}
+
+@test "run --print-hash (check: printed when the command can't run)" {
+  deja hash -- missing-command
+  hash=$output
+
+  deja run --print-hash -- missing-command
+  assert_handled_failure
+  assert_equal "$stderr" "deja: hash $hash"$'\n'"deja: command not found: missing-command"
+}
+
+@test "run --print-hash (error: invalid sink)" {
+  deja run --print-hash=stdout -- mock-command
+  assert_failure 2
+  assert_regex "$stderr" "invalid sink 'stdout', use stderr, fd:N or file:PATH"
+}
+
+@test "run --on-hit --on-miss" {
+  deja run --on-miss 'echo "miss $DEJA_COMMAND $DEJA_STATUS"' --on-hit 'echo hit' -- mock-command
+  assert_success_with_mock_command_output "hook output isn't in stdout"
+  first_output=$output
+  assert_equal "$stderr" "miss mock-command 0"
+
+  DEJA_ON_HIT='echo "hit $DEJA_HASH $DEJA_ENTRY_AGE"; exit 1' deja run -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "failing hook doesn't change the exit status"
+  assert_regex "$stderr" "^hit [0-9a-f]{64} [0-9]+"
+  assert_regex "$stderr" "deja: warning: --on-hit hook failed with exit code 1"
+
+  deja run -- mock-command
+  assert_equal "$stderr" ""
+}
+
+@test "read --on-miss (check: hooks run from a hook don't run hooks)" {
+  hook="$deja_bin read --on-miss 'echo nested' -- missing; echo outer"
+  deja read --on-miss "$hook" -- missing
+  assert_failure 1
+  assert_equal "$stderr" "outer"
+}
+
+@test "run --verify" {
+  deja run -- mock-command
+  first_output=$output
+
+  deja run --verify 0 -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "never verifies"
+  assert_equal "$stderr" ""
+
+  deja run --verify always -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns the cached result"
+  assert_equal "$stderr" "deja: warning: cached result differs from a fresh run (stdout differs)"
+
+  deja list
+  assert_regex "$output" "exit code 0, diverged"
+
+  deja run --verify always --verify-update -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns the cached result"
+  assert_equal "$stderr" "deja: warning: cached result differs from a fresh run (stdout differs), replaced it with the fresh result"
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "returns the fresh result"
+  deja list
+  refute_output --partial "diverged"
+}
+
+@test "run --verify (error: invalid probability)" {
+  deja run --verify 2 -- mock-command
+  assert_failure 2
+  assert_regex "$stderr" "invalid probability '2', use a number from 0 to 1, or always"
+}
+
+@test "read --print-status" {
+  DEJA_PRINT_STATUS=1 deja read -- echo "status"
+  assert_failure 1
+  assert_output ""
+  assert_equal "$stderr" "deja: miss"
+
+  deja run -- echo "status"
+  DEJA_PRINT_STATUS=1 deja read -- echo "status"
+  assert_success
+  assert_output "status"
+  assert_regex "$stderr" "^deja: hit \(age [0-9]+s\)$"
+
+  DEJA_PRINT_STATUS=0 deja read -- echo "status"
+  assert_equal "$stderr" ""
+}
+
+@test "explain --json" {
+  ENV_A=1 deja explain --json --watch-env ENV_A --watch-env ENV_B -- mock-command
+  assert_success
+  first="$(echo "$output" | grep -o '"watch_env":{[^}]*}' | tail -1)"
+
+  ENV_A=2 deja explain --json --watch-env ENV_A --watch-env ENV_B -- mock-command
+  second="$(echo "$output" | grep -o '"watch_env":{[^}]*}' | tail -1)"
+
+  assert_equal "$(echo "$first" | grep -o '"ENV_B":"[0-9a-f]*"')" "$(echo "$second" | grep -o '"ENV_B":"[0-9a-f]*"')"
+  refute [ "$(echo "$first" | grep -o '"ENV_A":"[0-9a-f]*"')" = "$(echo "$second" | grep -o '"ENV_A":"[0-9a-f]*"')" ]
+}
+
+@test "run --record-only-if-output" {
+  deja run --record-only-if-output -- true
+  assert_success
+
+  deja test -- true
+  assert_failure 1
+
+  deja run --record-only-if-output -- sh -c "echo error >&2"
+  deja test -- sh -c "echo error >&2"
+  assert_failure 1
+
+  deja run --record-only-if-any-output -- sh -c "echo error >&2"
+  deja test -- sh -c "echo error >&2"
+  assert_success
+
+  MOCK_COMMAND_STATUS=3 deja run --record-only-if-output --record-exit-codes 0 -- mock-command
+  assert_failure 3
+
+  deja test -- mock-command
+  assert_failure 1
+
+  deja run --record-only-if-output -- mock-command
+  deja test -- mock-command
+  assert_success
+}
+
+@test "run --timeout" {
+  deja run --timeout 1s -- sh -c "echo partial; exec sleep 10"
+  assert_failure 124
+  assert_output "partial"
+
+  deja test -- sh -c "echo partial; exec sleep 10"
+  assert_failure 1
+
+  assert_equal "$(ls $DEJA_CACHE | grep -c '\.out$')" "0"
+
+  deja run --timeout 1s --timeout-signal INT --timeout-exit-code 99 -- sleep 10
+  assert_failure 99
+
+  deja run --timeout 10s -- mock-command
+  assert_success_with_mock_command_output "runs command within timeout"
+
+  deja test -- mock-command
+  assert_success
+}
+
+@test "run --timeout (check: processes the command started are stopped)" {
+  deja run --timeout 1s -- sh -c 'sleep 64; true'
+  assert_failure 124
+
+  # The orphaned sleep may be left as a zombie, which is fine as long as it's not running
+  sleep 0.1
+  refute pgrep --runstates R,S,D,T -f '^sleep 64$'
+}
+
+@test "run --timeout (check: processes ignoring the signal are stopped)" {
+  deja run --timeout 1s -- sh -c 'trap "" TERM; sleep 65 & exec sleep 10'
+  assert_failure 124
+
+  sleep 0.1
+  refute pgrep --runstates R,S,D,T -f '^sleep 65$'
+}
+
+@test "run --timeout (error: invalid signal)" {
+  deja run --timeout 1s --timeout-signal NOPE -- mock-command
+  assert_handled_failure "fails when signal can't be parsed"
+  assert_equal "$stderr" "deja: invalid signal 'NOPE'"
+
+  deja run --timeout 1s --timeout-signal 0 -- mock-command
+  assert_handled_failure "fails when signal is out of range"
+  assert_equal "$stderr" "deja: invalid signal '0'"
+
+  deja run --timeout 1s --timeout-signal 1000 -- mock-command
+  assert_handled_failure "fails when signal is out of range"
+  assert_equal "$stderr" "deja: invalid signal '1000'"
+}
+
+@test "run (check: commands killed by a signal aren't recorded)" {
+  deja run --record-exit-codes 0+ -- sh -c 'kill -KILL $$'
+  assert_failure 137
+
+  deja test -- sh -c 'kill -KILL $$'
+  assert_failure 1
+
+  deja run --record-exit-codes 0+ --record-signals -- sh -c 'kill -KILL $$'
+  assert_failure 137
+
+  deja explain -- sh -c 'kill -KILL $$'
+  assert_output --partial "(killed by SIGKILL)"
+
+  deja run -- sh -c 'kill -KILL $$'
+  assert_failure 137
+}
+
+@test "run (check: concurrent identical commands only run once)" {
+  $deja_bin run -- sh -c "sleep 1; uuidgen" > $WORKSPACE/first &
+  sleep 0.3
+
+  deja run -- sh -c "sleep 1; uuidgen"
+  wait
+
+  assert_success_with_mock_command_output_matching "$(cat $WORKSPACE/first)" "replays result of concurrent run"
+}
+
+@test "run (check: signals are forwarded to the command and nothing is recorded)" {
+  for signal in INT TERM HUP; do
+    # Background jobs ignore SIGINT unless job control is on
+    set -m
+    $deja_bin run -- sleep 61 &
+    pid=$!
+    set +m
+    sleep 0.3
+
+    child=$(pgrep -P $pid sleep)
+    kill -$signal $pid
+    status=0
+    wait $pid || status=$?
+    assert_equal "$status" "$((128 + $(kill -l $signal)))"
+
+    refute kill -0 $child 2> /dev/null
+    assert_equal "$(command find $DEJA_CACHE -name '*.out' -o -name '*.err' -o -name '*.ron')" ""
+  done
+}
+
+@test "run (check: signals are forwarded to processes the command started)" {
+  $deja_bin run -- sh -c 'sleep 62; true' &
+  pid=$!
+  sleep 0.3
+
+  shell=$(pgrep -P $pid sh)
+  child=$(pgrep -P $shell sleep)
+  kill -TERM $pid
+  status=0
+  wait $pid || status=$?
+  assert_equal "$status" "143"
+
+  # The orphaned sleep may be left as a zombie, which is fine as long as it's not running
+  sleep 0.1
+  assert_equal "$(ps -o stat= -p $child | grep -v Z)" ""
+}
+
+@test "run (check: the command can read from the terminal)" {
+  script --version > /dev/null 2>&1 || skip "script from util-linux is not installed"
+
+  # script runs deja in the foreground of a new terminal, typing what it reads from stdin
+  run bash -c "(sleep 0.5; echo hello) | timeout 10 script -qec \"$deja_bin run -- sh -c 'read line; echo got \\\$line'\" /dev/null"
+  assert_success
+  assert_output --partial "got hello"
+}
+
+@test "run --no-wait" {
+  $deja_bin run -- sh -c "sleep 1; uuidgen" > $WORKSPACE/first &
+  sleep 0.3
+
+  deja run --no-wait -- sh -c "sleep 1; uuidgen"
+  wait
+
+  assert_success_with_mock_command_output_not_matching "$(cat $WORKSPACE/first)" "runs command without waiting"
+}
+
+@test "run --lock-timeout" {
+  $deja_bin run -- sh -c "sleep 2; uuidgen" > $WORKSPACE/first &
+  sleep 0.3
+
+  deja run --lock-timeout 100ms -- sh -c "sleep 2; uuidgen"
+  wait
+
+  assert_success_with_mock_command_output_not_matching "$(cat $WORKSPACE/first)" "runs command after timeout"
+}
+
+@test "run --stale-while-revalidate" {
+  deja run --cache-for 1s -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  first_output=$output
+
+  sleep 1
+
+  deja run --cache-for 1s --stale-while-revalidate 1m -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns stale result immediately"
+
+  for i in $(seq 1 50); do
+    deja read -- mock-command
+    if [ "$output" != "$first_output" ]; then break; fi
+    sleep 0.1
+  done
+
+  deja read -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "records fresh result in the background"
+}
+
+@test "run --stale-while-revalidate (check: global option with a value of run)" {
+  cd $WORKSPACE
+
+  deja --log-file run run --cache-for 1s -- mock-command
+  first_output=$output
+
+  sleep 1
+
+  deja --log-file run run --cache-for 1s --stale-while-revalidate 1m -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns stale result immediately"
+
+  for i in $(seq 1 50); do
+    deja read -- mock-command
+    if [ "$output" != "$first_output" ]; then break; fi
+    sleep 0.1
+  done
+
+  deja read -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "records fresh result in the background"
+}
+
+@test "run --stale-while-revalidate (check: only returns stale results within window)" {
+  deja run --cache-for 1s -- mock-command
+  first_output=$output
+
+  sleep 2
+
+  deja run --stale-while-revalidate 1s -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "runs command when stale for longer than window"
+}
+
+@test "run --exclude-pwd" {
+  folder=$(folder_fixture folder)
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  output_without_flag=$output
+
+  deja run --debug --exclude-pwd -- mock-command
+  assert_success_with_mock_command_output_not_matching $output_without_flag "generates different result when --exclude-pwd flag is set"
+
+  output_with_flag=$output
+
+  deja run --debug --exclude-pwd -- mock-command
+  assert_success_with_mock_command_output_matching $output_with_flag "returns previous result when --exclude-pwd flag is set"
+
+  cd $folder
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_not_matching $output_without_flag "returns different result when called without flag from different folder"
+  assert_not_equal "$output" "$output_with_flag" "doesn't return output generated with flag when called without flag"
+
+  deja run --debug --exclude-pwd -- mock-command
+  assert_success_with_mock_command_output_matching $output_with_flag "returns previous result from when called with flag from different folder"
+}
+
+@test "run --ignore-arg" {
+  deja run --ignore-arg '#2' -- mock-command a b
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  first_output=$output
+
+  deja run --ignore-arg '#2' -- mock-command a c
+  assert_success_with_mock_command_output_matching $first_output "returns previous result when ignored argument changes"
+
+  deja run --ignore-arg 2 -- mock-command a d
+  assert_success_with_mock_command_output_not_matching $first_output "matches numbers without # by value"
+
+  second_output=$output
+
+  deja run --ignore-arg 2 -- mock-command a d 2
+  assert_success_with_mock_command_output_matching $second_output "ignores arguments matching a number by value"
+
+  deja run --ignore-arg-with-value --log-file -- mock-command --log-file /tmp/a
+  first_output=$output
+
+  deja run --ignore-arg-with-value --log-file -- mock-command --log-file /tmp/b
+  assert_success_with_mock_command_output_matching $first_output "returns previous result when ignored option value changes"
+
+  deja explain --ignore-arg-with-value --log-file -- mock-command --log-file /tmp/b
+  assert_output --partial "ignored args: --log-file /tmp/b"
+
+  deja run --ignore-arg '#x' -- mock-command
+  assert_handled_failure
+  assert_equal "$stderr" "deja: invalid --ignore-arg '#x', use # and a position from 1, like '#2'"
+}
+
+@test "run --user-key" {
+  deja explain -- mock-command
+  assert_output --partial "user: $(whoami)"
+
+  deja explain --user-key uid -- mock-command
+  assert_output --partial "user: $(id -u)"$'\n'
+
+  deja explain --user-key uid-host -- mock-command
+  assert_output --partial "user: $(id -u)@$(hostname)"
+
+  deja run --user-key uid -- mock-command
+  first_output=$output
+
+  deja run --user-key uid -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns previous result with same identity"
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "returns fresh result with different identity"
+}
+
+@test "run --exclude-args" {
+  deja run --exclude-args -- mock-command --color
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  first_output=$output
+
+  deja run --exclude-args -- mock-command --verbose
+  assert_success_with_mock_command_output_matching $first_output "returns previous result with different arguments"
+
+  deja explain --exclude-args -- mock-command --verbose
+  assert_output --partial "ignored args: all"
+
+  deja run --exclude-args --ignore-arg '#1' -- mock-command
+  assert_failure 2
+}
+
+@test "run --key" {
+  deja run --key release-1 -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  first_output=$output
+
+  deja run --key release-1 --watch-scope other -- mock-command --with-args
+  assert_success_with_mock_command_output_matching $first_output "returns previous result for any command with the same key"
+
+  deja run --key release-2 -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "returns fresh result for a different key"
+
+  deja hash --key release-1 -- mock-command
+  first_hash=$output
+
+  deja hash --key release-1 -- other-command
+  assert_equal "$output" "$first_hash"
+
+  deja explain --key release-1 -- mock-command
+  assert_output --partial "key: release-1 (overridden"
+  refute_output --partial "cmd: "
+
+  deja test --key release-1 -- other-command
+  assert_success
+
+  deja remove --key release-1 -- other-command
+  assert_success
+
+  deja test --key release-1 -- mock-command
+  assert_failure
+}
+
+@test "run --exclude-user" {
+  deja run -- mock-command
+  output_without_flag=$output
+
+  deja run --exclude-user -- mock-command
+  assert_success_with_mock_command_output_not_matching $output_without_flag "generates different result when --exclude-user flag is set"
+
+  output_with_flag=$output
+
+  deja run --exclude-user -- mock-command
+  assert_success_with_mock_command_output_matching $output_with_flag "returns previous result when --exclude-user flag is set"
+
+  command find $DEJA_CACHE -type f -perm 600 | grep .
+
+  deja explain --exclude-user -- mock-command
+  assert_line "user: excluded"
+
+  deja run --exclude-user --share-cache -- mock-command
+  assert_success
+}
+
+@test "run --watch-command-binary" {
+  bin=$(folder_fixture bin)
+  cp test/bin/mock-command $bin/mock-command
+
+  PATH=$bin:$PATH deja run --watch-command-binary -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  first_output=$output
+
+  PATH=$bin:$PATH deja run --watch-command-binary -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns previous result when binary unchanged"
+
+  echo "# changed" >> $bin/mock-command
+  PATH=$bin:$PATH deja run --watch-command-binary -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "returns fresh result when binary changes"
+
+  PATH=$bin:$PATH deja explain --watch-command-binary=metadata -- mock-command
+  assert_output --partial "binary: $bin/mock-command (metadata: "
+
+  deja run --watch-command-binary -- unknown
+  assert_handled_failure
+  assert_equal "$stderr" "deja: unable to resolve command binary: unknown"
+}
+
+@test "run --watch-hostname" {
+  deja run -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  output_without_flag=$output
+
+  deja run --watch-hostname -- mock-command
+  assert_success_with_mock_command_output_not_matching $output_without_flag "generates different result when --watch-hostname flag is set"
+
+  output_with_flag=$output
+
+  deja run --watch-hostname -- mock-command
+  assert_success_with_mock_command_output_matching $output_with_flag "returns previous result when --watch-hostname flag is set"
+
+  deja explain --watch-hostname -- mock-command
+  assert_output --partial "hostname: $(hostname)"
+}
+
+@test "run --watch-platform" {
+  deja run -- mock-command
+  output_without_flag=$output
+
+  deja run --watch-platform -- mock-command
+  assert_success_with_mock_command_output_not_matching $output_without_flag "generates different result when --watch-platform flag is set"
+
+  output_with_flag=$output
+
+  deja run --watch-platform -- mock-command
+  assert_success_with_mock_command_output_matching $output_with_flag "returns previous result when --watch-platform flag is set"
+
+  deja explain --watch-platform -- mock-command
+  assert_output --partial "platform: $(uname -s | tr '[:upper:]' '[:lower:]')-"
+}
+
+@test "run --share-cache (includes platform unless --no-watch-platform)" {
+  deja explain --share-cache -- mock-command
+  assert_output --partial "platform: "
+
+  deja explain --share-cache --no-watch-platform -- mock-command
+  refute_output --partial "platform: "
+}
+
+@test "run --pwd" {
+  folder=$(folder_fixture folder)
+
+  cd $folder
+  deja run -- mock-command
+  first_output=$output
+
+  cd $WORKSPACE
+  deja run --pwd $folder -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns result recorded in given directory"
+
+  deja explain --pwd $folder -- mock-command
+  assert_output --partial "pwd: $folder"
+
+  deja run --pwd $WORKSPACE/missing -- mock-command
+  assert_handled_failure
+  assert_equal "$stderr" "deja: pwd '$WORKSPACE/missing' not found"
+
+  deja run --pwd $folder --exclude-pwd -- mock-command
+  assert_failure 2
+}
+
+@test "run --pwd-from-git-root" {
+  repo=$(folder_fixture repo)
+  mkdir -p $repo/.git $repo/nested/folder
+
+  cd $repo
+  deja run --pwd-from-git-root -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  first_output=$output
+
+  cd $repo/nested/folder
+  deja run --pwd-from-git-root -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns previous result from subdirectory of repository"
+
+  deja explain --pwd-from-git-root -- mock-command
+  assert_output --partial "pwd: $repo"$'\n'
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "returns fresh result without flag"
+}
+
+@test "run --watch-git" {
+  repo=$(folder_fixture repo)
+  cd $repo
+  git init -q
+  echo "a" > file
+  git add file
+  git -c user.name=deja -c user.email=deja@example.com commit -q -m first
+
+  deja run --watch-git=head-dirty -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+
+  first_output=$output
+
+  deja run --watch-git=head-dirty -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns previous result when repository unchanged"
+
+  deja explain --watch-git=head-dirty -- mock-command
+  assert_output --partial "git: $(git rev-parse HEAD) (head-dirty)"$'\n'
+  assert_output --partial "git dirty: false"$'\n'
+
+  deja run --watch-git -- mock-command
+  head_output=$output
+
+  echo "b" > file
+  deja run --watch-git=head-dirty -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "returns fresh result when tracked file changed"
+
+  deja run --watch-git -- mock-command
+  assert_success_with_mock_command_output_matching $head_output "ignores changed files with head mode"
+
+  git -c user.name=deja -c user.email=deja@example.com commit -q -a -m second
+  deja run --watch-git -- mock-command
+  assert_success_with_mock_command_output_not_matching $head_output "returns fresh result after new commit"
+}
+
+@test "run --watch-git (error: not in a git repository)" {
+  # Outside the workspace, which is itself inside a repository
+  cd $BATS_TEST_TMPDIR
+  deja run --watch-git -- mock-command
+  assert_handled_failure "fails outside a git repository"
+  assert_equal "$stderr" "deja: not in a git repository: $PWD"
+
+  deja run --watch-git --watch-git-optional -- mock-command
+  assert_success_with_mock_command_output "runs command with --watch-git-optional"
+}
+
+@test "run (check: private cache files and folders only read and writable by owner)" {
+  deja run -- mock-command
+  command find $DEJA_CACHE -type f -perm 600 | grep .
+  command find $DEJA_CACHE -type d -perm 700 | grep .
+}
+
+@test "run (check: shared cache files and folders read and writable by everyone)" {
+  deja run --share-cache -- mock-command
+  ls -al $DEJA_CACHE
+  command find $DEJA_CACHE -type f -perm 666 | grep .
+  command find $DEJA_CACHE -type d -perm 777 | grep .
+}
+
+@test "run --cache-mode --cache-dir-mode" {
+  deja run --cache-mode 640 --cache-dir-mode 2750 -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+  command find $DEJA_CACHE -type f -perm 640 | grep .
+  command find $DEJA_CACHE -type d -perm 2750 | grep .
+  assert_equal "$(command find $DEJA_CACHE -type f ! -perm 640)" ""
+
+  DEJA_CACHE_MODE=660 deja run --share-cache --cache $WORKSPACE/sqlite/cache.db -- mock-command
+  command find $WORKSPACE/sqlite -type f -perm 660 | grep .
+  command find $WORKSPACE/sqlite -type d -perm 777 | grep .
+}
+
+@test "run --cache-mode (error: invalid mode)" {
+  deja run --cache-mode 9 -- mock-command
+  assert_failure 2
+  assert_regex "$stderr" "invalid mode '9', expected octal permissions like 660 or 2770"
+}
+
+@test "run --encrypt" {
+  head -c 32 /dev/urandom > $WORKSPACE/key
+
+  deja run --encrypt --encryption-key $WORKSPACE/key -- echo secret-value
+  assert_success
+  assert_output "secret-value"
+  refute grep -rq secret-value $DEJA_CACHE
+
+  DEJA_ENCRYPTION_KEY=$WORKSPACE/key deja read -- echo secret-value
+  assert_success
+  assert_output "secret-value"
+
+  deja show --encryption-key $WORKSPACE/key -- echo secret-value
+  assert_success
+  assert_output --partial "echo secret-value"
+
+  deja run -- echo plain-value
+  deja read -- echo plain-value
+  assert_success
+  assert_output "plain-value"
+}
+
+@test "run --encrypt (check: wrong or missing key)" {
+  head -c 32 /dev/urandom > $WORKSPACE/key
+  head -c 32 /dev/urandom > $WORKSPACE/other-key
+  deja run --encrypt --encryption-key $WORKSPACE/key -- echo secret-value
+  entry=$(cat $DEJA_CACHE/*.ron)
+
+  deja read -- echo secret-value
+  assert_failure 1 "missing without a key"
+  assert_output ""
+
+  deja read --encryption-key $WORKSPACE/other-key -- echo secret-value
+  assert_failure 1 "missing with the wrong key"
+
+  deja run -- echo secret-value
+  assert_success "runs the command without a key"
+  assert_output "secret-value"
+  assert_equal "$(cat $DEJA_CACHE/*.ron)" "$entry"
+
+  deja show -- echo secret-value
+  assert_handled_failure "show fails without a key"
+  assert_regex "$stderr" "^deja: unable to decrypt cache entry .*: wrong or missing encryption key$"
+
+  deja explain --encryption-key $WORKSPACE/other-key -- echo secret-value
+  assert_handled_failure "explain fails with the wrong key"
+  assert_regex "$stderr" "^deja: unable to decrypt cache entry .*: wrong or missing encryption key$"
+
+  deja read --encryption-key $WORKSPACE/key -- echo secret-value
+  assert_success
+  assert_output "secret-value"
+
+  deja run --encrypt -- echo secret-value
+  assert_handled_failure "fails to encrypt without a key"
+  assert_equal "$stderr" "deja: --encrypt needs a key, given with --encryption-key or DEJA_ENCRYPTION_KEY"
+
+  echo short > $WORKSPACE/short-key
+  deja run --encrypt --encryption-key $WORKSPACE/short-key -- echo secret-value
+  assert_handled_failure "fails with a short key"
+  assert_equal "$stderr" "deja: encryption key $WORKSPACE/short-key is too short, it needs at least 32 bytes"
+}
+
+@test "run (error: command not found)" {
+  deja run -- unknown
+  assert_handled_failure "fails when unknown command"
+  assert_equal "$stderr" "deja: command not found: unknown"
+}
+
+@test "run (error: permission denied to run command)" {
+  deja run -- ./README.md
+  assert_handled_failure "fails when unknown command"
+  assert_equal "$stderr" "deja: permission denied running command: ./README.md"
+}
+
+@test "run (error: unable to write to cache)" {
+  folder=$(folder_fixture read-only)
+  chmod 500 $folder
+
+  deja run --cache $folder/cache -- mock-command
+  assert_handled_failure "fails when cache can't be created"
+  assert_equal "$stderr" "deja: unable to create cache directory $folder/cache: Permission denied (os error 13)"
+
+  deja run --cache $folder/parent/cache -- mock-command
+  assert_handled_failure "fails when cache parent can't be created"
+  assert_equal "$stderr" "deja: unable to create cache directory $folder/parent: Permission denied (os error 13)"
+
+  deja run --cache $folder -- mock-command
+  assert_handled_failure "fails when cache can't be written to"
+  assert_equal "$stderr" "deja: unable to write to cache directory $folder: Permission denied (os error 13)"
+
+  chmod 700 $folder
+}
+
+@test "run (error: cache is not a directory)" {
+  touch $WORKSPACE/file
+
+  deja run --cache $WORKSPACE/file -- mock-command
+  assert_handled_failure "fails when cache is a file"
+  assert_equal "$stderr" "deja: cache $WORKSPACE/file is not a directory"
+
+  deja run --cache $WORKSPACE/file/cache -- mock-command
+  assert_handled_failure "fails when cache is inside a file"
+  assert_equal "$stderr" "deja: unable to use cache $WORKSPACE/file/cache: $WORKSPACE/file is not a directory"
+}
+
+@test "run --cache-fallback" {
+  touch $WORKSPACE/file
+  export TMPDIR=$(folder_fixture tmpdir)
+  fallback="$TMPDIR/deja-$(id -u)"
+
+  deja run --cache $WORKSPACE/file --cache-fallback tmp -- mock-command
+  assert_success_with_mock_command_output "runs command"
+  assert_equal "$stderr" "deja: warning: cache $WORKSPACE/file is not a directory, using $fallback instead"
+  first_output=$output
+
+  DEJA_CACHE_FALLBACK=tmp deja run --cache $WORKSPACE/file -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns result cached in fallback"
+
+  chmod 755 $fallback
+  deja run --cache $WORKSPACE/file --cache-fallback tmp -- mock-command
+  assert_handled_failure "fails when fallback isn't private"
+  assert_equal "$stderr" "deja: fallback cache $fallback isn't private to the current user"
+}
+
+@test "run (error: unable to read from cache)" {
+  deja run -- mock-command
+
+  chmod 300 $DEJA_CACHE/*.ron
+
+  deja run -- mock-command
+
+  assert_handled_failure "fails when unable to read cache entry"
+  assert_regex "$stderr" "deja: unable to read file from cache $DEJA_CACHE/.*"
+}
+
+@test "run --look-back (error: invalid duration)" {
+  deja run --look-back 1xyz -- mock-command
+  assert_handled_failure "fails when duration can't be parsed"
+  assert_equal "$stderr" "deja: invalid duration '1xyz', use values like 15s, 30m, 3h, 4d etc"
+}
+
+@test "run --record-exit-codes (error: invalid exit codes)" {
+  deja run --record-exit-codes 9-3 -- mock-command
+  assert_failure 2
+  refute_regex "$stderr" "panicked"
+  assert_regex "$stderr" "invalid exit code range '9-3', start is after end"
+}
+
+@test "run --cache-for (error: invalid duration)" {
+  deja run --cache-for 1xyz -- mock-command
+  assert_handled_failure "fails when duration can't be parsed"
+  assert_equal "$stderr" "deja: invalid duration '1xyz', use values like 15s, 30m, 3h, 4d etc"
+}
+
+@test "run --cache-for (error: watch-path not found)" {
+  deja run --watch-path missing -- mock-command
+  assert_handled_failure "fails when --watch-path is missing"
+  assert_equal "$stderr" "deja: watch path 'missing' not found"
+}
+
+@test "run (check: identical output is stored once)" {
+  deja run --watch-scope first -- echo same
+  deja run --watch-scope second -- echo same
+  assert_output "same"
+
+  # One blob for stdout, and one for the empty stderr
+  assert_equal "$(ls $DEJA_CACHE/blobs | grep -c '^[0-9a-f]\{40\}$')" "2"
+
+  deja remove --watch-scope first -- echo same
+  deja read --watch-scope second -- echo same
+  assert_success
+  assert_output "same"
+
+  deja remove --watch-scope second -- echo same
+  assert_equal "$(ls $DEJA_CACHE/blobs | grep -c '^[0-9a-f]\{40\}$')" "0"
+}
+
+@test "run --backend sqlite" {
+  deja run --backend sqlite --cache $DEJA_CACHE/cache.db -- mock-command
+  assert_success_with_mock_command_output "runs command and returns result"
+  first_output=$output
+
+  deja run --cache $DEJA_CACHE/cache.db -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns cached result, detecting backend from path"
+
+  deja list --cache $DEJA_CACHE/cache.db
+  assert_line --index 0 --regexp "mock-command$"
+
+  deja force --keep-history 1 --cache $DEJA_CACHE/cache.db -- mock-command
+  deja history --cache $DEJA_CACHE/cache.db -- mock-command
+  assert_success
+  assert_equal "${#lines[@]}" "2"
+
+  deja remove --cache $DEJA_CACHE/cache.db -- mock-command
+  assert_success
+
+  deja read --cache $DEJA_CACHE/cache.db -- mock-command
+  assert_handled_failure "result removed"
+}
+
+@test "import" {
+  deja run -- mock-command
+  first_output=$output
+
+  deja import --from $DEJA_CACHE --cache $BATS_TEST_TMPDIR/cache.db
+  assert_success
+  assert_output "imported 1 cached results"
+
+  deja read --cache $BATS_TEST_TMPDIR/cache.db -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns imported result"
+}
+
+@test "import (error: cache isn't sqlite)" {
+  deja import --from $DEJA_CACHE
+  assert_handled_failure "fails when importing into a disk cache"
+  assert_equal "$stderr" "deja: import needs a sqlite cache, use --backend sqlite or a cache path ending in .db"
+}
+
+@test "run --cache redis://" {
+  deja run --cache redis://127.0.0.1:1/0 -- mock-command
+  assert_success_with_mock_command_output "runs command when redis is unavailable"
+  first_output=$output
+
+  deja run --cache redis://127.0.0.1:1/0 -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "runs command again, as nothing could be cached"
+}
+
+@test "run --secondary-cache" {
+  secondary=$BATS_TEST_TMPDIR/secondary
+  deja run --cache $secondary -- mock-command
+  first_output=$output
+
+  deja run --secondary-cache $secondary -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns result from secondary cache"
+
+  deja read -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "copies result into primary cache"
+
+  deja run --secondary-cache $secondary -- mock-command --other
+  deja read --cache $secondary -- mock-command --other
+  assert_handled_failure "only records to primary cache"
+
+  deja run --secondary-cache $secondary --populate-secondary -- mock-command --another
+  second_output=$output
+  deja read --cache $secondary -- mock-command --another
+  assert_success_with_mock_command_output_matching $second_output "records to secondary with --populate-secondary"
+
+  deja remove --secondary-cache $secondary -- mock-command
+  deja read --cache $secondary -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "only removes from primary cache"
+
+  deja remove --secondary-cache $secondary --all-layers -- mock-command
+  deja read --secondary-cache $secondary -- mock-command
+  assert_handled_failure "removes from all layers with --all-layers"
+}
+
+@test "push and pull" {
+  remote=$BATS_TEST_TMPDIR/remote
+
+  deja push --remote $remote -- mock-command
+  assert_failure 1
+
+  deja run -- mock-command
+  first_output=$output
+
+  deja push --remote $remote -- mock-command
+  assert_success
+  assert_output --regexp "^pushed [0-9a-f]+$"
+
+  DEJA_REMOTE=$remote deja push -- mock-command
+  assert_success
+  assert_output --regexp "^[0-9a-f]+ already pushed$"
+
+  deja remove -- mock-command
+  deja pull --remote $remote -- mock-command
+  assert_success
+  assert_output --regexp "^pulled [0-9a-f]+$"
+
+  deja read -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "reads pulled result"
+}
+
+@test "run --log-file" {
+  deja hash -- mock-command
+  hash=$output
+
+  deja run --log-file "$WORKSPACE/deja.log" -- mock-command
+  assert_success_with_mock_command_output
+  assert_equal "$stderr" ""
+
+  run cat "$WORKSPACE/deja.log"
+  assert_line --regexp "^[0-9T:.-]+Z \[[0-9]+\] INFO  hash $hash for mock-command"
+  assert_line --regexp "INFO  no fresh result for $hash: missing$"
+  assert_line --regexp "INFO  recording result with exit code 0$"
+  assert_line --regexp "DEBUG wrote entry to "
+
+  DEJA_LOG="$WORKSPACE/deja.log" deja run --log-level info -- mock-command
+  run cat "$WORKSPACE/deja.log"
+  assert_line --regexp "INFO  found fresh result for $hash$"
+  refute_line --regexp "DEBUG replayed result"
+}
+
+@test "run --log-level" {
+  deja run --log-level info -- mock-command
+  assert_success_with_mock_command_output
+  assert_equal "$(echo "$stderr" | grep "recording")" "- recording result with exit code 0"
+  refute_regex "$stderr" "looking for path"
+
+  deja run --log-level warn -- mock-command
+  assert_equal "$stderr" ""
+}
+
+@test "run --disable" {
+  deja run -- mock-command
+  first_output=$output
+
+  deja run --disable --debug -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "runs command rather than returning cached result"
+  assert_regex "$stderr" "caching disabled"
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "doesn't record result when disabled"
+
+  MOCK_COMMAND_STATUS=3 deja run --disable -- mock-command
+  assert_failure 3
+}
+
+@test "run (check: DEJA_DISABLE disables caching)" {
+  deja run -- mock-command
+  first_output=$output
+
+  DEJA_DISABLE=1 deja run -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "runs command rather than returning cached result"
+
+  DEJA_DISABLE=0 deja run -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "is enabled when DEJA_DISABLE is 0"
+
+  DEJA_DISABLE=1 deja read -- mock-command
+  assert_handled_failure "read behaves as a miss"
+
+  DEJA_DISABLE=1 deja test -- mock-command
+  assert_failure 1
+}
+
+@test "run --read-only" {
+  deja run -- mock-command
+  first_output=$output
+  chmod -R a-w $DEJA_CACHE
+  files=$(find $DEJA_CACHE | sort)
+
+  deja run --read-only -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "replays cached result"
+
+  DEJA_READ_ONLY=1 deja run -- mock-command --other
+  assert_success_with_mock_command_output "runs command on a miss"
+  second_output=$output
+
+  deja run --read-only -- mock-command --other
+  assert_success_with_mock_command_output_not_matching $second_output "doesn't record result"
+
+  deja remove --read-only -- mock-command
+  assert_handled_failure "can't remove results"
+  assert_equal "$stderr" "deja: cache $DEJA_CACHE is read-only"
+
+  assert_equal "$(find $DEJA_CACHE | sort)" "$files"
+  chmod -R u+w $DEJA_CACHE
+}
+
+@test "run --read-only (error: unsupported backend)" {
+  deja run --read-only --cache $DEJA_CACHE/cache.db -- mock-command
+  assert_handled_failure "fails with a sqlite cache"
+  assert_equal "$stderr" "deja: --read-only is only supported by the disk backend"
+}
+
+@test "read --wait" {
+  (sleep 1; deja run -- mock-command > /dev/null) &
+
+  deja read --wait 10s -- mock-command
+  assert_success_with_mock_command_output "returns result once cached by another process"
+
+  wait
+}
+
+@test "read --wait (check: exits after waiting when no result cached)" {
+  deja read --wait 1s --cache-miss-exit-code 9 -- mock-command
+  assert_failure 9
+}
+
+@test "read --allow-expired" {
+  deja run --cache-for 1s -- mock-command
+  first_output=$output
+
+  sleep 1
+
+  deja read --allow-expired -- mock-command
+  assert_success
+  assert_equal "$output" "$first_output"
+  assert_equal "$stderr" "deja: replaying expired result cached 1s ago"
+
+  deja read --allow-expired --quiet -- mock-command
+  assert_success
+  assert_equal "$stderr" ""
+
+  deja read --allow-expired-for 1m --quiet -- mock-command
+  assert_success
+  assert_equal "$output" "$first_output"
+
+  sleep 1
+
+  deja read --allow-expired-for 1s -- mock-command
+  assert_handled_failure "fails when result expired for longer than --allow-expired-for"
+
+  deja read -- mock-command
+  assert_handled_failure "fails when result expired"
+
+  deja read --allow-expired -- mock-command
+  assert_handled_failure "expired result removed once found without --allow-expired"
+}
+
+@test "run --cache-for (check: expired results are removed)" {
+  deja run --cache-for 1s -- mock-command
+  sleep 1.1
+
+  deja test -- mock-command
+  assert_failure 2
+  command find $DEJA_CACHE -name '*.ron' | grep .
+
+  deja read -- mock-command
+  assert_failure
+  assert_equal "$(command find $DEJA_CACHE -name '*.ron')" ""
+  assert_equal "$(command find $DEJA_CACHE/blobs -type f ! -name lock ! -name '*.refs')" ""
+}
+
+@test "run (check: corrupt results are set aside and recorded again)" {
+  deja run -- mock-command
+  first_output=$output
+  command find $DEJA_CACHE -maxdepth 1 -name '*.ron' -exec truncate -s 10 {} \;
+
+  deja explain -- mock-command
+  assert_success
+  assert_line --partial "Missing: corrupt entry found in cache"
+  command find $DEJA_CACHE -name '*.ron.corrupt' | grep .
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output "runs command again"
+  assert_not_equal "$output" "$first_output"
+  second_output=$output
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_matching $second_output "records new result"
+}
+
+@test "run (check: corrupt output is set aside and recorded again)" {
+  deja run -- mock-command
+  first_output=$output
+  command find $DEJA_CACHE/blobs -type f -size +0 ! -name lock ! -name '*.refs' -exec truncate -s 20 {} \;
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output "runs command again"
+  assert_not_equal "$output" "$first_output"
+  second_output=$output
+  command find $DEJA_CACHE -name '*.ron.corrupt' | grep .
+  command find $DEJA_CACHE/blobs -name '*.corrupt' | grep .
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_matching $second_output "records new result"
+}
+
+@test "doctor" {
+  deja run -- mock-command
+  deja run -- mock-command second
+
+  deja doctor --verify
+  assert_success
+  assert_output "checked 2 results, found 0 problems, 0 B reclaimable"
+
+  # Same length, so only noticed when the contents are checked
+  blob=$(command find $DEJA_CACHE/blobs -type f -size +0 ! -name lock ! -name '*.refs' | head -1)
+  printf 'X' | dd of="$blob" bs=1 seek=20 conv=notrunc 2>/dev/null
+
+  deja doctor
+  assert_success
+
+  deja doctor --verify
+  assert_failure 1
+  assert_line --index 0 --regexp "^mock-command( second)?: corrupt output $DEJA_CACHE/blobs/[0-9a-f]{40}: contents don't match checksum$"
+  assert_line --index 1 "checked 2 results, found 1 problems, 0 B reclaimable"
+
+  rm "$blob"
+  deja doctor
+  assert_failure 1
+  assert_line --index 0 --regexp "^mock-command( second)?: output is missing$"
+
+  deja doctor --fix
+  assert_success
+  assert_line --index 0 --regexp "^mock-command( second)?: output is missing, set aside$"
+  assert_line --index 1 "checked 2 results, fixed 1 problems, 0 B reclaimed"
+
+  deja doctor
+  assert_success
+  assert_output "checked 1 results, found 0 problems, 0 B reclaimable"
+}
+
+@test "doctor (check: orphaned output, permissions and unreadable entries)" {
+  deja run -- mock-command
+  deja run -- mock-command second
+
+  # Orphaned output is only noticed once it's old enough not to belong to a result being recorded
+  printf 'orphaned' > "$DEJA_CACHE/blobs/0123456789abcdef0123456789abcdef01234567"
+  chmod 600 "$DEJA_CACHE/blobs/0123456789abcdef0123456789abcdef01234567"
+  deja doctor
+  assert_success
+
+  touch -d '2 hours ago' "$DEJA_CACHE/blobs/0123456789abcdef0123456789abcdef01234567"
+  entry=$(command ls $DEJA_CACHE/*.ron | head -1)
+  echo "not an entry" > "$entry"
+  chmod 644 "$(command ls $DEJA_CACHE/*.ron | tail -1)"
+
+  deja doctor
+  assert_failure 1
+  assert_line "orphaned $DEJA_CACHE/blobs/0123456789abcdef0123456789abcdef01234567 (8 B)"
+  assert_line "unreadable entry $entry"
+  assert_line --regexp "^$DEJA_CACHE/[0-9a-f]+.ron has mode 644, expected 600$"
+  assert_line "checked 2 results, found 3 problems, 8 B reclaimable"
+
+  deja doctor --fix
+  assert_success
+  assert_line "orphaned $DEJA_CACHE/blobs/0123456789abcdef0123456789abcdef01234567 (8 B), removed"
+  assert_line "unreadable entry $entry, set aside"
+  assert_line --regexp "^$DEJA_CACHE/[0-9a-f]+.ron has mode 644, expected 600, changed to 600$"
+  assert_line "checked 2 results, fixed 3 problems, 8 B reclaimed"
+  assert [ -f "$entry.corrupt" ]
+
+  deja doctor
+  assert_success
+  assert_output "checked 1 results, found 0 problems, 0 B reclaimable"
+}
+
+@test "doctor (error: needs a disk cache)" {
+  deja doctor --cache "$BATS_TEST_TMPDIR/cache.db"
+  assert_handled_failure
+  assert_equal "$stderr" "deja: doctor needs a disk cache"
+}
+
+@test "read --on-miss-exec" {
+  deja read --on-miss-exec "echo 'pending…'" -- mock-command
+  assert_success
+  assert_output "pending…"
+
+  deja read --on-miss-exec "sh -c 'exit 7'" -- mock-command
+  assert_failure 7
+
+  deja read --on-miss-shell --on-miss-exec "echo one && exit 5" -- mock-command
+  assert_failure 5
+  assert_output "one"
+
+  deja test -- mock-command
+  assert_failure
+
+  deja run -- mock-command
+  first_output=$output
+
+  deja read --on-miss-exec "echo 'pending…'" -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns cached result when present"
+}
+
+@test "read --on-miss-exec (error: command not found)" {
+  deja read --on-miss-exec "unknown-fallback" -- mock-command
+  assert_handled_failure "fails when fallback command not found"
+  assert_equal "$stderr" "deja: command not found: unknown-fallback"
+}
+
+@test "read" {
+  deja read -- mock-command
+  assert_handled_failure "fails when no result cached"
+
+  deja run -- mock-command
+  first_output=$output
+
+  deja read -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "returns cached result"
+}
+
+@test "read --cache-miss-exit-code" {
+  deja read --cache-miss-exit-code 123 -- mock-command
+  assert_handled_failure "fails when no result cached"
+  assert_equal "$status" "123" "returns exit code specified when no result cached"
+}
+
+@test "force" {
+  deja run -- mock-command
+
+  first_output=$output
+
+  deja force -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "forces new result"
+
+  forced_output=$output
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_matching $forced_output "forced result now cached"
+}
+
+@test "force (check: returns exit status of failing command)" {
+  deja run -- mock-command
+  first_output=$output
+
+  MOCK_COMMAND_STATUS=3 deja force -- mock-command
+  assert_failure 3
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "failing result not cached"
+
+  MOCK_COMMAND_STATUS=3 deja force --record-exit-codes 3 -- mock-command
+  assert_failure 3
+  failed_output=$output
+
+  deja run -- mock-command
+  assert_failure 3
+  assert_equal "$output" "$failed_output"
+}
+
+@test "force --exit-zero" {
+  MOCK_COMMAND_STATUS=3 deja force --exit-zero -- mock-command
+  assert_success
+
+  MOCK_COMMAND_STATUS=3 deja force --exit-zero --record-exit-codes 3 -- mock-command
+  assert_success
+}
+
+@test "show" {
+  deja show -- mock-command
+  assert_failure 1
+
+  deja run --cache-for 1h -- mock-command
+
+  deja show -- mock-command
+  assert_success
+  assert_line --index 1 "command: mock-command"
+  assert_line --index 2 --regexp "^created: [0-9T:-]+Z \([0-9]+s ago\)$"
+  assert_line --index 3 --regexp "^expires: [0-9T:-]+Z \(in (1h|59m [0-9]+s)\)$"
+  assert_line --index 4 "status: exit code 0"
+  assert_line --index 5 --regexp "^duration: [0-9]+\.[0-9]s$"
+  assert_line --index 6 --regexp "^cpu time: [0-9]+\.[0-9]s user, [0-9]+\.[0-9]s sys$"
+  assert_line --index 7 --regexp "^max rss: [0-9]+\.[0-9] MiB$"
+}
+
+@test "show (check: entries recorded without resource usage)" {
+  deja run -- mock-command
+  # As recorded by older versions, without a hash of the record to check it against
+  sed -i -e '/usage: Some((/,/^        )),/d' -e 's/,record:Some("[0-9a-f]*")//' $DEJA_CACHE/*.ron
+
+  deja show -- mock-command
+  assert_success
+  assert_line "cpu time: unknown"
+  assert_line "max rss: unknown"
+}
+
+@test "show (check: entries recorded without a duration)" {
+  deja run -- mock-command
+  # As recorded by older versions, without a hash of the record to check it against
+  sed -i -e '/duration: Some((/,/)),/d' -e 's/,record:Some("[0-9a-f]*")//' $DEJA_CACHE/*.ron
+
+  deja show -- mock-command
+  assert_success
+  assert_line "duration: unknown"
+
+  deja explain -- mock-command
+  assert_line "entry recorded in unknown"
+}
+
+@test "run --keep-history" {
+  deja force --keep-history 2 -- mock-command
+  first_output=$output
+  deja force --keep-history 2 -- mock-command
+  second_output=$output
+  deja force --keep-history 2 -- mock-command
+  third_output=$output
+  deja force --keep-history 2 -- mock-command
+  fourth_output=$output
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_matching $fourth_output "returns current result"
+
+  deja history -- mock-command
+  assert_success
+  assert_equal "${#lines[@]}" "3"
+  assert_line --index 0 --regexp "^0  "
+  assert_line --index 2 --regexp "^2  "
+
+  deja diff -- mock-command
+  assert_failure 1
+  assert_output "--- generation 1
++++ generation 0
+@@ -1,1 +1,1 @@
+-$third_output
++$fourth_output"
+
+  deja diff --generations 2..1 -- mock-command
+  assert_failure 1
+  assert_line "-$second_output"
+
+  deja diff --generations 0..0 -- mock-command
+  assert_success
+  assert_output ""
+
+  deja diff --generations 3..0 -- mock-command
+  assert_handled_failure "fails when generation missing"
+
+  deja show --generation 2 -- mock-command
+  assert_success
+
+  # One blob for each stdout, and one for the empty stderr they share
+  assert_equal "$(ls $DEJA_CACHE/blobs | grep -c '^[0-9a-f]\{40\}$')" "4"
+
+  deja remove -- mock-command
+  deja history -- mock-command
+  assert_failure 1
+  assert_equal "$(ls $DEJA_CACHE | grep -c '\.ron$')" "0"
+  assert_equal "$(ls $DEJA_CACHE/blobs | grep -c '^[0-9a-f]\{40\}$')" "0"
+
+  deja list
+  assert_output ""
+}
+
+@test "diff --fresh" {
+  deja diff --fresh -- mock-command
+  assert_failure 2 "fails when nothing is cached"
+
+  deja run -- mock-command
+  first_output=$output
+
+  deja diff --fresh -- mock-command
+  assert_failure 1
+  assert_output --regexp "^--- cached stdout
+\+\+\+ fresh stdout
+@@ -1,1 \+1,1 @@
+-$first_output
+\+[A-z0-9-]+$"
+
+  deja diff --fresh --ignore-matching-lines '-' -- mock-command
+  assert_success
+  assert_output ""
+
+  set_next_mock_command_return_status 3
+  deja diff --fresh --ignore-matching-lines '-' -- mock-command
+  assert_failure 1
+  assert_output "exit status differs: cached 0, fresh 3"
+
+  deja run -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "doesn't record the fresh result"
+
+  deja diff --fresh --update -- mock-command
+  assert_failure 1
+  deja run -- mock-command
+  assert_success_with_mock_command_output_not_matching $first_output "records the fresh result with --update"
+}
+
+@test "list" {
+  deja list
+  assert_success
+  assert_output ""
+
+  deja run -- sh -c "sleep 0.2; echo first"
+  deja run -- mock-command
+
+  deja list
+  assert_success
+  assert_line --index 0 --regexp "^[0-9a-f]{12}  [0-9T:-]+Z  +[0-9]+s ago      0\.[0-9]s  exit code 0           sh -c sleep 0.2; echo first$"
+  assert_line --index 1 --regexp "mock-command$"
+}
+
+@test "list --long" {
+  deja run -- mock-command
+
+  deja list --long
+  assert_success
+  assert_line --index 0 --regexp "^[0-9a-f]{12}  [0-9T:-]+Z +[0-9]+s ago +[0-9.]+s +[0-9.]+s user, [0-9.]+s sys +[0-9.]+ MiB  exit code 0 +mock-command$"
+}
+
+@test "gc" {
+  deja run --cache-for 1s -- mock-command one
+  deja run -- mock-command two
+  sleep 1.1
+
+  deja gc --dry-run
+  assert_success
+  assert_line --index 0 "would remove mock-command one (expired)"
+  assert_line --index 1 --regexp "^would remove 1 results, [0-9.]+ (B|KiB)$"
+
+  deja gc
+  assert_success
+  assert_line --index 0 "removed mock-command one (expired)"
+
+  deja test -- mock-command two
+  assert_success "unexpired results are kept"
+
+  deja gc --older-than 1h --created-before "2000-01-01T00:00:00Z"
+  assert_output "removed 0 results, 0 B"
+
+  deja gc --older-than 0s
+  assert_line --index 0 "removed mock-command two (too old)"
+
+  deja gc --older-than soon
+  assert_handled_failure
+  assert_equal "$stderr" "deja: invalid duration 'soon', use values like 15s, 30m, 3h, 4d etc"
+}
+
+@test "gc (check: shared and missing output)" {
+  deja run -- echo same
+  deja run -- sh -c "echo same"
+
+  deja gc --older-than 0s
+  assert_success
+  assert_line --index 2 "removed 2 results, 21 B"
+
+  deja run -- echo missing
+  rm $DEJA_CACHE/blobs/*
+
+  deja gc --older-than 0s
+  assert_success
+  assert_line --index 0 "removed echo missing (too old)"
+  assert_line --index 1 "removed 1 results, 0 B"
+}
+
+@test "gc (check: lock files)" {
+  deja run -- mock-command recorded
+  deja run -- sh -c "exit 1"
+  assert_equal "$(ls $DEJA_CACHE | grep -c '^[0-9a-f]*\.lock$')" "2"
+
+  deja gc --dry-run
+  assert_equal "$(ls $DEJA_CACHE | grep -c '^[0-9a-f]*\.lock$')" "2"
+
+  deja gc
+  assert_success
+  assert_equal "$(ls $DEJA_CACHE | grep -c '^[0-9a-f]*\.lock$')" "0"
+
+  deja run -- mock-command recorded
+  deja remove -- mock-command recorded
+  assert_equal "$(ls $DEJA_CACHE | grep -c '^[0-9a-f]*\.lock$')" "0"
+}
+
+@test "gc (check: retention policy)" {
+  deja run --tag keep -- mock-command one
+  deja run -- mock-command two
+  deja run -- echo three
+  mkdir -p "$DEJA_CACHE"
+  printf 'keep = "1h"\n\n[rules.mock]\ncommand = "mock-command *"\nkeep = "1s"\n\n[rules.tagged]\ntags = ["keep"]\nkeep = "forever"\n' > "$DEJA_CACHE/retention.toml"
+  sleep 1.1
+
+  deja gc --verbose
+  assert_success
+  assert_line --index 0 "removed mock-command one (older than 1s, by rule 'mock' in $DEJA_CACHE/retention.toml)"
+  assert_line --index 1 "removed mock-command two (older than 1s, by rule 'mock' in $DEJA_CACHE/retention.toml)"
+  assert_line --index 2 --regexp "^removed 2 results, "
+
+  deja test -- echo three
+  assert_success "the default keep applies to other results"
+}
+
+@test "gc (check: retention policy max-size)" {
+  deja run -- echo one
+  deja run -- echo two
+  mkdir -p "$DEJA_CACHE"
+  printf 'max-size = 30\n' > "$DEJA_CACHE/retention.toml"
+
+  deja gc --verbose
+  assert_success
+  assert_line --index 0 "removed echo one (over max-size 30 B, by max-size in $DEJA_CACHE/retention.toml)"
+  assert_line --index 1 --regexp "^removed 1 results, [0-9]+ B$"
+
+  deja test -- echo two
+  assert_success "newer results are kept"
+}
+
+@test "gc (check: retention policy max-size counts shared output once)" {
+  deja run -- echo same
+  deja run -- sh -c "echo same"
+  deja run -- echo missing
+  rm $(grep -l missing $DEJA_CACHE/blobs/*)
+  mkdir -p "$DEJA_CACHE"
+  printf 'max-size = 30\n' > "$DEJA_CACHE/retention.toml"
+
+  deja gc
+  assert_success
+  assert_output "removed 0 results, 0 B"
+}
+
+@test "gc --ignore-policy" {
+  deja run -- mock-command
+  mkdir -p "$DEJA_CACHE"
+  printf '[rules.all]\ncommand = "*"\nkeep = "0s"\n' > "$DEJA_CACHE/retention.toml"
+
+  deja gc --ignore-policy
+  assert_success
+  assert_output "removed 0 results, 0 B"
+
+  deja gc
+  assert_line --index 0 "removed mock-command (older than 0s)"
+}
+
+@test "gc (error: invalid retention policy)" {
+  mkdir -p "$DEJA_CACHE"
+  printf '[rules.terraform]\ncommand = "terraform *"\nkeep = "a week"\n' > "$DEJA_CACHE/retention.toml"
+
+  deja gc
+  assert_handled_failure
+  assert_equal "$stderr" "deja: rule 'terraform' in '$DEJA_CACHE/retention.toml': invalid duration 'a week', use values like 12h, 7d or forever"
+
+  deja gc --ignore-policy
+  assert_success
+}
+
+@test "stats" {
+  deja stats
+  assert_success
+  assert_output --regexp "^total +0 hits \( +0\.0%\) +0 misses  0s saved$"
+
+  deja run -- mock-command
+  deja run -- mock-command
+  deja read -- mock-command other
+
+  deja stats
+  assert_success
+  assert_line --index 0 --regexp "^total +1 hits \( 33\.3%\) +2 misses  [0-9a-z ]+ saved$"
+  assert_line --index 1 --regexp "^[0-9]{4}-[0-9]{2}-[0-9]{2} +1 hits \( 33\.3%\) +2 misses  [0-9a-z ]+ saved$"
+
+  deja stats --json
+  assert_output --regexp '^\{"hits":1,"misses":2,"hit_rate":33\.3+[0-9]*,"saved_secs":[0-9.]+,"days":\[\{"date":"[0-9-]+",'
+
+  deja run --read-only -- mock-command
+  deja stats --json
+  assert_output --regexp '^\{"hits":1,'
+}
+
+@test "stats --reset" {
+  deja run -- mock-command
+
+  deja stats --reset
+  assert_handled_failure
+  assert_equal "$stderr" "deja: stats --reset needs stdin to be a terminal to confirm, or --yes"
+
+  deja stats --reset --yes
+  assert_success
+  assert_output "reset counters"
+
+  deja stats
+  assert_output --regexp "^total +0 hits"
+}
+
+@test "pin" {
+  deja run --cache-for 1s -- mock-command
+  first_output=$output
+  deja pin -- mock-command
+  assert_success
+  sleep 1.1
+
+  deja run --cache-for 1s -- mock-command
+  assert_success_with_mock_command_output_matching $first_output "pinned results don't expire"
+
+  deja list
+  assert_output --regexp "exit code 0, pinned +mock-command$"
+
+  deja gc --older-than 0s
+  assert_output "removed 0 results, 0 B"
+
+  deja gc --include-pinned
+  assert_line --index 0 "removed mock-command (expired)"
+}
+
+@test "unpin" {
+  deja run --cache-for 1s -- mock-command
+  deja pin -- mock-command
+  deja unpin -- mock-command
+  assert_success
+  sleep 1.1
+
+  deja test -- mock-command
+  assert_failure 2
+
+  deja gc
+  assert_line --index 0 "removed mock-command (expired)"
+}
+
+@test "pin (error: no cached result)" {
+  deja pin -- mock-command
+  assert_failure 1
+  assert_regex "$stderr" "^deja: no entry found in cache for [0-9a-f]+$"
+}
+
+@test "run --tag" {
+  deja run --tag infra --tag prod -- mock-command one
+  deja run --tag infra -- mock-command two
+  deja run -- mock-command three
+
+  deja list
+  assert_success
+  assert_line --index 0 --regexp "mock-command one  \[infra, prod\]$"
+  assert_line --index 1 --regexp "mock-command two  \[infra\]$"
+  assert_line --index 2 --regexp "mock-command three$"
+
+  deja list --tag infra --tag prod
+  assert_success
+  assert_equal "${#lines[@]}" 1
+  assert_line --index 0 --regexp "mock-command one"
+
+  deja list --tag prod --tag missing --any-tag
+  assert_equal "${#lines[@]}" 1
+
+  deja show -- mock-command one
+  assert_line "tags: infra, prod"
+
+  deja remove --tag infra
+  assert_success
+  assert_line --index 0 "removed mock-command one"
+  assert_line --index 1 "removed mock-command two"
+
+  deja remove --tag infra
+  assert_handled_failure "nothing left to remove"
+
+  deja test -- mock-command three
+  assert_success "untagged results are kept"
+}
+
+@test "run --tag (error: invalid tag)" {
+  deja run --tag "a b" -- mock-command
+  assert_failure 2
+  assert_regex "$stderr" "invalid tag 'a b', tags can't be empty or contain whitespace or commas"
+
+  deja remove --any-tag
+  assert_failure 2 "--any-tag needs --tag"
+}
+
+@test "run --id" {
+  deja run --id JIRA-123 --tag infra -- mock-command one
+  deja run --id build-7 -- mock-command two
+  deja run -- mock-command three
+
+  deja list
+  assert_success
+  assert_line --index 0 --regexp "mock-command one  \(id: JIRA-123\)  \[infra\]$"
+  assert_line --index 1 --regexp "mock-command two  \(id: build-7\)$"
+  assert_line --index 2 --regexp "mock-command three$"
+
+  deja show -- mock-command one
+  assert_line "id: JIRA-123"
+
+  deja explain -- mock-command one
+  assert_line "entry id: JIRA-123"
+
+  deja force --id JIRA-124 -- mock-command one
+  deja show -- mock-command one
+  assert_line "id: JIRA-124"
+
+  deja list --id 'jira-*'
+  assert_success
+  assert_equal "${#lines[@]}" 1
+  assert_line --index 0 --regexp "mock-command one"
+
+  deja remove --id 'JIRA-*'
+  assert_success
+  assert_output "removed mock-command one"
+
+  deja test -- mock-command two
+  assert_success "results with other ids are kept"
+}
+
+@test "run --id (error: invalid id)" {
+  deja run --id "$(printf 'a%.0s' {1..129})" -- mock-command
+  assert_failure 2
+  assert_regex "$stderr" "id is 129 characters long, ids can be at most 128 characters"
+
+  deja run --id "" -- mock-command
+  assert_failure 2
+  assert_regex "$stderr" "invalid id '', ids can't be empty or contain control characters"
+}
+
+@test "run --record-env" {
+  export MY_VAR=value
+  export MY_TOKEN=hunter2
+
+  deja run --record-env --watch-env MY_VAR -- mock-command
+  first_output=$output
+
+  deja show --watch-env MY_VAR -- mock-command
+  assert_line "  MY_VAR=value"
+  assert_line "  PATH=$PATH"
+  refute_output --partial "MY_TOKEN"
+
+  deja run --record-env='MY_*' -- mock-command
+  deja show -- mock-command
+  assert_line "  MY_VAR=value"
+  assert_line "  MY_TOKEN=[redacted]"
+
+  deja force --record-env='MY_*' --redact-env 'my_var' -- mock-command
+  second_output=$output
+
+  deja run --record-env -- mock-command
+  assert_success_with_mock_command_output_matching $second_output "doesn't affect cache key"
+
+  deja explain -- mock-command
+  assert_output --partial "  MY_VAR=[redacted]"
+}
+
+@test "remove" {
+  deja run -- mock-command
+
+  first_output=$output
 
   deja remove -- mock-command
   deja run -- mock-command
@@ -288,6 +2466,23 @@ setup() {
   assert_handled_failure "removing result that doesn't exist fails"
 }
 
+@test "remove (check: --interactive needs a terminal)" {
+  deja run -- mock-command
+
+  deja remove --interactive < /dev/null
+  assert_failure 1
+  assert_regex "$stderr" "remove --interactive needs stdin to be a terminal"
+
+  deja test -- mock-command
+  assert_success "nothing is removed"
+
+  deja remove --matching mock -- mock-command
+  assert_failure 2 "--matching is only for --interactive"
+
+  deja remove -i -- mock-command
+  assert_failure 2 "--interactive doesn't take a command"
+}
+
 @test "test" {
   deja test -- mock-command
   assert_handled_failure "fails when no result cached"
@@ -303,9 +2498,178 @@ setup() {
   assert_handled_failure "fails when result removed"
 }
 
+@test "test (check: exit status explains why no result is usable)" {
+  deja test -- mock-command
+  assert_failure 1
+
+  deja run --cache-for 1s -- mock-command
+  deja test -- mock-command
+  assert_success
+
+  sleep 1
+
+  deja test -- mock-command
+  assert_failure 2
+
+  deja run -- mock-command
+  deja test --look-back 1s -- mock-command
+  assert_success
+
+  sleep 1
+
+  deja test --look-back 1s -- mock-command
+  assert_failure 3
+}
+
+@test "test --exit-with-status" {
+  deja test --exit-with-status -- mock-command
+  assert_failure 125
+
+  deja test --exit-with-status --cache-miss-exit-code 99 -- mock-command
+  assert_failure 99
+
+  deja run -- mock-command
+  deja test --exit-with-status -- mock-command
+  assert_success
+
+  deja run --look-back 1s -- mock-command
+  sleep 1
+  deja test --exit-with-status --look-back 1s -- mock-command
+  assert_failure 125
+}
+
+@test "test --exit-with-status (check: cached failure)" {
+  set_next_mock_command_return_status 3
+  deja run --record-exit-codes 0,3 -- mock-command
+  assert_failure 3
+
+  deja test --exit-with-status -- mock-command
+  assert_failure 3
+
+  deja test -- mock-command
+  assert_success
+}
+
+@test "test --cache-miss-exit-code (error: requires --exit-with-status)" {
+  deja test --cache-miss-exit-code 99 -- mock-command
+  assert_handled_failure
+  assert_equal "$status" 2
+}
+
 @test "explain" {
   deja explain -- mock-command
   assert_success
+  assert_line --index 0 --regexp "^format: [0-9]+$"
+}
+
+@test "config (options from .deja.toml are defaults)" {
+  cd $WORKSPACE
+  deja hash --watch-env CI --exclude-pwd -- mock-command
+  flag_hash=$output
+
+  mkdir nested
+  printf 'watch-env = ["CI"]\nexclude_pwd = true\n' > .deja.toml
+  cd nested
+  deja hash -- mock-command
+  assert_success
+  assert_output "$flag_hash"
+
+  deja explain -- mock-command
+  assert_line "options from config:"
+  assert_line "  watch-env = [\"CI\"] ($WORKSPACE/.deja.toml)"
+  assert_line "  exclude-pwd = true ($WORKSPACE/.deja.toml)"
+
+  deja hash --watch-env OTHER -- mock-command
+  assert_not_equal "$output" "$flag_hash"
+  DEJA_IGNORE_PWD=false deja hash -- mock-command
+  assert_not_equal "$output" "$flag_hash"
+}
+
+@test "config (project options take precedence over the user's)" {
+  cd $WORKSPACE
+  mkdir -p "$XDG_CONFIG_HOME/deja"
+  printf 'watch-scope = "user"\nwatch-env = ["CI"]\n' > "$XDG_CONFIG_HOME/deja/config.toml"
+  printf 'watch-scope = "project"\n' > .deja.toml
+
+  deja hash -- mock-command
+  config_hash=$output
+  deja hash --watch-scope project --watch-env CI -- mock-command
+  assert_equal "$output" "$config_hash"
+}
+
+@test "config (warning: unknown option)" {
+  cd $WORKSPACE
+  printf 'cache-four = "1h"\n' > .deja.toml
+  deja run -- mock-command
+  assert_success
+  assert_equal "$stderr" "deja: warning: unknown option 'cache-four' in $WORKSPACE/.deja.toml"
+}
+
+@test "config (error: invalid file)" {
+  cd $WORKSPACE
+  printf 'cache-for = 1h\n' > .deja.toml
+  deja run -- mock-command
+  assert_failure
+  assert_equal "$stderr" "deja: unable to parse '$WORKSPACE/.deja.toml': line 1, column 14: expected newline, \`#\`"
+
+  printf 'backend = "floppy"\n' > .deja.toml
+  deja run -- mock-command
+  assert_failure
+  assert_equal "$stderr" "deja: invalid value \"floppy\" for 'backend' in $WORKSPACE/.deja.toml"
+
+  printf 'exclude-pwd = "yes"\n' > .deja.toml
+  deja run -- mock-command
+  assert_failure
+  assert_equal "$stderr" "deja: invalid value \"yes\" for 'exclude-pwd' in $WORKSPACE/.deja.toml, expected true or false"
+}
+
+@test "presets" {
+  cd $WORKSPACE
+  printf '[presets.mock]\ncommand = ["mock-command", "a"]\nwatch-env = ["CI"]\nexclude-pwd = true\n' > .deja.toml
+
+  deja hash --watch-env CI --exclude-pwd -- mock-command a b
+  flag_hash=$output
+  deja hash @mock b
+  assert_success
+  assert_output "$flag_hash"
+
+  deja run @mock b
+  assert_success_with_mock_command_output
+  first_output=$output
+  deja run @mock b
+  assert_equal "$output" "$first_output"
+
+  deja explain @mock b
+  assert_line "cmd: mock-command a b"
+  assert_line "  watch-env = [\"CI\"] ($WORKSPACE/.deja.toml)"
+
+  deja hash --watch-env OTHER @mock b
+  assert_not_equal "$output" "$flag_hash"
+}
+
+@test "presets (check: listed by list-presets and run @)" {
+  cd $WORKSPACE
+  printf '[presets.pipe]\ncommand = "echo a | tr a b"\nshell = true\n\n[presets.echo]\ncommand = "echo"\n' > .deja.toml
+
+  deja list-presets
+  assert_success
+  assert_output "@pipe  'echo a | tr a b'
+       shell = true
+@echo  echo"
+
+  deja run @
+  assert_success
+  assert_line "@echo  echo"
+
+  deja run @pipe
+  assert_output "b"
+}
+
+@test "presets (error: unknown preset)" {
+  cd $WORKSPACE
+  deja run @missing
+  assert_failure
+  assert_equal "$stderr" "deja: unknown preset '@missing', see deja list-presets"
 }
 
 @test "hash" {
@@ -321,6 +2685,197 @@ setup() {
   assert_not_equal $first_output $output "returns different hash with different options"
 }
 
+@test "hash --components" {
+  deja hash -- mock-command
+  hash=$output
+
+  deja hash --components -- mock-command
+  assert_success
+  assert_line --index 0 --regexp "^format_version: [0-9]+$"
+  assert_line --index 1 --regexp "^format: [0-9a-f]{64}$"
+  assert_line --index 2 --regexp "^cmd: [0-9a-f]{64}$"
+  assert_line "hash: $hash"
+
+  deja hash --components --watch-path src -- mock-command
+  assert_line --regexp "^watch_path $PWD/src: [0-9a-f]{64}$"
+}
+
+@test "hash --stdin" {
+  deja hash --watch-path src -- mock-command one
+  first_hash=$output
+  deja hash --watch-path src -- mock-command "two words"
+  second_hash=$output
+
+  printf '# Commands\nmock-command one\n\nmock-command "two words"\n' > "$WORKSPACE/commands.txt"
+  deja hash --stdin --watch-path src < "$WORKSPACE/commands.txt"
+  assert_success
+  assert_equal "${#lines[@]}" 2
+  assert_line --index 0 "$first_hash	mock-command one"
+  assert_line --index 1 "$second_hash	mock-command \"two words\""
+}
+
+@test "hash --stdin (error: malformed line)" {
+  deja hash -- mock-command three
+  hash=$output
+
+  printf "mock-command 'unterminated\nmock-command three\n" > "$WORKSPACE/commands.txt"
+  deja hash --stdin < "$WORKSPACE/commands.txt"
+  assert_failure 1
+  assert_equal "$stderr" "deja: line 1: missing closing quote"
+  assert_output "$hash	mock-command three"
+}
+
+check_init() {
+  local shell=$1 source=$2
+  if ! command -v "$shell" > /dev/null; then
+    skip "$shell is not installed"
+  fi
+
+  deja init "$shell" --alias mock-command -- --cache-for 1h
+  assert_success
+  echo "$output" > "$WORKSPACE/init"
+
+  # Arguments are passed through intact, and exit statuses returned
+  run "$shell" -c "$source $WORKSPACE/init; deja-memo sh -c 'echo \"\$1\"; exit 3' sh \"two words\""
+  assert_failure 3
+  assert_output "two words"
+
+  run "$shell" -c "$source $WORKSPACE/init; mock-command"
+  assert_success
+  first_output=$output
+
+  run "$shell" -c "$source $WORKSPACE/init; mock-command"
+  assert_success_with_mock_command_output_matching "$first_output" "replays cached result"
+}
+
+@test "init bash" {
+  check_init bash source
+}
+
+@test "init zsh" {
+  check_init zsh source
+}
+
+@test "init fish" {
+  check_init fish source
+}
+
+@test "init (error: invalid alias)" {
+  deja init bash --alias "rm -rf"
+  assert_handled_failure
+  assert_equal "$stderr" "deja: invalid alias 'rm -rf', aliases must be command names"
+}
+
+@test "wrap" {
+  deja wrap --output-dir "$WORKSPACE/bin" wrapped --cache-for 1h -- mock-command
+  assert_success
+  assert_output "$WORKSPACE/bin/wrapped"
+  assert_equal "$stderr" "deja: warning: '$WORKSPACE/bin' isn't on PATH"
+
+  run --separate-stderr "$WORKSPACE/bin/wrapped" arg
+  assert_success_with_mock_command_output
+  first_output=$output
+
+  run --separate-stderr "$WORKSPACE/bin/wrapped" arg
+  assert_success_with_mock_command_output_matching $first_output "returns previous result"
+
+  deja run -- mock-command arg
+  assert_success_with_mock_command_output_matching $first_output "caches like deja run"
+
+  MOCK_COMMAND_STATUS=3 run --separate-stderr "$WORKSPACE/bin/wrapped" other
+  assert_failure 3
+}
+
+@test "wrap (check: a wrapper shadowing the command it wraps runs the real command)" {
+  deja wrap --output-dir "$WORKSPACE/bin" mock-command -- mock-command
+  assert_success
+
+  PATH="$WORKSPACE/bin:$PATH" run --separate-stderr timeout 10 mock-command
+  assert_success_with_mock_command_output
+}
+
+@test "wrap (error: existing file)" {
+  deja wrap --output-dir "$WORKSPACE/bin" wrapped -- mock-command
+  deja wrap --output-dir "$WORKSPACE/bin" wrapped -- mock-command
+  assert_handled_failure
+  assert_equal "$stderr" "deja: '$WORKSPACE/bin/wrapped' already exists, use --force to replace it"
+
+  deja wrap --output-dir "$WORKSPACE/bin" --force wrapped -- mock-command
+  assert_success
+}
+
+@test "wrap (error: invalid options)" {
+  deja wrap --output-dir "$WORKSPACE/bin" wrapped --unknown -- mock-command
+  assert_handled_failure
+  refute [ -e "$WORKSPACE/bin/wrapped" ]
+}
+
+@test "warm" {
+  printf '# Slow commands\nmock-command one\n\nsh -c "echo broken >&2; exit 3"\n' > "$WORKSPACE/commands.txt"
+  deja run -- mock-command one
+  first_output=$output
+
+  deja warm --file "$WORKSPACE/commands.txt"
+  assert_failure 1
+  assert_line --index 0 "skipped   mock-command one (fresh)"
+  assert_line --index 1 'failed    sh -c "echo broken >&2; exit 3" (exit code 3)'
+  assert_line --index 2 "0 recorded, 1 skipped, 1 failed"
+  assert_equal "$stderr" '[sh -c "echo broken >&2; exit 3"] broken'
+
+  deja run -- mock-command one
+  assert_success_with_mock_command_output_matching $first_output "fresh results aren't replaced"
+}
+
+@test "warm (check: toml manifest with --jobs)" {
+  cat > "$WORKSPACE/commands.toml" <<'TOML'
+cache-for = "1h"
+
+[commands.first]
+command = ["mock-command", "first"]
+
+[commands.second]
+command = ["mock-command", "second"]
+record-exit-codes = "3"
+TOML
+  MOCK_COMMAND_STATUS=3 deja warm --jobs 2 --file "$WORKSPACE/commands.toml"
+  assert_failure 1
+  assert_line "failed    first (exit code 3)"
+  assert_line "failed    second (exit code 3)"
+  assert_line "0 recorded, 0 skipped, 2 failed"
+
+  deja test --cache-for 1h -- mock-command second
+  assert_success
+  deja test --cache-for 1h -- mock-command first
+  assert_failure
+}
+
+@test "warm (error: invalid manifest)" {
+  echo "echo 'unterminated" > "$WORKSPACE/commands.txt"
+  deja warm --file "$WORKSPACE/commands.txt"
+  assert_handled_failure
+  assert_equal "$stderr" "deja: unable to parse '$WORKSPACE/commands.txt': line 1: missing closing quote"
+}
+
+@test "generate-man" {
+  deja generate-man --output-dir "$WORKSPACE/man"
+  assert_success
+  assert [ -f "$WORKSPACE/man/deja.1" ]
+  assert [ -f "$WORKSPACE/man/deja-run.1" ]
+  refute [ -f "$WORKSPACE/man/deja-explain.1" ]
+  run grep -c "cache\\\\-for" "$WORKSPACE/man/deja-run.1"
+  assert_success
+
+  # Output doesn't depend on the machine it's generated on
+  HOME=/elsewhere XDG_CACHE_HOME=/elsewhere deja generate-man --output-dir "$WORKSPACE/man2"
+  run diff -r "$WORKSPACE/man" "$WORKSPACE/man2"
+  assert_success
+
+  deja generate-man --format markdown --output-dir "$WORKSPACE/docs"
+  assert_success
+  run grep "^## \`deja run\`" "$WORKSPACE/docs/deja.md"
+  assert_success
+}
+
 @test "completions --shell bash" {
   deja completions --shell bash
   assert_success
@@ -335,3 +2890,38 @@ setup() {
   deja completions --shell zsh
   assert_success
 }
+
+@test "completions (check: cached commands are completed)" {
+  deja run -- mock-command first
+  deja run -- mock-command second
+  deja run --shell 'mock-command third'
+
+  deja __complete -- remove mock
+  assert_success
+  assert_output "mock-command"
+
+  deja __complete -- show mock-command ""
+  assert_output "$(printf 'first\nsecond')"
+
+  deja __complete -- test mock-command s
+  assert_output "second"
+
+  deja __complete -- remove --shell ""
+  assert_output "mock-command third"
+
+  deja __complete -- remove --cache "$WORKSPACE/elsewhere" ""
+  assert_output ""
+
+  deja __complete -- remove --ca
+  assert_output ""
+}
+
+@test "completions --shell bash (check: cached commands are completed)" {
+  deja run -- mock-command first
+  deja completions --shell bash
+  echo "$output" > "$WORKSPACE/completions.bash"
+
+  PATH="$(dirname "$deja_bin"):$PATH" run bash -c "source '$WORKSPACE/completions.bash' && COMP_WORDS=(deja remove mock-command '') && COMP_CWORD=3 && _deja && echo \"\${COMPREPLY[@]}\""
+  assert_success
+  assert_output "first"
+}